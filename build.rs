@@ -0,0 +1,15 @@
+//! Embeds the git commit the binary was built from, when building from a
+//! checkout
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    if let Ok(output) = Command::new("git").args(["rev-parse", "HEAD"]).output() {
+        if output.status.success() {
+            let commit = String::from_utf8_lossy(&output.stdout);
+            println!("cargo:rustc-env=FATIMG_GIT_COMMIT={}", commit.trim());
+        }
+    }
+}
@@ -0,0 +1,16 @@
+//! Trees Windows creates on its own at the top of removable drives
+
+/// Top-level directories managed by Windows, compared case-insensitively
+pub const WINDOWS_ARTIFACTS: &[&str] = &[
+    "System Volume Information",
+    "$RECYCLE.BIN",
+    "RECYCLER",
+    "RECYCLED",
+];
+
+/// Whether `name`, an entry of the root directory, is one of these
+pub fn is_windows_artifact(name: &str) -> bool {
+    WINDOWS_ARTIFACTS
+        .iter()
+        .any(|a| a.eq_ignore_ascii_case(name))
+}
@@ -0,0 +1,264 @@
+//! Rendering and editing the attribute byte of directory entries.
+//!
+//! The text form has one position per flag, `rhsadv`, with `-` for flags
+//! that are not set: `0x21` is `r--a--`. It is produced here rather than
+//! through fatfs, so its output doesn't change with fatfs versions.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::json::{self, Value};
+use crate::ondisk::{BootSector, Fat, RawDir, ATTR_DIRECTORY, ATTR_LFN, ATTR_VOLUME_ID};
+use crate::region::ImgSlice;
+
+/// Flag bits with their letter and JSON name, in display order
+const FLAGS: [(u8, char, &str); 6] = [
+    (0x01, 'r', "read_only"),
+    (0x02, 'h', "hidden"),
+    (0x04, 's', "system"),
+    (0x20, 'a', "archive"),
+    (ATTR_DIRECTORY, 'd', "directory"),
+    (ATTR_VOLUME_ID, 'v', "volume_id"),
+];
+
+/// Bits that tell what kind of entry it is, which `attrib` never changes
+const KIND: u8 = ATTR_DIRECTORY | ATTR_VOLUME_ID;
+
+/// The `rhsadv` form
+pub fn render(byte: u8) -> String {
+    FLAGS
+        .iter()
+        .map(|&(bit, letter, _)| if byte & bit != 0 { letter } else { '-' })
+        .collect()
+}
+
+/// The `0x21` form
+pub fn render_numeric(byte: u8) -> String {
+    format!("0x{:02x}", byte)
+}
+
+/// Names of the flags set in `byte`
+pub fn names(byte: u8) -> impl Iterator<Item = &'static str> {
+    FLAGS
+        .into_iter()
+        .filter(move |&(bit, _, _)| byte & bit != 0)
+        .map(|(_, _, name)| name)
+}
+
+/// JSON members for the attribute byte, both as a number and decoded
+pub fn json_members(byte: u8) -> [(&'static str, Value); 2] {
+    [
+        ("attribute_byte", (byte as u64).into()),
+        ("attributes", names(byte).collect()),
+    ]
+}
+
+/// The `attrib --json` document of the entry `path`
+fn to_json(path: &str, byte: u8) -> Value {
+    let [number, list] = json_members(byte);
+    json::versioned(json::object([("path", path.into()), number, list]))
+}
+
+/// Parses an attribute byte: hex like `0x21`, the `rhsadv` form, or
+/// flag names separated by commas like `read_only,archive`
+pub fn parse(s: &str) -> Result<u8, String> {
+    let invalid = || {
+        format!(
+            "Invalid attributes {:?}, expected hex like 0x21, letters like r--a-- or names like read_only,archive",
+            s
+        )
+    };
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        return u8::from_str_radix(hex, 16).map_err(|_| invalid());
+    }
+    if s.len() == FLAGS.len() && s.is_ascii() {
+        let letters = FLAGS
+            .iter()
+            .zip(s.chars())
+            .map(|(&(bit, letter, _), c)| match c {
+                '-' => Some(0),
+                c if c == letter => Some(bit),
+                _ => None,
+            })
+            .collect::<Option<Vec<u8>>>();
+        if let Some(bits) = letters {
+            return Ok(bits.into_iter().fold(0, |a, b| a | b));
+        }
+    }
+    let mut byte = 0;
+    for name in s.split(',').filter(|n| !n.is_empty()) {
+        match FLAGS.iter().find(|f| f.2 == name) {
+            Some(&(bit, _, _)) => byte |= bit,
+            None => return Err(invalid()),
+        }
+    }
+    Ok(byte)
+}
+
+/// Parses `--attributes` of commands creating entries, like `parse` but
+/// without the directory and volume label bits, which the kind of entry
+/// decides
+pub fn parse_creation(s: &str) -> Result<u8, String> {
+    let byte = parse(s)?;
+    if byte & KIND != 0 {
+        return Err(format!(
+            "Attributes {:?} include directory or volume_id, which can't be chosen",
+            s
+        ));
+    }
+    Ok(byte)
+}
+
+/// Replaces the flags of the entry at the normalized image path
+/// `inner_path` with `flags`, keeping what kind of entry it is
+pub fn set(img_file: &Path, inner_path: &str, flags: u8) -> Result<()> {
+    let mut img = ImgSlice::open(img_file, true)?;
+    let layout = BootSector::read(&mut img)?.layout()?;
+    let fat = Fat::read(&mut img, &layout)?;
+    let mut entry = RawDir::entry_at(&mut img, &layout, &fat, inner_path)?;
+    entry.raw[11] = entry.attr() & KIND | flags;
+    entry
+        .write(&mut img)
+        .with_context(|| format!("failed writing /{}", inner_path))?;
+    img.sync_all()?;
+    Ok(())
+}
+
+/// A change like `+h` or `-rs`: whether to set the bits, and which
+#[derive(Debug, Clone, Copy)]
+pub struct Change {
+    pub set: bool,
+    pub bits: u8,
+}
+
+/// Parses `+` or `-` followed by letters of `r`, `h`, `s` and `a`
+pub fn parse_change(s: &str) -> Result<Change, String> {
+    let invalid = || format!("Invalid change {:?}, expected e.g. +h or -rs", s);
+    let (set, letters) = match s.as_bytes().first() {
+        Some(b'+') => (true, &s[1..]),
+        Some(b'-') => (false, &s[1..]),
+        _ => return Err(invalid()),
+    };
+    if letters.is_empty() {
+        return Err(invalid());
+    }
+    let mut bits = 0;
+    for c in letters.chars() {
+        match FLAGS[..4].iter().find(|f| f.1 == c) {
+            Some(&(bit, _, _)) => bits |= bit,
+            None => return Err(invalid()),
+        }
+    }
+    Ok(Change { set, bits })
+}
+
+/// Prints the attributes of the normalized image path `inner_path`, and
+/// first replaces them with `raw` or applies `changes`. The directory and
+/// volume label bits must stay as they are. Only the attribute byte is
+/// written, contents and timestamps are left alone.
+pub fn run(
+    img_file: &Path, inner_path: &str, raw: Option<u8>, changes: &[Change], numeric: bool,
+    json: bool,
+) -> Result<()> {
+    let editing = raw.is_some() || !changes.is_empty();
+    let mut img = ImgSlice::open(img_file, editing)?;
+    let layout = BootSector::read(&mut img)?.layout()?;
+    let fat = Fat::read(&mut img, &layout)?;
+    let path = format!("/{}", inner_path);
+    let mut entry = RawDir::entry_at(&mut img, &layout, &fat, inner_path)?;
+
+    let changed = changes.iter().fold(raw.unwrap_or(entry.attr()), |byte, c| {
+        if c.set {
+            byte | c.bits
+        } else {
+            byte & !c.bits
+        }
+    });
+    if editing && changed != entry.attr() {
+        let byte = changed;
+        if byte & KIND != entry.attr() & KIND {
+            bail!(
+                "{}: attributes {} would change the entry between file, directory and volume label",
+                path,
+                render_numeric(byte)
+            );
+        }
+        if byte & ATTR_LFN == ATTR_LFN {
+            bail!("Attributes {} mark a long name entry", render_numeric(byte));
+        }
+        entry.raw[11] = byte;
+        entry
+            .write(&mut img)
+            .with_context(|| format!("failed writing {}", path))?;
+        img.sync_all()?;
+    }
+
+    let byte = entry.attr();
+    if json {
+        println!("{}", to_json(&path, byte));
+    } else if numeric {
+        println!("{} {}", render_numeric(byte), path);
+    } else {
+        println!("{} {}", render(byte), path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Document;
+
+    #[test]
+    fn json_matches_the_schema() {
+        for byte in [0x00, 0x21, 0x10, 0x08] {
+            assert_eq!(Document::Attrib.validate(&to_json("/A", byte)), Ok(()));
+        }
+    }
+
+    #[test]
+    fn rendering() {
+        assert_eq!(render(0x21), "r--a--");
+        assert_eq!(render(0x16), "-hs-d-");
+        assert_eq!(render(0x08), "-----v");
+        assert_eq!(render_numeric(0x21), "0x21");
+        assert_eq!(
+            names(0x23).collect::<Vec<_>>(),
+            ["read_only", "hidden", "archive"]
+        );
+        assert_eq!(names(0).count(), 0);
+    }
+
+    #[test]
+    fn parsing_every_form() {
+        assert_eq!(parse("0x21"), Ok(0x21));
+        assert_eq!(parse("0X06"), Ok(0x06));
+        assert_eq!(parse("r--a--"), Ok(0x21));
+        assert_eq!(parse("------"), Ok(0));
+        assert_eq!(parse("read_only,archive"), Ok(0x21));
+        assert_eq!(parse("hidden"), Ok(0x02));
+        assert_eq!(parse(""), Ok(0));
+        for byte in 0..=0x3f {
+            assert_eq!(parse(&render(byte)), Ok(byte & 0x3f));
+        }
+        for invalid in ["0x100", "0xzz", "a--r--", "hidden,secret", "rhs"] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+
+        assert_eq!(parse_creation("hidden,system"), Ok(0x06));
+        assert!(parse_creation("0x10").is_err());
+        assert!(parse_creation("-----v").is_err());
+    }
+
+    #[test]
+    fn changes() {
+        let c = parse_change("+hs").unwrap();
+        assert_eq!((c.set, c.bits), (true, 0x06));
+        let c = parse_change("-ra").unwrap();
+        assert_eq!((c.set, c.bits), (false, 0x21));
+        for invalid in ["+", "h", "+d", "-v", "+x", ""] {
+            assert!(parse_change(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
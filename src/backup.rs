@@ -0,0 +1,204 @@
+//! `check --fix` metadata backups and `restore-metadata`.
+//!
+//! Before `--fix` changes anything, the reserved area with the boot
+//! sectors and FSInfo, the FATs, the fixed root directory and the clusters
+//! of every directory are saved to a backup file and synced to disk. The
+//! file has a header with where the volume starts in the image file and
+//! how long it is, then ranges of the volume as offset, length and the
+//! bytes, little endian. `restore-metadata` writes the ranges back as they
+//! were, to the same volume only.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use crate::ondisk::{BootSector, Fat, RawDir};
+use crate::region::{self, ImgSlice};
+
+const MAGIC: &[u8; 8] = b"FATIMGMB";
+
+/// Saved without a readable layout, the boot sector alone
+const BOOT_SECTOR: u64 = 512;
+
+/// Byte ranges of the volume to back up, sorted and merged
+fn metadata_ranges(img: &mut ImgSlice) -> Result<Vec<(u64, u64)>> {
+    let bs = BootSector::read(img)?;
+    let layout = match bs.layout() {
+        Ok(layout) => layout,
+        Err(_) => return Ok(vec![(0, BOOT_SECTOR)]),
+    };
+    let mut ranges = vec![(0, layout.data_offset)];
+    // Directories that can't be read have nothing left to save
+    if let Ok(fat) = Fat::read(img, &layout) {
+        let mut stack: Vec<RawDir> = RawDir::read_root(img, &layout, &fat).into_iter().collect();
+        let mut visited = HashSet::new();
+        while let Some(dir) = stack.pop() {
+            ranges.extend_from_slice(dir.regions());
+            for (name, entry) in dir.files() {
+                let start = entry.first_cluster();
+                if name == "." || name == ".." || !entry.is_dir() {
+                    continue;
+                }
+                if !layout.is_data_cluster(start) || !visited.insert(start) {
+                    continue;
+                }
+                if let Ok(sub) = RawDir::read(img, &layout, &fat, start) {
+                    stack.push(sub);
+                }
+            }
+        }
+    }
+
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::new();
+    for (offset, len) in ranges {
+        match merged.last_mut() {
+            Some((last, last_len)) if offset <= *last + *last_len => {
+                *last_len = (*last_len).max(offset + len - *last);
+            },
+            _ => merged.push((offset, len)),
+        }
+    }
+    Ok(merged)
+}
+
+/// Creates the first of `<image>.metadata`, `<image>.metadata.1` and so on
+/// that doesn't exist yet
+fn create_next_to(img_file: &Path) -> Result<(PathBuf, File)> {
+    for n in 0.. {
+        let mut name = OsString::from(img_file.as_os_str());
+        name.push(".metadata");
+        if n > 0 {
+            name.push(format!(".{}", n));
+        }
+        let path = PathBuf::from(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed creating {}", path.display()))
+            },
+        }
+    }
+    unreachable!("ran out of backup names")
+}
+
+/// Saves the metadata of the volume in `img_file` to `backup`, or next to
+/// the image without one, returning where it went. The backup is synced
+/// to disk before this returns.
+pub fn save(img_file: &Path, backup: Option<&Path>) -> Result<PathBuf> {
+    let mut img = ImgSlice::open(img_file, false)?;
+    let ranges = metadata_ranges(&mut img)?;
+    let (path, file) = match backup {
+        Some(path) => {
+            let file = File::create(path)
+                .with_context(|| format!("failed creating {}", path.display()))?;
+            (path.to_owned(), file)
+        },
+        None => create_next_to(img_file)?,
+    };
+    let context = || format!("failed writing metadata backup {}", path.display());
+
+    let mut out = BufWriter::new(file);
+    out.write_all(MAGIC).with_context(context)?;
+    out.write_all(&region::start().to_le_bytes())
+        .with_context(context)?;
+    out.write_all(&img.len()?.to_le_bytes())
+        .with_context(context)?;
+    out.write_all(&(ranges.len() as u32).to_le_bytes())
+        .with_context(context)?;
+    for &(offset, len) in &ranges {
+        out.write_all(&offset.to_le_bytes()).with_context(context)?;
+        out.write_all(&len.to_le_bytes()).with_context(context)?;
+        img.seek(SeekFrom::Start(offset))?;
+        let copied = io::copy(&mut (&mut img).take(len), &mut out).with_context(context)?;
+        if copied != len {
+            bail!("failed reading {} bytes at {} of the volume", len, offset);
+        }
+    }
+    let file = out
+        .into_inner()
+        .map_err(|err| err.into_error())
+        .with_context(context)?;
+    file.sync_all().with_context(context)?;
+    Ok(path)
+}
+
+fn read_u64(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let bytes = buf.get(*pos..*pos + 8)?;
+    *pos += 8;
+    Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Writes the ranges saved in `backup` back to the volume in `img_file`,
+/// returning how many ranges and bytes that was. The whole backup is read
+/// and checked before anything is written.
+pub fn restore(img_file: &Path, backup: &Path) -> Result<(usize, u64)> {
+    let buf = fs::read(backup).with_context(|| format!("failed reading {}", backup.display()))?;
+    let invalid = || format!("{} is not a complete metadata backup", backup.display());
+    if !buf.starts_with(MAGIC) {
+        bail!(invalid());
+    }
+    let mut pos = MAGIC.len();
+    let (start, len) = match (read_u64(&buf, &mut pos), read_u64(&buf, &mut pos)) {
+        (Some(start), Some(len)) => (start, len),
+        _ => bail!(invalid()),
+    };
+    let count = match buf.get(pos..pos + 4) {
+        Some(bytes) => u32::from_le_bytes(bytes.try_into().unwrap()),
+        None => bail!(invalid()),
+    };
+    pos += 4;
+
+    let mut img = ImgSlice::open(img_file, true)?;
+    if start != region::start() {
+        bail!(
+            "{} was saved from the volume at byte {} of the image, this one is at {}, give \
+             the same --offset or --partition",
+            backup.display(),
+            start,
+            region::start()
+        );
+    }
+    if len != img.len()? {
+        bail!(
+            "{} was saved from a volume of {} bytes, this one has {}",
+            backup.display(),
+            len,
+            img.len()?
+        );
+    }
+    let mut ranges = Vec::new();
+    for _ in 0..count {
+        let (offset, range_len) = match (read_u64(&buf, &mut pos), read_u64(&buf, &mut pos)) {
+            (Some(offset), Some(range_len)) => (offset, range_len),
+            _ => bail!(invalid()),
+        };
+        let bytes = match buf
+            .get(pos..)
+            .and_then(|rest| rest.get(..range_len as usize))
+        {
+            Some(bytes) if offset.checked_add(range_len).is_some_and(|end| end <= len) => bytes,
+            _ => bail!(invalid()),
+        };
+        pos += bytes.len();
+        ranges.push((offset, bytes));
+    }
+    if pos != buf.len() {
+        bail!(invalid());
+    }
+
+    let mut restored = 0;
+    for (offset, bytes) in &ranges {
+        img.seek(SeekFrom::Start(*offset))?;
+        img.write_all(bytes)
+            .with_context(|| format!("failed writing {} bytes at {}", bytes.len(), offset))?;
+        restored += bytes.len() as u64;
+    }
+    img.sync_all()?;
+    Ok((ranges.len(), restored))
+}
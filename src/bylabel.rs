@@ -0,0 +1,113 @@
+//! Finding the device of a FAT volume by its label
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+
+/// Device holding the FAT volume labeled `label`, compared
+/// case-insensitively like DOS does. Exactly one must match.
+#[cfg(target_os = "linux")]
+pub fn find(label: &str) -> Result<PathBuf> {
+    let mut found = by_label_links(label)?;
+    if found.is_empty() {
+        found = probe_block_devices(label);
+    }
+    match found.len() {
+        0 => bail!("No FAT volume labeled {:?} found", label),
+        1 => Ok(found.remove(0)),
+        _ => {
+            let names: Vec<String> = found.iter().map(|p| p.display().to_string()).collect();
+            bail!(
+                "Several volumes are labeled {:?}: {}",
+                label,
+                names.join(", ")
+            )
+        },
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn find(_label: &str) -> Result<PathBuf> {
+    bail!("--image-by-label is only supported on Linux")
+}
+
+fn matches(found: &str, label: &str) -> bool {
+    found.trim_end().eq_ignore_ascii_case(label.trim_end())
+}
+
+/// The udev symlinks, whose names escape unusual bytes as `\xHH`
+#[cfg(target_os = "linux")]
+fn by_label_links(label: &str) -> Result<Vec<PathBuf>> {
+    use std::fs;
+    use std::io::ErrorKind;
+
+    let entries = match fs::read_dir("/dev/disk/by-label") {
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        r => r?,
+    };
+    let mut found = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        if matches(&unescape(&name.to_string_lossy()), label) {
+            found.push(fs::canonicalize(entry.path())?);
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(target_os = "linux")]
+fn unescape(name: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut rest = name.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail
+            .strip_prefix(b"x")
+            .and_then(|t| t.get(..2))
+            .and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok());
+        match (b, hex) {
+            (b'\\', Some(v)) => {
+                bytes.push(v);
+                rest = &tail[3..];
+            },
+            _ => {
+                bytes.push(b);
+                rest = tail;
+            },
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Reads the boot sector of every block device that can be opened.
+/// Used when udev doesn't provide the links.
+#[cfg(target_os = "linux")]
+fn probe_block_devices(label: &str) -> Vec<PathBuf> {
+    use std::fs::{self, File};
+
+    use crate::ondisk::BootSector;
+
+    let mut found = Vec::new();
+    let entries = match fs::read_dir("/sys/class/block") {
+        Ok(entries) => entries,
+        Err(_) => return found,
+    };
+    for entry in entries.flatten() {
+        let device = PathBuf::from("/dev").join(entry.file_name());
+        let bs = File::open(&device)
+            .ok()
+            .and_then(|mut f| BootSector::read(&mut f).ok());
+        match bs {
+            Some(bs)
+                if bs.has_signature()
+                    && bs.layout().is_ok()
+                    && matches(&bs.volume_label(), label) =>
+            {
+                found.push(device);
+            },
+            _ => {},
+        }
+    }
+    found.sort();
+    found
+}
@@ -0,0 +1,386 @@
+//! Filesystem consistency checks
+
+use std::collections::HashSet;
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{bail, Result};
+
+use crate::limits::PathLimits;
+use crate::ondisk::{self, BootSector, Fat, Layout, RawDir, RawDirEntry};
+use crate::profile::{Outcome, Profile, Volume};
+use crate::region::{self, ImgSlice};
+
+/// How `check --fix` repairs a file entry with an invalid start cluster
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FixPolicy {
+    /// Keep the entry as an empty file
+    Truncate,
+    /// Remove the entry
+    Delete,
+}
+
+/// Problems found (and fixed) by the `check` command
+#[derive(Debug, Default)]
+pub struct Report {
+    problems: usize,
+    fixed: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn problem(&mut self, msg: String) {
+        println!("{}", msg);
+        self.problems += 1;
+    }
+
+    fn fixed(&mut self, msg: String) {
+        println!("{} (fixed)", msg);
+        self.fixed += 1;
+    }
+
+    /// Something that isn't wrong as such, but worth knowing
+    fn warning(&mut self, msg: String) {
+        println!("{} (warning)", msg);
+        self.warnings += 1;
+    }
+
+    /// Failures are only warnings when `advisory`
+    fn rule(&mut self, profile: &str, rule: &str, outcome: Outcome, advisory: bool) {
+        match outcome {
+            Ok(why) => println!("{} {}: pass, {}", profile, rule, why),
+            Err(why) if advisory => self.warning(format!("{} {}: FAIL, {}", profile, rule, why)),
+            Err(why) => self.problem(format!("{} {}: FAIL, {}", profile, rule, why)),
+        }
+    }
+
+    /// Prints a summary, failing if any problems remain
+    pub fn finish(self) -> Result<()> {
+        if self.warnings > 0 {
+            println!("{} warning(s)", self.warnings);
+        }
+        if self.problems == 0 && self.fixed == 0 {
+            println!("no problems found");
+            return Ok(());
+        }
+        if self.fixed > 0 {
+            println!("{} problem(s) fixed", self.fixed);
+        }
+        if self.problems > 0 {
+            bail!("{} problem(s) found", self.problems);
+        }
+        Ok(())
+    }
+}
+
+/// Checks the image, repairing what can be repaired if `fix` is set.
+/// Paths exceeding `portability` limits are warnings, or problems with
+/// `strict`. The rules of `profile` are checked after the repairs.
+pub fn run(
+    img: &mut ImgSlice, fix: Option<FixPolicy>, portability: Option<PathLimits>, strict: bool,
+    profile: Option<(&Profile, bool)>,
+) -> Result<Report> {
+    let policy = fix;
+    let fix = policy.is_some();
+    let mut report = Report::default();
+    let mut bs = BootSector::read(img)?;
+
+    if !bs.has_signature() {
+        report.problem("boot sector: missing 0x55AA signature".to_owned());
+    }
+
+    for problem in ondisk::fat32_layout_problems(&bs) {
+        report.problem(format!("reserved area: {}", problem));
+    }
+
+    check_backup_boot_sector(img, &bs, fix, &mut report)?;
+    check_hidden_sectors(img, &mut bs, fix, &mut report)?;
+    check_total_sectors(img, &mut bs, fix, &mut report)?;
+
+    let layout = bs.layout()?;
+    let fat = Fat::read(img, &layout)?;
+    check_dot_entries(img, &layout, &fat, fix, &mut report)?;
+    check_file_starts(img, &layout, &fat, policy, &mut report)?;
+    if let Some(limits) = portability {
+        check_paths(img, &layout, &fat, &limits, strict, &mut report)?;
+    }
+    if let Some((profile, advisory)) = profile {
+        let mut volume = Volume {
+            img,
+            bs: &bs,
+            layout: &layout,
+            fat: &fat,
+        };
+        for rule in profile.rules {
+            let outcome = (rule.check)(&mut volume)?;
+            report.rule(profile.name, rule.name, outcome, advisory);
+        }
+    }
+    Ok(report)
+}
+
+/// FAT32 keeps a copy of the boot sector in the reserved area
+fn check_backup_boot_sector(
+    img: &mut ImgSlice, bs: &BootSector, fix: bool, report: &mut Report,
+) -> Result<()> {
+    if !bs.is_fat32() {
+        return Ok(());
+    }
+
+    let backup = bs.backup_boot_sector();
+    if backup == 0 || backup == 0xffff || backup >= bs.reserved_sectors() {
+        report.problem("backup boot sector: not present".to_owned());
+        return Ok(());
+    }
+
+    let offset = backup as u64 * bs.bytes_per_sector() as u64;
+    let mut copy = [0u8; 512];
+    img.seek(SeekFrom::Start(offset))?;
+    img.read_exact(&mut copy)?;
+
+    if copy != bs.raw {
+        let msg = format!(
+            "backup boot sector: sector {} differs from the primary",
+            backup
+        );
+        if fix {
+            bs.write_at(img, offset)?;
+            report.fixed(msg);
+        } else {
+            report.problem(msg);
+        }
+    }
+    Ok(())
+}
+
+/// The sectors before the volume in the image file, none unless it was
+/// given with `--offset` or `--partition`. Images cut out of a partitioned
+/// disk keep the old value.
+fn check_hidden_sectors(
+    img: &mut ImgSlice, bs: &mut BootSector, fix: bool, report: &mut Report,
+) -> Result<()> {
+    let expected = (region::start() / bs.bytes_per_sector() as u64) as u32;
+    let hidden = bs.hidden_sectors();
+    if hidden == expected {
+        return Ok(());
+    }
+
+    let msg = format!(
+        "boot sector: hidden sectors is {}, expected {} for where the volume starts in the \
+         image",
+        hidden, expected
+    );
+    if fix {
+        bs.set_hidden_sectors(expected);
+        bs.write(img)?;
+        report.fixed(msg);
+    } else {
+        report.problem(msg);
+    }
+    Ok(())
+}
+
+/// The fix keeps the count that fits the image, stored in the field the
+/// specification asks for
+fn check_total_sectors(
+    img: &mut ImgSlice, bs: &mut BootSector, fix: bool, report: &mut Report,
+) -> Result<()> {
+    let image_len = img.len()?;
+    let msg = match ondisk::total_sectors_conflict(bs, image_len) {
+        Some(conflict) => format!("boot sector: {}", conflict),
+        None => return Ok(()),
+    };
+    if fix {
+        bs.set_total_sectors(bs.total_sectors_for(image_len));
+        bs.write(img)?;
+        report.fixed(msg);
+    } else {
+        report.problem(msg);
+    }
+    Ok(())
+}
+
+/// Every subdirectory must start with `.` and `..` entries pointing at
+/// itself and its parent. The parent of a top-level directory is 0.
+fn check_dot_entries(
+    img: &mut ImgSlice, layout: &Layout, fat: &Fat, fix: bool, report: &mut Report,
+) -> Result<()> {
+    let root = RawDir::read_root(img, layout, fat)?;
+    let mut stack = vec![(String::new(), 0, root)];
+    let mut visited = HashSet::new();
+
+    while let Some((path, cluster, dir)) = stack.pop() {
+        for (name, entry) in dir.files() {
+            if !entry.is_dir() || name == "." || name == ".." {
+                continue;
+            }
+            let sub_path = format!("{}/{}", path, name);
+            let start = entry.first_cluster();
+            if !layout.is_data_cluster(start) {
+                report.problem(format!(
+                    "{}: directory has invalid start cluster {}",
+                    sub_path, start
+                ));
+                continue;
+            }
+            if !visited.insert(start) {
+                report.problem(format!(
+                    "{}: directory cluster {} is already used by another directory",
+                    sub_path, start
+                ));
+                continue;
+            }
+            let sub = match RawDir::read(img, layout, fat, start) {
+                Ok(sub) => sub,
+                Err(err) => {
+                    report.problem(format!("{}: {:#}", sub_path, err));
+                    continue;
+                },
+            };
+            check_dots(img, &sub_path, &sub, start, cluster, fix, report)?;
+            stack.push((sub_path, start, sub));
+        }
+    }
+    Ok(())
+}
+
+/// Files with contents must start at a data cluster
+fn check_file_starts(
+    img: &mut ImgSlice, layout: &Layout, fat: &Fat, policy: Option<FixPolicy>, report: &mut Report,
+) -> Result<()> {
+    let root = RawDir::read_root(img, layout, fat)?;
+    let mut stack = vec![(String::new(), root)];
+    let mut visited = HashSet::new();
+
+    while let Some((path, dir)) = stack.pop() {
+        for (name, entry) in dir.files() {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let sub_path = format!("{}/{}", path, name);
+            if entry.has_invalid_start() {
+                let msg = format!(
+                    "{}: {} byte file has invalid start cluster {}",
+                    sub_path,
+                    entry.size(),
+                    entry.first_cluster()
+                );
+                match policy {
+                    Some(FixPolicy::Truncate) => {
+                        let mut entry = entry.clone();
+                        entry.set_size(0);
+                        entry.set_first_cluster(0);
+                        entry.write(img)?;
+                        report.fixed(format!("{}, truncated", msg));
+                    },
+                    Some(FixPolicy::Delete) => {
+                        dir.remove(img, entry.offset)?;
+                        report.fixed(format!("{}, deleted", msg));
+                    },
+                    None => report.problem(msg),
+                }
+                continue;
+            }
+
+            // Broken directories were already reported by `check_dot_entries`
+            let start = entry.first_cluster();
+            if !entry.is_dir() || !layout.is_data_cluster(start) || !visited.insert(start) {
+                continue;
+            }
+            if let Ok(sub) = RawDir::read(img, layout, fat, start) {
+                stack.push((sub_path, sub));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Full paths of all entries must be within `limits`
+fn check_paths(
+    img: &mut ImgSlice, layout: &Layout, fat: &Fat, limits: &PathLimits, strict: bool,
+    report: &mut Report,
+) -> Result<()> {
+    let root = RawDir::read_root(img, layout, fat)?;
+    let mut stack = vec![(String::new(), root)];
+    let mut visited = HashSet::new();
+
+    while let Some((path, dir)) = stack.pop() {
+        for (name, entry) in dir.files() {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let sub_path = format!("{}/{}", path, name);
+            if let Some(msg) = limits.violation(&sub_path) {
+                if strict {
+                    report.problem(msg);
+                } else {
+                    report.warning(msg);
+                }
+            }
+
+            // Broken directories were already reported by `check_dot_entries`
+            let start = entry.first_cluster();
+            if !entry.is_dir() || !layout.is_data_cluster(start) || !visited.insert(start) {
+                continue;
+            }
+            if let Ok(sub) = RawDir::read(img, layout, fat, start) {
+                stack.push((sub_path, sub));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_dots(
+    img: &mut ImgSlice, path: &str, dir: &RawDir, own: u32, parent: u32, fix: bool,
+    report: &mut Report,
+) -> Result<()> {
+    let expected = [(ondisk::DOT_NAME, own), (ondisk::DOTDOT_NAME, parent)];
+    for (i, &(name, cluster)) in expected.iter().enumerate() {
+        let slot = dir.entries.get(i);
+        let valid = matches!(slot, Some(e) if e.name_bytes() == name
+            && e.attr() & ondisk::ATTR_DIRECTORY != 0
+            && e.first_cluster() == cluster);
+        if valid {
+            continue;
+        }
+
+        let dots = if i == 0 { "." } else { ".." };
+        let msg = match slot {
+            Some(e) if e.name_bytes() == name => format!(
+                "{}: `{}` entry points at cluster {}, expected {}",
+                path,
+                dots,
+                e.first_cluster(),
+                cluster
+            ),
+            _ => format!("{}: `{}` entry is missing", path, dots),
+        };
+
+        // Only rewrite slots that hold nothing else worth keeping
+        let repairable = match slot {
+            None => true,
+            Some(e) => e.is_deleted() || e.raw[0] == b'.',
+        };
+        let offset = dir.slot_offset(i);
+        match (fix && repairable, offset) {
+            (true, Some(offset)) => {
+                let mut entry = match slot {
+                    Some(e) if !e.is_deleted() => e.clone(),
+                    _ => RawDirEntry {
+                        raw: [0u8; 32],
+                        offset,
+                    },
+                };
+                entry.raw[..11].copy_from_slice(&name);
+                entry.raw[11] = ondisk::ATTR_DIRECTORY;
+                entry.set_first_cluster(cluster);
+                entry.write(img)?;
+                report.fixed(msg);
+            },
+            (true, None) => report.problem(format!("{} (no room to repair)", msg)),
+            (false, _) if fix => report.problem(format!("{} (slot is used by another entry)", msg)),
+            (false, _) => report.problem(msg),
+        }
+    }
+    Ok(())
+}
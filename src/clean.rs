@@ -0,0 +1,155 @@
+//! Removing zero-byte files and empty directories
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::protect::{ProtectArgs, Protection};
+use crate::{delta, glob, inner_join, open_fs_rw, ImgDir};
+
+/// Options of the `clean-empty` command
+pub struct CleanOptions {
+    /// Only list what would be removed
+    pub dry_run: bool,
+    /// Paths matching these are never removed
+    pub keep: Vec<String>,
+    pub protect: ProtectArgs,
+}
+
+struct Cleanup<'a> {
+    opts: &'a CleanOptions,
+    protection: Protection,
+    files: usize,
+    dirs: usize,
+}
+
+/// A directory `Cleanup::dir` is in the middle of
+struct Frame<'a> {
+    dir: ImgDir<'a>,
+    path: String,
+    /// Name in the parent directory, `None` for the one cleaned up
+    name: Option<String>,
+    /// Names of the entries left to look at, whether they are directories
+    /// and their lengths
+    entries: std::vec::IntoIter<(String, bool, u64)>,
+    /// Entries not removed so far
+    remaining: usize,
+}
+
+impl<'a> Frame<'a> {
+    fn new(dir: ImgDir<'a>, path: String, name: Option<String>) -> Result<Self> {
+        let mut entries = Vec::new();
+        for entry in dir.iter() {
+            let entry = entry.with_context(|| format!("failed reading directory {}", path))?;
+            let name = entry.file_name();
+            if name != "." && name != ".." {
+                entries.push((name, entry.is_dir(), entry.len()));
+            }
+        }
+        Ok(Self {
+            dir,
+            path,
+            name,
+            remaining: entries.len(),
+            entries: entries.into_iter(),
+        })
+    }
+}
+
+impl Cleanup<'_> {
+    /// Removes empty entries below `dir`, children first, so that
+    /// directories left empty go too. Directories are kept on a stack
+    /// instead of recursing, so that deep trees can't overflow it.
+    fn dir(&mut self, dir: ImgDir<'_>, path: &str) -> Result<()> {
+        let mut stack = vec![Frame::new(dir, path.to_owned(), None)?];
+        while let Some(frame) = stack.last_mut() {
+            let (name, is_dir, len) = match frame.entries.next() {
+                Some(next) => next,
+                None => {
+                    let done = stack.pop().expect("the stack isn't empty");
+                    if let (Some(parent), Some(name)) = (stack.last_mut(), done.name) {
+                        self.remove(parent, &name, true, done.remaining == 0)?;
+                    }
+                    continue;
+                },
+            };
+            let sub_path = inner_join(&frame.path, &name);
+            let rel = sub_path.trim_start_matches('/');
+            // Kept directories are left alone along with their contents
+            if self.opts.keep.iter().any(|g| glob::matches(g, rel)) {
+                continue;
+            }
+            if is_dir {
+                let sub = frame
+                    .dir
+                    .open_dir(&name)
+                    .with_context(|| format!("failed opening directory {}", sub_path))?;
+                stack.push(Frame::new(sub, sub_path, Some(name))?);
+            } else {
+                self.remove(frame, &name, false, len == 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the entry `name` of `frame` if it is `empty` and not
+    /// protected
+    fn remove(
+        &mut self, frame: &mut Frame<'_>, name: &str, is_dir: bool, empty: bool,
+    ) -> Result<()> {
+        let sub_path = inner_join(&frame.path, name);
+        if !empty || self.protection.protects(&sub_path) {
+            return Ok(());
+        }
+
+        if self.opts.dry_run {
+            eprintln!("would remove {}", sub_path);
+        } else {
+            frame
+                .dir
+                .remove(name)
+                .with_context(|| format!("failed removing {}", sub_path))?;
+            delta::removed(is_dir);
+            eprintln!("removed {}", sub_path);
+        }
+        if is_dir {
+            self.dirs += 1;
+        } else {
+            self.files += 1;
+        }
+        frame.remaining -= 1;
+        Ok(())
+    }
+}
+
+/// Cleans up the image directory `inner_path`, which itself is kept
+pub fn run(img_file: &Path, inner_path: &str, opts: &CleanOptions) -> Result<()> {
+    let fs = open_fs_rw(img_file)?;
+    let mut dir = fs.root_dir();
+    if !inner_path.is_empty() {
+        dir = dir
+            .open_dir(inner_path)
+            .with_context(|| format!("failed opening directory /{}", inner_path))?;
+    }
+
+    let mut cleanup = Cleanup {
+        opts,
+        protection: Protection::load(&fs.root_dir(), &opts.protect)?,
+        files: 0,
+        dirs: 0,
+    };
+    cleanup.dir(dir, &format!("/{}", inner_path))?;
+    fs.unmount().context("failed flushing the filesystem")?;
+
+    let verb = if opts.dry_run {
+        "would remove"
+    } else {
+        "removed"
+    };
+    eprintln!(
+        "{} {} file(s) and {} directories",
+        verb, cleanup.files, cleanup.dirs
+    );
+    cleanup.protection.finish();
+    Ok(())
+}
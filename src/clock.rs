@@ -0,0 +1,470 @@
+//! The "current time" stored in timestamps of new and changed entries.
+//!
+//! FAT timestamps are local time without a zone. By default it's the host
+//! clock in the host time zone, as fatfs does it. `--now` and
+//! `SOURCE_DATE_EPOCH` fix the instant, `--tz` the UTC offset.
+
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use fatfs::{Date, DateTime, DefaultTimeProvider, Time, TimeProvider};
+
+/// An instant with the UTC offset it was written in
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp {
+    /// Seconds since the Unix epoch
+    secs: i64,
+    /// Seconds east of UTC
+    offset: i32,
+}
+
+impl Timestamp {
+    /// Seconds since the Unix epoch
+    pub fn unix_secs(&self) -> i64 {
+        self.secs
+    }
+}
+
+/// Time provider used for every image mounted for writing
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Clock {
+    /// Local seconds since the epoch
+    fixed: Option<i64>,
+    /// Seconds east of UTC, the host's if `None`
+    offset: Option<i32>,
+}
+
+static CLOCK: OnceLock<Clock> = OnceLock::new();
+
+/// Sets up the clock for this invocation. `--now` wins over
+/// `SOURCE_DATE_EPOCH`. Without `tz`, `--now` keeps its own offset and
+/// `SOURCE_DATE_EPOCH` is taken as UTC, so builds are reproducible across
+/// hosts.
+pub fn init(now: Option<Timestamp>, tz: Option<i32>) -> Result<()> {
+    let fixed = match now {
+        Some(now) => Some(Timestamp {
+            secs: now.secs,
+            offset: tz.unwrap_or(now.offset),
+        }),
+        None => match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(epoch) => match epoch.trim().parse() {
+                Ok(secs) => Some(Timestamp {
+                    secs,
+                    offset: tz.unwrap_or(0),
+                }),
+                Err(_) => bail!("Invalid SOURCE_DATE_EPOCH {:?}", epoch),
+            },
+            Err(_) => None,
+        },
+    };
+
+    let clock = match fixed {
+        Some(t) => {
+            let local = t.secs + t.offset as i64;
+            let year = civil_from_days(local.div_euclid(86400)).0;
+            if !(1980..=2107).contains(&year) {
+                bail!(
+                    "The fixed time is in {}, FAT can only store 1980 to 2107",
+                    year
+                );
+            }
+            Clock {
+                fixed: Some(local),
+                offset: Some(t.offset),
+            }
+        },
+        None => Clock {
+            fixed: None,
+            offset: tz,
+        },
+    };
+    CLOCK.set(clock).expect("clock initialized twice");
+    Ok(())
+}
+
+/// The clock set up by `init`
+pub fn get() -> Clock {
+    CLOCK.get().copied().unwrap_or_default()
+}
+
+impl Clock {
+    /// Current local time in seconds, see `local_secs`
+    pub fn now_local(&self) -> i64 {
+        local_secs(&self.get_current_date_time())
+    }
+
+    /// UTC offset of the local time written to images. Without `--tz`
+    /// it's the host's, as far as the host clock tells.
+    pub fn utc_offset(&self) -> i32 {
+        if let Some(offset) = self.offset {
+            return offset;
+        }
+        let local = local_secs(&DefaultTimeProvider::new().get_current_date_time());
+        let utc = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        // Offsets are whole quarter hours, the rest is the clock ticking
+        ((local - utc) as f64 / 900.0).round() as i32 * 900
+    }
+}
+
+/// A FAT local time as seconds since 1970-01-01 00:00 of the same zone,
+/// so they compare like the timestamps do
+pub fn local_secs(t: &DateTime) -> i64 {
+    let days = days_from_civil(t.date.year as i64, t.date.month as i64, t.date.day as i64);
+    days * 86400 + t.time.hour as i64 * 3600 + t.time.min as i64 * 60 + t.time.sec as i64
+}
+
+impl TimeProvider for Clock {
+    fn get_current_date(&self) -> Date {
+        self.get_current_date_time().date
+    }
+
+    fn get_current_date_time(&self) -> DateTime {
+        let (local, millis) = match (self.fixed, self.offset) {
+            (Some(local), _) => (local, 0),
+            (None, Some(offset)) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                (
+                    now.as_secs() as i64 + offset as i64,
+                    now.subsec_millis() as u16,
+                )
+            },
+            (None, None) => return DefaultTimeProvider::new().get_current_date_time(),
+        };
+        date_time(local, millis)
+    }
+}
+
+/// The FAT timestamp of local seconds, see `local_secs`
+pub fn date_time(local: i64, millis: u16) -> DateTime {
+    let (year, month, day) = civil_from_days(local.div_euclid(86400));
+    let secs = local.rem_euclid(86400);
+    DateTime::new(
+        Date::new(year as u16, month as u16, day as u16),
+        Time::new(
+            (secs / 3600) as u16,
+            (secs / 60 % 60) as u16,
+            (secs % 60) as u16,
+            millis,
+        ),
+    )
+}
+
+/// Which way a time was outside of what FAT can store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutOfRange {
+    Before1980,
+    After2107,
+}
+
+/// Clamps local seconds to the FAT range, 1980-01-01 00:00:00 to
+/// 2107-12-31 23:59:58. Comparing clamped times keeps out of range host
+/// times from looking changed on every run.
+pub fn clamp_local(local: i64) -> (i64, Option<OutOfRange>) {
+    let min = days_from_civil(1980, 1, 1) * 86400;
+    let max = days_from_civil(2108, 1, 1) * 86400 - 2;
+    if local < min {
+        (min, Some(OutOfRange::Before1980))
+    } else if local > max {
+        (max, Some(OutOfRange::After2107))
+    } else {
+        (local, None)
+    }
+}
+
+/// Local seconds of a host time, in the offset timestamps are written in
+pub fn host_local(t: SystemTime) -> i64 {
+    let secs = match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    };
+    secs + get().utc_offset() as i64
+}
+
+/// The host time of a FAT timestamp stored at `utc_offset` seconds from
+/// UTC, the inverse of `host_local`
+pub fn host_time(t: &DateTime, utc_offset: i32) -> SystemTime {
+    let secs = local_secs(t) - utc_offset as i64;
+    let millis = std::time::Duration::from_millis(t.time.millis as u64);
+    match u64::try_from(secs) {
+        Ok(secs) => UNIX_EPOCH + std::time::Duration::from_secs(secs) + millis,
+        Err(_) => UNIX_EPOCH - std::time::Duration::from_secs(secs.unsigned_abs()) + millis,
+    }
+}
+
+/// `t` in RFC 3339 form in UTC, to the second
+pub fn format_utc(t: SystemTime) -> String {
+    let secs = match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    };
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// A FAT timestamp in RFC 3339 form, in the UTC offset timestamps are
+/// written in. Milliseconds are only given if there are any.
+pub fn format_rfc3339(t: &DateTime) -> String {
+    let offset = get().utc_offset();
+    let sign = if offset < 0 { '-' } else { '+' };
+    let millis = match t.time.millis {
+        0 => String::new(),
+        ms => format!(".{:03}", ms),
+    };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}{}{:02}:{:02}",
+        t.date.year,
+        t.date.month,
+        t.date.day,
+        t.time.hour,
+        t.time.min,
+        t.time.sec,
+        millis,
+        sign,
+        offset.abs() / 3600,
+        offset.abs() / 60 % 60
+    )
+}
+
+/// `--now`, an RFC 3339 time or seconds since the Unix epoch, which are
+/// taken as UTC like `SOURCE_DATE_EPOCH`
+pub fn parse_now(s: &str) -> Result<Timestamp, String> {
+    if !s.is_empty() && s.bytes().all(|c| c.is_ascii_digit()) {
+        let secs = s.parse().map_err(|_| format!("Invalid time {:?}", s))?;
+        return Ok(Timestamp { secs, offset: 0 });
+    }
+    parse_rfc3339(s)
+}
+
+/// Parses a time like `2024-05-01T12:00:00Z` or
+/// `2024-05-01 14:00:00.5+02:00`. Fractions of a second are dropped.
+pub fn parse_rfc3339(s: &str) -> Result<Timestamp, String> {
+    let invalid = || format!("Invalid RFC 3339 time {:?}", s);
+    let b = s.as_bytes();
+    if !s.is_ascii() || b.len() < 20 || !matches!(b[10], b'T' | b't' | b' ') {
+        return Err(invalid());
+    }
+    let num = |range: std::ops::Range<usize>| -> Result<i64, String> {
+        let part = &s[range];
+        if !part.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        part.parse().map_err(|_| invalid())
+    };
+    let separators = [(4, b'-'), (7, b'-'), (13, b':'), (16, b':')];
+    if separators.iter().any(|&(i, c)| b[i] != c) {
+        return Err(invalid());
+    }
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, min, sec) = (num(11..13)?, num(14..16)?, num(17..19)?);
+
+    let mut rest = &s[19..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return Err(invalid());
+        }
+        rest = &fraction[digits..];
+    }
+    let offset = parse_tz(rest).map_err(|_| invalid())?;
+
+    let days = days_from_civil(year, month, day);
+    if civil_from_days(days) != (year, month, day) || hour > 23 || min > 59 || sec > 59 {
+        return Err(invalid());
+    }
+    Ok(Timestamp {
+        secs: days * 86400 + hour * 3600 + min * 60 + sec - offset as i64,
+        offset,
+    })
+}
+
+/// A time given on the command line
+#[derive(Debug, Clone, Copy)]
+pub enum GivenTime {
+    /// With a UTC offset, stored in the offset timestamps are written in
+    Instant(Timestamp),
+    /// Without one, stored as written, in local seconds
+    Local(i64),
+}
+
+impl GivenTime {
+    /// Local seconds to store, see `local_secs`
+    pub fn local_secs(&self) -> i64 {
+        match self {
+            Self::Instant(t) => t.secs + get().utc_offset() as i64,
+            Self::Local(local) => *local,
+        }
+    }
+}
+
+/// Parses an RFC 3339 time, or one without offset like
+/// `2024-05-01T12:00:00` that is taken as the local time to store
+pub fn parse_given_time(s: &str) -> Result<GivenTime, String> {
+    if s.len() == 19 {
+        let t = parse_rfc3339(&format!("{}Z", s))
+            .map_err(|_| format!("Invalid time {:?}, expected e.g. 2024-05-01T12:00:00", s))?;
+        return Ok(GivenTime::Local(t.secs));
+    }
+    Ok(GivenTime::Instant(parse_rfc3339(s)?))
+}
+
+/// Parses a `--tz` UTC offset: `Z`, `UTC`, `+02:00` or `-0530`
+pub fn parse_tz(s: &str) -> Result<i32, String> {
+    let invalid = || format!("Invalid UTC offset {:?}, expected e.g. +02:00", s);
+    if matches!(s, "Z" | "z" | "UTC") {
+        return Ok(0);
+    }
+    let (sign, digits) = match s.as_bytes().first() {
+        Some(b'+') => (1, &s[1..]),
+        Some(b'-') => (-1, &s[1..]),
+        _ => return Err(invalid()),
+    };
+    let digits = digits.replacen(':', "", 1);
+    if digits.len() != 4 || !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let (hours, mins): (i32, i32) = (digits[..2].parse().unwrap(), digits[2..].parse().unwrap());
+    if hours > 23 || mins > 59 {
+        return Err(invalid());
+    }
+    Ok(sign * (hours * 3600 + mins * 60))
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc3339_times() {
+        let t = parse_rfc3339("2024-05-01T12:00:00Z").unwrap();
+        assert_eq!((t.secs, t.offset), (1_714_564_800, 0));
+        let t = parse_rfc3339("2024-05-01 14:00:00.5+02:00").unwrap();
+        assert_eq!((t.secs, t.offset), (1_714_564_800, 7200));
+        let t = parse_rfc3339("2024-05-01t06:30:00-0530").unwrap();
+        assert_eq!((t.secs, t.offset), (1_714_564_800, -19800));
+        for invalid in [
+            "2024-05-01T12:00:00",
+            "2024-02-30T12:00:00Z",
+            "2024-05-01T24:00:00Z",
+            "2024-05-01T12:00:00.Z",
+            "2024/05/01T12:00:00Z",
+            "2024-05-01T12:00:00+2",
+        ] {
+            assert!(parse_rfc3339(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn now_and_given_times() {
+        let t = parse_now("1714564800").unwrap();
+        assert_eq!((t.unix_secs(), t.offset), (1_714_564_800, 0));
+        assert_eq!(
+            parse_now("2024-05-01T12:00:00Z").unwrap().unix_secs(),
+            1_714_564_800
+        );
+        assert!(parse_now("").is_err());
+        assert!(parse_now("-1").is_err());
+
+        assert!(matches!(
+            parse_given_time("2024-05-01T12:00:00"),
+            Ok(GivenTime::Local(1_714_564_800))
+        ));
+        assert!(matches!(
+            parse_given_time("2024-05-01T12:00:00+01:00"),
+            Ok(GivenTime::Instant(Timestamp {
+                secs: 1_714_561_200,
+                offset: 3600
+            }))
+        ));
+        assert!(parse_given_time("2024-05-01 noon").is_err());
+    }
+
+    #[test]
+    fn utc_offsets() {
+        assert_eq!(parse_tz("Z"), Ok(0));
+        assert_eq!(parse_tz("UTC"), Ok(0));
+        assert_eq!(parse_tz("+02:00"), Ok(7200));
+        assert_eq!(parse_tz("-0530"), Ok(-19800));
+        for invalid in ["02:00", "+2:00", "+24:00", "+01:60", "+01:00:00", ""] {
+            assert!(parse_tz(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn civil_days_round_trip() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1980, 1, 1), 3652);
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        for days in (-800_000..800_000).step_by(997) {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+
+    #[test]
+    fn fat_timestamps() {
+        let t = date_time(1_714_564_801, 250);
+        assert_eq!((t.date.year, t.date.month, t.date.day), (2024, 5, 1));
+        assert_eq!(
+            (t.time.hour, t.time.min, t.time.sec, t.time.millis),
+            (12, 0, 1, 250)
+        );
+        assert_eq!(local_secs(&t), 1_714_564_801);
+        assert_eq!(
+            format_utc(UNIX_EPOCH + std::time::Duration::from_secs(1_714_564_801)),
+            "2024-05-01T12:00:01Z"
+        );
+        assert_eq!(
+            format_utc(UNIX_EPOCH - std::time::Duration::from_secs(1)),
+            "1969-12-31T23:59:59Z"
+        );
+    }
+
+    #[test]
+    fn clamping_to_the_fat_range() {
+        let min = days_from_civil(1980, 1, 1) * 86400;
+        let max = days_from_civil(2107, 12, 31) * 86400 + 86398;
+        assert_eq!(clamp_local(min), (min, None));
+        assert_eq!(clamp_local(min - 1), (min, Some(OutOfRange::Before1980)));
+        assert_eq!(clamp_local(max), (max, None));
+        assert_eq!(clamp_local(max + 1), (max, Some(OutOfRange::After2107)));
+    }
+}
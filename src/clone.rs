@@ -0,0 +1,127 @@
+//! Instantiating customized copies of a template image
+
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use crate::fanout::WriteSource;
+use crate::ondisk::{self, BootSector};
+use crate::overwrite::OverwritePolicy;
+use crate::paths;
+use crate::region::ImgSlice;
+use crate::WriteOptions;
+
+/// Changes applied to the copy
+pub struct Customizations {
+    pub volume_id: Option<String>,
+    pub label: Option<String>,
+    /// `<inner path>=<host path>` pairs
+    pub write: Vec<String>,
+    /// `<inner path>=<text>` pairs
+    pub data: Vec<String>,
+}
+
+fn split_assignment(arg: &str) -> Result<(String, &str)> {
+    match arg.split_once('=') {
+        Some((inner_path, value)) => Ok((paths::normalize_entry(inner_path)?, value)),
+        None => bail!("Expected <inner path>=<value>, got {:?}", arg),
+    }
+}
+
+/// A volume id in hex, or `random`
+pub fn parse_volume_id(s: &str) -> Result<u32> {
+    if s == "random" {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        return Ok(hasher.finish() as u32);
+    }
+    let hex = s.trim_start_matches("0x");
+    u32::from_str_radix(hex, 16)
+        .with_context(|| format!("Invalid volume id {:?}, expected hex or `random`", s))
+}
+
+/// Copies `base` to `target` and applies the customizations to the copy.
+/// The copy is prepared under a temporary name, so `target` only appears
+/// once all changes were made.
+pub fn run(
+    base: &Path, target: &Path, overwrite: OverwritePolicy, c: &Customizations,
+) -> Result<()> {
+    if target.exists() {
+        overwrite.check(false, target.display())?;
+    }
+
+    // Validate everything before copying potentially large files
+    let volume_id = c.volume_id.as_deref().map(parse_volume_id).transpose()?;
+    let label = c
+        .label
+        .as_deref()
+        .map(ondisk::parse_volume_label)
+        .transpose()?;
+    let mut writes = Vec::new();
+    for arg in &c.write {
+        let (inner_path, host_path) = split_assignment(arg)?;
+        let source = if host_path == "-" {
+            if writes.iter().any(|(_, s)| matches!(s, WriteSource::Stdin)) {
+                bail!("Only one --write can read from stdin");
+            }
+            WriteSource::Stdin
+        } else {
+            WriteSource::HostFile(PathBuf::from(host_path))
+        };
+        writes.push((inner_path, source));
+    }
+    for arg in &c.data {
+        let (inner_path, text) = split_assignment(arg)?;
+        writes.push((inner_path, WriteSource::Buffered(text.as_bytes().to_vec())));
+    }
+
+    let file_name = target
+        .file_name()
+        .context("Target path has no file name")?
+        .to_string_lossy();
+    let tmp = target.with_file_name(format!(".{}.fatimg-{}", file_name, std::process::id()));
+
+    let result = customize(base, &tmp, volume_id, label, &writes, overwrite);
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+        return result;
+    }
+    fs::rename(&tmp, target)?;
+    Ok(())
+}
+
+fn customize(
+    base: &Path, tmp: &Path, volume_id: Option<u32>, label: Option<[u8; 11]>,
+    writes: &[(String, WriteSource)], overwrite: OverwritePolicy,
+) -> Result<()> {
+    // Uses copy_file_range or similar where the platform supports it
+    fs::copy(base, tmp)?;
+
+    let opts = WriteOptions {
+        allow_empty: true,
+        overwrite,
+        ..WriteOptions::default()
+    };
+    for (inner_path, source) in writes {
+        crate::write_file(tmp, inner_path, source, &opts)
+            .with_context(|| format!("Writing {}", inner_path))?;
+    }
+
+    if volume_id.is_some() || label.is_some() {
+        let mut f = ImgSlice::open(tmp, true)?;
+        let mut bs = BootSector::read(&mut f)?;
+        if let Some(id) = volume_id {
+            bs.set_volume_id(id);
+        }
+        if let Some(label) = label {
+            bs.set_volume_label(label);
+            ondisk::write_root_volume_label(&mut f, label)?;
+        }
+        bs.write(&mut f)?;
+        f.sync_all()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,77 @@
+//! Raw access to data clusters, bypassing the filesystem
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::ondisk::{BootSector, Fat, Layout};
+use crate::region::ImgSlice;
+
+fn check_range(layout: &Layout, cluster: u32, count: u32) -> Result<()> {
+    let last = cluster as u64 + count as u64 - 1;
+    if !layout.is_data_cluster(cluster) || last >= layout.total_clusters as u64 + 2 {
+        bail!(
+            "Clusters {}..={} are outside of the data area (clusters 2..={})",
+            cluster,
+            last,
+            layout.total_clusters + 1
+        );
+    }
+    Ok(())
+}
+
+/// Copies `count` clusters starting from `cluster` to `out`
+pub fn read(img_file: &Path, cluster: u32, count: u32, out: &mut dyn Write) -> Result<()> {
+    if count == 0 {
+        bail!("Cluster count must be at least 1");
+    }
+    let mut f = ImgSlice::open(img_file, false)?;
+    let layout = BootSector::read(&mut f)?.layout()?;
+    check_range(&layout, cluster, count)?;
+
+    f.seek(SeekFrom::Start(layout.cluster_offset(cluster)))?;
+    let len = count as u64 * layout.cluster_size;
+    let copied = std::io::copy(&mut (&mut f).take(len), out)?;
+    if copied != len {
+        bail!(
+            "Image ends inside cluster {}",
+            cluster as u64 + copied / layout.cluster_size
+        );
+    }
+    Ok(())
+}
+
+/// Writes `data` to clusters starting from `cluster`, zero-filling the
+/// rest of the last one. Clusters that belong to files are refused unless
+/// `allow_allocated` is set. Free clusters and ones marked bad are fine.
+pub fn write(img_file: &Path, cluster: u32, data: &[u8], allow_allocated: bool) -> Result<()> {
+    if data.is_empty() {
+        bail!("Nothing to write");
+    }
+    let mut f = ImgSlice::open(img_file, true)?;
+    let layout = BootSector::read(&mut f)?.layout()?;
+    let count = (data.len() as u64).div_ceil(layout.cluster_size) as u32;
+    check_range(&layout, cluster, count)?;
+
+    if !allow_allocated {
+        let fat = Fat::read(&mut f, &layout)?;
+        let allocated: Vec<String> = (cluster..cluster + count)
+            .filter(|&c| fat.get(c) != 0 && !fat.is_bad(fat.get(c)))
+            .map(|c| c.to_string())
+            .collect();
+        if !allocated.is_empty() {
+            bail!(
+                "Clusters in use by the filesystem: {}. Use --allow-allocated to overwrite them anyway",
+                allocated.join(", ")
+            );
+        }
+    }
+
+    let mut padded = data.to_vec();
+    padded.resize((count as u64 * layout.cluster_size) as usize, 0);
+    f.seek(SeekFrom::Start(layout.cluster_offset(cluster)))?;
+    f.write_all(&padded)?;
+    f.sync_all()?;
+    Ok(())
+}
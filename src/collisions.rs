@@ -0,0 +1,146 @@
+//! Detecting names that collide once case is ignored: host names going
+//! into FAT, and image names going onto a case-insensitive host
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::hostpath;
+
+/// What to do with host entries whose names differ only by case
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnCollision {
+    /// Fail before writing anything
+    Error,
+    /// Keep the first name in sorted order
+    First,
+    /// Keep the last name in sorted order
+    Last,
+    /// Add a numeric suffix to all but the first name
+    Rename,
+}
+
+/// Decision for one colliding host entry
+#[derive(Debug, Clone)]
+pub enum Resolution {
+    Skip,
+    Rename(String),
+}
+
+/// FAT compares names case-insensitively
+pub fn fold(name: &str) -> String {
+    name.to_uppercase()
+}
+
+/// `name-N.ext`, or `name-N` without an extension
+fn with_suffix(name: &str, n: usize) -> String {
+    match name.rfind('.') {
+        Some(i) if i > 0 => format!("{}-{}{}", &name[..i], n, &name[i..]),
+        _ => format!("{}-{}", name, n),
+    }
+}
+
+/// Decides what happens to the entries of one directory, given as
+/// `(name, is_dir)`. Entries without a resolution keep their names.
+/// With `OnCollision::Error` only the kept entry of a colliding group is
+/// returned and the group is described in `errors`, prefixed by `dir`.
+pub fn resolve_dir(
+    entries: Vec<(String, bool)>, policy: OnCollision, dir: &str, errors: &mut Vec<String>,
+) -> Vec<(String, bool, Option<Resolution>)> {
+    let mut groups: BTreeMap<String, Vec<(String, bool)>> = BTreeMap::new();
+    for (name, is_dir) in entries {
+        groups.entry(fold(&name)).or_default().push((name, is_dir));
+    }
+    let mut used: HashSet<String> = groups.keys().cloned().collect();
+
+    let mut result = Vec::new();
+    for group in groups.values_mut() {
+        group.sort();
+        let keep = match policy {
+            OnCollision::Last => group.len() - 1,
+            _ => 0,
+        };
+        for (i, (name, is_dir)) in group.iter().enumerate() {
+            if i == keep {
+                result.push((name.clone(), *is_dir, None));
+                continue;
+            }
+            let resolution = match policy {
+                OnCollision::Error => {
+                    let names: Vec<_> = group.iter().map(|(n, _)| n.as_str()).collect();
+                    errors.push(format!("{}: {}", dir, names.join(", ")));
+                    break;
+                },
+                OnCollision::First | OnCollision::Last => Resolution::Skip,
+                OnCollision::Rename => {
+                    let new_name = (1..)
+                        .map(|n| with_suffix(name, n))
+                        .find(|n| !used.contains(&fold(n)))
+                        .expect("Unbounded suffix search");
+                    used.insert(fold(&new_name));
+                    Resolution::Rename(new_name)
+                },
+            };
+            result.push((name.clone(), *is_dir, Some(resolution)));
+        }
+    }
+    result
+}
+
+/// Walks the host tree and decides what to do with each entry that
+/// collides with a sibling. Entries missing from the result are written
+/// under their own names. With `nfc`, names are compared once normalized
+/// like `write-tree --nfc-names` stores them.
+pub fn plan(
+    host_path: &Path, policy: OnCollision, nfc: bool,
+) -> Result<HashMap<PathBuf, Resolution>> {
+    let mut result = HashMap::new();
+    let mut errors = Vec::new();
+    let mut stack = vec![host_path.to_owned()];
+
+    while let Some(dir) = stack.pop() {
+        let mut entries = Vec::new();
+        // Host paths by name, which differ for names that aren't UTF-8 or
+        // normalized, so that there can be several
+        let mut paths: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            // Names without a reading are refused or skipped by write-tree
+            let name = match hostpath::decode_name(&entry.file_name()) {
+                Some(name) => name,
+                None => continue,
+            };
+            let name = if nfc { name.nfc().collect() } else { name };
+            entries.push((name.clone(), entry.file_type()?.is_dir()));
+            paths.entry(name).or_default().push(entry.path());
+        }
+
+        let display = dir.display().to_string();
+        for (name, is_dir, resolution) in resolve_dir(entries, policy, &display, &mut errors) {
+            let path = paths
+                .get_mut(&name)
+                .and_then(Vec::pop)
+                .expect("every entry has a path");
+            if is_dir && !matches!(resolution, Some(Resolution::Skip)) {
+                stack.push(path.clone());
+            }
+            if let Some(resolution) = resolution {
+                if let Resolution::Rename(new_name) = &resolution {
+                    eprintln!("Renaming {} -> {}", path.display(), new_name);
+                }
+                result.insert(path, resolution);
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        bail!(
+            "Host names that would collide in the image:\n  {}\nUse --on-collision to choose how to resolve them",
+            errors.join("\n  ")
+        );
+    }
+    Ok(result)
+}
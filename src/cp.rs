@@ -0,0 +1,152 @@
+//! Copying files and trees inside the image, without going through the
+//! host
+
+use std::io;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::ondisk::{BootSector, Fat, RawDir};
+use crate::overwrite::OverwritePolicy;
+use crate::region::ImgSlice;
+use crate::{delta, exit};
+use crate::{open_fs_rw, overlap, parent_of, refuse_file_components, walk, ImgFs, Overlap};
+
+/// Options of the `cp` command
+pub struct CpOptions {
+    /// Copy directories with everything in them
+    pub recursive: bool,
+    /// Keep attributes and timestamps of the copied entries
+    pub preserve: bool,
+    /// Existing files are replaced by default
+    pub overwrite: OverwritePolicy,
+}
+
+/// Bytes of a raw directory entry that `--preserve` copies: the
+/// attributes, and the timestamps around the high cluster word. The NT
+/// case byte in between belongs to the copy's own short name.
+const META: [std::ops::Range<usize>; 3] = [11..12, 13..20, 22..26];
+
+/// Whether the normalized image path is a directory, a file or missing
+fn kind(fs: &ImgFs, inner_path: &str) -> Option<bool> {
+    let root = fs.root_dir();
+    if inner_path.is_empty() || root.open_dir(inner_path).is_ok() {
+        Some(true)
+    } else {
+        root.open_file(inner_path).is_ok().then_some(false)
+    }
+}
+
+/// Copies attributes and timestamps of each `(source, copy)` pair onto the
+/// copy, on the raw entries since fatfs can't set attributes
+fn preserve(img_file: &Path, pairs: &[(String, String)]) -> Result<()> {
+    let mut img = ImgSlice::open(img_file, true)?;
+    let layout = BootSector::read(&mut img)?.layout()?;
+    let fat = Fat::read(&mut img, &layout)?;
+    for (src, dst) in pairs {
+        let from = RawDir::entry_at(&mut img, &layout, &fat, src)?;
+        let mut to = RawDir::entry_at(&mut img, &layout, &fat, dst)?;
+        for range in META {
+            to.raw[range.clone()].copy_from_slice(&from.raw[range]);
+        }
+        to.write(&mut img)
+            .with_context(|| format!("failed writing /{}", dst))?;
+    }
+    img.sync_all()?;
+    Ok(())
+}
+
+/// Copies the normalized image path `src` to `dst`. A directory is merged
+/// into an existing one, replacing files in it unless `opts.overwrite`
+/// says otherwise. Conflicts are found before anything is copied.
+pub fn run(img_file: &Path, src: &str, dst: &str, opts: &CpOptions) -> Result<()> {
+    refuse_file_components(img_file, parent_of(src))?;
+    refuse_file_components(img_file, parent_of(dst))?;
+    match overlap(img_file, src, dst)? {
+        Overlap::Same => bail!("/{} and /{} are the same entry", src, dst),
+        Overlap::Inside => bail!("Can't copy /{} into itself, /{} is inside it", src, dst),
+        Overlap::Disjoint => {},
+    }
+    let fs = open_fs_rw(img_file)?;
+    let src_is_dir = match kind(&fs, src) {
+        Some(is_dir) => is_dir,
+        None => return Err(exit::NotFound(format!("/{}", src)).into()),
+    };
+    if src_is_dir && !opts.recursive {
+        bail!("/{}: is a directory, use -r to copy it", src);
+    }
+    let parent = parent_of(dst);
+    if kind(&fs, parent) != Some(true) {
+        bail!("/{}: destination directory doesn't exist", parent);
+    }
+
+    // Everything to copy as (source, copy, is_dir), parents first
+    let mut items = vec![(src.to_owned(), dst.to_owned(), src_is_dir)];
+    if src_is_dir {
+        let dir = fs
+            .root_dir()
+            .open_dir(src)
+            .with_context(|| format!("failed opening directory /{}", src))?;
+        for item in walk::Walk::new(&dir, &format!("/{}", src), true) {
+            let walk::Entry { entry, path, .. } = item?;
+            let rel = &path[src.len() + 1..];
+            items.push((
+                path[1..].to_owned(),
+                format!("{}{}", dst, rel),
+                entry.is_dir(),
+            ));
+        }
+    }
+    for (from, to, is_dir) in &items {
+        match (kind(&fs, to), is_dir) {
+            (None, _) | (Some(true), true) => {},
+            (Some(false), false) => opts.overwrite.check(true, format_args!("/{}", to))?,
+            (Some(_), _) => bail!(
+                "/{} can't be copied to /{}, it is a {}",
+                from,
+                to,
+                if *is_dir { "file" } else { "directory" }
+            ),
+        }
+    }
+
+    let root = fs.root_dir();
+    for (from, to, is_dir) in &items {
+        if *is_dir {
+            if root.open_dir(to).is_err() {
+                root.create_dir(to)
+                    .with_context(|| format!("failed creating directory /{}", to))?;
+                delta::created(true);
+            }
+            continue;
+        }
+        let context = || format!("failed copying /{} to /{}", from, to);
+        let mut source = root.open_file(from).with_context(context)?;
+        if kind(&fs, to).is_none() {
+            delta::created(false);
+        }
+        let mut target = root.create_file(to).with_context(context)?;
+        target.truncate().with_context(context)?;
+        let mut reader = io::BufReader::new(&mut source);
+        io::copy(&mut reader, &mut target).with_context(context)?;
+        fatfs::Write::flush(&mut target).with_context(context)?;
+    }
+    drop(root);
+    fs.unmount().context("failed flushing the filesystem")?;
+
+    if opts.preserve {
+        let pairs: Vec<_> = items
+            .iter()
+            .map(|(f, t, _)| (f.clone(), t.clone()))
+            .collect();
+        preserve(img_file, &pairs)?;
+    }
+    let files = items.iter().filter(|(_, _, is_dir)| !is_dir).count();
+    eprintln!(
+        "copied {} file(s) and {} directories to /{}",
+        files,
+        items.len() - files,
+        dst
+    );
+    Ok(())
+}
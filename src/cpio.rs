@@ -0,0 +1,124 @@
+//! Exporting an image tree as a newc (SVR4) cpio archive, the format
+//! initramfs loaders and `cpio -H newc` read.
+//!
+//! FAT has no owners, links or device numbers, so those fields are zero
+//! and each entry gets an inode number of its own. Modes are 0755 for
+//! directories and 0644 for files, without the write bits for read-only
+//! entries. Times are the modification times, converted from the local
+//! time FAT stores with the clock's UTC offset.
+
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use fatfs::{FileSystem, FsOptions};
+use fscommon::BufStream;
+
+use crate::region::ImgSlice;
+use crate::walk::Walk;
+use crate::{artifacts, clock, glob, paths};
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// Options of the `export-cpio` command
+pub struct CpioOptions {
+    /// Paths relative to the subtree to leave out, with their contents
+    pub exclude: Vec<String>,
+    /// Leave out `artifacts::WINDOWS_ARTIFACTS` at the top of the volume
+    pub skip_windows_artifacts: bool,
+}
+
+/// Writes a newc header and the NUL-terminated name, padded to 4 bytes
+fn header(
+    out: &mut impl Write, ino: u32, mode: u32, nlink: u32, mtime: u32, size: u32, name: &str,
+) -> io::Result<()> {
+    let namesize = name.len() as u32 + 1;
+    write!(
+        out,
+        "070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+        ino, mode, 0, 0, nlink, mtime, size, 0, 0, 0, 0, namesize, 0
+    )?;
+    out.write_all(name.as_bytes())?;
+    out.write_all(&[0])?;
+    pad(out, 110 + namesize as u64)
+}
+
+/// Pads what has been written since the last boundary to 4 bytes
+fn pad(out: &mut impl Write, len: u64) -> io::Result<()> {
+    let zeros = [0u8; 3];
+    out.write_all(&zeros[..((4 - len % 4) % 4) as usize])
+}
+
+/// Writes the image directory `inner_path` and everything below it,
+/// named relative to it, followed by the `TRAILER!!!` record
+pub fn run(img_file: &Path, inner_path: &str, opts: &CpioOptions, out: impl Write) -> Result<()> {
+    let file = ImgSlice::open(img_file, false)?;
+    let buf_file = BufStream::new(file);
+    let fs = FileSystem::new(buf_file, FsOptions::new().time_provider(clock::get()))?;
+    let mut dir = fs.root_dir();
+    if !inner_path.is_empty() {
+        dir = dir
+            .open_dir(inner_path)
+            .with_context(|| format!("failed opening directory /{}", inner_path))?;
+    }
+
+    let mut out = BufWriter::new(out);
+    let offset = clock::get().utc_offset() as i64;
+    let prefix = format!("/{}", inner_path);
+    let mut walk = Walk::new(&dir, &prefix, true);
+    let mut ino = 0;
+    while let Some(item) = walk.next() {
+        let item = item?;
+        let rel = paths::relative(&prefix, &item.path).to_owned();
+        let artifact = inner_path.is_empty()
+            && item.depth == 0
+            && opts.skip_windows_artifacts
+            && artifacts::is_windows_artifact(&rel);
+        if artifact || opts.exclude.iter().any(|g| glob::matches(g, &rel)) {
+            if item.entry.is_dir() {
+                walk.skip_dir();
+            }
+            continue;
+        }
+
+        ino += 1;
+        let mtime = clock::local_secs(&item.entry.modified()) - offset;
+        let mtime = mtime.clamp(0, u32::MAX as i64) as u32;
+        let write_bits = if item.entry.attributes().bits() & 0x01 != 0 {
+            0
+        } else {
+            0o200
+        };
+        if item.entry.is_dir() {
+            let mode = S_IFDIR | 0o555 | write_bits;
+            header(&mut out, ino, mode, 2, mtime, 0, &rel)?;
+            continue;
+        }
+
+        let size = item.entry.len() as u32;
+        header(
+            &mut out,
+            ino,
+            S_IFREG | 0o444 | write_bits,
+            1,
+            mtime,
+            size,
+            &rel,
+        )?;
+        let copied = io::copy(&mut item.entry.to_file(), &mut out)
+            .with_context(|| format!("failed reading {}", item.path))?;
+        if copied != size as u64 {
+            bail!(
+                "{}: read {} bytes, the entry says {}",
+                item.path,
+                copied,
+                size
+            );
+        }
+        pad(&mut out, copied)?;
+    }
+    header(&mut out, 0, 0, 1, 0, 0, "TRAILER!!!")?;
+    out.flush()?;
+    Ok(())
+}
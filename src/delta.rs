@@ -0,0 +1,130 @@
+//! The summary mutating commands print on stderr once they are done, like
+//! `free: 432111616 -> 385351680 (-46759936), files: +213, dirs: +9`.
+//!
+//! Free space is taken from the filesystem stats before and after the
+//! command. Entry counts are noted by the operations as they create and
+//! remove entries, so that neither end needs to walk the tree.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use fatfs::{FileSystem, FsOptions};
+use fscommon::BufStream;
+
+use crate::json;
+use crate::region::ImgSlice;
+use crate::size::SizeFormat;
+
+/// Files and directories created and removed so far
+static CREATED: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+static REMOVED: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+/// Notes that an entry that didn't exist before was created
+pub fn created(is_dir: bool) {
+    CREATED[is_dir as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Notes that an entry was removed
+pub fn removed(is_dir: bool) {
+    REMOVED[is_dir as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Created and removed entries, files or directories
+#[derive(Debug, Clone, Copy)]
+pub struct Counts {
+    pub created: u64,
+    pub removed: u64,
+}
+
+impl Counts {
+    fn load(is_dir: bool) -> Self {
+        Self {
+            created: CREATED[is_dir as usize].load(Ordering::Relaxed),
+            removed: REMOVED[is_dir as usize].load(Ordering::Relaxed),
+        }
+    }
+
+    fn net(&self) -> i64 {
+        self.created as i64 - self.removed as i64
+    }
+
+    fn to_json(self) -> json::Value {
+        json::object([
+            ("created", self.created.into()),
+            ("removed", self.removed.into()),
+        ])
+    }
+}
+
+fn free_bytes(img_file: &Path) -> Result<u64> {
+    let file = ImgSlice::open(img_file, false)?;
+    let buf_file = BufStream::new(file);
+    let fs = FileSystem::new(buf_file, FsOptions::new())?;
+    let stats = fs.stats()?;
+    Ok(stats.free_clusters() as u64 * stats.cluster_size() as u64)
+}
+
+/// Free space before a mutating command
+pub struct Delta {
+    img_file: PathBuf,
+    free_before: u64,
+}
+
+/// What a command changed
+pub struct Changes {
+    pub free_before: u64,
+    pub free_after: u64,
+    pub files: Counts,
+    pub dirs: Counts,
+}
+
+impl Delta {
+    /// `None` for images that aren't a readable filesystem, like one that
+    /// is yet to be created
+    pub fn start(img_file: &Path) -> Option<Self> {
+        let free_before = free_bytes(img_file).ok()?;
+        Some(Self {
+            img_file: img_file.to_owned(),
+            free_before,
+        })
+    }
+
+    /// `None` if the image can't be read anymore
+    pub fn finish(self) -> Option<Changes> {
+        Some(Changes {
+            free_before: self.free_before,
+            free_after: free_bytes(&self.img_file).ok()?,
+            files: Counts::load(false),
+            dirs: Counts::load(true),
+        })
+    }
+}
+
+impl Changes {
+    pub fn print(&self, sizes: &SizeFormat) {
+        let (before, after) = (self.free_before, self.free_after);
+        let change = match after.checked_sub(before) {
+            Some(gain) => format!("+{}", sizes.allocated(gain)),
+            None => format!("-{}", sizes.allocated(before - after)),
+        };
+        eprintln!(
+            "free: {} -> {} ({}), files: {:+}, dirs: {:+}",
+            sizes.allocated(before),
+            sizes.allocated(after),
+            change,
+            self.files.net(),
+            self.dirs.net()
+        );
+    }
+
+    /// Sizes are in bytes, whatever `--block-size` says
+    pub fn to_json(&self) -> json::Value {
+        json::object([
+            ("free_before", self.free_before.into()),
+            ("free_after", self.free_after.into()),
+            ("files", self.files.to_json()),
+            ("dirs", self.dirs.to_json()),
+        ])
+    }
+}
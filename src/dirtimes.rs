@@ -0,0 +1,91 @@
+//! Directory timestamps of `write-tree`, which fatfs can't set.
+//!
+//! `--stable-dir-times` keeps the timestamps of the directories written
+//! into as they were, so that rebuilding an image changes no directory
+//! entries above the written tree. `--preserve-times` gives the created
+//! directories the times of the host ones, set once the whole tree is
+//! written so that populating them doesn't change them again.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::ondisk::{BootSector, Fat, RawDir};
+use crate::region::ImgSlice;
+
+/// Entry bytes of the creation time and date and the access date
+const CREATED_ACCESSED: std::ops::Range<usize> = 13..20;
+/// Entry bytes of the modification time and date
+const MODIFIED: std::ops::Range<usize> = 22..26;
+
+/// Directory timestamps saved by `save`, by image path
+pub struct Saved(Vec<(String, [u8; 32])>);
+
+/// `inner_path` and the directories above it, without the root, which
+/// has no entry
+fn ancestors(inner_path: &str) -> impl Iterator<Item = &str> {
+    inner_path
+        .match_indices('/')
+        .map(|(i, _)| &inner_path[..i])
+        .chain((!inner_path.is_empty()).then_some(inner_path))
+}
+
+/// Saves the entries of the normalized image directory `inner_path` and
+/// its parents. The ones that don't exist yet are left out.
+pub fn save(img_file: &Path, inner_path: &str) -> Result<Saved> {
+    let mut img = ImgSlice::open(img_file, false)?;
+    let layout = BootSector::read(&mut img)?.layout()?;
+    let fat = Fat::read(&mut img, &layout)?;
+    let saved = ancestors(inner_path)
+        .filter_map(|path| {
+            let entry = RawDir::entry_at(&mut img, &layout, &fat, path).ok()?;
+            Some((path.to_owned(), entry.raw))
+        })
+        .collect();
+    Ok(Saved(saved))
+}
+
+/// Sets the modification times of the image directories in `times`, by
+/// normalized path
+pub fn set_modified(img_file: &Path, times: &[(String, fatfs::DateTime)]) -> Result<()> {
+    if times.is_empty() {
+        return Ok(());
+    }
+    let mut img = ImgSlice::open(img_file, true)?;
+    let layout = BootSector::read(&mut img)?.layout()?;
+    let fat = Fat::read(&mut img, &layout)?;
+    for (path, t) in times {
+        let mut entry = RawDir::entry_at(&mut img, &layout, &fat, path)?;
+        entry.set_modified(t);
+        entry
+            .write(&mut img)
+            .with_context(|| format!("failed writing /{}", path))?;
+    }
+    img.sync_all()?;
+    Ok(())
+}
+
+/// Puts back the timestamps `save` found, returning how many entries had
+/// changed
+pub fn restore(img_file: &Path, saved: &Saved) -> Result<usize> {
+    let mut img = ImgSlice::open(img_file, true)?;
+    let layout = BootSector::read(&mut img)?.layout()?;
+    let fat = Fat::read(&mut img, &layout)?;
+    let mut restored = 0;
+    for (path, raw) in &saved.0 {
+        let mut entry = RawDir::entry_at(&mut img, &layout, &fat, path)?;
+        if entry.raw[CREATED_ACCESSED] == raw[CREATED_ACCESSED]
+            && entry.raw[MODIFIED] == raw[MODIFIED]
+        {
+            continue;
+        }
+        entry.raw[CREATED_ACCESSED].copy_from_slice(&raw[CREATED_ACCESSED]);
+        entry.raw[MODIFIED].copy_from_slice(&raw[MODIFIED]);
+        entry
+            .write(&mut img)
+            .with_context(|| format!("failed writing /{}", path))?;
+        restored += 1;
+    }
+    img.sync_all()?;
+    Ok(restored)
+}
@@ -0,0 +1,187 @@
+//! Space used by subtrees of the image
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Context, Result};
+use fatfs::FatType;
+
+use crate::artifacts;
+use crate::exit;
+use crate::ondisk::{BootSector, Fat, Layout, RawDir, RawDirEntry};
+use crate::region::ImgSlice;
+use crate::size::SizeFormat;
+
+/// Options of the `du` command
+pub struct DuOptions {
+    /// Sum file lengths instead of the clusters allocated to them
+    pub apparent_size: bool,
+    /// Only print the total of the given path
+    pub summarize: bool,
+    /// Leave out `artifacts::WINDOWS_ARTIFACTS`
+    pub skip_windows_artifacts: bool,
+    pub sizes: SizeFormat,
+}
+
+/// A directory `Walk::dir` is in the middle of
+struct Frame {
+    path: String,
+    /// The entries left to count
+    entries: std::vec::IntoIter<(String, RawDirEntry)>,
+    /// Usage counted so far
+    total: u64,
+}
+
+struct Walk<'a> {
+    img: &'a mut ImgSlice,
+    layout: Layout,
+    fat: Fat,
+    opts: &'a DuOptions,
+    visited: HashSet<u32>,
+}
+
+impl Walk<'_> {
+    /// Bytes taken up by the cluster chain starting at `start`
+    fn chain_bytes(&self, path: &str, start: u32) -> Result<u64> {
+        if start == 0 {
+            return Ok(0);
+        }
+        let chain = self.fat.chain(start).with_context(|| path.to_owned())?;
+        Ok(chain.len() as u64 * self.layout.cluster_size)
+    }
+
+    fn file(&self, path: &str, entry: &RawDirEntry) -> Result<u64> {
+        if self.opts.apparent_size {
+            Ok(entry.size() as u64)
+        } else {
+            self.chain_bytes(path, entry.first_cluster())
+        }
+    }
+
+    /// Usage of a directory and everything in it. In allocated mode this
+    /// includes the clusters of the directory itself, which add up on
+    /// images with lots of small directories. Directories are kept on a
+    /// stack instead of recursing, so that deep trees can't overflow it.
+    fn dir(&mut self, path: &str, dir: &RawDir, own: u64) -> Result<u64> {
+        let mut stack = vec![self.frame(path.to_owned(), dir, own)];
+        loop {
+            let frame = stack
+                .last_mut()
+                .expect("the start directory is popped last");
+            let (name, entry) = match frame.entries.next() {
+                Some(next) => next,
+                None => {
+                    let done = stack.pop().expect("the stack isn't empty");
+                    if !self.opts.summarize {
+                        self.print(done.total, &done.path);
+                    }
+                    match stack.last_mut() {
+                        Some(parent) => parent.total += done.total,
+                        None => return Ok(done.total),
+                    }
+                    continue;
+                },
+            };
+            if name == "." || name == ".." {
+                continue;
+            }
+            let sub_path = format!("{}/{}", frame.path, name);
+            if !entry.is_dir() {
+                frame.total += self.file(&sub_path, &entry)?;
+                continue;
+            }
+            if frame.path.is_empty()
+                && self.opts.skip_windows_artifacts
+                && artifacts::is_windows_artifact(&name)
+            {
+                eprintln!("skipped {}", sub_path);
+                continue;
+            }
+            let start = entry.first_cluster();
+            if !self.visited.insert(start) {
+                bail!(
+                    "{}: directory cluster {} is already used, run check",
+                    sub_path,
+                    start
+                );
+            }
+            let own = self.chain_bytes(&sub_path, start)?;
+            let sub = RawDir::read(self.img, &self.layout, &self.fat, start)
+                .with_context(|| sub_path.clone())?;
+            let frame = self.frame(sub_path, &sub, own);
+            stack.push(frame);
+        }
+    }
+
+    /// A directory for `dir` to go through, counting `own` bytes for its
+    /// clusters
+    fn frame(&self, path: String, dir: &RawDir, own: u64) -> Frame {
+        let entries: Vec<(String, RawDirEntry)> = dir
+            .files()
+            .into_iter()
+            .map(|(name, entry)| (name, entry.clone()))
+            .collect();
+        Frame {
+            path,
+            entries: entries.into_iter(),
+            total: if self.opts.apparent_size { 0 } else { own },
+        }
+    }
+
+    fn print(&self, bytes: u64, path: &str) {
+        let size = if self.opts.apparent_size {
+            self.opts.sizes.logical(bytes)
+        } else {
+            self.opts.sizes.allocated(bytes)
+        };
+        println!("{}\t{}", size, if path.is_empty() { "/" } else { path });
+    }
+}
+
+/// Prints the usage of `inner_path` and, unless summarizing, of each
+/// directory below it
+pub fn run(img: &mut ImgSlice, inner_path: &str, opts: &DuOptions) -> Result<()> {
+    let layout = BootSector::read(img)?.layout()?;
+    let fat = Fat::read(img, &layout)?;
+    let mut walk = Walk {
+        img,
+        layout,
+        fat,
+        opts,
+        visited: HashSet::new(),
+    };
+
+    // The FAT12/16 root directory has a fixed area outside of the clusters
+    let mut path = String::new();
+    let mut start = match walk.layout.fat_type {
+        FatType::Fat32 => walk.layout.root_cluster,
+        _ => 0,
+    };
+    let mut dir = RawDir::read_root(walk.img, &walk.layout, &walk.fat)?;
+    let components: Vec<&str> = inner_path.split('/').filter(|c| !c.is_empty()).collect();
+    for (i, component) in components.iter().enumerate() {
+        path = format!("{}/{}", path, component);
+        let entry = match dir.lookup(component) {
+            Some(entry) => entry.clone(),
+            None => return Err(exit::NotFound(path).into()),
+        };
+        if !entry.is_dir() {
+            if i + 1 < components.len() {
+                return Err(exit::NotADirectory(path).into());
+            }
+            let bytes = walk.file(&path, &entry)?;
+            walk.print(bytes, &path);
+            return Ok(());
+        }
+        start = entry.first_cluster();
+        dir =
+            RawDir::read(walk.img, &walk.layout, &walk.fat, start).with_context(|| path.clone())?;
+    }
+
+    walk.visited.insert(start);
+    let own = walk.chain_bytes(&path, start)?;
+    let total = walk.dir(&path, &dir, own)?;
+    if opts.summarize {
+        walk.print(total, &path);
+    }
+    Ok(())
+}
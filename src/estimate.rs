@@ -0,0 +1,236 @@
+//! How large an image a host tree needs.
+//!
+//! The host tree is walked the same way `write-tree --fit` plans an
+//! import. Every file takes whole clusters, every directory takes whole
+//! clusters for its entries including the long names. The FAT, the
+//! reserved area and for FAT12/16 the fixed root directory come on top.
+//! Sectors are 512 bytes and there are two FATs, like `create` makes them.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::fit;
+use crate::output::{Fields, Output};
+use crate::protect::ProtectArgs;
+use crate::size::SizeFormat;
+use crate::{PathLimits, TextMode, WriteTreeOptions};
+
+const SECTOR: u64 = 512;
+const FATS: u64 = 2;
+/// Entries of the FAT12/16 root directory
+const ROOT_ENTRIES: u64 = 512;
+
+/// Cluster counts each FAT type must have
+const FAT12_MAX_CLUSTERS: u64 = 4084;
+const FAT16_MAX_CLUSTERS: u64 = 65524;
+
+/// `--fat-type` of `estimate` and `create`
+pub fn parse_fat_type(s: &str) -> Result<u8, String> {
+    match s {
+        "12" => Ok(12),
+        "16" => Ok(16),
+        "32" => Ok(32),
+        _ => Err(format!("Invalid FAT type {:?}, expected 12, 16 or 32", s)),
+    }
+}
+
+/// Options of the `estimate` command
+pub struct EstimateOptions {
+    pub cluster_size: u64,
+    /// 12, 16 or 32, picked from the cluster count if not given
+    pub fat_type: Option<u8>,
+    /// Extra space for the recommended size, in percent of the data
+    pub margin: u64,
+}
+
+/// Sectors of one FAT for `clusters` clusters
+fn fat_sectors(fat_type: u8, clusters: u64) -> u64 {
+    let fat_bytes = ((clusters + 2) * fat_type as u64).div_ceil(8);
+    fat_bytes.div_ceil(SECTOR)
+}
+
+/// Sectors of the area before the data region for `clusters` clusters
+fn overhead_sectors(fat_type: u8, clusters: u64) -> u64 {
+    let reserved = if fat_type == 32 { 32 } else { 1 };
+    let root_sectors = if fat_type == 32 {
+        0
+    } else {
+        ROOT_ENTRIES * 32 / SECTOR
+    };
+    reserved + FATS * fat_sectors(fat_type, clusters) + root_sectors
+}
+
+/// Smallest cluster count of a FAT type
+fn min_clusters(fat_type: u8) -> u64 {
+    match fat_type {
+        12 => 1,
+        16 => FAT12_MAX_CLUSTERS + 1,
+        _ => FAT16_MAX_CLUSTERS + 1,
+    }
+}
+
+fn max_clusters(fat_type: u8) -> u64 {
+    match fat_type {
+        12 => FAT12_MAX_CLUSTERS,
+        16 => FAT16_MAX_CLUSTERS,
+        _ => 0x0fff_fff5,
+    }
+}
+
+/// Smallest and largest image a FAT type fits in, with the smallest and
+/// the largest cluster size
+pub fn size_range(fat_type: u8) -> (u64, u64) {
+    let (min, max) = (min_clusters(fat_type), max_clusters(fat_type));
+    (
+        (overhead_sectors(fat_type, min) + min) * SECTOR,
+        overhead_sectors(fat_type, max) * SECTOR + max * 64 * 1024,
+    )
+}
+
+/// Prints what `host_path` needs in the `output` format, ending with a
+/// recommended `--size`
+pub fn run(
+    host_path: &Path, opts: &EstimateOptions, sizes: &SizeFormat, output: Output,
+) -> Result<()> {
+    let cs = opts.cluster_size;
+    if !cs.is_power_of_two() || !(SECTOR..=64 * 1024).contains(&cs) {
+        bail!(
+            "Invalid cluster size {}, expected a power of two from 512 to 65536",
+            cs
+        );
+    }
+    let clusters = |bytes: u64| bytes.div_ceil(cs);
+
+    let tree = WriteTreeOptions {
+        text_mode: TextMode::None,
+        text_globs: Vec::new(),
+        gzip_globs: Vec::new(),
+        keep_name: false,
+        collisions: HashMap::new(),
+        limits: PathLimits {
+            max_path: None,
+            max_depth: None,
+        },
+        preserve_times: false,
+        strict_times: false,
+        one_file_system: None,
+        skip_invalid_names: false,
+        excludes: Vec::new(),
+        includes: Vec::new(),
+        include_sidecars: false,
+        nfc_names: false,
+        no_overwrite: false,
+        clean: false,
+        delete: false,
+        protect: ProtectArgs::default(),
+        dry_run: false,
+    };
+    let mut items = Vec::new();
+    fit::collect(host_path, "", "", &tree, &mut items)?;
+
+    // Every directory but the root starts with `.` and `..`
+    let mut dir_bytes: HashMap<&str, u64> = HashMap::new();
+    dir_bytes.insert("", 0);
+    let (mut files, mut data_clusters, mut lfn_entries) = (0, 0, 0);
+    for item in &items {
+        *dir_bytes.entry(&item.parent).or_insert(64) += item.dirent;
+        lfn_entries += item.dirent / 32 - 1;
+        if item.is_dir {
+            dir_bytes.entry(&item.rel).or_insert(64);
+        } else {
+            files += 1;
+            data_clusters += clusters(item.size);
+        }
+    }
+    let root_bytes = dir_bytes[""];
+    let dir_clusters: u64 = dir_bytes
+        .iter()
+        .filter(|(dir, _)| !dir.is_empty())
+        .map(|(_, &bytes)| clusters(bytes))
+        .sum();
+
+    let fat_type = opts
+        .fat_type
+        .unwrap_or(match data_clusters + dir_clusters + 1 {
+            n if n <= FAT12_MAX_CLUSTERS => 12,
+            n if n <= FAT16_MAX_CLUSTERS => 16,
+            _ => 32,
+        });
+    let root_clusters = if fat_type == 32 {
+        clusters(root_bytes).max(1)
+    } else {
+        if root_bytes > ROOT_ENTRIES * 32 {
+            bail!(
+                "The root directory needs {} entries, FAT{} only has {}",
+                root_bytes / 32,
+                fat_type,
+                ROOT_ENTRIES
+            );
+        }
+        0
+    };
+    let needed_clusters = data_clusters + dir_clusters + root_clusters;
+    let with_margin = needed_clusters + (needed_clusters * opts.margin).div_ceil(100);
+    let total_clusters = with_margin.max(min_clusters(fat_type));
+    if total_clusters > max_clusters(fat_type) {
+        bail!(
+            "{} clusters of {} bytes are too many for FAT{}, use larger clusters",
+            total_clusters,
+            cs,
+            fat_type
+        );
+    }
+
+    let needed = overhead_sectors(fat_type, needed_clusters) * SECTOR + needed_clusters * cs;
+    let recommended = overhead_sectors(fat_type, total_clusters) * SECTOR + total_clusters * cs;
+    let mut fields = Fields::new(19);
+    fields.push(
+        "files",
+        format!("{} in {} directories", files, dir_bytes.len() - 1),
+    );
+    fields.push(
+        "data clusters",
+        format!(
+            "{} ({})",
+            data_clusters,
+            sizes.allocated(data_clusters * cs)
+        ),
+    );
+    fields.push(
+        "directory clusters",
+        format!(
+            "{} ({}), {} long name entries",
+            dir_clusters + root_clusters,
+            sizes.allocated((dir_clusters + root_clusters) * cs),
+            lfn_entries
+        ),
+    );
+    fields.push(
+        "FAT",
+        format!(
+            "FAT{}, {} x {}",
+            fat_type,
+            FATS,
+            sizes.allocated(fat_sectors(fat_type, total_clusters) * SECTOR)
+        ),
+    );
+    fields.push("needed", sizes.allocated(needed));
+    if total_clusters > with_margin {
+        fields.push(
+            "recommended --size",
+            format!(
+                "{} (the smallest FAT{} with {} byte clusters)",
+                recommended, fat_type, cs
+            ),
+        );
+    } else {
+        fields.push(
+            "recommended --size",
+            format!("{} (with {}% margin)", recommended, opts.margin),
+        );
+    }
+    fields.print(output);
+    Ok(())
+}
@@ -0,0 +1,111 @@
+//! Exit codes, so that scripts can tell a missing path from a broken image.
+//!
+//! 1 is for usage errors and anything not covered below, 2 for a path that
+//! isn't in the image or goes through a file and 3 for I/O errors,
+//! including an image too damaged to read. 4 means the command finished,
+//! but left out host entries `--skip-invalid-names` told it to.
+
+use std::fmt;
+use std::io;
+
+pub const FAILURE: i32 = 1;
+pub const NOT_FOUND: i32 = 2;
+pub const IO: i32 = 3;
+pub const SKIPPED: i32 = 4;
+
+/// A path that isn't in the image, exiting with `NOT_FOUND`
+#[derive(Debug)]
+pub struct NotFound(pub String);
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: no such file or directory", self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// A path going through a file as if it was a directory, given up to that
+/// file, exiting with `NOT_FOUND`
+#[derive(Debug)]
+pub struct NotADirectory(pub String);
+
+impl fmt::Display for NotADirectory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "path component {} is a file, not a directory", self.0)
+    }
+}
+
+impl std::error::Error for NotADirectory {}
+
+/// How many host entries `--skip-invalid-names` left out, exiting with
+/// `SKIPPED`
+#[derive(Debug)]
+pub struct Skipped(pub usize);
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} host entries with invalid names were skipped", self.0)
+    }
+}
+
+impl std::error::Error for Skipped {}
+
+/// The exit code for `err`, from the first cause in its chain that has one
+pub fn code(err: &anyhow::Error) -> i32 {
+    for cause in err.chain() {
+        if cause.is::<NotFound>() || cause.is::<NotADirectory>() {
+            return NOT_FOUND;
+        }
+        if cause.is::<Skipped>() {
+            return SKIPPED;
+        }
+        if let Some(err) = cause.downcast_ref::<fatfs::Error<io::Error>>() {
+            return match err {
+                fatfs::Error::NotFound => NOT_FOUND,
+                fatfs::Error::Io(_)
+                | fatfs::Error::UnexpectedEof
+                | fatfs::Error::CorruptedFileSystem => IO,
+                _ => FAILURE,
+            };
+        }
+        if cause.is::<io::Error>() {
+            return IO;
+        }
+    }
+    FAILURE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn codes_come_from_the_first_cause_with_one() {
+        let missing: anyhow::Error = NotFound("/A".to_owned()).into();
+        assert_eq!(missing.to_string(), "/A: no such file or directory");
+        assert_eq!(code(&missing), NOT_FOUND);
+
+        let through_file = Err::<(), _>(NotADirectory("/A/B".to_owned()))
+            .context("failed opening /A/B/C")
+            .unwrap_err();
+        assert_eq!(code(&through_file), NOT_FOUND);
+        assert_eq!(
+            format!("{:#}", through_file),
+            "failed opening /A/B/C: path component /A/B is a file, not a directory"
+        );
+
+        assert_eq!(code(&Skipped(3).into()), SKIPPED);
+        let io = Err::<(), _>(io::Error::other("dying card"))
+            .context("failed reading")
+            .unwrap_err();
+        assert_eq!(code(&io), IO);
+        assert_eq!(code(&anyhow::anyhow!("Invalid size")), FAILURE);
+        assert_eq!(code(&fatfs::Error::<io::Error>::NotFound.into()), NOT_FOUND);
+        assert_eq!(
+            code(&fatfs::Error::<io::Error>::CorruptedFileSystem.into()),
+            IO
+        );
+    }
+}
@@ -0,0 +1,336 @@
+//! Where the clusters of a file are in the image, for forensic work.
+//!
+//! An extent is a run of consecutive clusters. Their image byte ranges
+//! can be listed, turned into `dd` commands that rebuild the file from
+//! the raw image, or saved as JSON. `import-extents` reads that JSON back
+//! and copies the ranges into a new file, e.g. after the directory entry
+//! was lost.
+
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::exit;
+use crate::json::{self, Value};
+use crate::ondisk::{BootSector, Fat, Layout, RawDir};
+use crate::region::ImgSlice;
+use crate::text::TextMode;
+use crate::{parent_of, write_file, OverwritePolicy, WriteOptions, WriteSource};
+
+/// How `extents` prints them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    DdScript,
+    Json,
+}
+
+/// Consecutive clusters of a file
+struct Extent {
+    cluster: u32,
+    count: u32,
+    /// Byte offset in the image
+    offset: u64,
+    /// Bytes of file contents, less than the clusters for the last one
+    length: u64,
+}
+
+/// Splits the chain of a file of `size` bytes into extents
+fn extents(layout: &Layout, chain: &[u32], size: u64) -> Vec<Extent> {
+    let mut result: Vec<Extent> = Vec::new();
+    for &cluster in chain {
+        match result.last_mut() {
+            Some(last) if last.cluster + last.count == cluster => last.count += 1,
+            _ => result.push(Extent {
+                cluster,
+                count: 1,
+                offset: layout.cluster_offset(cluster),
+                length: 0,
+            }),
+        }
+    }
+    let mut left = size;
+    for extent in &mut result {
+        extent.length = left.min(extent.count as u64 * layout.cluster_size);
+        left -= extent.length;
+    }
+    result
+}
+
+/// The `extents --json` document of the file `path`
+fn to_json(path: &str, size: u64, cluster_size: u64, extents: &[Extent]) -> Value {
+    let list = extents.iter().map(|e| {
+        json::object([
+            ("cluster", (e.cluster as u64).into()),
+            ("count", (e.count as u64).into()),
+            ("offset", e.offset.into()),
+            ("length", e.length.into()),
+        ])
+    });
+    json::versioned(json::object([
+        ("path", path.into()),
+        ("size", size.into()),
+        ("cluster_size", cluster_size.into()),
+        ("extents", list.collect()),
+    ]))
+}
+
+/// Quotes `s` for `sh`
+fn sh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Prints the extents of the normalized image path `inner_path`
+pub fn run(img_file: &Path, inner_path: &str, format: Format) -> Result<()> {
+    let mut img = ImgSlice::open(img_file, false)?;
+    let layout = BootSector::read(&mut img)?.layout()?;
+    let fat = Fat::read(&mut img, &layout)?;
+    let path = format!("/{}", inner_path);
+    let name = inner_path.rsplit('/').next().unwrap_or(inner_path);
+    let dir = RawDir::read_path(&mut img, &layout, &fat, parent_of(inner_path))?;
+    let entry = match dir.lookup(name) {
+        Some(entry) => entry,
+        None => return Err(exit::NotFound(path).into()),
+    };
+    if entry.has_invalid_start() {
+        bail!(
+            "{}: entry has invalid start cluster {}, run check --fix",
+            path,
+            entry.first_cluster()
+        );
+    }
+
+    let chain = match entry.first_cluster() {
+        0 => Vec::new(),
+        start => fat.chain(start).with_context(|| path.clone())?,
+    };
+    // Directories use all of their clusters
+    let size = if entry.is_dir() {
+        chain.len() as u64 * layout.cluster_size
+    } else {
+        entry.size() as u64
+    };
+    let allocated = chain.len() as u64 * layout.cluster_size;
+    if size > allocated {
+        bail!(
+            "{}: {} bytes don't fit the {} allocated bytes, run check",
+            path,
+            size,
+            allocated
+        );
+    }
+    let extents = extents(&layout, &chain, size);
+
+    match format {
+        Format::Text => {
+            for e in &extents {
+                println!(
+                    "{}..{} -> {}..{}",
+                    e.cluster,
+                    e.cluster + e.count,
+                    e.offset,
+                    e.offset + e.length
+                );
+            }
+        },
+        Format::Json => println!("{}", to_json(&path, size, layout.cluster_size, &extents)),
+        Format::DdScript => {
+            // Clusters start at sector boundaries, so a block size dividing
+            // both the cluster size and the data area offset works for all
+            let bs = gcd(layout.cluster_size, layout.data_offset);
+            let img = std::fs::canonicalize(img_file).unwrap_or_else(|_| img_file.to_owned());
+            println!("#!/bin/sh");
+            println!("# {}, {} bytes in {} extents", path, size, extents.len());
+            println!("set -e");
+            println!(
+                "out=\"${{1:-{}}}\"",
+                name.replace(['"', '$', '`', '\\'], "_")
+            );
+            println!(": > \"$out\"");
+            let mut file_offset = 0;
+            for e in &extents {
+                println!(
+                    "dd if={} of=\"$out\" bs={} skip={} seek={} count={} conv=notrunc 2>/dev/null",
+                    sh_quote(&img.to_string_lossy()),
+                    bs,
+                    e.offset / bs,
+                    file_offset / bs,
+                    e.count as u64 * layout.cluster_size / bs
+                );
+                file_offset += e.count as u64 * layout.cluster_size;
+            }
+            println!("truncate -s {} \"$out\"", size);
+        },
+    }
+    Ok(())
+}
+
+/// Extents as listed by `extents --json`
+fn read_list(host_path: &Path) -> Result<(u64, Vec<(u64, u64)>)> {
+    let text = fs::read_to_string(host_path)?;
+    let doc = json::parse(&text)?;
+    if let Some(version) = doc.get("schema_version") {
+        match version.as_u64() {
+            Some(v) if v <= json::SCHEMA_VERSION => {},
+            _ => bail!(
+                "Unsupported schema version {}, written by a newer fatimg",
+                version
+            ),
+        }
+    }
+    let number = |v: &Value, name: &str| {
+        v.get(name)
+            .and_then(Value::as_u64)
+            .with_context(|| format!("missing number {:?}", name))
+    };
+    let list = doc
+        .get("extents")
+        .and_then(Value::as_array)
+        .context("missing array \"extents\"")?;
+    let ranges = list
+        .iter()
+        .map(|e| Ok((number(e, "offset")?, number(e, "length")?)))
+        .collect::<Result<Vec<_>>>()?;
+    let size = match doc.get("size") {
+        Some(size) => size.as_u64().context("\"size\" is not a number")?,
+        None => ranges.iter().map(|(_, len)| len).sum(),
+    };
+    Ok((size, ranges))
+}
+
+/// Copies the image byte ranges listed in `host_path` into the new file
+/// `inner_path`. The data is read before anything is written, since the
+/// new file may be given the very clusters it is rebuilt from.
+pub fn import(
+    img_file: &Path, host_path: &Path, inner_path: &str, overwrite: OverwritePolicy,
+) -> Result<()> {
+    let (size, ranges) =
+        read_list(host_path).with_context(|| format!("failed reading {}", host_path.display()))?;
+    let total: u64 = ranges.iter().map(|(_, len)| len).sum();
+    if total != size {
+        bail!(
+            "The extents hold {} bytes, but the file size is {}",
+            total,
+            size
+        );
+    }
+
+    let mut img = ImgSlice::open(img_file, false)?;
+    let layout = BootSector::read(&mut img)?.layout()?;
+    let data_end = layout.cluster_offset(layout.total_clusters + 2);
+    let mut data = Vec::new();
+    for &(offset, length) in &ranges {
+        if offset < layout.data_offset || offset.saturating_add(length) > data_end {
+            bail!(
+                "Extent {}..{} is outside of the data area {}..{}",
+                offset,
+                offset.saturating_add(length),
+                layout.data_offset,
+                data_end
+            );
+        }
+        img.seek(SeekFrom::Start(offset))?;
+        let read = (&mut img).take(length).read_to_end(&mut data)?;
+        if read as u64 != length {
+            bail!("Image ends inside extent {}..{}", offset, offset + length);
+        }
+    }
+    drop(img);
+
+    let opts = WriteOptions {
+        text_mode: TextMode::None,
+        pad: None,
+        verify: true,
+        allow_empty: true,
+        gzip: false,
+        overwrite,
+        attributes: None,
+        times: None,
+    };
+    write_file(img_file, inner_path, &WriteSource::Buffered(data), &opts)?;
+    println!(
+        "imported {} bytes in {} extents to /{}",
+        size,
+        ranges.len(),
+        inner_path
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Document;
+
+    fn list() -> Vec<Extent> {
+        vec![
+            Extent {
+                cluster: 2,
+                count: 2,
+                offset: 0x4000,
+                length: 4096,
+            },
+            Extent {
+                cluster: 9,
+                count: 1,
+                offset: 0x7800,
+                length: 100,
+            },
+        ]
+    }
+
+    /// Writes `text` to a file of its own for the test `name`
+    fn temp_file(name: &str, text: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "fatimg-extents-{}-{}.json",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn json_matches_the_schema() {
+        let doc = to_json("/A.BIN", 4196, 2048, &list());
+        assert_eq!(Document::Extents.validate(&doc), Ok(()));
+        assert_eq!(
+            Document::Extents.validate(&to_json("/E", 0, 2048, &[])),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn list_reads_back() {
+        let path = temp_file(
+            "round-trip",
+            &to_json("/A.BIN", 4196, 2048, &list()).to_string(),
+        );
+        let read = read_list(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read.unwrap(), (4196, vec![(0x4000, 4096), (0x7800, 100)]));
+    }
+
+    #[test]
+    fn newer_lists_are_rejected() {
+        let text = format!(
+            r#"{{"schema_version":{},"extents":[]}}"#,
+            json::SCHEMA_VERSION + 1
+        );
+        let path = temp_file("newer", &text);
+        let read = read_list(&path);
+        fs::remove_file(&path).unwrap();
+        let err = read.unwrap_err().to_string();
+        assert!(err.starts_with("Unsupported schema version"), "{}", err);
+    }
+}
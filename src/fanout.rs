@@ -0,0 +1,106 @@
+//! Applying one mutating command to several images
+
+use std::fs::File;
+use std::io::{self, BufRead, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+/// Largest input that is buffered in memory to be written to several
+/// images. Larger host files are re-read for each image instead.
+const BUFFER_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Additional images a mutating command is applied to
+#[derive(clap::Args, Debug)]
+pub struct FanOut {
+    /// Apply the same operation to this image as well. Can be repeated.
+    #[clap(long, parse(from_os_str))]
+    also: Vec<PathBuf>,
+
+    /// With `--also`, stop at the first image that fails
+    #[clap(long)]
+    fail_fast: bool,
+}
+
+impl FanOut {
+    pub fn is_multi(&self) -> bool {
+        !self.also.is_empty()
+    }
+
+    /// Runs `op` on each image in turn, reporting per-image results when
+    /// there is more than one. Fails if any of the images failed.
+    pub fn run<F: FnMut(&Path) -> Result<()>>(&self, img_file: &Path, mut op: F) -> Result<()> {
+        if !self.is_multi() {
+            return op(img_file);
+        }
+
+        let images: Vec<&Path> = std::iter::once(img_file)
+            .chain(self.also.iter().map(|p| p.as_path()))
+            .collect();
+        let mut failed = 0;
+        for (i, img) in images.iter().enumerate() {
+            match op(img) {
+                Ok(()) => eprintln!("{}: ok", img.display()),
+                Err(err) => {
+                    eprintln!("{}: failed: {:#}", img.display(), err);
+                    failed += 1;
+                    if self.fail_fast {
+                        let skipped = images.len() - i - 1;
+                        if skipped > 0 {
+                            eprintln!("Skipping {} remaining image(s)", skipped);
+                        }
+                        break;
+                    }
+                },
+            }
+        }
+
+        if failed > 0 {
+            bail!("{} of {} images failed", failed, images.len());
+        }
+        Ok(())
+    }
+}
+
+/// Input of the `write` command, possibly shared between several images
+pub enum WriteSource {
+    Stdin,
+    HostFile(PathBuf),
+    Buffered(Vec<u8>),
+}
+
+impl WriteSource {
+    /// Prepares the input so that it can be opened once per image.
+    /// Stdin can only be read once, so it's always buffered in that case.
+    pub fn new(host_path: Option<PathBuf>, multi: bool) -> Result<Self> {
+        if host_path.is_none() && io::stdin().is_terminal() {
+            bail!("Stdin is a terminal, pipe the data in or give a file with --input");
+        }
+        match host_path {
+            None if multi => {
+                let mut data = Vec::new();
+                io::stdin().read_to_end(&mut data)?;
+                Ok(Self::Buffered(data))
+            },
+            None => Ok(Self::Stdin),
+            Some(p) if multi => {
+                // Pipes like `/dev/fd/N` can only be read once, and have no size
+                let meta = p.metadata()?;
+                if !meta.is_file() || meta.len() <= BUFFER_LIMIT {
+                    Ok(Self::Buffered(std::fs::read(p)?))
+                } else {
+                    Ok(Self::HostFile(p))
+                }
+            },
+            Some(p) => Ok(Self::HostFile(p)),
+        }
+    }
+
+    pub fn open(&self) -> Result<Box<dyn BufRead + '_>> {
+        Ok(match self {
+            Self::Stdin => Box::new(io::BufReader::new(io::stdin())),
+            Self::HostFile(p) => Box::new(io::BufReader::new(File::open(p)?)),
+            Self::Buffered(data) => Box::new(&data[..]),
+        })
+    }
+}
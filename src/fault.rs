@@ -0,0 +1,50 @@
+//! Faults tests inject into the image writes of their own thread, to see
+//! what a crash leaves behind on disk and what a failing disk is blamed
+//! on.
+
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// After this many more bytes, writes are reported as done but never
+    /// reach the image, like on a machine that lost power
+    Crash(u64),
+    /// After this many more bytes, writes fail
+    Fail(u64),
+}
+
+thread_local! {
+    static FAULT: Cell<Option<Fault>> = const { Cell::new(None) };
+}
+
+/// Injects `fault` into the image writes of this thread
+pub fn inject(fault: Fault) {
+    FAULT.set(Some(fault));
+}
+
+/// Stops injecting, returning what was left of the fault
+pub fn clear() -> Option<Fault> {
+    FAULT.take()
+}
+
+/// Writes `buf` to the image `file` as far as the injected fault lets it
+pub fn write(file: &mut File, buf: &[u8]) -> io::Result<usize> {
+    match FAULT.get() {
+        None => file.write(buf),
+        Some(Fault::Crash(left)) => {
+            let kept = buf.len().min(left.try_into().unwrap_or(usize::MAX));
+            file.write_all(&buf[..kept])?;
+            file.seek(SeekFrom::Current((buf.len() - kept) as i64))?;
+            FAULT.set(Some(Fault::Crash(left - kept as u64)));
+            Ok(buf.len())
+        },
+        Some(Fault::Fail(0)) => Err(io::Error::other("injected write failure")),
+        Some(Fault::Fail(left)) => {
+            let n = file.write(&buf[..buf.len().min(left.try_into().unwrap_or(usize::MAX))])?;
+            FAULT.set(Some(Fault::Fail(left - n as u64)));
+            Ok(n)
+        },
+    }
+}
@@ -0,0 +1,135 @@
+//! Searching the image tree by name, type, size, emptiness and
+//! modification time, printing one path per line for `xargs`
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::{
+    artifacts, glob, json, open_fs, paths, refuse_file_components, surrogates, walk, when,
+};
+
+/// What `find --type` matches
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Files
+    F,
+    /// Directories
+    D,
+}
+
+/// Options of the `find` command
+pub struct FindOptions {
+    /// Glob the long or short name matches, ignoring case
+    pub name: Option<String>,
+    pub kind: Option<Kind>,
+    /// Only files longer than this
+    pub larger: Option<u64>,
+    /// Only files shorter than this
+    pub smaller: Option<u64>,
+    pub modified: when::TimeFilter,
+    /// Only empty files and directories with nothing but `.` and `..`
+    pub empty: bool,
+    /// Paths relative to the searched directory
+    pub relative: bool,
+    /// Leave out `artifacts::WINDOWS_ARTIFACTS` at the top of the volume
+    pub skip_windows_artifacts: bool,
+    /// Print a JSON array of `ls --jsonl` objects instead of paths
+    pub json: bool,
+}
+
+impl FindOptions {
+    fn matches<IO: fatfs::ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter>(
+        &self, entry: &fatfs::DirEntry<IO, TP, OCC>,
+    ) -> bool {
+        if let Some(pattern) = &self.name {
+            let pattern = pattern.to_uppercase();
+            if !glob::matches(&pattern, &entry.file_name().to_uppercase())
+                && !glob::matches(&pattern, &entry.short_file_name().to_uppercase())
+            {
+                return false;
+            }
+        }
+        match self.kind {
+            Some(Kind::F) if !entry.is_file() => return false,
+            Some(Kind::D) if !entry.is_dir() => return false,
+            _ => {},
+        }
+        // Directories have no size to compare
+        let sized = self.larger.is_some() || self.smaller.is_some();
+        if sized && !entry.is_file() {
+            return false;
+        }
+        if self.larger.is_some_and(|min| entry.len() <= min)
+            || self.smaller.is_some_and(|max| entry.len() >= max)
+        {
+            return false;
+        }
+        if self.empty && !is_empty(entry) {
+            return false;
+        }
+        self.modified.matches(&entry.modified())
+    }
+}
+
+fn is_empty<IO: fatfs::ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter>(
+    entry: &fatfs::DirEntry<IO, TP, OCC>,
+) -> bool {
+    if !entry.is_dir() {
+        return entry.len() == 0;
+    }
+    // A directory that can't be read isn't known to be empty
+    !entry
+        .to_dir()
+        .iter()
+        .any(|e| e.map_or(true, |e| e.file_name() != "." && e.file_name() != ".."))
+}
+
+/// Prints the entries below the normalized image directory `inner_path`
+/// that match `opts`, in directory order
+pub fn run(img_file: &Path, inner_path: &str, opts: &FindOptions) -> Result<()> {
+    refuse_file_components(img_file, inner_path)?;
+    let fs = open_fs(img_file, false)?;
+    let path = format!("/{}", inner_path);
+    let mut dir = fs.root_dir();
+    if !inner_path.is_empty() {
+        dir = dir
+            .open_dir(inner_path)
+            .with_context(|| format!("failed opening directory {}", path))?;
+    }
+    let mut found = Vec::new();
+    let prefix = path;
+    let mut walk = walk::Walk::new(&dir, &prefix, true);
+    while let Some(item) = walk.next() {
+        let walk::Entry { entry, path, depth } = item?;
+        if inner_path.is_empty()
+            && depth == 0
+            && entry.is_dir()
+            && opts.skip_windows_artifacts
+            && artifacts::is_windows_artifact(&entry.file_name())
+        {
+            eprintln!("skipped {}", path);
+            walk.skip_dir();
+            continue;
+        }
+        if !opts.matches(&entry) {
+            continue;
+        }
+        let shown = if opts.relative {
+            paths::relative(&prefix, &path).to_owned()
+        } else {
+            path.clone()
+        };
+        if opts.json {
+            let name = surrogates::display_name(&entry, &path)?;
+            found.push(crate::ls_json(&entry, shown, name));
+        } else {
+            println!("{}", shown);
+        }
+    }
+    if opts.json {
+        let entries = json::Value::Array(found);
+        println!("{}", json::versioned(json::object([("entries", entries)])));
+    }
+    Ok(())
+}
@@ -0,0 +1,279 @@
+//! Choosing which part of a host tree to import when it may not fit
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use crate::collisions::Resolution;
+use crate::size::SizeFormat;
+use crate::text::TextMode;
+use crate::{glob, gzip, sidecars, WriteTreeOptions};
+
+/// `write-tree --fit`
+#[derive(Debug, Clone)]
+pub enum Fit {
+    /// Import everything or nothing
+    Fail,
+    /// Import the largest files that fit, skipping ones that don't
+    LargestFirst,
+    /// Import in the order given by the globs in a file, stopping at the
+    /// first file that doesn't fit
+    PriorityList(PathBuf),
+}
+
+pub fn parse_fit(s: &str) -> Result<Fit, String> {
+    match s {
+        "fail" => Ok(Fit::Fail),
+        "largest-first" => Ok(Fit::LargestFirst),
+        _ => match s.strip_prefix("priority-list=") {
+            Some(path) if !path.is_empty() => Ok(Fit::PriorityList(path.into())),
+            _ => Err(format!(
+                "Invalid fit {:?}, expected fail, largest-first or priority-list=<file>",
+                s
+            )),
+        },
+    }
+}
+
+/// A host file or directory along with the space it needs
+pub(crate) struct Item {
+    /// Path relative to the imported tree
+    pub rel: String,
+    /// Relative path of the directory holding it
+    pub parent: String,
+    /// Path relative to the target directory of the image, as stored
+    pub stored: String,
+    pub is_dir: bool,
+    /// Largest size the stored file can end up with
+    pub size: u64,
+    /// Bytes of directory entries, including the long name
+    pub dirent: u64,
+}
+
+/// Could `name` be stored as a bare 8.3 entry, with the NT case flags
+/// covering lowercase like `ntcase` does
+fn fits_short(name: &str) -> bool {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    let valid = |part: &str, max: usize| {
+        let lower = part.bytes().any(|c| c.is_ascii_lowercase());
+        let upper = part.bytes().any(|c| c.is_ascii_uppercase());
+        part.len() <= max
+            && !(lower && upper)
+            && part
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || b"!#$%&'()-@^_`{}~".contains(&c))
+    };
+    !base.is_empty() && valid(base, 8) && valid(ext, 3)
+}
+
+/// Directory entry bytes for `name`: the 8.3 entry, and an LFN entry per
+/// 13 UTF-16 units unless the name fits the 8.3 entry
+pub(crate) fn dirent_bytes(name: &str) -> u64 {
+    if fits_short(name) {
+        return 32;
+    }
+    let units = name.encode_utf16().count() as u64;
+    32 * (1 + units.div_ceil(13))
+}
+
+/// Walks the host tree like `write_tree_to_img` does, naming entries as
+/// they will be stored. `stored_path` is `rel_path` with stored names.
+pub(crate) fn collect(
+    host_path: &Path, rel_path: &str, stored_path: &str, opts: &WriteTreeOptions,
+    items: &mut Vec<Item>,
+) -> Result<()> {
+    let entries = fs::read_dir(host_path)
+        .with_context(|| format!("failed reading host directory {}", host_path.display()))?;
+    for entry in entries {
+        let entry = entry
+            .with_context(|| format!("failed reading host directory {}", host_path.display()))?;
+        let host = entry.path();
+        let meta = fs::symlink_metadata(&host)
+            .with_context(|| format!("failed reading {}", host.display()))?;
+        let name = match opts.host_name(&host, &entry.file_name())? {
+            Some(name) => name,
+            None => continue,
+        };
+        let rel = if rel_path.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", rel_path, name)
+        };
+        if opts.is_excluded(&rel, meta.is_dir())
+            || (!opts.include_sidecars && sidecars::recognize(&name).is_some())
+        {
+            continue;
+        }
+        let name = match opts.collisions.get(&host) {
+            Some(Resolution::Skip) => continue,
+            Some(Resolution::Rename(new_name)) => new_name.clone(),
+            None => name,
+        };
+
+        if meta.is_dir() {
+            if opts.one_file_system.is_some_and(|same| same.leaves(&meta)) {
+                continue;
+            }
+            let stored = stored_join(stored_path, &name);
+            items.push(Item {
+                rel: rel.clone(),
+                parent: rel_path.to_owned(),
+                stored: stored.clone(),
+                is_dir: true,
+                size: 0,
+                dirent: dirent_bytes(&name),
+            });
+            collect(&host, &rel, &stored, opts, items)?;
+        } else if meta.is_file() {
+            let gzip = opts.is_gzip(&rel);
+            let name = if gzip && !opts.keep_name {
+                gzip::stored_name(&name)
+            } else {
+                name
+            };
+            let mut size = meta.len();
+            if opts.is_text(&rel) && opts.text_mode == TextMode::LfToCrlf {
+                size *= 2;
+            }
+            if gzip {
+                // Incompressible data grows by the stored block headers
+                size += size / 1000 + 64;
+            }
+            items.push(Item {
+                rel,
+                parent: rel_path.to_owned(),
+                stored: stored_join(stored_path, &name),
+                is_dir: false,
+                size,
+                dirent: dirent_bytes(&name),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn stored_join(stored_path: &str, name: &str) -> String {
+    if stored_path.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", stored_path, name)
+    }
+}
+
+/// Moves items matching earlier lines of the priority list first
+fn priority_order(list: &Path, files: &mut [&Item]) -> Result<()> {
+    let content = fs::read_to_string(list)
+        .with_context(|| format!("failed reading priority list {}", list.display()))?;
+    let globs: Vec<&str> = content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect();
+    files.sort_by_key(|f| {
+        globs
+            .iter()
+            .position(|g| glob::matches(g, &f.rel))
+            .unwrap_or(globs.len())
+    });
+    Ok(())
+}
+
+/// Space used by directory entries, for each directory
+struct Dirs {
+    cluster_size: u64,
+    bytes: HashMap<String, u64>,
+}
+
+impl Dirs {
+    fn clusters(&self, bytes: u64) -> u64 {
+        bytes.div_ceil(self.cluster_size)
+    }
+
+    /// Bytes of new clusters needed to add `dirent` bytes to `dir`
+    fn growth(&self, dir: &str, dirent: u64) -> u64 {
+        let used = self.bytes[dir];
+        (self.clusters(used + dirent) - self.clusters(used)) * self.cluster_size
+    }
+}
+
+/// What gets imported
+pub struct Plan {
+    /// Relative paths of the files to import, `None` for all of them
+    pub selected: Option<HashSet<String>>,
+    /// Files left out, with their sizes
+    pub omitted: Vec<(String, u64)>,
+}
+
+/// Decides what of `host_path` fits in `free` bytes of an image with
+/// clusters of `cluster_size`. Directories are always created, so they
+/// are accounted for first. With `Fit::Fail` everything fits or this fails.
+pub fn plan(
+    host_path: &Path, opts: &WriteTreeOptions, fit: &Fit, free: u64, cluster_size: u64,
+    sizes: &SizeFormat,
+) -> Result<Plan> {
+    let mut items = Vec::new();
+    collect(host_path, "", "", opts, &mut items)?;
+
+    // A new directory starts with one cluster holding `.` and `..`. The
+    // target directory is assumed to have a cluster already.
+    let mut dirs = Dirs {
+        cluster_size,
+        bytes: HashMap::new(),
+    };
+    dirs.bytes.insert(String::new(), 0);
+    let mut used = 0;
+    for dir in items.iter().filter(|i| i.is_dir) {
+        used += dirs.growth(&dir.parent, dir.dirent) + cluster_size;
+        *dirs.bytes.get_mut(&dir.parent).unwrap() += dir.dirent;
+        dirs.bytes.insert(dir.rel.clone(), 64);
+    }
+    if used > free {
+        bail!(
+            "The directories alone need {}, only {} is free",
+            sizes.allocated(used),
+            sizes.allocated(free)
+        );
+    }
+
+    let mut files: Vec<&Item> = items.iter().filter(|i| !i.is_dir).collect();
+    match fit {
+        Fit::Fail => {},
+        Fit::LargestFirst => files.sort_by(|a, b| b.size.cmp(&a.size).then(a.rel.cmp(&b.rel))),
+        Fit::PriorityList(list) => priority_order(list, &mut files)?,
+    }
+
+    let mut selected = HashSet::new();
+    let mut omitted = Vec::new();
+    let mut stopped = false;
+    for file in files {
+        let cost = dirs.clusters(file.size) * cluster_size + dirs.growth(&file.parent, file.dirent);
+        if stopped || used + cost > free {
+            omitted.push((file.rel.clone(), file.size));
+            stopped = matches!(fit, Fit::PriorityList(_));
+            continue;
+        }
+        used += cost;
+        *dirs.bytes.get_mut(&file.parent).unwrap() += file.dirent;
+        selected.insert(file.rel.clone());
+    }
+
+    if let Fit::Fail = fit {
+        if !omitted.is_empty() {
+            bail!(
+                "The tree needs up to {} more than the {} free, nothing was written (see --fit)",
+                sizes.allocated(used + omitted.iter().map(|(_, s)| *s).sum::<u64>() - free),
+                sizes.allocated(free)
+            );
+        }
+        return Ok(Plan {
+            selected: None,
+            omitted,
+        });
+    }
+    Ok(Plan {
+        selected: Some(selected),
+        omitted,
+    })
+}
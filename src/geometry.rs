@@ -0,0 +1,157 @@
+//! Volume geometry `create` asks fatfs for, instead of what it picks
+
+use anyhow::{bail, Result};
+use fatfs::{FatType, FormatVolumeOptions};
+
+use crate::estimate;
+use crate::ondisk::BootSector;
+
+#[derive(clap::Args, Debug)]
+pub struct Geometry {
+    /// FAT type, 12, 16 or 32. Picked from the size if not given.
+    #[clap(long, parse(try_from_str = estimate::parse_fat_type))]
+    pub fat_type: Option<u8>,
+
+    /// Bytes per sector, a power of two from 512 to 4096
+    #[clap(long)]
+    pub sector_size: Option<u16>,
+
+    /// Bytes per cluster, a power of two of at least the sector size and
+    /// at most 128 sectors
+    #[clap(long)]
+    pub cluster_size: Option<u32>,
+
+    /// Number of FATs, 1 or 2
+    #[clap(long)]
+    pub fats: Option<u8>,
+
+    /// Entries of the FAT12/16 root directory, filling whole sectors
+    #[clap(long)]
+    pub root_entries: Option<u16>,
+}
+
+/// fatfs type for `--fat-type`, which `estimate::parse_fat_type` checked
+pub fn fat_type_of(bits: u8) -> FatType {
+    match bits {
+        12 => FatType::Fat12,
+        16 => FatType::Fat16,
+        _ => FatType::Fat32,
+    }
+}
+
+impl Geometry {
+    /// Fails on the first constraint the options break, before anything
+    /// is formatted
+    pub fn validate(&self) -> Result<()> {
+        let sector = self.sector_size.unwrap_or(512);
+        if !sector.is_power_of_two() || !(512..=4096).contains(&sector) {
+            bail!(
+                "Invalid sector size {}, expected a power of two from 512 to 4096",
+                sector
+            );
+        }
+        if let Some(cluster) = self.cluster_size {
+            if !cluster.is_power_of_two() || cluster < sector as u32 {
+                bail!(
+                    "Invalid cluster size {}, expected a power of two multiple of the {} byte \
+                     sector size",
+                    cluster,
+                    sector
+                );
+            }
+            if cluster / sector as u32 > 128 {
+                bail!(
+                    "Cluster size {} is {} sectors, at most 128 fit in the boot sector",
+                    cluster,
+                    cluster / sector as u32
+                );
+            }
+        }
+        if let Some(fats) = self.fats {
+            if !(1..=2).contains(&fats) {
+                bail!("Invalid number of FATs {}, expected 1 or 2", fats);
+            }
+        }
+        if let Some(entries) = self.root_entries {
+            if self.fat_type == Some(32) {
+                bail!(
+                    "--root-entries only applies to FAT12 and FAT16, FAT32 has no fixed root \
+                     directory"
+                );
+            }
+            let per_sector = sector / 32;
+            if entries == 0 || entries % per_sector != 0 {
+                bail!(
+                    "Invalid root entries {}, expected a non-zero multiple of {} to fill whole {} \
+                     byte sectors",
+                    entries,
+                    per_sector,
+                    sector
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, mut options: FormatVolumeOptions) -> FormatVolumeOptions {
+        if let Some(bits) = self.fat_type {
+            options = options.fat_type(fat_type_of(bits));
+        }
+        if let Some(sector) = self.sector_size {
+            options = options.bytes_per_sector(sector);
+        }
+        if let Some(cluster) = self.cluster_size {
+            options = options.bytes_per_cluster(cluster);
+        }
+        if let Some(fats) = self.fats {
+            options = options.fats(fats);
+        }
+        if let Some(entries) = self.root_entries {
+            options = options.max_root_dir_entries(entries);
+        }
+        options
+    }
+
+    /// How the formatted volume differs from the requested geometry
+    pub fn mismatches(&self, bs: &BootSector, fat_type: FatType) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        if let Some(bits) = self.fat_type {
+            if fat_type != fat_type_of(bits) {
+                mismatches.push(format!("fs type is {:?}, requested FAT{}", fat_type, bits));
+            }
+        }
+        let sector = bs.bytes_per_sector();
+        if let Some(requested) = self.sector_size {
+            if sector != requested {
+                mismatches.push(format!(
+                    "sector size is {}, requested {}",
+                    sector, requested
+                ));
+            }
+        }
+        let cluster = sector as u32 * bs.sectors_per_cluster() as u32;
+        if let Some(requested) = self.cluster_size {
+            if cluster != requested {
+                mismatches.push(format!(
+                    "cluster size is {}, requested {}",
+                    cluster, requested
+                ));
+            }
+        }
+        if let Some(requested) = self.fats {
+            if bs.fats() != requested {
+                mismatches.push(format!("{} FATs, requested {}", bs.fats(), requested));
+            }
+        }
+        if let Some(requested) = self.root_entries {
+            if bs.root_entries() != requested {
+                mismatches.push(format!(
+                    "{} root entries, requested {}",
+                    bs.root_entries(),
+                    requested
+                ));
+            }
+        }
+        mismatches
+    }
+}
@@ -0,0 +1,269 @@
+//! Minimal glob matching for host and image paths, and expanding
+//! wildcards in the image paths `ls`, `read` and `rm` take, like mtools
+//! does
+
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::{Context, Result};
+use fatfs::Dir;
+
+use crate::exit;
+
+/// `--no-fail-empty` of the commands taking image path patterns
+#[derive(clap::Args, Clone, Debug)]
+pub struct GlobArgs {
+    /// Do nothing instead of failing when a path with wildcards matches
+    /// no entries
+    #[clap(long)]
+    pub no_fail_empty: bool,
+}
+
+/// What `expand` found
+pub struct Matches {
+    /// Image paths like `/EFI/BOOT`, sorted so that directories come
+    /// before their contents, with whether they are directories
+    pub entries: Vec<(String, bool)>,
+    /// The image paths of `entries`
+    paths: HashSet<String>,
+    /// The directories above entries
+    above: HashSet<String>,
+}
+
+impl Matches {
+    /// Whether the image path `path` is a match, or with `below` inside one
+    pub fn contains(&self, path: &str, below: bool) -> bool {
+        self.paths.contains(path) || (below && self.is_below(path))
+    }
+
+    /// Whether the image path `path` is inside a matched directory
+    pub fn is_below(&self, path: &str) -> bool {
+        path.match_indices('/')
+            .any(|(i, _)| i > 0 && self.paths.contains(&path[..i]))
+    }
+
+    /// Whether there are matches inside the image directory `path`
+    pub fn is_above(&self, path: &str) -> bool {
+        self.above.contains(path)
+    }
+}
+
+/// Whether the normalized image path has `*` or `?` for `expand`. FAT
+/// names can't contain them, so they need no escaping.
+pub fn is_pattern(inner_path: &str) -> bool {
+    inner_path.contains(['*', '?'])
+}
+
+/// The directories of the normalized image path `pattern` before the first
+/// one with wildcards, which all matches are in
+pub fn literal_dir(pattern: &str) -> &str {
+    let first = pattern.find(['*', '?']).unwrap_or(pattern.len());
+    pattern[..first].rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+/// The entries below `root` matching the normalized image path `pattern`,
+/// like `EFI/**/*.bak`. Each component is matched with `matches` against
+/// the long and the short name, regardless of case like FAT looks names
+/// up, and `**` matches any number of directories. Fails with
+/// `exit::NotFound` when nothing matches, unless `args` says not to.
+pub fn expand<IO: fatfs::ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter>(
+    root: &Dir<'_, IO, TP, OCC>, pattern: &str, args: &GlobArgs,
+) -> Result<Matches>
+where
+    fatfs::Error<IO::Error>: std::error::Error + Send + Sync + 'static,
+{
+    let components: Vec<String> = pattern
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .map(str::to_uppercase)
+        .collect();
+    let mut found = BTreeMap::new();
+    // Directories to look in, by image path, with the index of the
+    // component their entries have to match
+    let mut pending = vec![(String::new(), 0)];
+    let mut seen = HashSet::new();
+    while let Some((dir_path, i)) = pending.pop() {
+        if !seen.insert((dir_path.clone(), i)) {
+            continue;
+        }
+        let component = &components[i];
+        let last = i + 1 == components.len();
+        if component == "**" && !last {
+            pending.push((dir_path.clone(), i + 1));
+        }
+        let entries = if dir_path.is_empty() {
+            root.iter()
+        } else {
+            root.open_dir(&dir_path[1..])
+                .with_context(|| format!("failed opening directory {}", dir_path))?
+                .iter()
+        };
+        for entry in entries {
+            let entry = entry.with_context(|| {
+                let shown = if dir_path.is_empty() { "/" } else { &dir_path };
+                format!("failed reading directory {}", shown)
+            })?;
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = format!("{}/{}", dir_path, name);
+            if component == "**" {
+                if last {
+                    found.insert(path.clone(), entry.is_dir());
+                }
+                if entry.is_dir() {
+                    pending.push((path, i));
+                }
+                continue;
+            }
+            if !matches(component, &name.to_uppercase())
+                && !matches(component, &entry.short_file_name().to_uppercase())
+            {
+                continue;
+            }
+            if last {
+                found.insert(path, entry.is_dir());
+            } else if entry.is_dir() {
+                pending.push((path, i + 1));
+            }
+        }
+    }
+    if found.is_empty() && !args.no_fail_empty {
+        return Err(exit::NotFound(format!("/{}", pattern)).into());
+    }
+
+    let mut above = HashSet::new();
+    for path in found.keys() {
+        for (i, _) in path.match_indices('/').skip(1) {
+            above.insert(path[..i].to_owned());
+        }
+    }
+    Ok(Matches {
+        paths: found.keys().cloned().collect(),
+        entries: found.into_iter().collect(),
+        above,
+    })
+}
+
+/// Matches a `/`-separated relative `path` against `pattern`.
+///
+/// `*` and `?` never match `/`, while `**` matches any number of whole
+/// components. A pattern without a `/` is matched against the last
+/// component of the path only, so `*.txt` matches `a/b/c.txt`.
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let path = if pattern.contains('/') {
+        path
+    } else {
+        path.rsplit('/').next().unwrap_or(path)
+    };
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = path.chars().collect();
+    match_here(&p, &t)
+}
+
+fn match_here(p: &[char], t: &[char]) -> bool {
+    match p {
+        [] => t.is_empty(),
+        ['*', '*', '/', rest @ ..] => {
+            (0..=t.len()).any(|i| (i == 0 || t[i - 1] == '/') && match_here(rest, &t[i..]))
+        },
+        ['*', '*', rest @ ..] => (0..=t.len()).any(|i| match_here(rest, &t[i..])),
+        ['*', rest @ ..] => {
+            for i in 0..=t.len() {
+                if match_here(rest, &t[i..]) {
+                    return true;
+                }
+                if i < t.len() && t[i] == '/' {
+                    break;
+                }
+            }
+            false
+        },
+        ['?', rest @ ..] => !t.is_empty() && t[0] != '/' && match_here(rest, &t[1..]),
+        [c, rest @ ..] => !t.is_empty() && t[0] == *c && match_here(rest, &t[1..]),
+    }
+}
+
+/// Matches a single name against `pattern`, returning the text matched
+/// by each `*` and `?` in order. A `*` matches as little as it can.
+pub fn captures(pattern: &str, name: &str) -> Option<Vec<String>> {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = name.chars().collect();
+    let mut caps = Vec::new();
+    if !capture_here(&p, &t, &mut caps) {
+        return None;
+    }
+    Some(caps.into_iter().map(|c| c.iter().collect()).collect())
+}
+
+fn capture_here<'t>(p: &[char], t: &'t [char], caps: &mut Vec<&'t [char]>) -> bool {
+    let (len_range, rest) = match p {
+        [] => return t.is_empty(),
+        ['*', rest @ ..] => (0..=t.len(), rest),
+        ['?', rest @ ..] if !t.is_empty() => (1..=1, rest),
+        ['?', ..] => return false,
+        [c, rest @ ..] => return !t.is_empty() && t[0] == *c && capture_here(rest, &t[1..], caps),
+    };
+    for len in len_range {
+        caps.push(&t[..len]);
+        if capture_here(rest, &t[len..], caps) {
+            return true;
+        }
+        caps.pop();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_stay_within_components() {
+        assert!(matches("*.txt", "a/b/c.txt"));
+        assert!(matches("b/*.txt", "b/c.txt"));
+        assert!(!matches("*/c.txt", "a/b/c.txt"));
+        assert!(!matches("a*", "a/b"));
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "a/c"));
+        assert!(!matches("a?c", "ac"));
+    }
+
+    #[test]
+    fn double_star_spans_directories() {
+        assert!(matches("**/*.o", "main.o"));
+        assert!(matches("**/*.o", "src/deep/main.o"));
+        assert!(matches("**/.git/**", "a/.git/objects/pack"));
+        assert!(matches("src/**", "src/a/b"));
+        assert!(!matches("**/x.o", "ax.o/y"));
+        assert!(!matches("src/**/*.rs", "lib/a.rs"));
+    }
+
+    #[test]
+    fn captures_are_minimal() {
+        assert_eq!(
+            captures("*.BIN", "FIRMWARE.BIN"),
+            Some(vec!["FIRMWARE".to_owned()])
+        );
+        assert_eq!(
+            captures("*.*", "A.TAR.GZ"),
+            Some(vec!["A".to_owned(), "TAR.GZ".to_owned()])
+        );
+        assert_eq!(
+            captures("LOG?-*", "LOG7-X"),
+            Some(vec!["7".to_owned(), "X".to_owned()])
+        );
+        assert_eq!(captures("*.BIN", "README"), None);
+        assert_eq!(captures("?", ""), None);
+    }
+
+    #[test]
+    fn literal_dir_ends_before_the_first_wildcard() {
+        assert!(is_pattern("EFI/*/GRUB.CFG"));
+        assert!(!is_pattern("EFI/BOOT/GRUB.CFG"));
+        assert_eq!(literal_dir("EFI/BOOT/*.EFI"), "EFI/BOOT");
+        assert_eq!(literal_dir("EFI/B*/X"), "EFI");
+        assert_eq!(literal_dir("*.CFG"), "");
+        assert_eq!(literal_dir("EFI/BOOT"), "EFI");
+    }
+}
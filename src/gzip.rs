@@ -0,0 +1,108 @@
+//! Compressing file contents on their way into the image
+
+use std::io::{self, Read, Seek, Write};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::size::SizeFormat;
+use crate::text::{self, TextMode};
+
+/// Name a compressed file is stored under, unless the name is kept
+pub fn stored_name(name: &str) -> String {
+    format!("{}.gz", name)
+}
+
+/// Copies `source` to `target`, converting newlines and then compressing.
+/// Returns the uncompressed and the stored size.
+pub fn copy<R: Read, W: Write + Seek>(
+    mode: TextMode, source: &mut R, target: &mut W,
+) -> io::Result<(u64, u64)> {
+    let start = target.stream_position()?;
+    let mut encoder = GzEncoder::new(&mut *target, Compression::default());
+    let original = text::copy(mode, source, &mut encoder)?;
+    encoder.finish()?;
+    Ok((original, target.stream_position()? - start))
+}
+
+/// Sizes of the files compressed by one command
+#[derive(Debug, Default)]
+pub struct Totals {
+    files: usize,
+    original: u64,
+    stored: u64,
+}
+
+impl Totals {
+    pub fn add(&mut self, (original, stored): (u64, u64)) {
+        self.files += 1;
+        self.original += original;
+        self.stored += stored;
+    }
+
+    /// Prints the totals, if anything was compressed
+    pub fn report(&self, sizes: &SizeFormat) {
+        if self.files > 0 {
+            eprintln!(
+                "gzip: {} file(s), {} compressed to {}",
+                self.files,
+                sizes.logical(self.original),
+                sizes.logical(self.stored)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trip() {
+        let input: Vec<u8> = (0..20_000u32)
+            .flat_map(|i| format!("line {}\n", i % 97).into_bytes())
+            .collect();
+        let mut target = Cursor::new(b"head".to_vec());
+        target.seek(io::SeekFrom::End(0)).unwrap();
+        let (original, stored) = copy(TextMode::None, &mut &input[..], &mut target).unwrap();
+
+        let target = target.into_inner();
+        assert_eq!(&target[..4], b"head");
+        assert_eq!(&target[4..6], [0x1f, 0x8b]);
+        assert_eq!(original, input.len() as u64);
+        assert_eq!(stored, target.len() as u64 - 4);
+        assert!(stored < original / 10);
+        let mut decoded = Vec::new();
+        MultiGzDecoder::new(&target[4..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, input);
+    }
+
+    #[test]
+    fn newlines_are_converted_before_compressing() {
+        let mut target = Cursor::new(Vec::new());
+        let (original, _) = copy(TextMode::LfToCrlf, &mut &b"a\nb\n"[..], &mut target).unwrap();
+        assert_eq!(original, 6);
+        let mut decoded = Vec::new();
+        MultiGzDecoder::new(&target.into_inner()[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, b"a\r\nb\r\n");
+    }
+
+    #[test]
+    fn empty_input() {
+        let mut target = Cursor::new(Vec::new());
+        let (original, stored) = copy(TextMode::None, &mut io::empty(), &mut target).unwrap();
+        assert_eq!(original, 0);
+        assert_eq!(stored, target.get_ref().len() as u64);
+        let mut decoded = Vec::new();
+        MultiGzDecoder::new(&target.into_inner()[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert!(decoded.is_empty());
+    }
+}
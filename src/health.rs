@@ -0,0 +1,300 @@
+//! The `health` command, a quick health document for monitoring.
+//!
+//! Unlike `check` this doesn't look for every problem. It mounts the
+//! volume, compares the FAT copies, checks the FSInfo sector and the dirty
+//! flag, and walks at most a given number of directory entries, so that it
+//! stays cheap on large images.
+
+use std::collections::{HashSet, VecDeque};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use fatfs::{FatType, FileSystem, FsOptions};
+use fscommon::BufStream;
+
+use crate::json::{self, Value};
+use crate::ondisk::{BootSector, Fat, FsInfo, Layout, RawDir};
+use crate::region::ImgSlice;
+
+/// What the bounded walk found
+#[derive(Default)]
+struct Walk {
+    files: u64,
+    dirs: u64,
+    entries: usize,
+    complete: bool,
+    deepest: Option<(String, usize)>,
+    largest: Option<(String, u32)>,
+}
+
+struct Health {
+    mountable: bool,
+    fat_type: Option<FatType>,
+    fat_copies_consistent: Option<bool>,
+    fsinfo_consistent: Option<bool>,
+    dirty: Option<bool>,
+    total_bytes: Option<u64>,
+    free_bytes: Option<u64>,
+    walk: Walk,
+    problems: Vec<String>,
+}
+
+impl Health {
+    fn healthy(&self) -> bool {
+        self.mountable
+            && self.fat_copies_consistent == Some(true)
+            && self.fsinfo_consistent != Some(false)
+            && self.dirty != Some(true)
+            && self.problems.is_empty()
+    }
+
+    fn to_json(&self, elapsed_ms: u64) -> Value {
+        let fat_type = self.fat_type.map(|t| match t {
+            FatType::Fat12 => "FAT12",
+            FatType::Fat16 => "FAT16",
+            FatType::Fat32 => "FAT32",
+        });
+        let w = &self.walk;
+        let largest = match &w.largest {
+            Some((path, bytes)) => json::object([
+                ("path", path.as_str().into()),
+                ("bytes", (*bytes as u64).into()),
+            ]),
+            None => Value::Null,
+        };
+        json::versioned(json::object([
+            ("healthy", self.healthy().into()),
+            ("mountable", self.mountable.into()),
+            ("fat_type", fat_type.into()),
+            ("fat_copies_consistent", self.fat_copies_consistent.into()),
+            ("fsinfo_consistent", self.fsinfo_consistent.into()),
+            ("dirty", self.dirty.into()),
+            ("total_bytes", self.total_bytes.into()),
+            ("free_bytes", self.free_bytes.into()),
+            ("files", w.files.into()),
+            ("directories", w.dirs.into()),
+            (
+                "deepest_path",
+                w.deepest.as_ref().map(|(p, _)| p.as_str()).into(),
+            ),
+            (
+                "depth",
+                (w.deepest.as_ref().map_or(0, |(_, d)| *d) as u64).into(),
+            ),
+            ("largest_file", largest),
+            ("walk_complete", w.complete.into()),
+            (
+                "problems",
+                self.problems.iter().map(String::as_str).collect(),
+            ),
+            ("elapsed_ms", elapsed_ms.into()),
+        ]))
+    }
+}
+
+/// Whether all FAT copies hold the same bytes, compared a chunk at a time
+fn fat_copies_match(img: &mut ImgSlice, bs: &BootSector, layout: &Layout) -> Result<bool> {
+    let mut first = vec![0u8; 64 * 1024];
+    let mut other = vec![0u8; 64 * 1024];
+    let mut done = 0;
+    while done < layout.fat_size {
+        let len = (layout.fat_size - done).min(first.len() as u64) as usize;
+        img.seek(SeekFrom::Start(layout.fat_offset + done))?;
+        img.read_exact(&mut first[..len])?;
+        for copy in 1..bs.fats() as u64 {
+            img.seek(SeekFrom::Start(
+                layout.fat_offset + copy * layout.fat_size + done,
+            ))?;
+            img.read_exact(&mut other[..len])?;
+            if first[..len] != other[..len] {
+                return Ok(false);
+            }
+        }
+        done += len as u64;
+    }
+    Ok(true)
+}
+
+/// FSInfo signatures must be present, and its free count either unknown
+/// or the one the FAT gives
+fn fsinfo_matches(img: &mut ImgSlice, bs: &BootSector, free: u32) -> Result<bool> {
+    let fs_info = FsInfo::read(img, bs)?;
+    let count = fs_info.free_count();
+    Ok(fs_info.is_signed() && (count == FsInfo::FREE_UNKNOWN || count == free))
+}
+
+/// The clean shutdown bit in the second FAT entry, which FAT12 lacks
+fn is_dirty(fat: &Fat, fat_type: FatType) -> Option<bool> {
+    match fat_type {
+        FatType::Fat12 => None,
+        FatType::Fat16 => Some(fat.get(1) & 0x8000 == 0),
+        FatType::Fat32 => Some(fat.get(1) & 0x0800_0000 == 0),
+    }
+}
+
+/// Walks the directories from the normalized image directory `inner_path`
+/// breadth-first until `max_entries` entries are seen
+fn walk(
+    img: &mut ImgSlice, layout: &Layout, fat: &Fat, inner_path: &str, max_entries: usize,
+    problems: &mut Vec<String>,
+) -> Result<Walk> {
+    let mut walk = Walk::default();
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::new();
+    let start = RawDir::read_path(img, layout, fat, inner_path)?;
+    let path = if inner_path.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", inner_path)
+    };
+    queue.push_back((path, 0, start));
+
+    while let Some((path, depth, dir)) = queue.pop_front() {
+        for (name, entry) in dir.files() {
+            if name == "." || name == ".." {
+                continue;
+            }
+            if walk.entries >= max_entries {
+                return Ok(walk);
+            }
+            walk.entries += 1;
+
+            let sub_path = format!("{}/{}", path, name);
+            if walk.deepest.as_ref().is_none_or(|(_, d)| depth + 1 > *d) {
+                walk.deepest = Some((sub_path.clone(), depth + 1));
+            }
+            if !entry.is_dir() {
+                walk.files += 1;
+                if walk.largest.as_ref().is_none_or(|(_, s)| entry.size() > *s) {
+                    walk.largest = Some((sub_path, entry.size()));
+                }
+                continue;
+            }
+
+            walk.dirs += 1;
+            let start = entry.first_cluster();
+            if !layout.is_data_cluster(start) || !visited.insert(start) {
+                problems.push(format!("{}: invalid directory start cluster", sub_path));
+                continue;
+            }
+            match RawDir::read(img, layout, fat, start) {
+                Ok(sub) => queue.push_back((sub_path, depth + 1, sub)),
+                Err(err) => problems.push(format!("{}: {:#}", sub_path, err)),
+            }
+        }
+    }
+    walk.complete = true;
+    Ok(walk)
+}
+
+fn inspect(img_file: &Path, inner_path: &str, max_entries: usize) -> Result<Health> {
+    let mut health = Health {
+        mountable: false,
+        fat_type: None,
+        fat_copies_consistent: None,
+        fsinfo_consistent: None,
+        dirty: None,
+        total_bytes: None,
+        free_bytes: None,
+        walk: Walk::default(),
+        problems: Vec::new(),
+    };
+
+    let mut img = ImgSlice::open(img_file, false)?;
+    match FileSystem::new(BufStream::new(img.try_clone()?), FsOptions::new()) {
+        Ok(_) => health.mountable = true,
+        Err(err) => health.problems.push(format!("doesn't mount: {}", err)),
+    }
+
+    let bs = match BootSector::read(&mut img) {
+        Ok(bs) => bs,
+        Err(err) => {
+            health.problems.push(format!("boot sector: {:#}", err));
+            return Ok(health);
+        },
+    };
+    let (layout, fat) = match bs.layout().and_then(|l| Ok((l, Fat::read(&mut img, &l)?))) {
+        Ok(found) => found,
+        Err(err) => {
+            health.problems.push(format!("FAT: {:#}", err));
+            return Ok(health);
+        },
+    };
+
+    health.fat_type = Some(layout.fat_type);
+    health.fat_copies_consistent = Some(fat_copies_match(&mut img, &bs, &layout)?);
+    let free = fat.free_clusters();
+    if layout.fat_type == FatType::Fat32 {
+        health.fsinfo_consistent = Some(fsinfo_matches(&mut img, &bs, free)?);
+    }
+    health.dirty = is_dirty(&fat, layout.fat_type);
+    health.total_bytes = Some(layout.total_clusters as u64 * layout.cluster_size);
+    health.free_bytes = Some(free as u64 * layout.cluster_size);
+    health.walk = walk(
+        &mut img,
+        &layout,
+        &fat,
+        inner_path,
+        max_entries,
+        &mut health.problems,
+    )?;
+    Ok(health)
+}
+
+/// Prints the health document of the image, failing if it isn't healthy
+pub fn run(img_file: &Path, inner_path: &str, max_entries: usize) -> Result<()> {
+    let started = Instant::now();
+    let health = inspect(img_file, inner_path, max_entries)?;
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    println!("{}", health.to_json(elapsed_ms));
+    if !health.healthy() {
+        bail!("image is not healthy");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Document;
+
+    #[test]
+    fn json_matches_the_schema() {
+        let unmountable = Health {
+            mountable: false,
+            fat_type: None,
+            fat_copies_consistent: None,
+            fsinfo_consistent: None,
+            dirty: None,
+            total_bytes: None,
+            free_bytes: None,
+            walk: Walk::default(),
+            problems: vec!["no boot signature".to_owned()],
+        };
+        let healthy = Health {
+            mountable: true,
+            fat_type: Some(FatType::Fat32),
+            fat_copies_consistent: Some(true),
+            fsinfo_consistent: Some(true),
+            dirty: Some(false),
+            total_bytes: Some(1 << 20),
+            free_bytes: Some(4096),
+            walk: Walk {
+                files: 2,
+                dirs: 1,
+                entries: 3,
+                complete: true,
+                deepest: Some(("/A/B".to_owned(), 2)),
+                largest: Some(("/A/B".to_owned(), 1000)),
+            },
+            problems: Vec::new(),
+        };
+        for (health, verdict) in [(unmountable, false), (healthy, true)] {
+            let doc = health.to_json(5);
+            assert_eq!(Document::Health.validate(&doc), Ok(()));
+            assert_eq!(doc.get("healthy"), Some(&verdict.into()));
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Host paths of tree operations.
+//!
+//! Win32 path handling stops at 260 characters and drops trailing dots
+//! and spaces, both of which FAT trees run into. Paths in the `\\?\`
+//! extended-length form are passed through as they are.
+//!
+//! Host names aren't necessarily UTF-8, while FAT long names are UCS-2.
+//! Unix names that aren't UTF-8 are read as Latin-1, which has a character
+//! for every byte. Windows names that aren't are UTF-16 with unpaired
+//! surrogates, which can't be written with fatfs.
+
+use std::ffi::OsStr;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Turns an existing host path into one deep trees can be walked below.
+/// On Windows this is the extended-length form, elsewhere it's unchanged.
+#[cfg(windows)]
+pub fn extended(path: &Path) -> io::Result<PathBuf> {
+    // Canonical paths on Windows come with the `\\?\` prefix
+    std::fs::canonicalize(path)
+}
+
+#[cfg(not(windows))]
+pub fn extended(path: &Path) -> io::Result<PathBuf> {
+    Ok(path.to_owned())
+}
+
+/// Sets the modification time of the host file or directory `path`
+#[cfg(not(windows))]
+pub fn set_modified(path: &Path, t: SystemTime) -> io::Result<()> {
+    fs::File::open(path)?.set_modified(t)
+}
+
+#[cfg(windows)]
+pub fn set_modified(path: &Path, t: SystemTime) -> io::Result<()> {
+    use std::os::windows::fs::OpenOptionsExt;
+    // Needed to open directories
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)?
+        .set_modified(t)
+}
+
+/// Parses an octal permission mode like `0644`, for `read-tree`
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("{:?} is not an octal mode from 0 to 7777", s)),
+    }
+}
+
+/// Sets the permission mode of the host file or directory `path`. Other
+/// hosts than Unix have no modes, there it does nothing.
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// The host file name `name` as text, `None` if it has no reading
+#[cfg(unix)]
+pub fn decode_name(name: &OsStr) -> Option<String> {
+    use std::os::unix::ffi::OsStrExt;
+    match name.to_str() {
+        Some(name) => Some(name.to_owned()),
+        None => Some(name.as_bytes().iter().map(|&b| b as char).collect()),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn decode_name(name: &OsStr) -> Option<String> {
+    name.to_str().map(str::to_owned)
+}
+
+/// `path` with the bytes of names that aren't UTF-8 escaped, like
+/// `dir/caf\xe9.txt`
+#[cfg(unix)]
+pub fn raw_display(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().escape_ascii().to_string()
+}
+
+#[cfg(not(unix))]
+pub fn raw_display(path: &Path) -> String {
+    format!("{:?}", path)
+}
+
+/// Keeps tree walks on the filesystem they started on, like `du -x`
+#[derive(Debug, Clone, Copy)]
+pub struct SameFilesystem {
+    #[cfg(unix)]
+    dev: u64,
+}
+
+impl SameFilesystem {
+    /// Walks starting at the directory `root`
+    pub fn new(root: &Path) -> io::Result<Self> {
+        let meta = fs::metadata(root)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            Ok(Self { dev: meta.dev() })
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = meta;
+            Ok(Self {})
+        }
+    }
+
+    /// Whether the directory with metadata `meta` is on another
+    /// filesystem. On Windows that's any reparse point, which covers
+    /// mounted folders and junctions.
+    pub fn leaves(&self, meta: &Metadata) -> bool {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            meta.dev() != self.dev
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::fs::MetadataExt;
+            const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
+            meta.file_attributes() & FILE_ATTRIBUTE_REPARSE_POINT != 0
+        }
+        #[cfg(not(any(unix, windows)))]
+        {
+            let _ = meta;
+            false
+        }
+    }
+}
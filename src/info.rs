@@ -0,0 +1,252 @@
+//! Printing what `info` found out about the volume, in each `--output`
+//! format
+
+use fatfs::FatType;
+
+use crate::json;
+use crate::output::{Fields, Output};
+use crate::region::Partition;
+use crate::size::SizeFormat;
+
+/// What `info` prints
+pub struct Info {
+    pub fat_type: FatType,
+    pub volume_id: u32,
+    /// As stored, padded with spaces
+    pub volume_label: String,
+    pub cluster_size: u64,
+    pub sector_size: u16,
+    pub reserved_sectors: u16,
+    pub fats: u8,
+    pub root_entries: u16,
+    pub total_clusters: u32,
+    pub free_clusters: u32,
+    pub partition: Option<Partition>,
+    pub hidden_sectors: u32,
+}
+
+impl Info {
+    pub fn print(&self, output: Output, sizes: &SizeFormat, verbose: bool) {
+        match output {
+            Output::Classic => self.classic(sizes, verbose).print(output),
+            Output::V2 => self.v2(sizes, verbose).print(output),
+            Output::Json => println!("{}", self.to_json()),
+        }
+    }
+
+    fn used_clusters(&self) -> u32 {
+        self.total_clusters - self.free_clusters
+    }
+
+    /// The format of earlier releases, which must not change
+    fn classic(&self, sizes: &SizeFormat, verbose: bool) -> Fields {
+        let (cs, ct, cf) = (self.cluster_size, self.total_clusters, self.free_clusters);
+        let mut fields = Fields::new(14);
+        fields.push("fs type", format!("{:?}", self.fat_type));
+        fields.push("volume id", format!("{:?}", self.volume_id));
+        fields.push("volume label", format!("{:?}", self.volume_label));
+        fields.push("cluster size", sizes.allocated(cs));
+        fields.push("sector size", self.sector_size);
+        fields.push("reserved", format!("{} sectors", self.reserved_sectors));
+        fields.push("fats", self.fats);
+        fields.push("root entries", self.root_entries);
+        fields.push("cluster count", ct);
+        fields.push("clusters free", cf);
+        fields.push("space total", sizes.allocated(ct as u64 * cs));
+        fields.push("space free", sizes.allocated(cf as u64 * cs));
+        fields.push("usage", format!("{}%", (self.used_clusters() * 100) / ct));
+        if let Some(p) = self.partition {
+            fields.push("partition", format_partition(&p));
+        }
+        if verbose {
+            fields.push("hidden sectors", self.hidden_sectors);
+        }
+        fields
+    }
+
+    /// Like classic, with the label as it shows in a file manager, the
+    /// volume id as `dir` prints it and the usage to a tenth of a percent
+    fn v2(&self, sizes: &SizeFormat, verbose: bool) -> Fields {
+        let (cs, ct, cf) = (self.cluster_size, self.total_clusters, self.free_clusters);
+        let permille = self.used_clusters() as u64 * 1000 / ct as u64;
+        let mut fields = Fields::new(0);
+        fields.push("fs type", fat_type_name(self.fat_type));
+        fields.push("volume id", format_volume_id(self.volume_id));
+        fields.push("volume label", self.volume_label.trim_end());
+        fields.push("cluster size", sizes.allocated(cs));
+        fields.push("sector size", self.sector_size);
+        fields.push("reserved", format!("{} sectors", self.reserved_sectors));
+        fields.push("fats", self.fats);
+        fields.push("root entries", self.root_entries);
+        fields.push("cluster count", ct);
+        fields.push("clusters free", cf);
+        fields.push("space total", sizes.allocated(ct as u64 * cs));
+        fields.push("space free", sizes.allocated(cf as u64 * cs));
+        fields.push("usage", format!("{}.{}%", permille / 10, permille % 10));
+        if let Some(p) = self.partition {
+            fields.push("partition", format_partition(&p));
+        }
+        if verbose {
+            fields.push("hidden sectors", self.hidden_sectors);
+        }
+        fields
+    }
+
+    /// Sizes are in bytes, whatever `--block-size` says
+    pub fn to_json(&self) -> json::Value {
+        let cs = self.cluster_size;
+        let partition = match self.partition {
+            Some(p) => json::object([
+                ("number", (p.number as u64).into()),
+                ("type", (p.kind as u64).into()),
+                ("start_lba", (p.start_lba as u64).into()),
+                ("sectors", (p.sectors as u64).into()),
+            ]),
+            None => json::Value::Null,
+        };
+        let doc = json::object([
+            ("fat_type", fat_type_name(self.fat_type).into()),
+            ("volume_id", format!("{:08x}", self.volume_id).into()),
+            ("volume_label", self.volume_label.trim_end().into()),
+            ("cluster_size", cs.into()),
+            ("sector_size", (self.sector_size as u64).into()),
+            ("reserved_sectors", (self.reserved_sectors as u64).into()),
+            ("fats", (self.fats as u64).into()),
+            ("root_entries", (self.root_entries as u64).into()),
+            ("total_clusters", (self.total_clusters as u64).into()),
+            ("free_clusters", (self.free_clusters as u64).into()),
+            ("total_bytes", (self.total_clusters as u64 * cs).into()),
+            ("free_bytes", (self.free_clusters as u64 * cs).into()),
+            ("used_bytes", (self.used_clusters() as u64 * cs).into()),
+            ("partition", partition),
+            ("hidden_sectors", (self.hidden_sectors as u64).into()),
+        ]);
+        json::versioned(doc)
+    }
+}
+
+fn fat_type_name(fat_type: FatType) -> &'static str {
+    match fat_type {
+        FatType::Fat12 => "FAT12",
+        FatType::Fat16 => "FAT16",
+        FatType::Fat32 => "FAT32",
+    }
+}
+
+fn format_partition(p: &Partition) -> String {
+    format!(
+        "{} (type 0x{:02x}), start LBA {}, {} sectors",
+        p.number, p.kind, p.start_lba, p.sectors
+    )
+}
+
+/// `1234-ABCD`
+fn format_volume_id(id: u32) -> String {
+    format!("{:04X}-{:04X}", id >> 16, id & 0xffff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Document;
+
+    fn info(partition: Option<Partition>) -> Info {
+        Info {
+            fat_type: FatType::Fat16,
+            volume_id: 0x1234_abcd,
+            volume_label: "BOOT       ".to_owned(),
+            cluster_size: 2048,
+            sector_size: 512,
+            reserved_sectors: 1,
+            fats: 2,
+            root_entries: 512,
+            total_clusters: 100,
+            free_clusters: 40,
+            partition,
+            hidden_sectors: 2048,
+        }
+    }
+
+    #[test]
+    fn json_matches_the_schema() {
+        let partition = Partition {
+            number: 1,
+            kind: 0x0e,
+            start_lba: 2048,
+            sectors: 8192,
+        };
+        for info in [info(None), info(Some(partition))] {
+            assert_eq!(Document::Info.validate(&info.to_json()), Ok(()));
+        }
+    }
+
+    #[test]
+    fn json_sizes_are_bytes() {
+        let doc = info(None).to_json();
+        assert_eq!(doc.get("volume_label"), Some(&"BOOT".into()));
+        assert_eq!(doc.get("volume_id"), Some(&"1234abcd".into()));
+        assert_eq!(
+            doc.get("total_bytes").and_then(json::Value::as_u64),
+            Some(204_800)
+        );
+        assert_eq!(
+            doc.get("free_bytes").and_then(json::Value::as_u64),
+            Some(81_920)
+        );
+    }
+
+    #[test]
+    fn text_formats_byte_for_byte() {
+        let partition = Partition {
+            number: 1,
+            kind: 0x0e,
+            start_lba: 2048,
+            sectors: 8192,
+        };
+        let sizes = SizeFormat {
+            block_size: 1,
+            human: false,
+        };
+        let info = info(Some(partition));
+        assert_eq!(
+            info.classic(&sizes, true).render(Output::Classic),
+            concat!(
+                "fs type:       Fat16\n",
+                "volume id:     305441741\n",
+                "volume label:  \"BOOT       \"\n",
+                "cluster size:  2048\n",
+                "sector size:   512\n",
+                "reserved:      1 sectors\n",
+                "fats:          2\n",
+                "root entries:  512\n",
+                "cluster count: 100\n",
+                "clusters free: 40\n",
+                "space total:   204800\n",
+                "space free:    81920\n",
+                "usage:         60%\n",
+                "partition:     1 (type 0x0e), start LBA 2048, 8192 sectors\n",
+                "hidden sectors: 2048\n",
+            )
+        );
+        assert_eq!(
+            info.v2(&sizes, true).render(Output::V2),
+            concat!(
+                "fs type:         FAT16\n",
+                "volume id:       1234-ABCD\n",
+                "volume label:    BOOT\n",
+                "cluster size:    2048\n",
+                "sector size:     512\n",
+                "reserved:        1 sectors\n",
+                "fats:            2\n",
+                "root entries:    512\n",
+                "cluster count:   100\n",
+                "clusters free:   40\n",
+                "space total:     204800\n",
+                "space free:      81920\n",
+                "usage:           60.0%\n",
+                "partition:       1 (type 0x0e), start LBA 2048, 8192 sectors\n",
+                "hidden sectors:  2048\n",
+            )
+        );
+    }
+}
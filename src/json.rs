@@ -0,0 +1,356 @@
+//! Writing JSON output, and reading back what we wrote, without pulling
+//! in a serialization framework
+
+use std::fmt::{self, Display, Write};
+
+use anyhow::{bail, Context, Result};
+
+/// Version of the documents the commands print, the `schema_version`
+/// member of each. Bumped whenever a member changes, see `schema`.
+pub const SCHEMA_VERSION: u64 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Value>),
+    /// Members in output order
+    Object(Vec<(String, Value)>),
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Self::Bool(b)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(n: u64) -> Self {
+        Self::Number(n)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Self::String(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Self::String(s.to_owned())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        v.map_or(Self::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> FromIterator<T> for Value {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self::Array(iter.into_iter().map(Into::into).collect())
+    }
+}
+
+/// Builds an object from `(name, value)` pairs
+pub fn object<const N: usize>(members: [(&str, Value); N]) -> Value {
+    Value::Object(
+        members
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), v))
+            .collect(),
+    )
+}
+
+/// Adds `schema_version` as the first member of `doc`, an object printed
+/// as a document of its own
+pub fn versioned(doc: Value) -> Value {
+    match doc {
+        Value::Object(mut members) => {
+            members.insert(0, ("schema_version".to_owned(), SCHEMA_VERSION.into()));
+            Value::Object(members)
+        },
+        _ => panic!("only objects carry a schema version"),
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Number(n) => write!(f, "{}", n),
+            Self::String(s) => write_str(f, s),
+            Self::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_char(']')
+            },
+            Self::Object(members) => {
+                f.write_char('{')?;
+                for (i, (name, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_str(f, name)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_char('}')
+            },
+        }
+    }
+}
+
+impl Value {
+    /// Member `name` of an object
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        match self {
+            Self::Object(members) => members.iter().find(|(k, _)| k == name).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Self::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_ws(&mut self) {
+        let rest = &self.s[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_ws();
+        self.s[self.pos..].chars().next()
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if self.peek() != Some(c) {
+            bail!("expected {:?} at byte {}", c, self.pos);
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn keyword(&mut self, word: &str, value: Value) -> Result<Value> {
+        if !self.s[self.pos..].starts_with(word) {
+            bail!("unexpected input at byte {}", self.pos);
+        }
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        let mut chars = self.s[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(out);
+                },
+                '\\' => {
+                    let escaped = match chars.next() {
+                        Some((_, 'u')) => {
+                            let hex: String = (0..4)
+                                .filter_map(|_| chars.next())
+                                .map(|(_, c)| c)
+                                .collect();
+                            let code =
+                                u32::from_str_radix(&hex, 16).context("invalid \\u escape")?;
+                            // Surrogate pairs aren't written by us
+                            char::from_u32(code).context("invalid \\u escape")?
+                        },
+                        Some((_, 'n')) => '\n',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'b')) => '\u{8}',
+                        Some((_, 'f')) => '\u{c}',
+                        Some((_, c @ ('"' | '\\' | '/'))) => c,
+                        _ => bail!("invalid escape at byte {}", self.pos + i),
+                    };
+                    out.push(escaped);
+                },
+                c => out.push(c),
+            }
+        }
+        bail!("unterminated string")
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        match self.peek() {
+            Some('{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                loop {
+                    let name = self.string()?;
+                    self.expect(':')?;
+                    members.push((name, self.value()?));
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect('}')?;
+                Ok(Value::Object(members))
+            },
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.peek() == Some(']') {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    match self.peek() {
+                        Some(',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(']')?;
+                Ok(Value::Array(items))
+            },
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some('t') => self.keyword("true", Value::Bool(true)),
+            Some('f') => self.keyword("false", Value::Bool(false)),
+            Some('n') => self.keyword("null", Value::Null),
+            Some(c) if c.is_ascii_digit() => {
+                let rest = &self.s[self.pos..];
+                let len = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                if rest[len..].starts_with(['.', 'e', 'E']) {
+                    bail!("only whole numbers are supported, at byte {}", self.pos);
+                }
+                let n = rest[..len]
+                    .parse::<u64>()
+                    .with_context(|| format!("number out of range at byte {}", self.pos))?;
+                self.pos += len;
+                Ok(Value::Number(n))
+            },
+            Some('-') => bail!("negative numbers are not supported, at byte {}", self.pos),
+            _ => bail!("unexpected input at byte {}", self.pos),
+        }
+    }
+}
+
+/// Parses a JSON document. Numbers must be non-negative integers, which
+/// is all this tool writes.
+pub fn parse(s: &str) -> Result<Value> {
+    let mut parser = Parser { s, pos: 0 };
+    let value = parser.value()?;
+    if parser.peek().is_some() {
+        bail!("trailing input at byte {}", parser.pos);
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn written_documents_parse_back() {
+        let doc = versioned(object([
+            ("path", "/a \"b\"\\\n\t\u{1}é".into()),
+            ("size", u64::MAX.into()),
+            ("dir", false.into()),
+            ("label", Value::Null),
+            ("items", [1u64, 2].into_iter().collect()),
+            ("empty", object([])),
+            ("none", Vec::<Value>::new().into_iter().collect()),
+        ]));
+        let text = doc.to_string();
+        assert!(
+            text.starts_with("{\"schema_version\":1,\"path\":\"/a \\\"b\\\"\\\\\\n\\t\\u0001é\"")
+        );
+        assert_eq!(parse(&text).unwrap(), doc);
+    }
+
+    #[test]
+    fn foreign_documents() {
+        assert_eq!(
+            parse(" { \"a\" : [ true , null ] , \"b\":\"\\u00e9\\/\" } ").unwrap(),
+            object([
+                ("a", Value::Array(vec![true.into(), Value::Null])),
+                ("b", "é/".into()),
+            ])
+        );
+        for (input, error) in [
+            ("-1", "negative numbers"),
+            ("1.5", "whole numbers"),
+            ("1e3", "whole numbers"),
+            ("18446744073709551616", "out of range"),
+            ("[1,]", "unexpected input"),
+            ("{\"a\":1", "expected '}'"),
+            ("\"abc", "unterminated string"),
+            ("\"\\x\"", "invalid escape"),
+            ("\"\\ud800\"", "invalid \\u escape"),
+            ("nul", "unexpected input"),
+            ("{} {}", "trailing input"),
+        ] {
+            let err = parse(input).unwrap_err().to_string();
+            assert!(err.contains(error), "{}: {}", input, err);
+        }
+    }
+
+    #[test]
+    fn members_and_numbers() {
+        let doc = parse("{\"n\":7,\"a\":[3]}").unwrap();
+        assert_eq!(doc.get("n").and_then(Value::as_u64), Some(7));
+        assert_eq!(
+            doc.get("a").and_then(Value::as_array),
+            Some(&[Value::Number(3)][..])
+        );
+        assert_eq!(doc.get("missing"), None);
+        assert_eq!(Value::Number(1).get("n"), None);
+    }
+}
@@ -0,0 +1,41 @@
+//! Path length and depth limits of target systems
+
+/// Windows and many firmware file APIs can't open longer paths
+pub const PORTABLE_MAX_PATH: usize = 255;
+
+/// Limits for full inner paths, e.g. `/EFI/BOOT/BOOTX64.EFI`
+#[derive(clap::Args, Debug, Clone, Copy)]
+pub struct PathLimits {
+    /// Longest allowed full inner path, in UTF-16 units like on Windows
+    #[clap(long)]
+    pub max_path: Option<usize>,
+
+    /// Deepest allowed nesting, counting the file itself
+    #[clap(long)]
+    pub max_depth: Option<usize>,
+}
+
+impl PathLimits {
+    /// Describes how `inner_path` exceeds the limits, if it does
+    pub fn violation(&self, inner_path: &str) -> Option<String> {
+        let len = inner_path.encode_utf16().count();
+        if let Some(max) = self.max_path {
+            if len > max {
+                return Some(format!(
+                    "{}: path is {} UTF-16 units long, limit is {}",
+                    inner_path, len, max
+                ));
+            }
+        }
+        let depth = inner_path.split('/').filter(|c| !c.is_empty()).count();
+        if let Some(max) = self.max_depth {
+            if depth > max {
+                return Some(format!(
+                    "{}: path is {} levels deep, limit is {}",
+                    inner_path, depth, max
+                ));
+            }
+        }
+        None
+    }
+}
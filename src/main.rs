@@ -1,44 +1,432 @@
 #![deny(unused_must_use)]
 
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, Read};
-use std::path::PathBuf;
+use std::io::{self, BufRead, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{IntoApp, Parser};
 
 use fatfs::{format_volume, Dir, FileSystem, FormatVolumeOptions, FsOptions};
 use fatfs::{StdIoWrapper, Write};
+use flate2::read::MultiGzDecoder;
 use fscommon::BufStream;
+use unicode_normalization::UnicodeNormalization;
+
+mod artifacts;
+mod attrs;
+mod backup;
+mod bylabel;
+mod check;
+mod clean;
+mod clock;
+mod clone;
+mod cluster;
+mod collisions;
+mod cp;
+mod cpio;
+mod delta;
+mod dirtimes;
+mod du;
+mod estimate;
+mod exit;
+mod extents;
+mod fanout;
+#[cfg(test)]
+mod fault;
+mod find;
+mod fit;
+mod geometry;
+mod glob;
+mod gzip;
+mod health;
+mod hostpath;
+mod info;
+mod json;
+mod limits;
+mod mv;
+mod namemap;
+mod ntcase;
+mod ondisk;
+mod output;
+mod overwrite;
+mod paths;
+mod profile;
+mod protect;
+mod prune;
+mod region;
+mod rename;
+mod report;
+mod rm;
+mod schema;
+mod serve;
+mod sidecars;
+mod size;
+mod snapshot;
+mod subtree;
+mod surrogates;
+mod text;
+mod timeout;
+mod touch;
+mod verify;
+mod walk;
+mod warnings;
+mod watch;
+mod when;
+
+use collisions::{OnCollision, Resolution};
+use fanout::{FanOut, WriteSource};
+use limits::PathLimits;
+use output::Output;
+use overwrite::OverwritePolicy;
+use region::ImgSlice;
+use size::SizeFormat;
+use text::{NewlineReader, TextMode};
+use warnings::Warnings;
 
 /// FAT filesystem image manipulation tool
 #[derive(Parser, Debug)]
 #[clap(about, version, author)]
+#[clap(after_help = "Use --version-json for version and capabilities in JSON.
+
+Arguments after `--` are never taken as flags, for host paths and patterns \
+beginning with a dash: `fatimg disk.img read-tree -- -out` or \
+`fatimg disk.img rename / -- '-*' '{1}'`. Option values beginning with a dash \
+go after `=`, like `--input=-x.txt`. Image paths begin with `/`, so \
+`/-foo.txt` needs neither. A host file called `-` has to be given as `./-`, \
+`-` alone is stdin or stdout.
+
+The image is not locked. Commands that only read it (info, ls, du, read, \
+read-tree, cluster-read, extents, verify-boot, health, export-cpio, serve, \
+and check and attrib when not changing anything) open it read-only and can \
+run side by side. The others write in place and must not run alongside any \
+other command on the same image.")]
 struct Args {
     /// Operation
     #[clap(subcommand)]
     cmd: Command,
-    /// File to operate on
+    /// File to operate on. Required by all commands but `estimate`.
     #[clap(parse(from_os_str))]
-    img_file: PathBuf,
+    img_file: Option<PathBuf>,
+    /// Operate on the device of the FAT volume with this label instead,
+    /// found through /dev/disk/by-label or by probing block devices (Linux)
+    #[clap(long, conflicts_with = "img-file")]
+    image_by_label: Option<String>,
+    /// Print every warning instead of summarizing repeated ones, and log
+    /// what is being done, like files written and directories created,
+    /// to stderr. Give twice or more for more detail.
+    #[clap(short, long, global = true, parse(from_occurrences))]
+    verbose: u8,
+    /// Log nothing but errors, not even warnings of the FAT library, and
+    /// leave out the summary of what changed in the image. `RUST_LOG`
+    /// still wins over this and `-v`.
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Exit with an error if any warning was given, or one of these codes
+    /// like `--fail-on-warning=W001,W003`. The command runs to the end
+    /// first. `--warnings-as-errors` is the bare form under another name.
+    #[clap(
+        long,
+        alias = "warnings-as-errors",
+        global = true,
+        min_values = 0,
+        require_equals = true,
+        use_delimiter = true
+    )]
+    #[clap(parse(try_from_str = warnings::parse_code))]
+    fail_on_warning: Option<Vec<warnings::Category>>,
+    /// Report sizes in units of this many bytes, e.g. `512`, `1K` or `1MB`
+    #[clap(long, global = true, default_value = "1")]
+    #[clap(parse(try_from_str = size::parse_block_size))]
+    block_size: u64,
+    /// Report sizes like `1.5K` and `12M`. Overrides `--block-size`.
+    #[clap(short = 'H', long, global = true)]
+    human_readable: bool,
+    /// Use this time, e.g. `2024-05-01T12:00:00Z` or seconds since the
+    /// Unix epoch, as the current time in timestamps of created and
+    /// changed entries. Defaults to `SOURCE_DATE_EPOCH` if set. Together
+    /// with `create --volume-id` the same inputs make the same image.
+    #[clap(long, alias = "timestamp", global = true, parse(try_from_str = clock::parse_now))]
+    now: Option<clock::Timestamp>,
+    /// UTC offset timestamps are stored in, e.g. `+02:00`. Defaults to the
+    /// host time zone, or to the offset `--now` is given in.
+    #[clap(long, global = true, parse(try_from_str = clock::parse_tz))]
+    tz: Option<i32>,
+    /// Never replace existing files or image entries, not even ones a
+    /// command replaces by default. Wins over `--force`.
+    #[clap(long, global = true)]
+    no_clobber: bool,
+    /// Write a JSON report of what the command did to this host file,
+    /// even if it fails
+    #[clap(long, global = true, parse(from_os_str))]
+    report: Option<PathBuf>,
+    /// Give up on image reads and writes that take longer than this many
+    /// seconds, like on a dying card. The image is reopened for the next
+    /// operation. Applies to every command that opens the image.
+    #[clap(long, global = true, parse(try_from_str = timeout::parse_timeout))]
+    io_timeout: Option<Duration>,
+    /// Operate on the FAT volume starting this many bytes into the image
+    /// file, like `1M` for a disk image partitioned the usual way
+    #[clap(long, global = true, parse(try_from_str = size::parse_size))]
+    offset: Option<u64>,
+    /// Operate on the FAT volume in this partition (1 to 4) of the MBR
+    /// partition table of the image. Writes past its end are refused.
+    #[clap(long, global = true, conflicts_with = "offset")]
+    partition: Option<u8>,
+    /// Output format of `info`, `ls -l`, `estimate` and `verify-boot`:
+    /// `classic` as in earlier releases, the easier to read `v2`, or
+    /// `json`, which also stands in for `--json` of other commands.
+    /// Defaults to `classic` for now, with a note on stderr.
+    #[clap(long, global = true, arg_enum)]
+    output: Option<Output>,
+    /// Read a temporary copy of the image, for looking into one another
+    /// command is writing. `metadata` copies the filesystem structures
+    /// first and the rest as it's read, `full` the whole image. Only for
+    /// commands that don't change the image.
+    #[clap(
+        long,
+        global = true,
+        arg_enum,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "metadata"
+    )]
+    snapshot: Option<snapshot::Mode>,
+}
+
+impl Command {
+    /// Whether the command may change the image
+    fn is_mutating(&self) -> bool {
+        match self {
+            Self::Check { fix, .. } => *fix,
+            Self::Attrib { raw, changes, .. } => raw.is_some() || !changes.is_empty(),
+            // Only the copy is written
+            Self::CloneImage { .. } => false,
+            Self::Info { .. }
+            | Self::Ls { .. }
+            | Self::Du { .. }
+            | Self::Find { .. }
+            | Self::Read { .. }
+            | Self::ReadTree { .. }
+            | Self::ClusterRead { .. }
+            | Self::Extents { .. }
+            | Self::VerifyBoot { .. }
+            | Self::Health { .. }
+            | Self::ExportCpio { .. }
+            | Self::Serve { .. }
+            | Self::Estimate { .. }
+            | Self::JsonSchema { .. } => false,
+            _ => true,
+        }
+    }
+
+    /// Whether the command operates on an image at all
+    fn needs_image(&self) -> bool {
+        !matches!(self, Self::Estimate { .. } | Self::JsonSchema { .. })
+    }
+}
+
+impl Args {
+    fn size_format(&self) -> SizeFormat {
+        SizeFormat {
+            block_size: self.block_size,
+            human: self.human_readable,
+        }
+    }
 }
 
 #[derive(Parser, Debug)]
 enum Command {
-    /// Create a new filesystem
+    /// Create a new filesystem. Replacing an existing image needs `--force`.
     Create {
-        /// Size of the created image, in bytes
-        #[clap(short, long)]
+        /// Size of the created image, in bytes or with a suffix like
+        /// `32M`, `1.5GiB` or `100MB`
+        #[clap(short, long, parse(try_from_str = parse_image_size))]
         size: u64,
         /// Overwrite existing output file
         #[clap(short, long)]
         force: bool,
+        /// Fail instead of warning when the FAT32 reserved area layout
+        /// is one some firmware rejects
+        #[clap(long)]
+        strict: bool,
+        /// Leave an existing image alone if it already is a filesystem of
+        /// the requested size, geometry and label. Otherwise `--force` is
+        /// needed as usual.
+        #[clap(long)]
+        if_needed: bool,
+        /// Volume label, at most 11 characters
+        #[clap(long, parse(try_from_str = ondisk::parse_volume_label))]
+        label: Option<[u8; 11]>,
+        /// Volume id in hex, like `1234abcd`, or `random`. fatfs picks a
+        /// fixed one by default.
+        #[clap(long)]
+        volume_id: Option<String>,
+        // Picked by fatfs from the size if not given
+        #[clap(flatten)]
+        geometry: geometry::Geometry,
+        /// Copy the jump instruction and boot code from the boot sector of
+        /// this image, keeping the BPB of the new one
+        #[clap(long, parse(from_os_str))]
+        boot_from: Option<PathBuf>,
+        /// With `--boot-from`, take the OEM name from the reference too
+        #[clap(long, requires = "boot-from")]
+        oem_from_reference: bool,
+        /// With `--boot-from`, allow boot code of a FAT12/16 volume on a
+        /// FAT32 one, or the other way around
+        #[clap(long, requires = "boot-from")]
+        allow_mixed_boot_code: bool,
+        /// Make a disk image: an MBR with one active partition holding the
+        /// volume, which later commands reach with `--partition 1`
+        #[clap(long)]
+        mbr: bool,
+        /// With `--mbr`, start the partition at this 512 byte sector. The
+        /// default 2048 aligns it to 1 MiB.
+        #[clap(long, requires = "mbr")]
+        partition_start: Option<u32>,
+    },
+    /// Copy the image, then customize the copy. Replacing an existing
+    /// target needs `--force`, files written into the copy are replaced.
+    #[clap(name = "clone")]
+    CloneImage {
+        /// New image file
+        #[clap(parse(from_os_str))]
+        target: PathBuf,
+        /// Overwrite existing output file
+        #[clap(short, long)]
+        force: bool,
+        /// New volume id in hex, or `random`
+        #[clap(long)]
+        set_volume_id: Option<String>,
+        /// New volume label
+        #[clap(long)]
+        label: Option<String>,
+        /// Write a host file into the copy, as `<inner path>=<host path>`.
+        /// A host path of `-` reads stdin. Can be repeated.
+        #[clap(long)]
+        write: Vec<String>,
+        /// Write a string into the copy, as `<inner path>=<text>`.
+        /// Can be repeated.
+        #[clap(long)]
+        data: Vec<String>,
+    },
+    /// Read filesystem info. With `--verbose`, also show raw BPB fields.
+    Info {
+        /// Print a JSON object instead, with sizes in bytes
+        #[clap(long)]
+        json: bool,
+    },
+    /// Change the volume label, both in the boot sector and in the root
+    /// directory
+    SetLabel {
+        /// New volume label, at most 11 characters
+        #[clap(parse(try_from_str = ondisk::parse_volume_label))]
+        label: [u8; 11],
+    },
+    /// Check the filesystem for problems
+    Check {
+        /// Repair the problems that can be repaired
+        #[clap(long)]
+        fix: bool,
+
+        /// How `--fix` repairs files with an invalid start cluster
+        #[clap(long, arg_enum, default_value = "truncate", requires = "fix")]
+        fix_policy: check::FixPolicy,
+
+        /// Also list paths too long or deep for the target systems. The
+        /// length limit is 255 unless `--max-path` is given.
+        #[clap(long)]
+        portability: bool,
+
+        /// Count `--portability` findings as problems instead of warnings
+        #[clap(long, requires = "portability")]
+        strict: bool,
+
+        #[clap(flatten)]
+        limits: PathLimits,
+
+        /// Also check the rules a target needs to boot the image.
+        /// Available: uefi-esp, bios.
+        #[clap(long, parse(try_from_str = profile::by_name))]
+        profile: Option<&'static profile::Profile>,
+
+        /// Report `--profile` failures as warnings, not problems
+        #[clap(long, requires = "profile")]
+        advisory: bool,
+
+        /// Where `--fix` saves the boot sectors, FATs and directories
+        /// before changing any of them, for `restore-metadata`. Defaults
+        /// to the image path with `.metadata`, and a number if taken.
+        #[clap(long, parse(from_os_str), requires = "fix")]
+        backup_metadata: Option<PathBuf>,
+
+        /// Don't save a metadata backup before `--fix`
+        #[clap(long, requires = "fix", conflicts_with = "backup-metadata")]
+        no_backup: bool,
+    },
+    /// Write back the metadata `check --fix` saved before changing it, to
+    /// the same volume
+    RestoreMetadata {
+        /// The backup file
+        #[clap(parse(from_os_str))]
+        backup_file: PathBuf,
+    },
+    /// Check what a BIOS needs to boot the volume: the boot sector
+    /// signature, its jump instruction and, on FAT32, the backup boot
+    /// sector
+    VerifyBoot {
+        /// Also require boot code other than the placeholder a formatter
+        /// leaves
+        #[clap(long)]
+        expect_bootcode: bool,
+
+        /// Print the results as a JSON object
+        #[clap(long)]
+        json: bool,
+    },
+    /// Print a JSON health summary for monitoring, failing if the image
+    /// isn't healthy. Quicker than `check`, which finds more.
+    Health {
+        /// Stop walking the directories after this many entries
+        #[clap(long, default_value = "100000")]
+        max_entries: usize,
+
+        #[clap(flatten)]
+        subtree: subtree::Subtree,
+    },
+    /// Show the attributes of an entry, or change them with `+h`, `-r`
+    /// and the like, or with `--raw`
+    Attrib {
+        /// Entry in the image, a file or a directory
+        inner_path: String,
+
+        /// Set (`+`) or clear (`-`) flags: `r` read-only, `h` hidden, `s`
+        /// system, `a` archive. Letters can be combined, like `+hs`. Give
+        /// `--` first if the first change begins with `-`.
+        #[clap(allow_hyphen_values = true, parse(try_from_str = attrs::parse_change))]
+        changes: Vec<attrs::Change>,
+
+        /// Replace the attribute byte: hex like `0x21`, flag letters like
+        /// `r--a--` or names like `read_only,archive`. The directory and
+        /// volume label bits can't be changed.
+        #[clap(long, parse(try_from_str = attrs::parse))]
+        raw: Option<u8>,
+
+        /// Show the attribute byte in hex
+        #[clap(long)]
+        numeric_attrs: bool,
+
+        /// Print the path, the attribute byte and the flag names as JSON
+        #[clap(long, conflicts_with = "numeric-attrs")]
+        json: bool,
     },
-    /// Read filesystem info
-    Info,
     /// List directory contents
     Ls {
-        /// Path in the image
+        /// Path in the image. With `*`, `?` or `**` wildcards, like
+        /// `'/boot/*.cfg'`, the matching entries are listed by path.
         #[clap(default_value = "/")]
         inner_path: String,
 
@@ -47,46 +435,543 @@ enum Command {
         #[clap(short, long, parse(from_occurrences), display_order = 1)]
         long: u8,
 
+        /// Show attributes as the raw byte in hex, like `0x21`, instead
+        /// of flag letters like `r--a--`
+        #[clap(long)]
+        numeric_attrs: bool,
+
         /// List subdirectory contents recursively, like `tree`
         #[clap(short, long)]
         recursive: bool,
+
+        /// Print one JSON object per entry and line instead. Entries are
+        /// printed as they are read, in directory order, so even huge
+        /// directories start listing right away.
+        #[clap(long)]
+        jsonl: bool,
+
+        /// Print a JSON array of the entries instead, with the contents of
+        /// directories as `children` with `-r`. Printed once the listing is
+        /// complete.
+        #[clap(long, conflicts_with_all = &["jsonl", "count"])]
+        json: bool,
+
+        /// Stop after listing this many entries
+        #[clap(long)]
+        limit: Option<usize>,
+
+        /// Only print the number of entries that would be listed, which
+        /// works for directories of any size
+        #[clap(long, conflicts_with = "jsonl")]
+        count: bool,
+
+        /// Only list entries modified after this: a duration before now
+        /// like `30d` or `1d12h`, an RFC 3339 time or `@<unix seconds>`.
+        /// Directories are still descended into with `-r`.
+        #[clap(long, parse(try_from_str = when::parse_time))]
+        newer_than: Option<when::TimeSpec>,
+
+        /// Only list entries modified before this, see `--newer-than`
+        #[clap(long, parse(try_from_str = when::parse_time))]
+        older_than: Option<when::TimeSpec>,
+
+        /// Show paths relative to the listed directory, like `grub.cfg`
+        /// for `ls -r --relative /EFI/ubuntu`, in place of the names and
+        /// the tree indent, and in the `path` of `--json` and `--jsonl`
+        #[clap(long)]
+        relative: bool,
+
+        #[clap(flatten)]
+        glob: glob::GlobArgs,
+    },
+    /// Serve the image tree read-only over HTTP, for browsing it without
+    /// mounting. Runs until interrupted.
+    Serve {
+        /// Address to listen on. Anyone who can connect can read the
+        /// whole tree.
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+
+        /// Directory in the image to serve as the root
+        #[clap(short = 's', long = "subtree", default_value = "/")]
+        inner_path: String,
+    },
+    /// Show space used by a file or directory tree, like `du`
+    Du {
+        /// Path in the image, `/` by default
+        inner_path: Option<String>,
+
+        #[clap(flatten)]
+        subtree: subtree::Subtree,
+
+        /// Sum file lengths instead of the clusters allocated to files and
+        /// directories
+        #[clap(long)]
+        apparent_size: bool,
+
+        /// Only show the total of the given path
+        #[clap(short, long)]
+        summarize: bool,
+
+        /// Leave out the trees Windows creates at the top of the volume,
+        /// like `System Volume Information` and `$RECYCLE.BIN`
+        #[clap(long)]
+        skip_windows_artifacts: bool,
+    },
+    /// Print the paths of the entries below a directory that match all of
+    /// the filters given, one per line
+    Find {
+        /// Directory in the image to search, `/` by default
+        inner_path: Option<String>,
+
+        #[clap(flatten)]
+        subtree: subtree::Subtree,
+
+        /// Only entries whose long or short name matches this glob,
+        /// ignoring case, like `'*.cfg'`
+        #[clap(long)]
+        name: Option<String>,
+
+        /// Only files or only directories
+        #[clap(long = "type", arg_enum)]
+        kind: Option<find::Kind>,
+
+        /// Only files larger than this, like `1M`
+        #[clap(long, parse(try_from_str = size::parse_size))]
+        larger: Option<u64>,
+
+        /// Only files smaller than this
+        #[clap(long, parse(try_from_str = size::parse_size))]
+        smaller: Option<u64>,
+
+        /// Only entries modified after this: a duration before now like
+        /// `30d`, an RFC 3339 time or `@<unix seconds>`
+        #[clap(long, parse(try_from_str = when::parse_time))]
+        newer: Option<when::TimeSpec>,
+
+        /// Only entries modified before this, see `--newer`
+        #[clap(long, parse(try_from_str = when::parse_time))]
+        older: Option<when::TimeSpec>,
+
+        /// Only entries modified at or after this, like `2024-03-01` for
+        /// its local midnight or any time `--newer` takes
+        #[clap(
+            long,
+            conflicts_with_all = &["newer", "older"],
+            parse(try_from_str = when::parse_time)
+        )]
+        since: Option<when::TimeSpec>,
+
+        /// Only entries modified before this, see `--since`. A date ends
+        /// the window at the start of that day.
+        #[clap(
+            long,
+            conflicts_with_all = &["newer", "older"],
+            parse(try_from_str = when::parse_time)
+        )]
+        until: Option<when::TimeSpec>,
+
+        /// Only empty files and directories with no entries besides `.`
+        /// and `..`
+        #[clap(long)]
+        empty: bool,
+
+        /// Print paths relative to the searched directory, like
+        /// `grub.cfg` for `find --relative /EFI/ubuntu`, also in the
+        /// `path` of `--json`
+        #[clap(long)]
+        relative: bool,
+
+        /// Leave out the trees Windows creates at the top of the volume,
+        /// like `System Volume Information` and `$RECYCLE.BIN`
+        #[clap(long)]
+        skip_windows_artifacts: bool,
+
+        /// Print a JSON array of the entries, with the members of
+        /// `ls --jsonl`, instead of their paths
+        #[clap(long)]
+        json: bool,
+    },
+    /// Estimate the image size a host tree needs, without an image. Counts
+    /// the clusters of files and directories, long names included, and
+    /// the FAT and reserved areas on top.
+    Estimate {
+        /// Host directory to estimate
+        #[clap(parse(from_os_str))]
+        host_path: PathBuf,
+
+        /// Bytes per cluster, a power of two from 512 to 65536
+        #[clap(long, default_value = "4096", parse(try_from_str = size::parse_size))]
+        cluster_size: u64,
+
+        /// FAT type, 12, 16 or 32. Defaults to the smallest one the tree
+        /// fits in.
+        #[clap(long, parse(try_from_str = estimate::parse_fat_type))]
+        fat_type: Option<u8>,
+
+        /// Room to leave for growth in the recommended size, in percent
+        #[clap(long, default_value = "10")]
+        margin: u64,
+    },
+    /// Print the JSON Schema of a JSON document the commands print. Each
+    /// document has the `schema_version` it follows as its first member.
+    JsonSchema {
+        /// The document, like `ls-jsonl` for each line of `ls --jsonl`.
+        /// All of them by name without one.
+        #[clap(arg_enum)]
+        document: Option<schema::Document>,
+    },
+    /// Remove zero-byte files, and directories that are or become empty
+    CleanEmpty {
+        /// Directory in the image, which itself is kept
+        #[clap(default_value = "/")]
+        inner_path: String,
+
+        /// Only list what would be removed
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Never remove paths matching this, e.g. `*.keep` or `logs`, or
+        /// anything in matching directories. Can be repeated.
+        #[clap(long)]
+        keep: Vec<String>,
+
+        #[clap(flatten)]
+        protect: protect::ProtectArgs,
+    },
+    /// Remove a file, or a directory with `-r`
+    Rm {
+        /// Entry in the image. With `*`, `?` or `**` wildcards, like
+        /// `'/EFI/**/*.bak'`, every matching entry is removed.
+        inner_path: String,
+
+        /// Remove a directory along with everything in it. Empty
+        /// directories can be removed without this.
+        #[clap(short, long)]
+        recursive: bool,
+
+        /// Succeed without doing anything if the entry doesn't exist.
+        /// With `-r`, allows emptying `/`.
+        #[clap(short, long, visible_alias = "missing-ok")]
+        force: bool,
+
+        /// Ask before removing each entry, on a terminal. Answer `a` to
+        /// remove the rest without asking, `q` to stop.
+        #[clap(short, long)]
+        interactive: bool,
+
+        /// Only list what would be removed
+        #[clap(long)]
+        dry_run: bool,
+
+        #[clap(flatten)]
+        protect: protect::ProtectArgs,
+
+        #[clap(flatten)]
+        glob: glob::GlobArgs,
+
+        #[clap(flatten)]
+        fan_out: FanOut,
+    },
+    /// Delete the oldest files of a directory, e.g. to rotate logs
+    Prune {
+        /// Directory in the image
+        inner_path: String,
+
+        /// Keep this many of the most recently modified files
+        #[clap(long)]
+        keep_newest: Option<usize>,
+
+        /// Delete the oldest files until the rest take up at most this,
+        /// e.g. `50M`
+        #[clap(long, parse(try_from_str = size::parse_size))]
+        max_total_size: Option<u64>,
+
+        /// Delete files modified before this: a duration before now like
+        /// `30d`, an RFC 3339 time or `@<unix seconds>`
+        #[clap(long, parse(try_from_str = when::parse_time))]
+        older_than: Option<when::TimeSpec>,
+
+        /// Also consider files in subdirectories, all as one set
+        #[clap(long)]
+        recursive: bool,
+
+        /// Only list what would be deleted
+        #[clap(long)]
+        dry_run: bool,
+
+        #[clap(flatten)]
+        protect: protect::ProtectArgs,
+    },
+    /// Rename the entries of a directory whose names match a pattern.
+    /// Other entries are never replaced.
+    Rename {
+        /// Directory in the image
+        inner_path: String,
+
+        /// Glob like `*.BIN` matched against whole names. `*` and `?`
+        /// are captured.
+        pattern: String,
+
+        /// New name, where `{1}`, `{2}`, ... are the captures and `{0}`
+        /// is the old name, e.g. `{1}.bin`
+        replacement: String,
+
+        /// The pattern is a regular expression, captures are its groups
+        #[clap(long)]
+        regex: bool,
+
+        /// Also rename entries in subdirectories
+        #[clap(short, long)]
+        recursive: bool,
+
+        /// Only print what would be renamed
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Move or rename one entry, between directories too. Directories
+    /// keep their contents.
+    Mv {
+        /// Entry to move
+        src: String,
+
+        /// New path of the entry, including its name
+        dst: String,
+
+        /// Replace an existing file or empty directory at the destination
+        #[clap(short, long)]
+        force: bool,
+    },
+    /// Copy a file, or with `-r` a directory tree, inside the image.
+    /// Directories are merged into existing ones and files in them are
+    /// replaced.
+    Cp {
+        /// Entry to copy
+        src: String,
+
+        /// Path of the copy, including its name
+        dst: String,
+
+        /// Copy directories with everything in them
+        #[clap(short, long)]
+        recursive: bool,
+
+        /// Keep attributes and timestamps instead of stamping the copies
+        /// with the current time
+        #[clap(short, long)]
+        preserve: bool,
+
+        /// Don't replace existing files, naming the first one before
+        /// anything is copied. Same as `--no-clobber`.
+        #[clap(short, long)]
+        no_overwrite: bool,
+    },
+    /// Create an empty file if it doesn't exist, and set its timestamps.
+    /// Without time flags the modification time is set to now.
+    Touch {
+        /// File in the image
+        inner_path: String,
+
+        /// Modification time, RFC 3339 like `2024-05-01T12:00:00Z` or a
+        /// local time as stored like `2024-05-01T12:00:00`. Times FAT
+        /// can't store are clamped to 1980 or 2107 with a warning.
+        #[clap(long, parse(try_from_str = clock::parse_given_time))]
+        mtime: Option<clock::GivenTime>,
+
+        /// Creation time, like `--mtime`
+        #[clap(long, parse(try_from_str = clock::parse_given_time))]
+        ctime: Option<clock::GivenTime>,
+
+        /// Access date, like `--mtime`. Only the date is stored.
+        #[clap(long, parse(try_from_str = clock::parse_given_time))]
+        atime: Option<clock::GivenTime>,
     },
     /// Create a directory
     Mkdir {
         /// Path in the image
         inner_path: String,
+
+        /// Attributes of the directory, like `hidden,system`, `-hs---` or
+        /// `0x06`
+        #[clap(long, parse(try_from_str = attrs::parse_creation))]
+        attributes: Option<u8>,
+
+        #[clap(flatten)]
+        fan_out: FanOut,
     },
     /// Read a file
     Read {
-        /// Path in the image
+        /// Path in the image. With `*`, `?` or `**` wildcards it can match
+        /// more than one file if `--output` is a directory to put them in.
         inner_path: String,
+
+        /// Convert newlines while reading
+        #[clap(long, arg_enum, default_value = "none")]
+        text_mode: TextMode,
+
+        /// Write to this file instead of stdout, `-` is stdout
+        #[clap(short, long = "output-file", parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        /// Overwrite an existing `--output` file
+        #[clap(short, long)]
+        force: bool,
+
+        /// Decompress the gzip compressed file while reading
+        #[clap(long)]
+        gunzip: bool,
+
+        #[clap(flatten)]
+        glob: glob::GlobArgs,
     },
-    /// Read a file
+    /// Write a file, replacing it if it exists
     Write {
         /// Path in the image
         inner_path: String,
 
-        /// Write contents of this file. Stdin is used if not specified.
+        /// Write contents of this file. Stdin is used if not specified or `-`.
         /// The file is overwritten is it exists.
         #[clap(short = 'i', long = "--input", parse(from_os_str))]
         host_path: Option<PathBuf>,
+
+        /// Convert newlines while writing
+        #[clap(long, arg_enum, default_value = "none")]
+        text_mode: TextMode,
+
+        /// Fill the unused tail of the file's last cluster with this byte
+        /// (default 0x00), without changing the file size. Give the value
+        /// as `--pad-to-cluster=0xff`.
+        #[clap(long, parse(try_from_str = parse_byte))]
+        pad_to_cluster: Option<Option<u8>>,
+
+        /// Read the cluster padding back after writing it
+        #[clap(long)]
+        verify: bool,
+
+        /// Allow empty input. Without this an empty input is an error and
+        /// the existing file is left untouched.
+        #[clap(long)]
+        allow_empty: bool,
+
+        /// Compress the contents with gzip, storing them as `<path>.gz`
+        #[clap(long)]
+        gzip: bool,
+
+        /// With `--gzip`, store the file under the given path as is
+        #[clap(long, requires = "gzip")]
+        keep_name: bool,
+
+        /// Attributes of the file instead of archive, like
+        /// `hidden,system`, `-hs---` or `0x06`
+        #[clap(long, parse(try_from_str = attrs::parse_creation))]
+        attributes: Option<u8>,
+
+        /// Store the modification and creation times of the input file
+        /// instead of the current time, like `write-tree --preserve-times`
+        #[clap(long, requires = "host-path")]
+        preserve_times: bool,
+
+        #[clap(flatten)]
+        fan_out: FanOut,
     },
-    /// Read filesystem tree into host fs
+    /// Read filesystem tree into host fs. A non-empty host directory
+    /// needs `--force`. If the subtree is a file, just that file is read.
     ReadTree {
         /// Path in the image
         #[clap(short = 's', long = "--subtree", default_value = "/")]
         inner_path: String,
 
-        /// Path in the image
+        /// Path on the host
         #[clap(parse(from_os_str))]
         host_path: PathBuf,
 
-        /// Overwrite existing output
+        /// Write into a non-empty host directory, overwriting files
+        #[clap(short, long)]
+        force: bool,
+
+        /// What to do with image names that only differ by case, which
+        /// would end up as a single file on the host. Checked on Windows
+        /// and macOS, where host names are assumed case-insensitive.
+        #[clap(long, arg_enum, default_value = "error")]
+        on_collision: OnCollision,
+
+        /// Don't check for names differing only by case, the host
+        /// directory tells them apart
+        #[clap(long)]
+        assume_case_sensitive: bool,
+
+        /// Extract entries under other names, given by this file of
+        /// `/DIR/NAME~1.TXT=Long name.txt` lines. A `[/DIR]` line makes
+        /// the names after it relative to that directory.
+        #[clap(long, parse(from_os_str))]
+        name_map: Option<PathBuf>,
+
+        /// Set the modification times of extracted files and directories
+        /// to the ones in the image, which are to 2 seconds. They are read
+        /// in the `--tz` offset or the host time zone, unless `--utc` is
+        /// given.
+        #[clap(long)]
+        preserve_times: bool,
+
+        /// With `--preserve-times`, read the image times as UTC, like
+        /// some cameras and embedded systems store them
+        #[clap(long, requires = "preserve-times")]
+        utc: bool,
+
+        /// Make files with the read-only attribute read-only on the host
+        #[clap(long)]
+        preserve_attrs: bool,
+
+        /// Give extracted files this octal permission mode, like `0644`,
+        /// instead of the default one. Wins over `--preserve-attrs`. Only
+        /// on Unix hosts, elsewhere it's ignored with a warning.
+        #[clap(long, parse(try_from_str = hostpath::parse_mode))]
+        file_mode: Option<u32>,
+
+        /// Give extracted directories this octal permission mode, like
+        /// `0755`. Modes are set once everything is extracted, so ones
+        /// like `0555` that don't allow writing work too. The host
+        /// directory itself is left alone.
+        #[clap(long, parse(try_from_str = hostpath::parse_mode))]
+        dir_mode: Option<u32>,
+
+        /// How to name files whose long names have unpaired UTF-16
+        /// surrogates, which FAT allows but host names can't hold:
+        /// `escape` writes them like `\u{dc80}`, `replace` as U+FFFD like
+        /// earlier releases, which can name two files the same, and
+        /// `error` fails
+        #[clap(long, arg_enum, default_value = "escape")]
+        surrogate_policy: surrogates::Policy,
+    },
+    /// Write the image tree as a newc cpio archive, like initramfs
+    /// loaders read. Names are relative to `--subtree`.
+    ExportCpio {
+        /// Path in the image
+        #[clap(short = 's', long = "--subtree", default_value = "/")]
+        inner_path: String,
+
+        /// Write to this file instead of stdout, `-` is stdout
+        #[clap(short, long = "output-file", parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        /// Overwrite an existing `--output` file
         #[clap(short, long)]
         force: bool,
+
+        /// Leave out entries matching this glob, relative to the subtree,
+        /// along with their contents. Can be repeated.
+        #[clap(long)]
+        exclude: Vec<String>,
+
+        /// Leave out the trees Windows creates at the top of the volume,
+        /// like `System Volume Information` and `$RECYCLE.BIN`
+        #[clap(long)]
+        skip_windows_artifacts: bool,
     },
     /// Write filesystem tree from host fs.
-    /// The tree is overwritten is it exists.
+    /// The tree is merged into the image directory: files of the same
+    /// name are replaced, directories written into and other entries
+    /// kept.
     WriteTree {
         /// Path in the image
         #[clap(short = 's', long = "--subtree", default_value = "/")]
@@ -95,19 +980,289 @@ enum Command {
         /// Path in the image
         #[clap(parse(from_os_str))]
         host_path: PathBuf,
+
+        /// Convert newlines of files matching `--text-glob`
+        #[clap(long, arg_enum, default_value = "none")]
+        text_mode: TextMode,
+
+        /// Files to apply `--text-mode` to, e.g. `*.cfg`. Can be repeated.
+        #[clap(long)]
+        text_glob: Vec<String>,
+
+        /// Files to compress with gzip, e.g. `*.mo`, stored as `<name>.gz`.
+        /// Can be repeated.
+        #[clap(long)]
+        gzip_glob: Vec<String>,
+
+        /// Store files matching `--gzip-glob` under their own names
+        #[clap(long)]
+        keep_name: bool,
+
+        /// What to do with host names that only differ by case, which
+        /// would end up as a single entry in the image
+        #[clap(long, arg_enum, default_value = "error")]
+        on_collision: OnCollision,
+
+        // Entries exceeding these are refused before being created
+        #[clap(flatten)]
+        limits: PathLimits,
+
+        /// Store the modification times of host files and directories
+        /// instead of the current time, and the creation times of files
+        /// where the host has them. Modification times FAT can't store are
+        /// clamped to 1980 or 2107 with a warning.
+        #[clap(long)]
+        preserve_times: bool,
+
+        /// Fail on host times FAT can't store instead of clamping them
+        #[clap(long, requires = "preserve-times")]
+        strict_times: bool,
+
+        /// Put back the timestamps of the target directory and the ones
+        /// above it afterwards, so that only the written tree changes
+        #[clap(long)]
+        stable_dir_times: bool,
+
+        /// Skip directories on other host filesystems than `host_path`,
+        /// like mount points, with a warning. On Windows all reparse
+        /// points are skipped.
+        #[clap(short = 'x', long)]
+        one_file_system: bool,
+
+        /// Skip host names that aren't UTF-8 with a warning, and exit with
+        /// 4 afterwards. Without this they are stored as Latin-1 where the
+        /// host allows it, and fail otherwise.
+        #[clap(long)]
+        skip_invalid_names: bool,
+
+        /// Leave out host entries matching this glob, relative to
+        /// `host_path`, like `*.o`, `**/.git/**` or `target/`. A trailing
+        /// `/` only matches directories, which are not descended into.
+        /// Can be repeated.
+        #[clap(long)]
+        exclude: Vec<String>,
+
+        /// Write entries matching this glob even if `--exclude` matches
+        /// them. Entries inside excluded directories stay left out. Can be
+        /// repeated.
+        #[clap(long, requires = "exclude")]
+        include: Vec<String>,
+
+        /// Copy metadata files other tools leave behind, like
+        /// `.rsync-meta` and `.nfs*`, which are skipped with a warning by
+        /// default
+        #[clap(long)]
+        include_sidecars: bool,
+
+        /// Store host names in Unicode normalization form C, like Windows
+        /// and most Linux tools write them, instead of as they are. macOS
+        /// keeps names decomposed, which other systems show differently
+        /// and can't always open by the name typed.
+        #[clap(long)]
+        nfc_names: bool,
+
+        /// Remove everything in the image directory first, except
+        /// `--protect`ed entries. This happens before the free space
+        /// check, so a tree that doesn't fit leaves the directory empty.
+        #[clap(long)]
+        clean: bool,
+
+        /// Afterwards remove image entries below the directory that have
+        /// no host entry, like `rsync --delete`. Names match regardless
+        /// of case, like FAT compares them. Entries `--exclude` matches
+        /// and `--protect`ed ones are kept.
+        #[clap(long, conflicts_with = "clean")]
+        delete: bool,
+
+        /// Only print what would be written and removed, leaving the
+        /// image as it is
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Fail before writing anything if a file already exists in the
+        /// image, instead of replacing it. `--no-clobber` implies this.
+        #[clap(long)]
+        no_overwrite: bool,
+
+        #[clap(flatten)]
+        protect: protect::ProtectArgs,
+
+        /// What to do if the tree doesn't fit: `fail` writes nothing,
+        /// `largest-first` imports the largest files that fit and
+        /// `priority-list=<file>` imports files in the order of the globs
+        /// listed in the file, one per line, until one doesn't fit.
+        /// A partial import still exits with an error.
+        #[clap(long, parse(try_from_str = fit::parse_fit), default_value = "fail")]
+        fit: fit::Fit,
+
+        #[clap(flatten)]
+        fan_out: FanOut,
+    },
+    /// Read raw contents of data clusters
+    ClusterRead {
+        /// First cluster, numbered from 2
+        cluster: u32,
+
+        /// Number of clusters to read
+        #[clap(long, default_value = "1")]
+        count: u32,
+
+        /// Write to this file instead of stdout, `-` is stdout
+        #[clap(short, long = "output-file", parse(from_os_str))]
+        output: Option<PathBuf>,
+
+        /// Overwrite an existing `--output` file
+        #[clap(short, long)]
+        force: bool,
+    },
+    /// Print the image byte ranges of the clusters of a file, as
+    /// `first..end -> offset..offset+length`
+    Extents {
+        /// Entry in the image
+        inner_path: String,
+
+        /// Print a `sh` script of `dd` commands rebuilding the file from
+        /// the raw image, given the output file as its argument
+        #[clap(long, conflicts_with = "json")]
+        dd_script: bool,
+
+        /// Print the extents as JSON, as `import-extents` reads them
+        #[clap(long)]
+        json: bool,
+    },
+    /// Create a file from image byte ranges saved with `extents --json`,
+    /// e.g. when its directory entry is gone. Replaces an existing file.
+    ImportExtents {
+        /// JSON extent list
+        #[clap(parse(from_os_str))]
+        host_path: PathBuf,
+
+        /// Path of the new file in the image
+        inner_path: String,
+    },
+    /// Overwrite raw contents of data clusters. The last cluster is zero
+    /// padded. Clusters in use are refused unless `--allow-allocated`.
+    ClusterWrite {
+        /// First cluster, numbered from 2
+        cluster: u32,
+
+        /// Write contents of this file. Stdin is used if not specified or `-`.
+        #[clap(short = 'i', long = "--input", parse(from_os_str))]
+        host_path: Option<PathBuf>,
+
+        /// Also overwrite clusters that are in use by files. Has no effect
+        /// with `--no-clobber`.
+        #[clap(long)]
+        allow_allocated: bool,
+    },
+    /// Copy a host directory into the image, then keep applying changes
+    /// to it until stopped. Image files in the way are replaced.
+    Watch {
+        /// Path in the image
+        #[clap(short = 's', long = "--subtree", default_value = "/")]
+        inner_path: String,
+
+        /// Directory on the host
+        #[clap(parse(from_os_str))]
+        host_path: PathBuf,
+
+        /// How often to scan for changes, in milliseconds
+        #[clap(long, default_value = "500")]
+        interval: u64,
+
+        /// Remove entries from the image when they are removed on the host
+        #[clap(long)]
+        delete: bool,
     },
 }
 
-fn normalize_inner_path(p: String) -> String {
-    let p = p.strip_prefix("/").expect("Absolute path required");
+/// Optional capabilities compiled into this build
+const FEATURES: &[&str] = &["gzip", "http"];
 
-    let mut result = Vec::new();
-    for s in p.split("/") {
-        if !s.is_empty() {
-            result.push(s);
-        }
+/// Changes to the behavior of existing flags, for scripts that relied on it
+const CHANGES: &[&str] = &[
+    "read -o and cluster-read -o refuse to replace an existing file unless --force is given",
+    "--no-clobber refuses replacing existing files and image entries, and wins over --force",
+    "write-tree checks free space first and writes nothing if the tree doesn't fit",
+    "rm removes empty directories without -r",
+    "ls -l shows attributes as rhsadv flag letters instead of fatfs debug output",
+    "ls --jsonl attributes include directory and volume_id, next to attribute_byte",
+    "commands no longer print their parsed arguments before the output",
+    "create --if-needed prints whether it reformats on stderr instead of stdout",
+    "rm, mv, cp, touch, clean-empty, prune and protect status lines go to stderr instead of stdout",
+    "-v can be given more than once, and conflicts with the new -q",
+    "warnings are printed with their code, like Warning [W001]: ...",
+    "--report warnings are objects with code and message instead of strings",
+    "usage errors exit with 1 instead of 2, paths missing from the image with 2 and I/O errors \
+     with 3",
+    "write-tree stores host names that aren't UTF-8 as Latin-1 instead of failing",
+    "write-tree skips .rsync-meta and .nfs* sidecar files unless --include-sidecars is given",
+    "write-tree writes the entries of each directory in name order instead of host order",
+    "write-tree merges into non-empty image directories, replacing files of the same name",
+    "commands that change the image end with a free space and entry count line on stderr, -q \
+     hides it",
+    "ls and read-tree show unpaired surrogates in long names like \\u{dc80} instead of U+FFFD",
+    "ls, read and rm expand * ? and ** in image paths, ignoring case",
+    "check --fix saves a metadata backup next to the image first, --no-backup skips it",
+    "every JSON document starts with schema_version; ls --json, find --json and serve ?json print \
+     an object with an entries array instead of a bare array",
+];
+
+/// Lets scripts detect what the installed version supports
+fn print_version_json() {
+    println!("{}", version_json());
+}
+
+fn version_json() -> json::Value {
+    let app = Args::into_app();
+    let subcommands = app
+        .get_subcommands()
+        .map(|c| c.get_name())
+        .filter(|&name| name != "help");
+    let info = json::object([
+        ("version", env!("CARGO_PKG_VERSION").into()),
+        ("commit", option_env!("FATIMG_GIT_COMMIT").into()),
+        ("features", FEATURES.iter().copied().collect()),
+        ("changes", CHANGES.iter().copied().collect()),
+        ("subcommands", subcommands.collect()),
+    ]);
+    json::versioned(info)
+}
+
+/// Smallest image `create` makes, below which formatting fails
+const MIN_IMAGE_SIZE: u64 = 64 * 1024;
+
+/// `create --size`, a size of at least `MIN_IMAGE_SIZE`
+fn parse_image_size(s: &str) -> Result<u64, String> {
+    let size = size::parse_size(s)?;
+    if size < MIN_IMAGE_SIZE {
+        return Err(format!(
+            "Image size {} is too small, at least {} bytes (64K) are needed",
+            size, MIN_IMAGE_SIZE
+        ));
+    }
+    Ok(size)
+}
+
+fn parse_byte(s: &str) -> Result<u8, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("Invalid byte value {:?}", s))
+}
+
+/// `-` as a host path stands for stdin or stdout, same as leaving it out
+fn dash_as_stdio(p: Option<PathBuf>) -> Option<PathBuf> {
+    p.filter(|p| p != Path::new("-"))
+}
+
+/// Creates the host output file `path`, which is never replaced by default
+fn create_output(path: &Path, overwrite: OverwritePolicy) -> Result<File> {
+    if path.exists() {
+        overwrite.check(false, path.display())?;
     }
-    result.join("/")
+    File::create(path).with_context(|| format!("failed creating {}", path.display()))
 }
 
 fn print_date(date: fatfs::Date) {
@@ -127,257 +1282,2644 @@ fn print_datetime(dt: fatfs::DateTime) {
     print_time(dt.time);
 }
 
-fn print_ls<'a, IO: fatfs::ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter>(
-    cursor: Dir<'a, IO, TP, OCC>, long: u8, recursive: bool, indent: usize,
-) -> Result<()> {
-    let indent_str = "  ".repeat(indent);
-    for entry in cursor.iter() {
-        let entry = entry.expect("Dir entry");
-        let name = entry.file_name();
+fn format_date(date: fatfs::Date) -> String {
+    format!("{:04}-{:02}-{:02}", date.year, date.month, date.day)
+}
 
-        if name == "." || name == ".." {
-            continue;
-        }
+/// Local time without a zone, like FAT stores it
+fn format_datetime(dt: fatfs::DateTime) -> String {
+    let t = dt.time;
+    format!(
+        "{}T{:02}:{:02}:{:02}.{:03}",
+        format_date(dt.date),
+        t.hour,
+        t.min,
+        t.sec,
+        t.millis
+    )
+}
 
-        print!("{}", indent_str);
+/// One `ls --jsonl` line, `name` as `surrogates::display_name` gives it
+fn ls_json<IO: fatfs::ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter>(
+    entry: &fatfs::DirEntry<IO, TP, OCC>, path: String, name: String,
+) -> json::Value {
+    let [attribute_byte, attributes] = attrs::json_members(entry.attributes().bits());
+    json::object([
+        ("path", path.into()),
+        ("name", name.into()),
+        ("type", if entry.is_dir() { "dir" } else { "file" }.into()),
+        ("size", entry.is_file().then(|| entry.len()).into()),
+        attribute_byte,
+        attributes,
+        ("created", format_datetime(entry.created()).into()),
+        ("modified", format_datetime(entry.modified()).into()),
+        ("accessed", format_date(entry.accessed()).into()),
+    ])
+}
 
-        if long >= 3 {
-            print!("created ");
+/// One `ls --json` entry, without the `children` of directories
+fn ls_json_tree<IO: fatfs::ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter>(
+    entry: &fatfs::DirEntry<IO, TP, OCC>, path: String, name: String,
+) -> json::Value {
+    let [attribute_byte, attributes] = attrs::json_members(entry.attributes().bits());
+    json::object([
+        ("name", name.into()),
+        ("path", path.into()),
+        ("is_dir", entry.is_dir().into()),
+        ("size", entry.is_file().then(|| entry.len()).into()),
+        attribute_byte,
+        attributes,
+        ("created", clock::format_rfc3339(&entry.created()).into()),
+        ("modified", clock::format_rfc3339(&entry.modified()).into()),
+        // Access times are dates only, an RFC 3339 full-date
+        ("accessed", format_date(entry.accessed()).into()),
+    ])
+}
+
+/// An `ls --json` directory whose entries are still being listed
+struct JsonDir {
+    /// `None` for the directory `ls` was given
+    entry: Option<json::Value>,
+    /// Whether the directory itself passed the time filter. Others are
+    /// only kept for entries below them that did.
+    matched: bool,
+    children: Vec<json::Value>,
+}
+
+/// Adds the innermost directory of `tree` to the children of its parent
+fn close_json_dir(tree: &mut Vec<JsonDir>) {
+    let dir = tree.pop().expect("the listed directory is never closed");
+    let keep = dir.matched || !dir.children.is_empty();
+    if let (Some(json::Value::Object(mut members)), true) = (dir.entry, keep) {
+        members.push(("children".to_owned(), json::Value::Array(dir.children)));
+        if let Some(parent) = tree.last_mut() {
+            parent.children.push(json::Value::Object(members));
+        }
+    }
+}
+
+/// Options of the `ls` command
+struct LsOptions {
+    long: u8,
+    numeric_attrs: bool,
+    recursive: bool,
+    jsonl: bool,
+    json: bool,
+    count: bool,
+    /// Paths relative to the listed directory instead of names
+    relative: bool,
+    /// Only list these entries of the directory and below it, by path
+    glob: Option<glob::Matches>,
+    modified: when::TimeFilter,
+    sizes: SizeFormat,
+    /// `Output::Json` is taken as `jsonl`
+    output: Output,
+}
+
+/// Lists `cursor`, the image directory `dir`, stopping after `limit`
+/// entries
+fn print_ls<'a, IO: fatfs::ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter>(
+    cursor: Dir<'a, IO, TP, OCC>, opts: &LsOptions, dir: &str, limit: Option<usize>,
+) -> Result<()>
+where
+    fatfs::Error<IO::Error>: std::error::Error + Send + Sync + 'static,
+{
+    let (long, sizes) = (opts.long, opts.sizes);
+    // Columns without labels, sizes padded to the ten digits of the
+    // largest FAT file and the tree indent next to the name, so that the
+    // columns line up
+    let v2 = opts.output == Output::V2;
+    let label = |name: &str| {
+        if !v2 {
+            print!("{} ", name);
+        }
+    };
+    let mut listed = 0;
+    // For `--json`, the directory given and the open ones below it
+    let mut tree = vec![JsonDir {
+        entry: None,
+        matched: true,
+        children: Vec::new(),
+    }];
+    // Matches can be anywhere below the directory, leaving out the rest
+    let mut walk = walk::Walk::new(&cursor, dir, opts.recursive || opts.glob.is_some());
+    while let Some(item) = walk.next() {
+        let walk::Entry { entry, path, depth } = item?;
+        let matched = match &opts.glob {
+            Some(matches) => {
+                let matched = matches.contains(&path, opts.recursive);
+                let descend = (matched && opts.recursive) || (!matched && matches.is_above(&path));
+                if entry.is_dir() && !descend {
+                    walk.skip_dir();
+                }
+                if !matched && !descend {
+                    continue;
+                }
+                matched
+            },
+            None => true,
+        };
+        // Paths stay as fatfs decodes them, which is how they are looked up
+        let name = surrogates::display_name(&entry, &path)?;
+        let at_root = path
+            .rsplit_once('/')
+            .is_some_and(|(parent, _)| parent.is_empty());
+        let path = if opts.relative {
+            paths::relative(dir, &path).to_owned()
+        } else {
+            path
+        };
+        while tree.len() > depth + 1 {
+            close_json_dir(&mut tree);
+        }
+        let opens_dir = opts.json && entry.is_dir() && (opts.recursive || !matched);
+        if !matched || !opts.modified.matches(&entry.modified()) {
+            if opens_dir {
+                tree.push(JsonDir {
+                    entry: Some(ls_json_tree(&entry, path, name)),
+                    matched: false,
+                    children: Vec::new(),
+                });
+            }
+            continue;
+        }
+        if limit == Some(listed) {
+            break;
+        }
+        listed += 1;
+        if opts.count {
+            continue;
+        }
+
+        if opts.json {
+            let node = ls_json_tree(&entry, path, name);
+            if opens_dir {
+                tree.push(JsonDir {
+                    entry: Some(node),
+                    matched: true,
+                    children: Vec::new(),
+                });
+            } else if let Some(dir) = tree.last_mut() {
+                dir.children.push(node);
+            }
+            continue;
+        }
+
+        if opts.jsonl {
+            println!("{}", json::versioned(ls_json(&entry, path, name)));
+            continue;
+        }
+
+        let indent = if opts.relative || opts.glob.is_some() {
+            String::new()
+        } else {
+            "  ".repeat(depth)
+        };
+        if !v2 {
+            print!("{}", indent);
+        }
+
+        if long >= 3 {
+            label("created");
             print_datetime(entry.created());
             print!(" ");
         }
         if long >= 2 {
-            print!("modified ");
+            label("modified");
             print_datetime(entry.modified());
             print!(" ");
         }
         if long >= 3 {
-            print!("accessed ");
+            label("accessed");
             print_date(entry.accessed());
             print!(" ");
         }
 
         if long >= 1 {
-            print!("{:?} ", entry.attributes());
+            let byte = entry.attributes().bits();
+            if opts.numeric_attrs {
+                print!("{} ", attrs::render_numeric(byte));
+            } else {
+                print!("{} ", attrs::render(byte));
+            }
 
-            if entry.is_file() {
-                print!("size {} ", entry.len());
+            if v2 {
+                let size = if entry.is_file() {
+                    sizes.logical(entry.len())
+                } else {
+                    String::new()
+                };
+                print!("{:>10} ", size);
+            } else if entry.is_file() {
+                print!("size {} ", sizes.logical(entry.len()));
             }
         }
+        if v2 {
+            print!("{}", indent);
+        }
 
-        println!("{}{}", name, if entry.is_dir() { "/" } else { "" });
-
-        if recursive && entry.is_dir() {
-            print_ls(entry.to_dir(), long, recursive, indent + 1)?;
+        let marker =
+            if long >= 1 && at_root && entry.is_dir() && artifacts::is_windows_artifact(&name) {
+                " [windows]"
+            } else {
+                ""
+            };
+        println!(
+            "{}{}{}",
+            if opts.relative || opts.glob.is_some() {
+                &path
+            } else {
+                &name
+            },
+            if entry.is_dir() { "/" } else { "" },
+            marker
+        );
+    }
+    if opts.count {
+        println!("{}", listed);
+    }
+    if opts.json {
+        while tree.len() > 1 {
+            close_json_dir(&mut tree);
         }
+        let entries = json::Value::Array(tree.remove(0).children);
+        println!("{}", json::versioned(json::object([("entries", entries)])));
     }
     Ok(())
 }
 
-fn write_tree_to_img<
-    'a,
-    IO: fatfs::ReadWriteSeek,
-    TP: fatfs::TimeProvider,
-    OCC: fatfs::OemCpConverter,
->(
-    cursor: Dir<'a, IO, TP, OCC>, host_path: PathBuf,
-) -> Result<()> {
-    for entry in cursor.iter() {
-        let entry = entry.expect("Entry");
-        let name = entry.file_name();
-        if name == "." || name == ".." {
-            continue;
+/// Options for `write_tree_to_img`
+struct WriteTreeOptions {
+    text_mode: TextMode,
+    text_globs: Vec<String>,
+    gzip_globs: Vec<String>,
+    keep_name: bool,
+    /// Host entries to skip or rename, see `collisions::plan`
+    collisions: HashMap<PathBuf, Resolution>,
+    limits: PathLimits,
+    /// Store host modification times
+    preserve_times: bool,
+    /// Fail on host times FAT can't store
+    strict_times: bool,
+    /// Skip directories on other host filesystems
+    one_file_system: Option<hostpath::SameFilesystem>,
+    /// Skip host names that aren't UTF-8 instead of reading them as
+    /// Latin-1
+    skip_invalid_names: bool,
+    /// Host entries to leave out, see `is_excluded`
+    excludes: Vec<String>,
+    /// Exceptions to `excludes`
+    includes: Vec<String>,
+    /// Copy files `sidecars::recognize` knows too
+    include_sidecars: bool,
+    /// Normalize host names to NFC
+    nfc_names: bool,
+    /// Fail on existing image files instead of replacing them
+    no_overwrite: bool,
+    /// Empty the image directory first
+    clean: bool,
+    /// Remove image entries without a host entry afterwards
+    delete: bool,
+    /// Entries `clean` and `delete` keep
+    protect: protect::ProtectArgs,
+    /// Only print what would change
+    dry_run: bool,
+}
+
+impl WriteTreeOptions {
+    /// Should newline conversion be applied to this file
+    fn is_text(&self, rel_path: &str) -> bool {
+        self.text_mode != TextMode::None
+            && self.text_globs.iter().any(|g| glob::matches(g, rel_path))
+    }
+
+    /// Should this file be compressed
+    fn is_gzip(&self, rel_path: &str) -> bool {
+        self.gzip_globs.iter().any(|g| glob::matches(g, rel_path))
+    }
+
+    /// Whether `--exclude` leaves out the host entry at `rel_path`. A glob
+    /// ending in `/` only matches directories, and directories also match
+    /// with a `/` appended, so that `**/.git/**` leaves out `.git` itself.
+    fn is_excluded(&self, rel_path: &str, is_dir: bool) -> bool {
+        let matches = |g: &String| match g.strip_suffix('/') {
+            Some(g) => is_dir && glob::matches(g, rel_path),
+            None => {
+                glob::matches(g, rel_path)
+                    || (is_dir && g.contains('/') && glob::matches(g, &format!("{}/", rel_path)))
+            },
+        };
+        self.excludes.iter().any(matches) && !self.includes.iter().any(matches)
+    }
+
+    /// The name of the host entry `host` as text, `None` if it is skipped
+    /// for not being UTF-8
+    fn host_name(&self, host: &Path, name: &OsStr) -> Result<Option<String>> {
+        if name.to_str().is_none() && self.skip_invalid_names {
+            return Ok(None);
+        }
+        match hostpath::decode_name(name) {
+            Some(name) if self.nfc_names => Ok(Some(name.nfc().collect())),
+            Some(name) => Ok(Some(name)),
+            None => bail!(
+                "{} is not a valid file name, use --skip-invalid-names to leave it out",
+                hostpath::raw_display(host)
+            ),
         }
-        todo!("Ovewrite non-empty directory?");
     }
+}
 
-    for entry in fs::read_dir(host_path)? {
-        let entry = entry?;
-        let t = entry.file_type()?;
-        let name = entry.file_name().into_string().expect("non-utf8 filename");
+type ImgIo = StdIoWrapper<BufStream<ImgSlice>>;
+pub(crate) type ImgFs = FileSystem<ImgIo, clock::Clock, fatfs::LossyOemCpConverter>;
+pub(crate) type ImgDir<'a> = Dir<'a, ImgIo, clock::Clock, fatfs::LossyOemCpConverter>;
+
+/// Joins an image directory path and an entry name
+pub(crate) fn inner_join(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+/// The modification and creation times to store for `host`, with the
+/// metadata `meta`. The creation time is left out where the host doesn't
+/// know it or FAT can't store it, the modification time is clamped like
+/// `stored_time` does.
+fn host_times(
+    host: &Path, meta: &fs::Metadata, strict: bool, warnings: &mut Warnings,
+) -> Result<(fatfs::DateTime, Option<fatfs::DateTime>)> {
+    let modified = meta
+        .modified()
+        .with_context(|| format!("failed reading the modification time of {}", host.display()))?;
+    let modified = stored_time(host, modified, strict, warnings)?;
+    let created = meta.created().ok().and_then(|t| {
+        let (local, out_of_range) = clock::clamp_local(clock::host_local(t));
+        out_of_range.is_none().then(|| clock::date_time(local, 0))
+    });
+    Ok((modified, created))
+}
+
+/// The FAT timestamp for the host time `t` of `host`. Times FAT can't
+/// store are clamped with a warning, or refused with `strict`.
+fn stored_time(
+    host: &Path, t: std::time::SystemTime, strict: bool, warnings: &mut Warnings,
+) -> Result<fatfs::DateTime> {
+    let (local, out_of_range) = clock::clamp_local(clock::host_local(t));
+    let category = match out_of_range {
+        None => return Ok(clock::date_time(local, 0)),
+        Some(clock::OutOfRange::Before1980) => warnings::Category::TimeBefore1980,
+        Some(clock::OutOfRange::After2107) => warnings::Category::TimeAfter2107,
+    };
+    if strict {
+        let when = match category {
+            warnings::Category::TimeBefore1980 => "before 1980",
+            _ => "after 2107",
+        };
+        bail!(
+            "{}: modification time is {}, which FAT can't store",
+            host.display(),
+            when
+        );
+    }
+    warnings.warn(category, host.display());
+    Ok(clock::date_time(local, 0))
+}
+
+/// What `write_tree_to_img` wrote and left out
+#[derive(Debug, Default)]
+struct TreeTotals {
+    /// Files and directories created
+    written: usize,
+    /// Entries left out by `--exclude`, not counting their contents
+    excluded: usize,
+    gzip: gzip::Totals,
+    /// Host modification times of the created directories, by normalized
+    /// image path, for `--preserve-times`
+    dir_times: Vec<(String, fatfs::DateTime)>,
+}
+
+impl TreeTotals {
+    fn report(&self, sizes: &SizeFormat) {
+        self.gzip.report(sizes);
+        if self.excluded > 0 {
+            eprintln!(
+                "{} entries written, {} excluded",
+                self.written, self.excluded
+            );
+        }
+    }
+}
+
+/// Copies the host directory `host_path` into `cursor`, which is the
+/// image directory `inner_dir`. Errors name both paths involved. With
+/// `only`, files whose relative paths aren't in it are left out.
+#[allow(clippy::too_many_arguments)]
+fn write_tree_to_img(
+    cursor: ImgDir<'_>, inner_dir: &str, host_path: PathBuf, rel_path: &str,
+    opts: &WriteTreeOptions, only: Option<&HashSet<String>>, warnings: &mut Warnings,
+    totals: &mut TreeTotals,
+) -> Result<()> {
+    let existing = existing_entries(&cursor, "")?.unwrap_or_default();
+    let exists = |name: &str| existing.contains_key(&collisions::fold(name));
+    // In name order, as the host order can differ between copies of a tree
+    let mut entries = fs::read_dir(&host_path)
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .with_context(|| format!("failed reading host directory {}", host_path.display()))?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let host = entry.path();
+        let t = entry
+            .file_type()
+            .with_context(|| format!("failed reading {}", host.display()))?;
+        let host_name = entry.file_name();
+        let name = match opts.host_name(&host, &host_name)? {
+            Some(name) => name,
+            None => {
+                warnings.warn(
+                    warnings::Category::NonUtf8Name,
+                    hostpath::raw_display(&host),
+                );
+                continue;
+            },
+        };
+        if host_name.to_str().is_none() {
+            let detail = format!("{} as {}", hostpath::raw_display(&host), name);
+            warnings.warn(warnings::Category::Latin1Name, detail);
+        }
+        let rel = if rel_path.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", rel_path, name)
+        };
+        if opts.is_excluded(&rel, t.is_dir()) {
+            log::info!("excluded {}", host.display());
+            totals.excluded += 1;
+            continue;
+        }
+        let sidecar = sidecars::recognize(&name).filter(|_| !opts.include_sidecars);
+        if let Some(what) = sidecar {
+            let detail = format!("{} ({})", host.display(), what);
+            warnings.warn(warnings::Category::SidecarSkipped, detail);
+            continue;
+        }
+        let name = match opts.collisions.get(&host) {
+            Some(Resolution::Skip) => continue,
+            Some(Resolution::Rename(new_name)) => new_name.clone(),
+            None => name,
+        };
+        let gzip = t.is_file() && opts.is_gzip(&rel);
+        let name = if gzip && !opts.keep_name {
+            gzip::stored_name(&name)
+        } else {
+            name
+        };
+        let inner = inner_join(inner_dir, &name);
+        if let Some(msg) = opts.limits.violation(&inner) {
+            bail!("{} (from {})", msg, host.display());
+        }
 
         if t.is_symlink() {
-            eprintln!("Warning: Not copying a symlink");
+            warnings.warn(warnings::Category::SymlinkSkipped, host.display());
+        }
+
+        if t.is_file() && only.is_some_and(|only| !only.contains(&rel)) {
+            continue;
         }
 
         if t.is_file() {
-            let source_file = File::open(entry.path())?;
-            let mut source = io::BufReader::new(source_file);
-            let mut target_file = cursor.create_file(&name).expect("Create file");
-
-            let mut buf = [0u8; 1024];
-            loop {
-                let n = source.read(&mut buf)?;
-                target_file.write(&buf[..n]).expect("Write");
-                if n == 0 {
-                    break;
+            let context = || format!("failed writing {} from {}", inner, host.display());
+            let source_file = File::open(&host).with_context(context)?;
+            let source = io::BufReader::new(source_file);
+            let mut source: Box<dyn Read> = if opts.is_text(&rel) {
+                Box::new(NewlineReader::new(source, opts.text_mode))
+            } else {
+                Box::new(source)
+            };
+            if !exists(&name) {
+                delta::created(false);
+            }
+            // Existing files are opened as they are
+            let mut target_file = cursor.create_file(&name).with_context(context)?;
+            target_file.truncate().with_context(context)?;
+            if gzip {
+                let sizes = gzip::copy(TextMode::None, &mut source, &mut target_file)
+                    .with_context(context)?;
+                totals.gzip.add(sizes);
+            } else {
+                io::copy(&mut source, &mut target_file).with_context(context)?;
+            }
+            // Writing stamps the current time, so this goes last
+            if opts.preserve_times {
+                let meta = entry.metadata().with_context(context)?;
+                let (modified, created) = host_times(&host, &meta, opts.strict_times, warnings)?;
+                target_file.set_modified(modified);
+                if let Some(created) = created {
+                    target_file.set_created(created);
                 }
             }
-
-            // let t = StdIoWrapper::from(target_file);
-            // io::copy(&mut source, &mut t)?;
+            target_file.flush().with_context(context)?;
+            log::info!("wrote {} from {}", inner, host.display());
+            totals.written += 1;
         }
 
         if t.is_dir() {
-            let subdir = cursor.create_dir(&name).expect("Dir entry");
-            write_tree_to_img(subdir, entry.path())?;
+            if let Some(same) = &opts.one_file_system {
+                let meta = entry
+                    .metadata()
+                    .with_context(|| format!("failed reading {}", host.display()))?;
+                if same.leaves(&meta) {
+                    warnings.warn(warnings::Category::OtherFilesystem, host.display());
+                    continue;
+                }
+            }
+            if !exists(&name) {
+                delta::created(true);
+            }
+            let subdir = cursor
+                .create_dir(&name)
+                .with_context(|| format!("failed creating directory {}", inner))?;
+            log::info!("created directory {}", inner);
+            totals.written += 1;
+            if opts.preserve_times {
+                let meta = entry
+                    .metadata()
+                    .with_context(|| format!("failed reading {}", host.display()))?;
+                let (modified, _) = host_times(&host, &meta, opts.strict_times, warnings)?;
+                let path = inner.trim_start_matches('/').to_owned();
+                totals.dir_times.push((path, modified));
+            }
+            write_tree_to_img(subdir, &inner, host, &rel, opts, only, warnings, totals)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Decides what to do with entries below `cursor` whose names collide
+/// once case is ignored, before anything is extracted
+fn plan_extract<IO: fatfs::ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter>(
+    cursor: &Dir<'_, IO, TP, OCC>, inner_dir: &str, policy: OnCollision,
+) -> Result<HashMap<String, Resolution>>
+where
+    fatfs::Error<IO::Error>: std::error::Error + Send + Sync + 'static,
+{
+    let mut dirs: BTreeMap<String, Vec<(String, bool)>> = BTreeMap::new();
+    for item in walk::Walk::new(cursor, inner_dir, true) {
+        let walk::Entry { entry, path, .. } = item?;
+        let (parent, name) = path.rsplit_once('/').expect("Walk paths are absolute");
+        dirs.entry(parent.to_owned())
+            .or_default()
+            .push((name.to_owned(), entry.is_dir()));
+    }
+
+    let mut result = HashMap::new();
+    let mut errors = Vec::new();
+    for (dir, entries) in dirs {
+        let shown = if dir.is_empty() { "/" } else { &dir };
+        for (name, _, resolution) in collisions::resolve_dir(entries, policy, shown, &mut errors) {
+            if let Some(resolution) = resolution {
+                result.insert(inner_join(&dir, &name), resolution);
+            }
+        }
+    }
+    if !errors.is_empty() {
+        bail!(
+            "Image names that would collide on the host:\n  {}\nUse --on-collision to choose how to resolve them, or --assume-case-sensitive",
+            errors.join("\n  ")
+        );
+    }
+    Ok(result)
+}
+
+/// Copies the image directory `cursor`, which is `inner_dir`, into the
+/// host directory `host_path`, which is created if needed. `preserve`
+/// carries image metadata over to the extracted files. Unless
+/// `overwrite` is `--force`, a non-empty host directory is refused before
+/// anything is written. With `on_collision`, names differing only by case
+/// are resolved first, as on a case-insensitive host. Entries in
+/// `name_map` are extracted under their mapped names, the rest named
+/// following `surrogate_policy`.
+#[allow(clippy::too_many_arguments)]
+fn read_tree<IO: fatfs::ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter>(
+    cursor: Dir<'_, IO, TP, OCC>, inner_dir: &str, host_path: &Path, overwrite: OverwritePolicy,
+    on_collision: Option<OnCollision>, name_map: Option<&namemap::NameMap>, preserve: Preserve,
+    surrogate_policy: surrogates::Policy,
+) -> Result<()>
+where
+    fatfs::Error<IO::Error>: std::error::Error + Send + Sync + 'static,
+    io::Error: From<fatfs::Error<IO::Error>>,
+{
+    let non_empty = fs::read_dir(host_path).is_ok_and(|mut d| d.next().is_some());
+    if non_empty && !overwrite.allows(false) {
+        if overwrite == OverwritePolicy::NoClobber {
+            bail!(
+                "{} is not empty, not writing into it with --no-clobber",
+                host_path.display()
+            );
         }
+        bail!(
+            "{} is not empty, use --force to overwrite files in it",
+            host_path.display()
+        );
+    }
+    let resolutions = match on_collision {
+        Some(policy) => plan_extract(&cursor, inner_dir, policy)?,
+        None => HashMap::new(),
+    };
+    if let Some(map) = name_map {
+        map.check(&cursor, inner_dir, on_collision.is_some())?;
     }
+    fs::create_dir_all(host_path)
+        .with_context(|| format!("failed creating host directory {}", host_path.display()))?;
+    let host_path = hostpath::extended(host_path)
+        .with_context(|| format!("failed opening {}", host_path.display()))?;
+
+    // Host directories by image path, missing for skipped directories
+    let mut hosts = HashMap::new();
+    // Set once their contents are written, which changes them
+    let mut dir_times = Vec::new();
+    // Set last, the modes may not allow writing into them
+    let mut dirs = Vec::new();
+    hosts.insert(inner_dir.trim_end_matches('/').to_owned(), host_path);
+    for item in walk::Walk::new(&cursor, inner_dir, true) {
+        let walk::Entry { entry, path, .. } = item?;
+        let (parent, _) = path.rsplit_once('/').expect("Walk paths are absolute");
+        let parent_host: &PathBuf = match hosts.get(parent) {
+            Some(parent_host) => parent_host,
+            None => continue,
+        };
+        let mapped = name_map.and_then(|map| map.get(&path));
+        let host = match (mapped, resolutions.get(&path)) {
+            (Some(host_name), _) => {
+                let host = parent_host.join(host_name);
+                report::renamed(path.clone(), host.to_string_lossy().into_owned());
+                host
+            },
+            (None, None) => {
+                parent_host.join(surrogates::host_name(&entry, &path, surrogate_policy)?)
+            },
+            (None, Some(Resolution::Skip)) => {
+                eprintln!("skipped {}, its name collides on the host", path);
+                continue;
+            },
+            (None, Some(Resolution::Rename(new_name))) => {
+                let host = parent_host.join(new_name);
+                eprintln!("Renaming {} -> {}", path, host.display());
+                report::renamed(path.clone(), host.to_string_lossy().into_owned());
+                host
+            },
+        };
+        if entry.is_dir() {
+            fs::create_dir_all(&host)
+                .with_context(|| format!("failed creating host directory {}", host.display()))?;
+            log::info!("created host directory {}", host.display());
+            if let Some(utc_offset) = preserve.times {
+                dir_times.push((
+                    host.clone(),
+                    clock::host_time(&entry.modified(), utc_offset),
+                ));
+            }
+            if preserve.dir_mode.is_some() {
+                dirs.push(host.clone());
+            }
+            hosts.insert(path, host);
+            continue;
+        }
 
+        let context = || format!("failed reading {} to {}", path, host.display());
+        let mut out = io::BufWriter::new(create_output(&host, overwrite)?);
+        let bytes = io::copy(&mut entry.to_file(), &mut out).with_context(context)?;
+        io::Write::flush(&mut out).with_context(context)?;
+        drop(out);
+        preserve.apply(&host, &entry.modified(), entry.attributes().bits())?;
+        log::info!("read {} to {}", path, host.display());
+        report::read(path, bytes);
+    }
+    for (host, t) in dir_times.iter().rev() {
+        hostpath::set_modified(host, *t)
+            .with_context(|| format!("failed setting the time of {}", host.display()))?;
+    }
+    if let Some(mode) = preserve.dir_mode {
+        // Subdirectories first, while their parents can still be entered
+        for host in dirs.iter().rev() {
+            hostpath::set_mode(host, mode)
+                .with_context(|| format!("failed setting the mode of {}", host.display()))?;
+        }
+    }
     Ok(())
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
+/// Image metadata `read-tree` carries over to host files
+#[derive(Debug, Clone, Copy, Default)]
+struct Preserve {
+    /// Set modification times, of timestamps stored at this UTC offset
+    times: Option<i32>,
+    /// Make files with the read-only attribute read-only on the host
+    attrs: bool,
+    /// Permission mode of extracted files, over `attrs`
+    file_mode: Option<u32>,
+    /// Permission mode of extracted directories
+    dir_mode: Option<u32>,
+}
 
-    let args = Args::parse();
-    println!("{:?}", args);
+impl Preserve {
+    /// Applies the timestamp `modified` and the attribute byte `attrs` of
+    /// an image file to the extracted host file `host`
+    fn apply(&self, host: &Path, modified: &fatfs::DateTime, attrs: u8) -> Result<()> {
+        if let Some(utc_offset) = self.times {
+            hostpath::set_modified(host, clock::host_time(modified, utc_offset))
+                .with_context(|| format!("failed setting the time of {}", host.display()))?;
+        }
+        if let Some(mode) = self.file_mode {
+            hostpath::set_mode(host, mode)
+                .with_context(|| format!("failed setting the mode of {}", host.display()))?;
+        } else if self.attrs && attrs & 0x01 != 0 {
+            let context = || format!("failed making {} read-only", host.display());
+            let mut permissions = fs::metadata(host).with_context(context)?.permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(host, permissions).with_context(context)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why an existing image doesn't match what `create` was asked for.
+/// Empty if it can be kept as is.
+fn format_mismatches(
+    img_file: &Path, size: u64, geometry: &geometry::Geometry, label: Option<[u8; 11]>,
+    partition: Option<(u32, u32)>,
+) -> Result<Vec<String>> {
+    let mut mismatches = Vec::new();
+    let f = File::open(img_file)?;
+    let len = f.metadata()?.len();
+    if len != size {
+        mismatches.push(format!("file size is {}, requested {}", len, size));
+    }
+
+    let (start, volume_len) = match partition {
+        Some((start_lba, sectors)) => {
+            match region::read_partition(img_file, 1) {
+                Ok(p) if (p.start_lba, p.sectors) == (start_lba, sectors) => {},
+                Ok(p) => {
+                    mismatches.push(format!(
+                        "partition 1 is {} sectors from sector {}, requested {} from {}",
+                        p.sectors, p.start_lba, sectors, start_lba
+                    ));
+                    return Ok(mismatches);
+                },
+                Err(err) => {
+                    mismatches.push(format!("{:#}", err));
+                    return Ok(mismatches);
+                },
+            }
+            (start_lba as u64 * 512, sectors as u64 * 512)
+        },
+        None => (0, size),
+    };
+    let mut f = ImgSlice::open_at(img_file, false, start, partition.map(|_| volume_len))?;
+
+    let bs = match ondisk::BootSector::read(&mut f) {
+        Ok(bs) => bs,
+        Err(err) => {
+            mismatches.push(format!("no valid boot sector: {:#}", err));
+            return Ok(mismatches);
+        },
+    };
+    f.rewind()?;
+    let fs = match FileSystem::new(BufStream::new(f), FsOptions::new()) {
+        Ok(fs) => fs,
+        Err(err) => {
+            mismatches.push(format!("filesystem doesn't mount: {}", err));
+            return Ok(mismatches);
+        },
+    };
+    mismatches.extend(geometry.mismatches(&bs, fs.fat_type()));
+    if let Some(label) = label {
+        let requested = String::from_utf8_lossy(&label).trim_end().to_owned();
+        if bs.volume_label() != requested {
+            mismatches.push(format!(
+                "label is {:?}, requested {:?}",
+                bs.volume_label(),
+                requested
+            ));
+        }
+    }
+
+    // The volume fills the image or partition, up to the last whole sector
+    let sector = bs.bytes_per_sector() as u64;
+    let volume = bs.total_sectors() as u64 * sector;
+    if volume > volume_len || volume_len - volume >= sector {
+        mismatches.push(format!(
+            "volume size is {}, requested {}",
+            volume, volume_len
+        ));
+    }
+    Ok(mismatches)
+}
+
+/// Boot sector of a `create --boot-from` image, which must be a FAT volume
+fn read_reference_boot_sector(path: &Path) -> Result<ondisk::BootSector> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed opening {}", path.display()))?;
+    let bs = ondisk::BootSector::read(&mut file)
+        .with_context(|| format!("{} is not a FAT volume", path.display()))?;
+    if !bs.has_signature() {
+        bail!("{} has no boot sector signature", path.display());
+    }
+    bs.layout()
+        .with_context(|| format!("{} is not a FAT volume", path.display()))?;
+    Ok(bs)
+}
+
+/// fatfs silently picks one of the total sector fields when they disagree,
+/// which shows up as odd errors near the end of the volume
+fn warn_total_sectors(img_file: &mut ImgSlice) -> Result<()> {
+    if let Ok(bs) = ondisk::BootSector::read(img_file) {
+        if let Some(conflict) = ondisk::total_sectors_conflict(&bs, img_file.len()?) {
+            warnings::warn(
+                warnings::Category::TotalSectorsConflict,
+                format!("{}, run check --fix", conflict),
+            );
+        }
+    }
+    img_file.rewind()?;
+    Ok(())
+}
+
+/// Name, start cluster and size of the first entry of `dir` (or the one
+/// called `name`) that has an invalid start cluster. Images that can't be
+/// read this way give `None`.
+fn find_invalid_start(
+    img_file: &mut ImgSlice, dir: &str, name: Option<&str>,
+) -> Option<(String, u32, u32)> {
+    let layout = ondisk::BootSector::read(img_file).ok()?.layout().ok()?;
+    let fat = ondisk::Fat::read(img_file, &layout).ok()?;
+    let dir = ondisk::RawDir::read_path(img_file, &layout, &fat, dir).ok()?;
+    let upper = name.map(str::to_uppercase);
+    dir.files().into_iter().find_map(|(n, e)| {
+        let named = upper
+            .as_ref()
+            .is_none_or(|u| n.to_uppercase() == *u || e.short_name() == *u);
+        (named && e.has_invalid_start()).then(|| (n, e.first_cluster(), e.size()))
+    })
+}
+
+/// Refuses entries of the image directory `dir`, or just the one called
+/// `name`, that have an invalid start cluster. fatfs gives confusing
+/// errors or empty data for them, and writing next to them can make
+/// things worse. Images it can't make sense of are left for fatfs to
+/// report.
+fn refuse_invalid_start(img_file: &mut ImgSlice, dir: &str, name: Option<&str>) -> Result<()> {
+    let found = find_invalid_start(img_file, dir, name);
+    img_file.rewind()?;
+    if let Some((entry, cluster, size)) = found {
+        bail!(
+            "{}: entry has invalid start cluster {} for its {} bytes, run check --fix",
+            inner_join(&format!("/{}", dir), &entry),
+            cluster,
+            size
+        );
+    }
+    Ok(())
+}
+
+/// Fails naming the file if the normalized image directory `dir` goes
+/// through one, before a command gets into fatfs with a path in it, which
+/// would only say it isn't a directory. Missing directories are left for
+/// the command to report or create.
+pub(crate) fn refuse_file_components(img_file: &Path, dir: &str) -> Result<()> {
+    let mut img = ImgSlice::open(img_file, false)?;
+    let layout = match ondisk::BootSector::read(&mut img).and_then(|bs| bs.layout()) {
+        Ok(layout) => layout,
+        Err(_) => return Ok(()),
+    };
+    let fat = ondisk::Fat::read(&mut img, &layout)?;
+    match ondisk::RawDir::read_path(&mut img, &layout, &fat, dir) {
+        Err(err) if err.is::<exit::NotADirectory>() => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Refuses writing to the image directory `dir`, see `refuse_invalid_start`
+/// and `refuse_file_components`
+fn refuse_invalid_dir(img_file: &Path, dir: &str) -> Result<()> {
+    refuse_file_components(img_file, dir)?;
+    refuse_invalid_start(&mut ImgSlice::open(img_file, false)?, dir, None)
+}
+
+/// Where the normalized image path `dst` is relative to `src`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Overlap {
+    Disjoint,
+    Same,
+    Inside,
+}
+
+/// Finds whether `dst` is `src` or inside it by the entries the paths
+/// lead to. Comparing the names would miss one given by an 8.3 alias,
+/// like `/LONGFI~1.TXT` for `/LongFileName.txt`. A missing `src` overlaps
+/// nothing.
+pub(crate) fn overlap(img_file: &Path, src: &str, dst: &str) -> Result<Overlap> {
+    let mut img = ImgSlice::open(img_file, false)?;
+    let layout = ondisk::BootSector::read(&mut img)?.layout()?;
+    let fat = ondisk::Fat::read(&mut img, &layout)?;
+    let depth = |path: &str| path.split('/').filter(|c| !c.is_empty()).count();
+    let src_at = ondisk::RawDir::offsets_along(&mut img, &layout, &fat, src)?;
+    let src_entry = match src_at.last() {
+        Some(&offset) if src_at.len() == depth(src) => offset,
+        _ => return Ok(Overlap::Disjoint),
+    };
+    let dst_at = ondisk::RawDir::offsets_along(&mut img, &layout, &fat, dst)?;
+    Ok(
+        match dst_at.iter().position(|&offset| offset == src_entry) {
+            Some(i) if i + 1 == depth(dst) => Overlap::Same,
+            Some(_) => Overlap::Inside,
+            None => Overlap::Disjoint,
+        },
+    )
+}
+
+/// The directory part of a normalized image path
+pub(crate) fn parent_of(inner_path: &str) -> &str {
+    inner_path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+pub(crate) fn open_fs_rw(img_file: &Path) -> Result<ImgFs> {
+    open_fs(img_file, true)
+}
+
+/// Opens the filesystem like commands that change the image do, for
+/// reading only without `write`
+fn open_fs(img_file: &Path, write: bool) -> Result<ImgFs> {
+    let mut file = ImgSlice::open(img_file, write)?;
+    warn_total_sectors(&mut file)?;
+    let buf_file = BufStream::new(file);
+    let options = FsOptions::new().time_provider(clock::get());
+    Ok(FileSystem::new(buf_file, options)?)
+}
+
+/// The files `read` extracts for the normalized image path `pattern`, each
+/// with where it goes. More than one match needs `output` to be a
+/// directory, which they are put in by name.
+fn read_targets(
+    img_file: &Path, pattern: &str, output: Option<PathBuf>, glob_args: &glob::GlobArgs,
+) -> Result<Vec<(String, Option<PathBuf>)>> {
+    refuse_file_components(img_file, glob::literal_dir(pattern))?;
+    let fs = open_fs(img_file, false)?;
+    let matches = glob::expand(&fs.root_dir(), pattern, glob_args)?;
+    let files: Vec<&str> = matches
+        .entries
+        .iter()
+        .filter(|(_, is_dir)| !is_dir)
+        .map(|(path, _)| &path[1..])
+        .collect();
+    if files.is_empty() && !glob_args.no_fail_empty {
+        bail!("/{}: only directories match", pattern);
+    }
+    if files.len() <= 1 {
+        return Ok(files
+            .into_iter()
+            .map(|f| (f.to_owned(), output.clone()))
+            .collect());
+    }
+    let dir = match output {
+        Some(dir) if dir.is_dir() => dir,
+        _ => bail!(
+            "/{} matches {} files, give a directory to read them into with -o",
+            pattern,
+            files.len()
+        ),
+    };
+    let mut names = HashSet::new();
+    let mut targets = Vec::new();
+    for file in files {
+        let name = file.rsplit('/').next().unwrap_or(file);
+        if !names.insert(name.to_owned()) {
+            bail!("More than one file matching /{} is named {}", pattern, name);
+        }
+        targets.push((file.to_owned(), Some(dir.join(name))));
+    }
+    Ok(targets)
+}
+
+fn mkdir(img_file: &Path, inner_path: &str, attributes: Option<u8>) -> Result<()> {
+    refuse_invalid_dir(img_file, parent_of(inner_path))?;
+    let fs = open_fs_rw(img_file)?;
+    if fs.root_dir().open_dir(inner_path).is_err() {
+        delta::created(true);
+    }
+    fs.root_dir()
+        .create_dir(inner_path)
+        .with_context(|| format!("failed creating directory /{}", inner_path))?;
+    fs.unmount().context("failed flushing the filesystem")?;
+    ntcase::fold_entry(img_file, inner_path)?;
+    match attributes {
+        Some(flags) => attrs::set(img_file, inner_path, flags),
+        None => Ok(()),
+    }
+}
+
+/// Options for `write_file`
+#[derive(Debug, Default)]
+pub(crate) struct WriteOptions {
+    pub text_mode: TextMode,
+    /// Byte to fill the tail of the last cluster with
+    pub pad: Option<u8>,
+    pub verify: bool,
+    /// Write the file even if the input is empty
+    pub allow_empty: bool,
+    /// Compress the contents
+    pub gzip: bool,
+    /// Attribute flags to give the file instead of archive
+    pub attributes: Option<u8>,
+    /// An existing file is replaced by default
+    pub overwrite: OverwritePolicy,
+    /// Modification and creation times to store instead of the current
+    /// time
+    pub times: Option<(fatfs::DateTime, Option<fatfs::DateTime>)>,
+}
+
+/// Writes one file. With `opts.gzip` returns the uncompressed and the
+/// stored size.
+pub(crate) fn write_file(
+    img_file: &Path, inner_path: &str, source: &WriteSource, opts: &WriteOptions,
+) -> Result<Option<(u64, u64)>> {
+    refuse_invalid_dir(img_file, parent_of(inner_path))?;
+    let fs = open_fs_rw(img_file)?;
+    let mut source = source.open()?;
+    // Checked before truncating, so a broken pipe can't wipe the old contents
+    if !opts.allow_empty && source.fill_buf()?.is_empty() {
+        bail!("Input is empty, use --allow-empty to write an empty file");
+    }
+    if fs.root_dir().open_file(inner_path).is_ok() {
+        opts.overwrite
+            .check(true, format_args!("/{}", inner_path))?;
+    } else {
+        delta::created(false);
+    }
+    let context = || format!("failed writing /{}", inner_path);
+    let mut target_file = fs
+        .root_dir()
+        .create_file(inner_path)
+        .with_context(context)?;
+    target_file.truncate().with_context(context)?;
+    // Commit the empty size before the freed clusters get reused. Until the
+    // final flush an interrupted write leaves an empty file, never the old
+    // size over a chain of new, partial data.
+    target_file.flush().with_context(context)?;
+
+    let mut compressed = None;
+    if opts.gzip {
+        let sizes =
+            gzip::copy(opts.text_mode, &mut source, &mut target_file).with_context(context)?;
+        compressed = Some(sizes);
+    } else {
+        text::copy(opts.text_mode, &mut source, &mut target_file).with_context(context)?;
+    }
+    if let Some((modified, created)) = opts.times {
+        target_file.set_modified(modified);
+        if let Some(created) = created {
+            target_file.set_created(created);
+        }
+    }
+    target_file.flush().with_context(context)?;
+
+    // The file API can't write past EOF without growing the file,
+    // so the padding is patched into the image after unmounting
+    let mut tail = None;
+    if opts.pad.is_some() {
+        let cluster_size = fs.stats()?.cluster_size() as u64;
+        for extent in target_file.extents() {
+            let extent = extent?;
+            let used = extent.size as u64;
+            tail = Some((extent.offset + used, cluster_size - used));
+        }
+    }
+
+    drop(target_file);
+    fs.unmount().context("failed flushing the filesystem")?;
+
+    if let (Some(byte), Some((offset, len))) = (opts.pad, tail) {
+        let mut f = ImgSlice::open(img_file, true)?;
+        ondisk::fill(&mut f, offset, len, byte)?;
+        if opts.verify && !ondisk::is_filled(&mut f, offset, len, byte)? {
+            bail!("Cluster padding at offset {} did not read back", offset);
+        }
+    }
+    ntcase::fold_entry(img_file, inner_path)?;
+    if let Some(flags) = opts.attributes {
+        attrs::set(img_file, inner_path, flags)?;
+    }
+    Ok(compressed)
+}
+
+/// Existing entries of the image directory `path` below `cursor` as
+/// `is_dir` by folded long and short name, `None` if there is no such
+/// directory yet
+fn existing_entries(cursor: &ImgDir<'_>, path: &str) -> Result<Option<HashMap<String, bool>>> {
+    let dir = if path.is_empty() {
+        cursor.clone()
+    } else {
+        match cursor.open_dir(path) {
+            Ok(dir) => dir,
+            Err(_) => return Ok(None),
+        }
+    };
+    let mut entries = HashMap::new();
+    for entry in dir.iter() {
+        let entry = entry.with_context(|| format!("failed reading directory {}", path))?;
+        let name = entry.file_name();
+        if name != "." && name != ".." {
+            entries.insert(collisions::fold(&name), entry.is_dir());
+            entries.insert(collisions::fold(&entry.short_file_name()), entry.is_dir());
+        }
+    }
+    Ok(Some(entries))
+}
+
+/// Fails before anything is written if a host entry would take the place
+/// of an image entry of the other kind, or with `--no-overwrite` of any
+/// existing file. `cursor` is the image directory `inner_dir`, files not
+/// in `only` are left out like `write_tree_to_img` leaves them out.
+fn check_existing(
+    cursor: &ImgDir<'_>, inner_dir: &str, host_path: &Path, opts: &WriteTreeOptions,
+    only: Option<&HashSet<String>>,
+) -> Result<()> {
+    if existing_entries(cursor, "")?.is_none_or(|entries| entries.is_empty()) {
+        return Ok(());
+    }
+    let mut items = Vec::new();
+    fit::collect(host_path, "", "", opts, &mut items)?;
+    let mut dirs = HashMap::new();
+    let mut conflicts = Vec::new();
+    for item in &items {
+        if !item.is_dir && only.is_some_and(|only| !only.contains(&item.rel)) {
+            continue;
+        }
+        let (parent, name) = item.stored.rsplit_once('/').unwrap_or(("", &item.stored));
+        if !dirs.contains_key(parent) {
+            dirs.insert(parent.to_owned(), existing_entries(cursor, parent)?);
+        }
+        let existing = match &dirs[parent] {
+            Some(entries) => entries.get(&collisions::fold(name)).copied(),
+            None => None,
+        };
+        let inner = inner_join(inner_dir, &item.stored);
+        let host = host_path.join(&item.rel);
+        match existing {
+            Some(true) if !item.is_dir => conflicts.push(format!(
+                "{} is a directory, {} is a file",
+                inner,
+                host.display()
+            )),
+            Some(false) if item.is_dir => conflicts.push(format!(
+                "{} is a file, {} is a directory",
+                inner,
+                host.display()
+            )),
+            Some(false) if opts.no_overwrite => {
+                conflicts.push(format!("{} exists and --no-overwrite is given", inner))
+            },
+            _ => {},
+        }
+    }
+    if !conflicts.is_empty() {
+        bail!(
+            "Nothing written, host entries conflict with the image:\n  {}",
+            conflicts.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+/// Image entries below `cursor`, the image directory `inner_dir`, that
+/// none of the host `items` is stored as, as `rm::remove_all` takes them.
+/// Entries `--exclude` matches are kept along with their contents.
+fn stale_entries(
+    cursor: &ImgDir<'_>, inner_dir: &str, items: &[fit::Item], opts: &WriteTreeOptions,
+) -> Result<Vec<(String, bool)>> {
+    let stored: HashSet<String> = items
+        .iter()
+        .map(|item| collisions::fold(&item.stored))
+        .collect();
+    let mut stale = Vec::new();
+    let mut walk = walk::Walk::new(cursor, inner_dir, true);
+    while let Some(item) = walk.next() {
+        let walk::Entry { entry, path, .. } = item?;
+        let rel = paths::relative(inner_dir, &path);
+        let is_dir = entry.is_dir();
+        if stored.contains(&collisions::fold(rel)) {
+            continue;
+        }
+        if is_dir {
+            walk.skip_dir();
+        }
+        if !opts.is_excluded(rel, is_dir) {
+            stale.push((path[1..].to_owned(), is_dir));
+        }
+    }
+    Ok(stale)
+}
+
+/// Copies `host_path` to the image directory `inner_path`, or as much of
+/// it as `fit` allows, merging it with what is there. The directory is
+/// emptied first with `--clean`, and with `--delete` what the host tree
+/// doesn't have is removed afterwards. Omitted files are listed and make
+/// this fail after writing the rest.
+fn write_tree(
+    img_file: &Path, inner_path: &str, host_path: &Path, opts: &WriteTreeOptions, fit: &fit::Fit,
+    sizes: &SizeFormat, warnings: &mut Warnings,
+) -> Result<TreeTotals> {
+    refuse_invalid_dir(img_file, inner_path)?;
+    let fs = open_fs(img_file, !opts.dry_run)?;
+    if opts.clean {
+        let children = rm::children(&fs, inner_path)?;
+        rm::remove_all(&fs, &children, &opts.protect, opts.dry_run)?;
+    }
+    let stats = fs.stats()?;
+    let cluster_size = stats.cluster_size() as u64;
+    let free = stats.free_clusters() as u64 * cluster_size;
+    let plan = fit::plan(host_path, opts, fit, free, cluster_size, sizes)?;
+    let mut cursor = fs.root_dir();
+    if !inner_path.is_empty() {
+        cursor = cursor
+            .open_dir(inner_path)
+            .with_context(|| format!("failed opening directory /{}", inner_path))?;
+    }
+    let inner_dir = format!("/{}", inner_path);
+    check_existing(&cursor, &inner_dir, host_path, opts, plan.selected.as_ref())?;
+    let mut items = Vec::new();
+    if opts.delete || opts.dry_run {
+        fit::collect(host_path, "", "", opts, &mut items)?;
+    }
+    let selected = |item: &fit::Item| {
+        item.is_dir
+            || plan
+                .selected
+                .as_ref()
+                .is_none_or(|selected| selected.contains(&item.rel))
+    };
+    if opts.dry_run {
+        for item in items.iter().filter(|item| selected(item)) {
+            let slash = if item.is_dir { "/" } else { "" };
+            println!(
+                "would write {}{}",
+                inner_join(&inner_dir, &item.stored),
+                slash
+            );
+        }
+        if opts.delete {
+            let stale = stale_entries(&cursor, &inner_dir, &items, opts)?;
+            rm::remove_all(&fs, &stale, &opts.protect, true)?;
+        }
+        return Ok(TreeTotals::default());
+    }
+    let mut totals = TreeTotals::default();
+    write_tree_to_img(
+        cursor.clone(),
+        &inner_dir,
+        host_path.to_owned(),
+        "",
+        opts,
+        plan.selected.as_ref(),
+        warnings,
+        &mut totals,
+    )?;
+    if opts.delete {
+        let stale = stale_entries(&cursor, &inner_dir, &items, opts)?;
+        rm::remove_all(&fs, &stale, &opts.protect, false)?;
+    }
+    drop(cursor);
+    fs.unmount().context("failed flushing the filesystem")?;
+    ntcase::fold_tree(img_file, inner_path)?;
+    dirtimes::set_modified(img_file, &totals.dir_times)?;
+
+    if !plan.omitted.is_empty() {
+        let mut omitted_bytes = 0;
+        for (rel, size) in &plan.omitted {
+            eprintln!("omitted {} ({})", rel, sizes.logical(*size));
+            omitted_bytes += size;
+        }
+        totals.report(sizes);
+        bail!(
+            "Partial import: {} file(s) totalling {} didn't fit and were omitted",
+            plan.omitted.len(),
+            sizes.logical(omitted_bytes)
+        );
+    }
+    Ok(totals)
+}
 
+/// Logs to stderr at the level `-v` and `-q` ask for, without timestamps
+fn init_logging(verbose: u8, quiet: bool) {
+    let level = match (quiet, verbose) {
+        (true, _) => log::LevelFilter::Error,
+        (false, 0) => log::LevelFilter::Warn,
+        (false, 1) => log::LevelFilter::Info,
+        (false, 2) => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .format_timestamp(None)
+        .init();
+}
+
+fn main() {
+    if let Err(err) = try_main() {
+        eprintln!("Error: {:?}", err);
+        std::process::exit(exit::code(&err));
+    }
+}
+
+fn try_main() -> Result<()> {
+    // Checked before parsing, which would insist on an image and a command
+    let mut raw_args = std::env::args_os().skip(1).take_while(|a| a != "--");
+    if raw_args.any(|a| a == "--version-json") {
+        print_version_json();
+        return Ok(());
+    }
+
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        // `--help` and `--version`, which exit successfully
+        Err(err) if !err.use_stderr() => err.exit(),
+        Err(err) => {
+            let _ = err.print();
+            std::process::exit(exit::FAILURE);
+        },
+    };
+    init_logging(args.verbose, args.quiet);
+    let mut report = args.report.as_ref().map(|path| {
+        let names: Vec<String> = Args::into_app()
+            .get_subcommands()
+            .map(|c| c.get_name().to_owned())
+            .collect();
+        // Paths after `--` could be called like a command
+        let command = std::env::args_os()
+            .skip(1)
+            .take_while(|a| a != "--")
+            .filter_map(|a| a.into_string().ok())
+            .find(|a| names.contains(a));
+        report::Report::start(path, command, args.cmd.is_mutating())
+    });
+    let mut changes = None;
+    let result = prepare_and_run(args, report.as_mut(), &mut changes);
+    if let Some(report) = report {
+        report.finish(&result, changes.as_ref())?;
+    }
+    result
+}
+
+/// Opens the image and runs the command, leaving the summary of what a
+/// mutating command changed in `changes`
+fn prepare_and_run(
+    mut args: Args, mut report: Option<&mut report::Report>, changes: &mut Option<delta::Changes>,
+) -> Result<()> {
+    let sizes = args.size_format();
+    let img_file = match (args.img_file.take(), &args.image_by_label) {
+        (Some(path), _) => path,
+        (None, Some(label)) => {
+            let device = bylabel::find(label)?;
+            eprintln!("using {}", device.display());
+            device
+        },
+        (None, None) if !args.cmd.needs_image() => PathBuf::new(),
+        (None, None) => bail!("An image file or --image-by-label is required"),
+    };
+    if let Some(report) = report.as_mut() {
+        report.image(&img_file)?;
+    }
+    clock::init(args.now, args.tz)?;
+    timeout::init(args.io_timeout);
+    if args.cmd.needs_image() && !matches!(args.cmd, Command::Create { .. }) {
+        region::check_image(&img_file, args.cmd.is_mutating())?;
+    }
+    region::init(&img_file, args.offset, args.partition)?;
+    if let Some(report) = report {
+        report.take_before();
+    }
+    let snapshot = match args.snapshot {
+        Some(_) if args.cmd.is_mutating() => {
+            bail!("--snapshot is only for commands that don't change the image")
+        },
+        Some(mode) => Some(snapshot::take(&img_file, mode)?),
+        None => None,
+    };
+    let img_file = match &snapshot {
+        Some(snapshot) => snapshot.path().to_owned(),
+        None => img_file,
+    };
+    surrogates::init(&img_file);
+    let quiet = args.quiet;
+    let delta = args
+        .cmd
+        .is_mutating()
+        .then(|| delta::Delta::start(&img_file))
+        .flatten();
+    let fail_on_warning = args.fail_on_warning.clone();
+    let mut result = run(args, img_file, sizes);
+    if let (Ok(()), Some(categories)) = (&result, &fail_on_warning) {
+        result = warnings::fail_on(categories);
+    }
+    *changes = delta.and_then(delta::Delta::finish);
+    if let (Some(changes), false) = (&*changes, quiet) {
+        changes.print(&sizes);
+    }
+    result
+}
+
+fn run(args: Args, img_file: PathBuf, sizes: SizeFormat) -> Result<()> {
+    let no_clobber = args.no_clobber;
     match args.cmd {
-        Command::Create { force, size } => {
-            let img_file = if force {
-                OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open(args.img_file)?
+        Command::Create {
+            force,
+            size,
+            strict,
+            if_needed,
+            label,
+            volume_id,
+            geometry,
+            boot_from,
+            oem_from_reference,
+            allow_mixed_boot_code,
+            mbr,
+            partition_start,
+        } => {
+            if !region::is_whole_file() {
+                bail!(
+                    "create formats whole image files, not volumes given by --offset or \
+                     --partition"
+                );
+            }
+            geometry.validate()?;
+            let volume_id = volume_id
+                .as_deref()
+                .map(clone::parse_volume_id)
+                .transpose()?;
+            // As `(start_lba, sectors)`
+            let partition = mbr
+                .then(|| {
+                    let start_lba = partition_start.unwrap_or(region::DEFAULT_PARTITION_START);
+                    region::partition_sectors(size, start_lba).map(|sectors| (start_lba, sectors))
+                })
+                .transpose()?;
+            let (start, len) = match partition {
+                Some((start_lba, sectors)) => (start_lba as u64 * 512, Some(sectors as u64 * 512)),
+                None => (0, None),
+            };
+            let open_volume = |write: bool| -> Result<ImgSlice> {
+                Ok(ImgSlice::open_at(&img_file, write, start, len)?)
+            };
+            let reference = boot_from
+                .as_deref()
+                .map(read_reference_boot_sector)
+                .transpose()?;
+            if if_needed && img_file.exists() {
+                let mismatches = format_mismatches(&img_file, size, &geometry, label, partition)?;
+                if mismatches.is_empty() {
+                    eprintln!("already formatted, skipping");
+                    return Ok(());
+                }
+                for mismatch in &mismatches {
+                    eprintln!("reformatting: {}", mismatch);
+                }
+            }
+
+            let mut options = OpenOptions::new();
+            options.write(true);
+            if img_file.exists() {
+                OverwritePolicy::new(force, no_clobber).check(false, img_file.display())?;
+                options.create(true);
             } else {
-                OpenOptions::new()
-                    .write(true)
-                    .create_new(true)
-                    .open(args.img_file)?
-            };
-
-            img_file.set_len(size)?;
-            let buf_file = BufStream::new(img_file);
-            format_volume(
-                &mut StdIoWrapper::from(buf_file),
-                FormatVolumeOptions::new(),
-            )?;
+                options.create_new(true);
+            }
+            options.open(&img_file)?.set_len(size)?;
+            let mut buf_file = StdIoWrapper::from(BufStream::new(open_volume(true)?));
+            let mut format_options = geometry.apply(FormatVolumeOptions::new());
+            if let Some(label) = label {
+                format_options = format_options.volume_label(label);
+            }
+            if let Some(id) = volume_id {
+                format_options = format_options.volume_id(id);
+            }
+            if let Err(err) = format_volume(&mut buf_file, format_options) {
+                match geometry.fat_type {
+                    Some(bits) => {
+                        let (min, max) = estimate::size_range(bits);
+                        bail!(
+                            "Can't format {} bytes as FAT{} ({}). FAT{} needs an image of about \
+                             {} to {} bytes, depending on the cluster size.",
+                            size,
+                            bits,
+                            err,
+                            bits,
+                            min,
+                            max
+                        );
+                    },
+                    None => return Err(err.into()),
+                }
+            }
+            buf_file.flush()?;
+            drop(buf_file);
+
+            if let (Some(reference), Some(path)) = (reference, boot_from) {
+                let mut file = open_volume(true)?;
+                let mut bs = ondisk::BootSector::read(&mut file)?;
+                if reference.is_fat32() != bs.is_fat32() && !allow_mixed_boot_code {
+                    let kind = |bs: &ondisk::BootSector| {
+                        if bs.is_fat32() {
+                            "FAT32"
+                        } else {
+                            "FAT12/16"
+                        }
+                    };
+                    bail!(
+                        "{} has {} boot code, the new image is {}. Pass \
+                         --allow-mixed-boot-code to copy it anyway.",
+                        path.display(),
+                        kind(&reference),
+                        kind(&bs)
+                    );
+                }
+                bs.copy_boot_code(&reference, oem_from_reference);
+                bs.write(&mut file)?;
+                file.sync_all()?;
+            }
+
+            if let Some((start_lba, sectors)) = partition {
+                let mut file = open_volume(true)?;
+                let mut bs = ondisk::BootSector::read(&mut file)?;
+                bs.set_hidden_sectors((start / bs.bytes_per_sector() as u64) as u32);
+                bs.write(&mut file)?;
+                let partition = region::Partition {
+                    number: 1,
+                    kind: region::partition_type(bs.layout()?.fat_type, sectors),
+                    start_lba,
+                    sectors,
+                };
+                let mut disk = OpenOptions::new().write(true).open(&img_file)?;
+                region::write_mbr(&mut disk, &partition)?;
+                disk.sync_all()?;
+            }
+
+            let mut file = open_volume(false)?;
+            let bs = ondisk::BootSector::read(&mut file)?;
+            let problems = ondisk::fat32_layout_problems(&bs);
+            for problem in &problems {
+                warnings::warn(warnings::Category::Fat32Layout, problem);
+            }
+            if strict && !problems.is_empty() {
+                bail!("Reserved area layout rejected by --strict");
+            }
+            Ok(())
+        },
+        Command::CloneImage {
+            target,
+            force,
+            set_volume_id,
+            label,
+            write,
+            data,
+        } => {
+            let c = clone::Customizations {
+                volume_id: set_volume_id,
+                label,
+                write,
+                data,
+            };
+            let overwrite = OverwritePolicy::new(force, no_clobber);
+            clone::run(&img_file, &target, overwrite, &c)
+        },
+        Command::SetLabel { label } => {
+            let mut file = ImgSlice::open(&img_file, true)?;
+            let mut bs = ondisk::BootSector::read(&mut file)?;
+            bs.set_volume_label(label);
+            ondisk::write_root_volume_label(&mut file, label)?;
+            bs.write(&mut file)?;
+            file.sync_all()?;
             Ok(())
         },
-        Command::Info => {
-            let img_file = File::open(args.img_file)?;
-            let buf_file = BufStream::new(img_file);
+        Command::Info { json } => {
+            let mut file = ImgSlice::open(&img_file, false)?;
+            let bs = ondisk::BootSector::read(&mut file)?;
+            warn_total_sectors(&mut file)?;
+            let buf_file = BufStream::new(file);
             let fs = FileSystem::new(buf_file, FsOptions::new())?;
-            println!("fs type:       {:?}", fs.fat_type());
-            println!("volume id:     {:?}", fs.volume_id());
-            println!("volume label:  {:?}", fs.volume_label());
             let stats = fs.stats()?;
-            println!("cluster size:  {:?}", stats.cluster_size());
-            let ct = stats.total_clusters();
-            let cf = stats.free_clusters();
-            println!("cluster count: {:?}", ct);
-            println!("clusters free: {:?}", cf);
-            println!("usage:         {:?}%", ((ct - cf) * 100) / ct);
+            let info = info::Info {
+                fat_type: fs.fat_type(),
+                volume_id: fs.volume_id(),
+                volume_label: fs.volume_label(),
+                cluster_size: stats.cluster_size() as u64,
+                sector_size: bs.bytes_per_sector(),
+                reserved_sectors: bs.reserved_sectors(),
+                fats: bs.fats(),
+                root_entries: bs.root_entries(),
+                total_clusters: stats.total_clusters(),
+                free_clusters: stats.free_clusters(),
+                partition: region::partition(),
+                hidden_sectors: bs.hidden_sectors(),
+            };
+            let output = if json {
+                Output::Json
+            } else {
+                Output::resolve(args.output)
+            };
+            info.print(output, &sizes, args.verbose > 0);
+            Ok(())
+        },
+        Command::Check {
+            fix,
+            fix_policy,
+            portability,
+            strict,
+            limits,
+            profile,
+            advisory,
+            backup_metadata,
+            no_backup,
+        } => {
+            if fix && !no_backup {
+                let saved = backup::save(&img_file, backup_metadata.as_deref())?;
+                eprintln!("saved a metadata backup to {}", saved.display());
+            }
+            let portability = portability.then(|| PathLimits {
+                max_path: limits.max_path.or(Some(limits::PORTABLE_MAX_PATH)),
+                ..limits
+            });
+            let mut img_file = ImgSlice::open(&img_file, fix)?;
+            let profile = profile.map(|p| (p, advisory));
+            let fix = fix.then_some(fix_policy);
+            check::run(&mut img_file, fix, portability, strict, profile)?.finish()
+        },
+        Command::RestoreMetadata { backup_file } => {
+            let (ranges, bytes) = backup::restore(&img_file, &backup_file)?;
+            println!(
+                "restored {} bytes in {} ranges from {}",
+                bytes,
+                ranges,
+                backup_file.display()
+            );
             Ok(())
         },
+        Command::VerifyBoot {
+            expect_bootcode,
+            json,
+        } => {
+            let output = if json {
+                Output::Json
+            } else {
+                Output::resolve(args.output)
+            };
+            verify::run(
+                &mut ImgSlice::open(&img_file, false)?,
+                expect_bootcode,
+                output,
+            )
+        },
+        Command::Health {
+            max_entries,
+            subtree,
+        } => {
+            let inner_path = subtree.resolve(&img_file, None)?;
+            health::run(&img_file, &inner_path, max_entries)
+        },
+        Command::ExportCpio {
+            inner_path,
+            output,
+            force,
+            exclude,
+            skip_windows_artifacts,
+        } => {
+            let inner_path = paths::normalize(&inner_path)?;
+            let opts = cpio::CpioOptions {
+                exclude,
+                skip_windows_artifacts,
+            };
+            match dash_as_stdio(output) {
+                Some(path) => {
+                    let out = create_output(&path, OverwritePolicy::new(force, no_clobber))?;
+                    cpio::run(&img_file, &inner_path, &opts, out)
+                },
+                None => cpio::run(&img_file, &inner_path, &opts, io::stdout().lock()),
+            }
+        },
         Command::Ls {
             inner_path,
             long,
+            numeric_attrs,
             recursive,
+            jsonl,
+            json,
+            limit,
+            count,
+            newer_than,
+            older_than,
+            relative,
+            glob,
         } => {
-            let inner_path = normalize_inner_path(inner_path);
-            let img_file = File::open(args.img_file)?;
-            let buf_file = BufStream::new(img_file);
+            let inner_path = paths::normalize(&inner_path)?;
+            // Only long listings differ between the text formats
+            let output = match args.output {
+                None if long == 0 || jsonl || json || count => Output::Classic,
+                requested => Output::resolve(requested),
+            };
+            let mut file = ImgSlice::open(&img_file, false)?;
+            warn_total_sectors(&mut file)?;
+            let (dir, pattern) = if glob::is_pattern(&inner_path) {
+                (glob::literal_dir(&inner_path), true)
+            } else {
+                (inner_path.as_str(), false)
+            };
+            refuse_file_components(&img_file, dir)?;
+            let buf_file = BufStream::new(file);
             let fs = FileSystem::new(buf_file, FsOptions::new())?;
+            let matches = if pattern {
+                Some(glob::expand(&fs.root_dir(), &inner_path, &glob)?)
+            } else {
+                None
+            };
             let mut cursor = fs.root_dir();
-            if !inner_path.is_empty() {
-                cursor = cursor.open_dir(&inner_path)?;
+            if !dir.is_empty() {
+                cursor = cursor.open_dir(dir)?;
             }
 
-            print_ls(cursor, long, recursive, 0)
+            let opts = LsOptions {
+                long,
+                numeric_attrs,
+                recursive,
+                jsonl: jsonl || output == Output::Json,
+                json,
+                count,
+                relative,
+                glob: matches,
+                modified: when::TimeFilter::new(newer_than, older_than),
+                sizes,
+                output,
+            };
+            print_ls(cursor, &opts, &format!("/{}", dir), limit)
         },
-        Command::Mkdir { inner_path } => {
-            let inner_path = normalize_inner_path(inner_path);
-            let img_file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(false)
-                .open(args.img_file)?;
-            let buf_file = BufStream::new(img_file);
-            let fs = FileSystem::new(buf_file, FsOptions::new())?;
-            fs.root_dir().create_dir(&inner_path)?;
-            Ok(())
+        Command::Cp {
+            src,
+            dst,
+            recursive,
+            preserve,
+            no_overwrite,
+        } => {
+            let src = paths::normalize_entry(&src)?;
+            let dst = paths::normalize_entry(&dst)?;
+            let opts = cp::CpOptions {
+                recursive,
+                preserve,
+                overwrite: OverwritePolicy::new(false, no_clobber || no_overwrite),
+            };
+            cp::run(&img_file, &src, &dst, &opts)
         },
-        Command::Read { inner_path } => {
-            let inner_path = normalize_inner_path(inner_path);
-
-            let img_file = OpenOptions::new()
-                .read(true)
-                .write(false)
-                .create(false)
-                .open(args.img_file)?;
-            let buf_file = BufStream::new(img_file);
-
-            let fs = FileSystem::new(buf_file, FsOptions::new())?;
-            let mut source = fs.root_dir().open_file(&inner_path)?;
-
-            io::copy(&mut source, &mut io::stdout())?;
-
+        Command::Touch {
+            inner_path,
+            mtime,
+            ctime,
+            atime,
+        } => {
+            let inner_path = paths::normalize_entry(&inner_path)?;
+            let opts = touch::TouchOptions {
+                modified: mtime,
+                created: ctime,
+                accessed: atime,
+            };
+            let mut warnings = Warnings::new(args.verbose > 0);
+            touch::run(&img_file, &inner_path, &opts, &mut warnings)?;
+            warnings.finish();
             Ok(())
         },
-        Command::Write {
+        Command::Mv { src, dst, force } => {
+            let src = paths::normalize_entry(&src)?;
+            let dst = paths::normalize_entry(&dst)?;
+            mv::run(
+                &img_file,
+                &src,
+                &dst,
+                OverwritePolicy::new(force, no_clobber),
+            )
+        },
+        Command::Attrib {
+            inner_path,
+            changes,
+            raw,
+            numeric_attrs,
+            json,
+        } => {
+            let inner_path = paths::normalize_entry(&inner_path)?;
+            let json = json || Output::is_json(args.output);
+            attrs::run(&img_file, &inner_path, raw, &changes, numeric_attrs, json)
+        },
+        Command::Serve { listen, inner_path } => {
+            let inner_path = paths::normalize(&inner_path)?;
+            serve::run(&img_file, &inner_path, &listen)
+        },
+        Command::Find {
+            inner_path,
+            subtree,
+            name,
+            kind,
+            larger,
+            smaller,
+            newer,
+            older,
+            since,
+            until,
+            empty,
+            relative,
+            skip_windows_artifacts,
+            json,
+        } => {
+            let inner_path = subtree.resolve(&img_file, inner_path.as_deref())?;
+            let modified = match (since, until) {
+                (None, None) => when::TimeFilter::new(newer, older),
+                _ => when::TimeFilter::window(since, until),
+            };
+            let opts = find::FindOptions {
+                name,
+                kind,
+                larger,
+                smaller,
+                modified,
+                empty,
+                relative,
+                skip_windows_artifacts,
+                json: json || Output::is_json(args.output),
+            };
+            find::run(&img_file, &inner_path, &opts)
+        },
+        Command::Du {
             inner_path,
+            subtree,
+            apparent_size,
+            summarize,
+            skip_windows_artifacts,
+        } => {
+            let inner_path = subtree.resolve(&img_file, inner_path.as_deref())?;
+            let mut img_file = ImgSlice::open(&img_file, false)?;
+            let opts = du::DuOptions {
+                apparent_size,
+                summarize,
+                skip_windows_artifacts,
+                sizes,
+            };
+            du::run(&mut img_file, &inner_path, &opts)
+        },
+        Command::Estimate {
             host_path,
+            cluster_size,
+            fat_type,
+            margin,
+        } => {
+            let opts = estimate::EstimateOptions {
+                cluster_size,
+                fat_type,
+                margin,
+            };
+            estimate::run(&host_path, &opts, &sizes, Output::resolve(args.output))
+        },
+        Command::JsonSchema { document } => {
+            let schema = match document {
+                Some(document) => document.to_json(),
+                None => schema::all(),
+            };
+            println!("{}", schema);
+            Ok(())
+        },
+        Command::CleanEmpty {
+            inner_path,
+            dry_run,
+            keep,
+            protect,
+        } => {
+            let inner_path = paths::normalize(&inner_path)?;
+            let opts = clean::CleanOptions {
+                dry_run,
+                keep,
+                protect,
+            };
+            clean::run(&img_file, &inner_path, &opts)
+        },
+        Command::Rm {
+            inner_path,
+            recursive,
+            force,
+            interactive,
+            dry_run,
+            protect,
+            glob,
+            fan_out,
+        } => {
+            let inner_path = paths::normalize(&inner_path)?;
+            let opts = rm::RmOptions {
+                recursive,
+                force,
+                interactive,
+                dry_run,
+                protect,
+            };
+            fan_out.run(&img_file, |img| {
+                if glob::is_pattern(&inner_path) {
+                    return rm::run_matches(img, &inner_path, &opts, &glob);
+                }
+                rm::run(img, &inner_path, &opts)
+            })
+        },
+        Command::Prune {
+            inner_path,
+            keep_newest,
+            max_total_size,
+            older_than,
+            recursive,
+            dry_run,
+            protect,
+        } => {
+            if keep_newest.is_none() && max_total_size.is_none() && older_than.is_none() {
+                bail!("prune needs --keep-newest, --max-total-size or --older-than");
+            }
+            let inner_path = paths::normalize(&inner_path)?;
+            let opts = prune::PruneOptions {
+                keep_newest,
+                max_total_size,
+                older_than: older_than.map(|t| t.local_secs()),
+                recursive,
+                dry_run,
+                protect,
+            };
+            prune::run(&img_file, &inner_path, &opts, &sizes)
+        },
+        Command::Rename {
+            inner_path,
+            pattern,
+            replacement,
+            regex,
+            recursive,
+            dry_run,
+        } => {
+            let inner_path = paths::normalize(&inner_path)?;
+            let matcher = rename::Matcher::new(&pattern, regex)?;
+            let opts = rename::RenameOptions { recursive, dry_run };
+            rename::run(&img_file, &inner_path, &matcher, &replacement, &opts)
+        },
+        Command::Mkdir {
+            inner_path,
+            attributes,
+            fan_out,
+        } => {
+            let inner_path = paths::normalize_entry(&inner_path)?;
+            fan_out.run(&img_file, |img| mkdir(img, &inner_path, attributes))
+        },
+        Command::Read {
+            inner_path,
+            text_mode,
+            output,
+            force,
+            gunzip,
+            glob,
         } => {
-            let inner_path = normalize_inner_path(inner_path);
-
-            let img_file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(false)
-                .open(args.img_file)?;
-            let buf_file = BufStream::new(img_file);
-
-            let mut source: Box<dyn io::BufRead> = if let Some(p) = host_path {
-                let source_file = File::open(p)?;
-                Box::new(io::BufReader::new(source_file))
+            let inner_path = paths::normalize_entry(&inner_path)?;
+            let targets = if glob::is_pattern(&inner_path) {
+                read_targets(&img_file, &inner_path, output, &glob)?
             } else {
-                Box::new(io::BufReader::new(io::stdin()))
+                vec![(inner_path, output)]
             };
 
-            let fs = FileSystem::new(buf_file, FsOptions::new())?;
-            let mut target_file = fs.root_dir().create_file(&inner_path)?;
-            target_file.truncate()?;
+            for (inner_path, output) in targets {
+                let mut file = ImgSlice::open(&img_file, false)?;
+                warn_total_sectors(&mut file)?;
+                let (parent, name) = inner_path.rsplit_once('/').unwrap_or(("", &inner_path));
+                refuse_file_components(&img_file, parent)?;
+                refuse_invalid_start(&mut file, parent, Some(name))?;
+                let buf_file = BufStream::new(file);
 
-            io::copy(&mut source, &mut target_file)?;
+                let fs = FileSystem::new(buf_file, FsOptions::new())?;
+                let file = fs.root_dir().open_file(&inner_path)?;
+                let mut source: Box<dyn Read> = if gunzip {
+                    Box::new(MultiGzDecoder::new(file))
+                } else {
+                    Box::new(file)
+                };
 
+                let bytes = match dash_as_stdio(output) {
+                    Some(path) => {
+                        let policy = OverwritePolicy::new(force, no_clobber);
+                        let mut out = create_output(&path, policy)?;
+                        text::copy(text_mode, &mut source, &mut out)?
+                    },
+                    None => text::copy(text_mode, &mut source, &mut io::stdout())?,
+                };
+                report::read(format!("/{}", inner_path), bytes);
+            }
             Ok(())
         },
+        Command::Write {
+            inner_path,
+            host_path,
+            text_mode,
+            pad_to_cluster,
+            verify,
+            allow_empty,
+            gzip,
+            keep_name,
+            attributes,
+            preserve_times,
+            fan_out,
+        } => {
+            let mut inner_path = paths::normalize_entry(&inner_path)?;
+            if gzip && !keep_name {
+                inner_path = gzip::stored_name(&inner_path);
+            }
+            let host_path = dash_as_stdio(host_path);
+            let mut warnings = Warnings::new(args.verbose > 0);
+            let times = match (&host_path, preserve_times) {
+                (Some(host), true) => {
+                    let meta = fs::metadata(host)
+                        .with_context(|| format!("failed reading {}", host.display()))?;
+                    Some(host_times(host, &meta, false, &mut warnings)?)
+                },
+                (None, true) => bail!("--preserve-times needs an input file, not stdin"),
+                (_, false) => None,
+            };
+            warnings.finish();
+            let source = WriteSource::new(host_path, fan_out.is_multi())?;
+            let opts = WriteOptions {
+                text_mode,
+                pad: pad_to_cluster.map(|byte| byte.unwrap_or(0)),
+                verify,
+                allow_empty,
+                gzip,
+                overwrite: OverwritePolicy::new(false, no_clobber),
+                attributes,
+                times,
+            };
+            fan_out.run(&img_file, |img| {
+                let mut totals = gzip::Totals::default();
+                if let Some(compressed) = write_file(img, &inner_path, &source, &opts)? {
+                    totals.add(compressed);
+                }
+                totals.report(&sizes);
+                Ok(())
+            })
+        },
         Command::ReadTree {
             inner_path,
             host_path,
             force,
+            on_collision,
+            assume_case_sensitive,
+            name_map,
+            preserve_times,
+            preserve_attrs,
+            file_mode,
+            dir_mode,
+            utc,
+            surrogate_policy,
         } => {
-            let inner_path = normalize_inner_path(inner_path);
-
-            let img_file = OpenOptions::new()
-                .read(true)
-                .write(false)
-                .create(false)
-                .open(args.img_file)?;
-            let buf_file = BufStream::new(img_file);
+            let inner_path = paths::normalize(&inner_path)?;
+            let name_map = name_map
+                .as_deref()
+                .map(namemap::NameMap::read)
+                .transpose()?;
+            let name_map = name_map.as_ref();
+            let overwrite = OverwritePolicy::new(force, no_clobber);
+            let case_insensitive = cfg!(any(windows, target_os = "macos"));
+            let on_collision = (case_insensitive && !assume_case_sensitive).then_some(on_collision);
+            let preserve = Preserve {
+                times: preserve_times.then(|| if utc { 0 } else { clock::get().utc_offset() }),
+                attrs: preserve_attrs,
+                file_mode,
+                dir_mode,
+            };
+            if !cfg!(unix) && (file_mode.is_some() || dir_mode.is_some()) {
+                warnings::warn(
+                    warnings::Category::ModeIgnored,
+                    "ignoring --file-mode and --dir-mode",
+                );
+            }
 
-            todo!("ReadTree");
+            let mut file = ImgSlice::open(&img_file, false)?;
+            warn_total_sectors(&mut file)?;
+            let buf_file = BufStream::new(file);
+            let fs = FileSystem::new(buf_file, FsOptions::new())?;
+            let root = fs.root_dir();
+            if inner_path.is_empty() {
+                return read_tree(
+                    root,
+                    "/",
+                    &host_path,
+                    overwrite,
+                    on_collision,
+                    name_map,
+                    preserve,
+                    surrogate_policy,
+                );
+            }
+            match root.open_dir(&inner_path) {
+                Ok(dir) => {
+                    let inner_dir = format!("/{}", inner_path);
+                    read_tree(
+                        dir,
+                        &inner_dir,
+                        &host_path,
+                        overwrite,
+                        on_collision,
+                        name_map,
+                        preserve,
+                        surrogate_policy,
+                    )
+                },
+                Err(_) => {
+                    let mut source = root
+                        .open_file(&inner_path)
+                        .with_context(|| format!("failed opening /{}", inner_path))?;
+                    let mut out = io::BufWriter::new(create_output(&host_path, overwrite)?);
+                    let context = || format!("failed reading /{}", inner_path);
+                    let bytes = io::copy(&mut source, &mut out).with_context(context)?;
+                    io::Write::flush(&mut out).with_context(context)?;
+                    drop(out);
+                    let (parent, name) = inner_path.rsplit_once('/').unwrap_or(("", &inner_path));
+                    let dir = if parent.is_empty() {
+                        root.clone()
+                    } else {
+                        root.open_dir(parent).with_context(context)?
+                    };
+                    let entry = dir
+                        .iter()
+                        .filter_map(|e| e.ok())
+                        .find(|e| collisions::fold(&e.file_name()) == collisions::fold(name));
+                    if let Some(entry) = entry {
+                        preserve.apply(&host_path, &entry.modified(), entry.attributes().bits())?;
+                    }
+                    report::read(format!("/{}", inner_path), bytes);
+                    Ok(())
+                },
+            }
         },
         Command::WriteTree {
             inner_path,
             host_path,
+            text_mode,
+            text_glob,
+            gzip_glob,
+            keep_name,
+            on_collision,
+            limits,
+            preserve_times,
+            strict_times,
+            stable_dir_times,
+            one_file_system,
+            skip_invalid_names,
+            exclude,
+            include,
+            include_sidecars,
+            nfc_names,
+            clean,
+            delete,
+            dry_run,
+            no_overwrite,
+            protect,
+            fit,
+            fan_out,
         } => {
-            let inner_path = normalize_inner_path(inner_path);
-
-            let img_file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(false)
-                .open(args.img_file)?;
-            let buf_file = BufStream::new(img_file);
-            let fs = FileSystem::new(buf_file, FsOptions::new())?;
-            let mut cursor = fs.root_dir();
-            if !inner_path.is_empty() {
-                cursor = cursor.open_dir(&inner_path)?;
-            }
-            write_tree_to_img(cursor, host_path)
+            let inner_path = paths::normalize(&inner_path)?;
+            let host_path = hostpath::extended(&host_path)
+                .with_context(|| format!("failed opening {}", host_path.display()))?;
+            let same_filesystem = if one_file_system {
+                let same = hostpath::SameFilesystem::new(&host_path)
+                    .with_context(|| format!("failed reading {}", host_path.display()))?;
+                Some(same)
+            } else {
+                None
+            };
+            let opts = WriteTreeOptions {
+                text_mode,
+                text_globs: text_glob,
+                gzip_globs: gzip_glob,
+                keep_name,
+                collisions: collisions::plan(&host_path, on_collision, nfc_names)?,
+                limits,
+                preserve_times,
+                strict_times,
+                one_file_system: same_filesystem,
+                skip_invalid_names,
+                excludes: exclude,
+                includes: include,
+                include_sidecars,
+                nfc_names,
+                no_overwrite: no_overwrite || no_clobber,
+                clean,
+                delete,
+                protect,
+                dry_run,
+            };
+            fan_out.run(&img_file, |img| {
+                let mut warnings = Warnings::new(args.verbose > 0);
+                let saved = (stable_dir_times && !dry_run)
+                    .then(|| dirtimes::save(img, &inner_path))
+                    .transpose()?;
+                let totals = write_tree(
+                    img,
+                    &inner_path,
+                    &host_path,
+                    &opts,
+                    &fit,
+                    &sizes,
+                    &mut warnings,
+                )?;
+                if let Some(saved) = &saved {
+                    let restored = dirtimes::restore(img, saved)?;
+                    log::info!("restored the timestamps of {} directories", restored);
+                }
+                totals.report(&sizes);
+                let skipped = warnings.count(warnings::Category::NonUtf8Name);
+                warnings.finish();
+                if skipped > 0 {
+                    return Err(exit::Skipped(skipped).into());
+                }
+                Ok(())
+            })
+        },
+        Command::ClusterRead {
+            cluster,
+            count,
+            output,
+            force,
+        } => match dash_as_stdio(output) {
+            Some(path) => {
+                let mut out = create_output(&path, OverwritePolicy::new(force, no_clobber))?;
+                cluster::read(&img_file, cluster, count, &mut out)
+            },
+            None => cluster::read(&img_file, cluster, count, &mut io::stdout().lock()),
+        },
+        Command::Extents {
+            inner_path,
+            dd_script,
+            json,
+        } => {
+            let inner_path = paths::normalize_entry(&inner_path)?;
+            let json = json || Output::is_json(args.output);
+            let format = match (dd_script, json) {
+                (true, _) => extents::Format::DdScript,
+                (_, true) => extents::Format::Json,
+                _ => extents::Format::Text,
+            };
+            extents::run(&img_file, &inner_path, format)
+        },
+        Command::ImportExtents {
+            host_path,
+            inner_path,
+        } => {
+            let inner_path = paths::normalize_entry(&inner_path)?;
+            let overwrite = OverwritePolicy::new(false, no_clobber);
+            extents::import(&img_file, &host_path, &inner_path, overwrite)
+        },
+        Command::ClusterWrite {
+            cluster,
+            host_path,
+            allow_allocated,
+        } => {
+            let data = match dash_as_stdio(host_path) {
+                Some(path) => fs::read(path)?,
+                None => {
+                    let mut data = Vec::new();
+                    io::stdin().read_to_end(&mut data)?;
+                    data
+                },
+            };
+            let allow_allocated = OverwritePolicy::new(false, no_clobber).allows(allow_allocated);
+            cluster::write(&img_file, cluster, &data, allow_allocated)
+        },
+        Command::Watch {
+            inner_path,
+            host_path,
+            interval,
+            delete,
+        } => {
+            let inner_path = paths::normalize(&inner_path)?;
+            let opts = watch::WatchOptions {
+                interval: Duration::from_millis(interval),
+                delete,
+                overwrite: OverwritePolicy::new(false, no_clobber),
+            };
+            watch::run(&img_file, &inner_path, &host_path, &opts)
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault::{self, Fault};
+    use crate::schema::Document;
+
+    /// A new 4M image file for the test `name`, removed when dropped
+    struct TestImage(PathBuf);
+
+    impl TestImage {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "fatimg-unit-{}-{}.img",
+                std::process::id(),
+                name
+            ));
+            File::create(&path).unwrap().set_len(4 << 20).unwrap();
+            let mut file = StdIoWrapper::from(BufStream::new(ImgSlice::open(&path, true).unwrap()));
+            format_volume(&mut file, FormatVolumeOptions::new()).unwrap();
+            Self(path)
+        }
+
+        /// A copy of the image for the test `name`
+        fn copy(&self, name: &str) -> Self {
+            let copy = Self::new(name);
+            fs::copy(&self.0, &copy.0).unwrap();
+            copy
+        }
+
+        /// The contents of the file `inner_path`, `None` if there's none
+        fn read(&self, inner_path: &str) -> Option<Vec<u8>> {
+            let fs = open_fs(&self.0, false).unwrap();
+            let mut file = fs.root_dir().open_file(inner_path).ok()?;
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents)
+                .unwrap_or_else(|err| panic!("reading /{} failed: {}", inner_path, err));
+            Some(contents)
+        }
+    }
+
+    impl Drop for TestImage {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn write(image: &TestImage, inner_path: &str, contents: &[u8]) -> Result<()> {
+        let source = WriteSource::Buffered(contents.to_vec());
+        let opts = WriteOptions {
+            allow_empty: true,
+            ..WriteOptions::default()
+        };
+        write_file(&image.0, inner_path, &source, &opts).map(drop)
+    }
+
+    #[test]
+    fn a_crash_never_leaves_a_partial_file_looking_whole() {
+        let old = [b'o'; 3000];
+        let new = [b'n'; 20_000];
+        for replacing in [false, true] {
+            let base = TestImage::new(&format!("crash-base-{}", replacing));
+            if replacing {
+                write(&base, "F.BIN", &old).unwrap();
+            }
+            // How many bytes the whole write takes
+            let probe = base.copy(&format!("crash-probe-{}", replacing));
+            fault::inject(Fault::Crash(u64::MAX));
+            write(&probe, "F.BIN", &new).unwrap();
+            let total = match fault::clear() {
+                Some(Fault::Crash(left)) => u64::MAX - left,
+                fault => panic!("{:?}", fault),
+            };
+            assert_eq!(probe.read("F.BIN").unwrap(), new);
+
+            for at in (0..total).step_by((total / 64).max(1) as usize) {
+                let image = base.copy(&format!("crash-{}-{}", replacing, at));
+                fault::inject(Fault::Crash(at));
+                // Whatever the write makes of the lost writes, only the
+                // image left behind counts
+                let _ = write(&image, "F.BIN", &new);
+                fault::clear();
+                match image.read("F.BIN") {
+                    None => assert!(!replacing, "lost /F.BIN crashing at {}", at),
+                    Some(contents) => assert!(
+                        contents.is_empty() || contents == new || contents == old,
+                        "a crash at byte {} of {} left {} bytes",
+                        at,
+                        total,
+                        contents.len()
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Options of a plain `write-tree`
+    fn tree_options() -> WriteTreeOptions {
+        WriteTreeOptions {
+            text_mode: TextMode::None,
+            text_globs: Vec::new(),
+            gzip_globs: Vec::new(),
+            keep_name: false,
+            collisions: HashMap::new(),
+            limits: PathLimits {
+                max_path: None,
+                max_depth: None,
+            },
+            preserve_times: false,
+            strict_times: false,
+            one_file_system: None,
+            skip_invalid_names: false,
+            excludes: Vec::new(),
+            includes: Vec::new(),
+            include_sidecars: false,
+            nfc_names: false,
+            no_overwrite: false,
+            clean: false,
+            delete: false,
+            protect: protect::ProtectArgs::default(),
+            dry_run: false,
+        }
+    }
+
+    #[test]
+    fn failed_writes_name_the_paths() {
+        let base = TestImage::new("fail-base");
+        let contents = [b'x'; 50_000];
+        let mut blamed = HashSet::new();
+        for at in (0..60_000).step_by(4096) {
+            let image = base.copy(&format!("fail-write-{}", at));
+            fault::inject(Fault::Fail(at));
+            let result = write(&image, "F.BIN", &contents);
+            fault::clear();
+            let err = match result {
+                Ok(()) => continue,
+                Err(err) => err,
+            };
+            let rendered = format!("{:#}", err);
+            assert!(rendered.contains("injected write failure"), "{}", rendered);
+            let stage = ["failed writing /F.BIN", "failed flushing the filesystem"]
+                .into_iter()
+                .find(|stage| rendered.starts_with(stage));
+            blamed.insert(stage.unwrap_or_else(|| panic!("failing at {}: {}", at, rendered)));
+        }
+        assert!(blamed.contains("failed writing /F.BIN"), "{:?}", blamed);
+
+        let host = std::env::temp_dir().join(format!("fatimg-unit-{}-tree", std::process::id()));
+        fs::create_dir_all(host.join("EFI/BOOT")).unwrap();
+        fs::write(host.join("EFI/BOOT/GRUB.CFG"), contents).unwrap();
+        let file = format!(
+            "failed writing /EFI/BOOT/GRUB.CFG from {}",
+            host.join("EFI/BOOT/GRUB.CFG").display()
+        );
+        let mut named_file = false;
+        for at in (0..60_000).step_by(4096) {
+            let image = base.copy(&format!("fail-tree-{}", at));
+            let fs = open_fs_rw(&image.0).unwrap();
+            fault::inject(Fault::Fail(at));
+            let result = write_tree_to_img(
+                fs.root_dir(),
+                "/",
+                host.clone(),
+                "",
+                &tree_options(),
+                None,
+                &mut Warnings::new(false),
+                &mut TreeTotals::default(),
+            );
+            fault::clear();
+            let err = match result {
+                Ok(()) => continue,
+                Err(err) => err,
+            };
+            let rendered = format!("{:#}", err);
+            assert!(
+                [
+                    "failed creating directory /EFI:",
+                    "failed creating directory /EFI/BOOT:",
+                    &file,
+                ]
+                .iter()
+                .any(|stage| rendered.starts_with(stage)),
+                "failing at {}: {}",
+                at,
+                rendered
+            );
+            named_file |= rendered.starts_with(&file);
+        }
+        assert!(named_file);
+        fs::remove_dir_all(&host).unwrap();
+    }
+
+    #[test]
+    fn version_json_matches_the_schema() {
+        assert_eq!(Document::Version.validate(&version_json()), Ok(()));
+    }
+
+    /// The subcommand name of each command. Without a wildcard, a new
+    /// command doesn't compile until it is added here and to `COMMANDS`.
+    fn command_name(cmd: &Command) -> &'static str {
+        match cmd {
+            Command::Create { .. } => "create",
+            Command::CloneImage { .. } => "clone",
+            Command::Info { .. } => "info",
+            Command::SetLabel { .. } => "set-label",
+            Command::Check { .. } => "check",
+            Command::RestoreMetadata { .. } => "restore-metadata",
+            Command::VerifyBoot { .. } => "verify-boot",
+            Command::Health { .. } => "health",
+            Command::Attrib { .. } => "attrib",
+            Command::Ls { .. } => "ls",
+            Command::Serve { .. } => "serve",
+            Command::Du { .. } => "du",
+            Command::Find { .. } => "find",
+            Command::Estimate { .. } => "estimate",
+            Command::JsonSchema { .. } => "json-schema",
+            Command::CleanEmpty { .. } => "clean-empty",
+            Command::Rm { .. } => "rm",
+            Command::Prune { .. } => "prune",
+            Command::Rename { .. } => "rename",
+            Command::Mv { .. } => "mv",
+            Command::Cp { .. } => "cp",
+            Command::Touch { .. } => "touch",
+            Command::Mkdir { .. } => "mkdir",
+            Command::Read { .. } => "read",
+            Command::Write { .. } => "write",
+            Command::ReadTree { .. } => "read-tree",
+            Command::ExportCpio { .. } => "export-cpio",
+            Command::WriteTree { .. } => "write-tree",
+            Command::ClusterRead { .. } => "cluster-read",
+            Command::Extents { .. } => "extents",
+            Command::ImportExtents { .. } => "import-extents",
+            Command::ClusterWrite { .. } => "cluster-write",
+            Command::Watch { .. } => "watch",
+        }
+    }
+
+    /// Every command, in the order `Command` declares them
+    const COMMANDS: [&str; 33] = [
+        "create",
+        "clone",
+        "info",
+        "set-label",
+        "check",
+        "restore-metadata",
+        "verify-boot",
+        "health",
+        "attrib",
+        "ls",
+        "serve",
+        "du",
+        "find",
+        "estimate",
+        "json-schema",
+        "clean-empty",
+        "rm",
+        "prune",
+        "rename",
+        "mv",
+        "cp",
+        "touch",
+        "mkdir",
+        "read",
+        "write",
+        "read-tree",
+        "export-cpio",
+        "write-tree",
+        "cluster-read",
+        "extents",
+        "import-extents",
+        "cluster-write",
+        "watch",
+    ];
+
+    #[test]
+    fn version_json_lists_every_command() {
+        let version = version_json();
+        let listed: Vec<&str> = match version.get("subcommands") {
+            Some(json::Value::Array(names)) => names
+                .iter()
+                .map(|name| match name {
+                    json::Value::String(name) => name.as_str(),
+                    other => panic!("not a name: {:?}", other),
+                })
+                .collect(),
+            other => panic!("no subcommands: {:?}", other),
+        };
+        assert_eq!(listed, COMMANDS);
+        let app = Args::into_app();
+        let parsed: Vec<&str> = app
+            .get_subcommands()
+            .map(|c| c.get_name())
+            .filter(|&name| name != "help")
+            .collect();
+        assert_eq!(parsed, COMMANDS);
+        // The commands that parse without arguments come back as theirs
+        for name in COMMANDS {
+            if let Ok(args) = Args::try_parse_from(["fatimg", "disk.img", name]) {
+                assert_eq!(command_name(&args.cmd), name);
+            }
+        }
+    }
+
+    #[test]
+    fn json_schema_takes_a_document() {
+        let args = Args::try_parse_from(["fatimg", "json-schema", "verify-boot"]).unwrap();
+        assert!(matches!(
+            args.cmd,
+            Command::JsonSchema {
+                document: Some(Document::VerifyBoot)
+            }
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+//! Moving and renaming single entries inside the image
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::ondisk::{BootSector, Fat, RawDir, DOTDOT_NAME};
+use crate::overwrite::OverwritePolicy;
+use crate::region::ImgSlice;
+use crate::{delta, exit};
+use crate::{open_fs_rw, overlap, parent_of, refuse_file_components, ImgDir, ImgFs, Overlap};
+
+fn name_of(inner_path: &str) -> &str {
+    inner_path.rsplit('/').next().unwrap_or(inner_path)
+}
+
+fn open_parent<'a>(fs: &'a ImgFs, inner_path: &str) -> Result<ImgDir<'a>> {
+    let parent = parent_of(inner_path);
+    if parent.is_empty() {
+        return Ok(fs.root_dir());
+    }
+    fs.root_dir()
+        .open_dir(parent)
+        .with_context(|| format!("failed opening directory /{}", parent))
+}
+
+/// Points `..` of the moved directory `inner_path` at its new parent.
+/// Done on the raw entries so that it holds whatever fatfs does.
+fn fix_dotdot(img_file: &Path, inner_path: &str) -> Result<()> {
+    let mut img = ImgSlice::open(img_file, true)?;
+    let layout = BootSector::read(&mut img)?.layout()?;
+    let fat = Fat::read(&mut img, &layout)?;
+    let parent = parent_of(inner_path);
+    // `..` of directories in the root refers to cluster 0, even on FAT32
+    let parent_cluster = match parent {
+        "" => 0,
+        _ => {
+            let grandparent = RawDir::read_path(&mut img, &layout, &fat, parent_of(parent))?;
+            match grandparent.lookup(name_of(parent)) {
+                Some(entry) => entry.first_cluster(),
+                None => bail!("/{}: directory not found", parent),
+            }
+        },
+    };
+    let dir = RawDir::read_path(&mut img, &layout, &fat, inner_path)?;
+    if let Some(entry) = dir.entries.iter().find(|e| e.name_bytes() == DOTDOT_NAME) {
+        if entry.first_cluster() != parent_cluster {
+            let mut entry = entry.clone();
+            entry.set_first_cluster(parent_cluster);
+            entry.write(&mut img)?;
+            img.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves the entry at the normalized image path `src` to `dst`, which is
+/// the new path and not a directory to move into. An existing `dst` is
+/// only replaced with `--force`, and only if it's a file or an empty
+/// directory.
+pub fn run(img_file: &Path, src: &str, dst: &str, overwrite: OverwritePolicy) -> Result<()> {
+    refuse_file_components(img_file, parent_of(src))?;
+    refuse_file_components(img_file, parent_of(dst))?;
+    let same_entry = match overlap(img_file, src, dst)? {
+        Overlap::Inside => bail!("Can't move /{} into itself", src),
+        relation => relation == Overlap::Same,
+    };
+    if same_entry && src == dst {
+        return Ok(());
+    }
+    let fs = open_fs_rw(img_file)?;
+    let root = fs.root_dir();
+    let is_dir = match root.open_dir(src) {
+        Ok(_) => true,
+        Err(_) if root.open_file(src).is_ok() => false,
+        Err(_) => return Err(exit::NotFound(format!("/{}", src)).into()),
+    };
+    let src_parent = open_parent(&fs, src)?;
+    let dst_parent = open_parent(&fs, dst)?;
+
+    if same_entry {
+        // Only the case changes, or `dst` is the 8.3 alias of `src`, which
+        // FAT sees as the same name. Going through another name makes
+        // fatfs store the new one.
+        let temp = format!("{}.mv~", name_of(src));
+        src_parent
+            .rename(name_of(src), &src_parent, &temp)
+            .with_context(|| format!("failed renaming /{}", src))?;
+        src_parent
+            .rename(&temp, &dst_parent, name_of(dst))
+            .with_context(|| format!("failed renaming /{} to /{}", src, dst))?;
+    } else {
+        let existing = match root.open_dir(dst) {
+            Ok(dir) => Some(
+                dir.iter()
+                    .any(|e| e.map_or(true, |e| e.file_name() != "." && e.file_name() != "..")),
+            ),
+            Err(_) if root.open_file(dst).is_ok() => Some(false),
+            Err(_) => None,
+        };
+        if let Some(non_empty) = existing {
+            overwrite.check(false, format_args!("/{}", dst))?;
+            if non_empty {
+                bail!("/{}: directory not empty, not replacing it", dst);
+            }
+            let dst_is_dir = root.open_dir(dst).is_ok();
+            dst_parent
+                .remove(name_of(dst))
+                .with_context(|| format!("failed removing /{}", dst))?;
+            delta::removed(dst_is_dir);
+        }
+        src_parent
+            .rename(name_of(src), &dst_parent, name_of(dst))
+            .with_context(|| format!("failed moving /{} to /{}", src, dst))?;
+    }
+    eprintln!("moved /{} -> /{}", src, dst);
+    drop((src_parent, dst_parent, root));
+    fs.unmount().context("failed flushing the filesystem")?;
+
+    if is_dir && parent_of(src) != parent_of(dst) {
+        fix_dotdot(img_file, dst)?;
+    }
+    Ok(())
+}
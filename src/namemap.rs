@@ -0,0 +1,157 @@
+//! `read-tree --name-map`: host names to extract image entries as, for
+//! files old DOS tools left with only `~1` style names.
+//!
+//! The map file has one `<image name>=<host name>` line per entry. The
+//! image name is either a path from the root of the image, like
+//! `/DOCS/REPORT~1.TXT`, or a name in the directory of the last `[/DOCS]`
+//! line, the root before any. Image names match regardless of case, like
+//! FAT compares them. `=` can't be part of a short name, so it needs no
+//! escaping. Empty lines and lines beginning with `#` are ignored.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use fatfs::Dir;
+
+use crate::collisions::fold;
+use crate::warnings::{self, Category};
+use crate::{inner_join, paths, walk};
+
+pub struct NameMap {
+    /// Host names by folded image path
+    names: HashMap<String, String>,
+    /// Image paths as the map file gives them, by folded path
+    given: BTreeMap<String, String>,
+}
+
+/// Whether `name` can be a single host path component
+fn is_host_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', '\0'])
+}
+
+impl NameMap {
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed reading name map {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("invalid name map {}", path.display()))
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut map = Self {
+            names: HashMap::new(),
+            given: BTreeMap::new(),
+        };
+        let mut dir = "/".to_owned();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let section =
+                    paths::normalize(section).with_context(|| format!("line {}", i + 1))?;
+                dir = format!("/{}", section);
+                continue;
+            }
+            let (image, host) = match line.split_once('=') {
+                Some((image, host)) => (image.trim(), host.trim()),
+                None => bail!("line {}: expected <image name>=<host name>", i + 1),
+            };
+            if !is_host_name(host) {
+                bail!("line {}: invalid host name {:?}", i + 1, host);
+            }
+            let image = if image.starts_with('/') {
+                image.to_owned()
+            } else {
+                inner_join(&dir, image)
+            };
+            let path = paths::normalize_entry(&image).with_context(|| format!("line {}", i + 1))?;
+            let path = format!("/{}", path);
+            let key = fold(&path);
+            if map.names.contains_key(&key) {
+                bail!("line {}: {} is mapped more than once", i + 1, path);
+            }
+            map.names.insert(key.clone(), host.to_owned());
+            map.given.insert(key, path);
+        }
+        Ok(map)
+    }
+
+    /// The host name for the image entry `path`
+    pub fn get(&self, path: &str) -> Option<&str> {
+        self.names.get(&fold(path)).map(String::as_str)
+    }
+
+    /// Fails if a mapped name would take the place of another entry of the
+    /// same directory, ignoring case with `case_insensitive`, before
+    /// anything is extracted. Map entries that match nothing below
+    /// `cursor`, the image directory `inner_dir`, are warned about.
+    pub fn check<IO: fatfs::ReadWriteSeek, TP: fatfs::TimeProvider, OCC: fatfs::OemCpConverter>(
+        &self, cursor: &Dir<'_, IO, TP, OCC>, inner_dir: &str, case_insensitive: bool,
+    ) -> Result<()>
+    where
+        fatfs::Error<IO::Error>: std::error::Error + Send + Sync + 'static,
+    {
+        let mapped_dirs: HashSet<String> = self
+            .names
+            .keys()
+            .map(|key| key.rsplit_once('/').map_or("", |(dir, _)| dir).to_owned())
+            .collect();
+        let mut unmatched: HashSet<&String> = self.names.keys().collect();
+
+        // `(host name, image path, mapped)` of the directories that have
+        // mapped entries
+        let mut dirs: BTreeMap<String, Vec<(String, String, bool)>> = BTreeMap::new();
+        for item in walk::Walk::new(cursor, inner_dir, true) {
+            let walk::Entry { path, .. } = item?;
+            let (parent, name) = path.rsplit_once('/').expect("Walk paths are absolute");
+            let folded_parent = fold(parent);
+            if !mapped_dirs.contains(&folded_parent) {
+                continue;
+            }
+            let entry = match self.get(&path) {
+                Some(host) => {
+                    unmatched.remove(&fold(&path));
+                    (host.to_owned(), path.clone(), true)
+                },
+                None => (name.to_owned(), path.clone(), false),
+            };
+            dirs.entry(folded_parent).or_default().push(entry);
+        }
+
+        let mut errors = Vec::new();
+        for entries in dirs.values() {
+            let mut by_name: BTreeMap<String, Vec<&(String, String, bool)>> = BTreeMap::new();
+            for entry in entries {
+                let name = if case_insensitive {
+                    fold(&entry.0)
+                } else {
+                    entry.0.clone()
+                };
+                by_name.entry(name).or_default().push(entry);
+            }
+            for group in by_name.values() {
+                if group.len() > 1 && group.iter().any(|(_, _, mapped)| *mapped) {
+                    let paths: Vec<&str> = group.iter().map(|(_, p, _)| p.as_str()).collect();
+                    errors.push(format!("{} -> {}", paths.join(", "), group[0].0));
+                }
+            }
+        }
+        if !errors.is_empty() {
+            bail!(
+                "Name map entries that would collide on the host:\n  {}",
+                errors.join("\n  ")
+            );
+        }
+
+        for (key, path) in &self.given {
+            if unmatched.contains(key) {
+                let msg = format!("name map entry {} matches no image entry", path);
+                warnings::warn(Category::NameMapUnmatched, msg);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+//! Storing names that are 8.3 except for case without long names.
+//!
+//! fatfs adds long name entries for names like `readme.txt`, while
+//! Windows keeps just the 8.3 entry and marks the lowercase parts in its
+//! NT reserved byte. Other tools show such names the same either way,
+//! but only the latter matches images made on Windows.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::ondisk::{BootSector, Fat, Layout, RawDir};
+use crate::region::ImgSlice;
+
+struct Volume {
+    img: ImgSlice,
+    layout: Layout,
+    fat: Fat,
+    /// Directory clusters folded so far, so broken images can't loop
+    visited: HashSet<u32>,
+}
+
+impl Volume {
+    fn open(img_file: &Path) -> Result<Self> {
+        let mut img = ImgSlice::open(img_file, true)?;
+        let layout = BootSector::read(&mut img)?.layout()?;
+        let fat = Fat::read(&mut img, &layout)?;
+        Ok(Self {
+            img,
+            layout,
+            fat,
+            visited: HashSet::new(),
+        })
+    }
+
+    fn dir(&mut self, path: &str) -> Result<RawDir> {
+        RawDir::read_path(&mut self.img, &self.layout, &self.fat, path)
+    }
+
+    fn fold_tree(&mut self, dir: &RawDir) -> Result<()> {
+        dir.fold_case(&mut self.img, None)?;
+        for (name, entry) in dir.files() {
+            if !entry.is_dir() || name == "." || name == ".." {
+                continue;
+            }
+            if !self.visited.insert(entry.first_cluster()) {
+                continue;
+            }
+            let sub = RawDir::read(
+                &mut self.img,
+                &self.layout,
+                &self.fat,
+                entry.first_cluster(),
+            )?;
+            self.fold_tree(&sub)?;
+        }
+        Ok(())
+    }
+}
+
+/// Folds the name of the entry at the normalized image path `inner_path`
+pub fn fold_entry(img_file: &Path, inner_path: &str) -> Result<()> {
+    let mut volume = Volume::open(img_file)?;
+    let (parent, name) = inner_path.rsplit_once('/').unwrap_or(("", inner_path));
+    let dir = volume.dir(parent)?;
+    dir.fold_case(&mut volume.img, Some(name))?;
+    Ok(())
+}
+
+/// Folds the names of everything below the image directory `inner_path`
+pub fn fold_tree(img_file: &Path, inner_path: &str) -> Result<()> {
+    let mut volume = Volume::open(img_file)?;
+    let dir = volume.dir(inner_path)?;
+    volume.fold_tree(&dir)
+}
@@ -0,0 +1,1351 @@
+//! Raw on-disk structures that fatfs doesn't expose.
+//!
+//! All multi-byte fields are little-endian on disk and are always decoded
+//! explicitly, never by casting.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use anyhow::{bail, Context, Result};
+use fatfs::FatType;
+
+use crate::{exit, surrogates};
+
+// On-disk fields are little-endian whatever the host is. Raw structures
+// of the image and the MBR are only read and written through these.
+
+pub(crate) fn le16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+pub(crate) fn le32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+pub(crate) fn set_le16(b: &mut [u8], off: usize, v: u16) {
+    b[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+pub(crate) fn set_le32(b: &mut [u8], off: usize, v: u32) {
+    b[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+/// Boot sector with the BIOS parameter block
+#[derive(Clone)]
+pub struct BootSector {
+    pub raw: [u8; 512],
+}
+
+impl BootSector {
+    /// Reads the boot sector from the given byte offset
+    pub fn read_at<R: Read + Seek>(r: &mut R, offset: u64) -> Result<Self> {
+        let mut raw = [0u8; 512];
+        r.seek(SeekFrom::Start(offset))?;
+        r.read_exact(&mut raw)?;
+        let bs = Self { raw };
+        if bs.bytes_per_sector() < 512 || !bs.bytes_per_sector().is_power_of_two() {
+            bail!("Invalid BPB: bytes per sector {}", bs.bytes_per_sector());
+        }
+        if bs.sectors_per_cluster() == 0 || !bs.sectors_per_cluster().is_power_of_two() {
+            bail!(
+                "Invalid BPB: sectors per cluster {}",
+                bs.sectors_per_cluster()
+            );
+        }
+        Ok(bs)
+    }
+
+    pub fn read<R: Read + Seek>(r: &mut R) -> Result<Self> {
+        Self::read_at(r, 0)
+    }
+
+    pub fn write_at<W: Write + Seek>(&self, w: &mut W, offset: u64) -> io::Result<()> {
+        w.seek(SeekFrom::Start(offset))?;
+        w.write_all(&self.raw)
+    }
+
+    /// Writes the boot sector, and for FAT32 also its backup copy
+    pub fn write<W: Write + Seek>(&self, w: &mut W) -> io::Result<()> {
+        self.write_at(w, 0)?;
+        if let Some(offset) = self.backup_offset() {
+            self.write_at(w, offset)?;
+        }
+        Ok(())
+    }
+
+    pub fn bytes_per_sector(&self) -> u16 {
+        le16(&self.raw, 11)
+    }
+
+    pub fn sectors_per_cluster(&self) -> u8 {
+        self.raw[13]
+    }
+
+    pub fn reserved_sectors(&self) -> u16 {
+        le16(&self.raw, 14)
+    }
+
+    pub fn fats(&self) -> u8 {
+        self.raw[16]
+    }
+
+    pub fn root_entries(&self) -> u16 {
+        le16(&self.raw, 17)
+    }
+
+    pub fn total_sectors_16(&self) -> u16 {
+        le16(&self.raw, 19)
+    }
+
+    pub fn sectors_per_fat_16(&self) -> u16 {
+        le16(&self.raw, 22)
+    }
+
+    /// Sectors before this volume on the disk. Only informational: it goes
+    /// stale when a partition is carved out of a disk image, so offsets are
+    /// never computed from it.
+    pub fn hidden_sectors(&self) -> u32 {
+        le32(&self.raw, 28)
+    }
+
+    pub fn set_hidden_sectors(&mut self, sectors: u32) {
+        set_le32(&mut self.raw, 28, sectors);
+    }
+
+    pub fn total_sectors_32(&self) -> u32 {
+        le32(&self.raw, 32)
+    }
+
+    pub fn total_sectors(&self) -> u32 {
+        match self.total_sectors_16() {
+            0 => self.total_sectors_32(),
+            n => n as u32,
+        }
+    }
+
+    /// The total sector count to trust. Some formatters set both fields
+    /// to different values, then the larger one that fits in `image_len`
+    /// bytes wins.
+    pub fn total_sectors_for(&self, image_len: u64) -> u32 {
+        let (t16, t32) = (self.total_sectors_16() as u32, self.total_sectors_32());
+        if t16 == 0 || t32 == 0 {
+            return self.total_sectors();
+        }
+        let fits = |n: u32| n as u64 * self.bytes_per_sector() as u64 <= image_len;
+        [t16, t32]
+            .into_iter()
+            .filter(|&n| fits(n))
+            .max()
+            .unwrap_or_else(|| t16.min(t32))
+    }
+
+    /// Stores the total sector count in the field the FAT specification
+    /// asks for, zeroing the other one
+    pub fn set_total_sectors(&mut self, sectors: u32) {
+        if self.is_fat32() || sectors > 0xffff {
+            set_le16(&mut self.raw, 19, 0);
+            set_le32(&mut self.raw, 32, sectors);
+        } else {
+            set_le16(&mut self.raw, 19, sectors as u16);
+            set_le32(&mut self.raw, 32, 0);
+        }
+    }
+
+    /// FAT32 only
+    pub fn sectors_per_fat_32(&self) -> u32 {
+        le32(&self.raw, 36)
+    }
+
+    pub fn sectors_per_fat(&self) -> u32 {
+        match self.sectors_per_fat_16() {
+            0 => self.sectors_per_fat_32(),
+            n => n as u32,
+        }
+    }
+
+    /// FAT32 only
+    pub fn root_cluster(&self) -> u32 {
+        le32(&self.raw, 44)
+    }
+
+    /// FAT32 only
+    pub fn fs_info_sector(&self) -> u16 {
+        le16(&self.raw, 48)
+    }
+
+    /// FAT32 only
+    pub fn backup_boot_sector(&self) -> u16 {
+        le16(&self.raw, 50)
+    }
+
+    /// Byte offset of the FAT32 backup boot sector, if there is a usable one
+    pub fn backup_offset(&self) -> Option<u64> {
+        let backup = self.backup_boot_sector();
+        if !self.is_fat32() || backup == 0 || backup == 0xffff || backup >= self.reserved_sectors()
+        {
+            return None;
+        }
+        Some(backup as u64 * self.bytes_per_sector() as u64)
+    }
+
+    /// Offset of the extended BPB fields, which moved for FAT32
+    fn ext_offset(&self) -> usize {
+        if self.is_fat32() {
+            64
+        } else {
+            36
+        }
+    }
+
+    pub fn set_volume_id(&mut self, id: u32) {
+        let off = self.ext_offset() + 3;
+        set_le32(&mut self.raw, off, id);
+    }
+
+    /// The label in the BPB, without padding. The root directory may hold
+    /// a newer one.
+    pub fn volume_label(&self) -> String {
+        let off = self.ext_offset() + 7;
+        let label = &self.raw[off..off + 11];
+        String::from_utf8_lossy(label).trim_end().to_owned()
+    }
+
+    pub fn set_volume_label(&mut self, label: [u8; 11]) {
+        let off = self.ext_offset() + 7;
+        self.raw[off..off + 11].copy_from_slice(&label);
+    }
+
+    /// Boot code between the extended BPB and the signature
+    pub fn boot_code(&self) -> &[u8] {
+        &self.raw[self.boot_code_offset()..510]
+    }
+
+    /// The extended BPB takes 26 bytes on all FAT types
+    pub fn boot_code_offset(&self) -> usize {
+        self.ext_offset() + 26
+    }
+
+    /// Takes the jump instruction, the boot code and the signature from
+    /// `reference`, keeping the BPB and extended BPB of this volume. With
+    /// `oem`, the OEM name is taken as well.
+    pub fn copy_boot_code(&mut self, reference: &BootSector, oem: bool) {
+        let keep_from = if oem { 11 } else { 3 };
+        let mut raw = reference.raw;
+        raw[keep_from..self.boot_code_offset()]
+            .copy_from_slice(&self.raw[keep_from..self.boot_code_offset()]);
+        self.raw = raw;
+    }
+
+    /// FAT32 volumes have no 16-bit FAT size
+    pub fn is_fat32(&self) -> bool {
+        self.sectors_per_fat_16() == 0
+    }
+
+    pub fn has_signature(&self) -> bool {
+        self.raw[510] == 0x55 && self.raw[511] == 0xaa
+    }
+
+    /// Computes where everything is on the volume
+    pub fn layout(&self) -> Result<Layout> {
+        let bps = self.bytes_per_sector() as u64;
+        let fat_sectors = self.sectors_per_fat() as u64;
+        let root_dir_sectors = (self.root_entries() as u64 * 32).div_ceil(bps);
+        let meta_sectors =
+            self.reserved_sectors() as u64 + self.fats() as u64 * fat_sectors + root_dir_sectors;
+        let total_sectors = self.total_sectors() as u64;
+        if self.fats() == 0 || fat_sectors == 0 || meta_sectors >= total_sectors {
+            bail!("Invalid BPB: inconsistent volume layout");
+        }
+
+        let total_clusters =
+            ((total_sectors - meta_sectors) / self.sectors_per_cluster() as u64) as u32;
+        let fat_type = if total_clusters < 4085 {
+            FatType::Fat12
+        } else if total_clusters < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+
+        let fat_offset = self.reserved_sectors() as u64 * bps;
+        let root_dir_offset = fat_offset + self.fats() as u64 * fat_sectors * bps;
+        Ok(Layout {
+            fat_type,
+            cluster_size: self.sectors_per_cluster() as u64 * bps,
+            fat_offset,
+            fat_size: fat_sectors * bps,
+            root_dir_offset,
+            root_dir_size: root_dir_sectors * bps,
+            data_offset: root_dir_offset + root_dir_sectors * bps,
+            total_clusters,
+            root_cluster: if fat_type == FatType::Fat32 {
+                self.root_cluster()
+            } else {
+                0
+            },
+        })
+    }
+
+    /// Offset the jump instruction at the start goes to, if it is one
+    pub fn jump_target(&self) -> Option<isize> {
+        match self.raw[0] {
+            0xeb if self.raw[2] == 0x90 => Some(2 + self.raw[1] as i8 as isize),
+            0xe9 => Some(3 + le16(&self.raw, 1) as i16 as isize),
+            _ => None,
+        }
+    }
+}
+
+/// The FAT32 FSInfo sector
+pub struct FsInfo {
+    pub raw: [u8; 512],
+}
+
+impl FsInfo {
+    /// The free count when it isn't known
+    pub const FREE_UNKNOWN: u32 = 0xffff_ffff;
+
+    pub fn read<R: Read + Seek>(r: &mut R, bs: &BootSector) -> io::Result<Self> {
+        let mut raw = [0u8; 512];
+        r.seek(SeekFrom::Start(
+            bs.fs_info_sector() as u64 * bs.bytes_per_sector() as u64,
+        ))?;
+        r.read_exact(&mut raw)?;
+        Ok(Self { raw })
+    }
+
+    /// Whether the lead, structure and trail signatures are all there
+    pub fn is_signed(&self) -> bool {
+        le32(&self.raw, 0) == 0x4161_5252
+            && le32(&self.raw, 484) == 0x6141_7272
+            && le32(&self.raw, 508) == 0xaa55_0000
+    }
+
+    pub fn free_count(&self) -> u32 {
+        le32(&self.raw, 488)
+    }
+}
+
+/// Byte offsets and sizes of the volume regions
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub fat_type: FatType,
+    pub cluster_size: u64,
+    pub fat_offset: u64,
+    /// Size of a single FAT copy
+    pub fat_size: u64,
+    /// Fixed root directory of FAT12/16
+    pub root_dir_offset: u64,
+    pub root_dir_size: u64,
+    pub data_offset: u64,
+    /// Number of data clusters, numbered from 2
+    pub total_clusters: u32,
+    /// Root directory start cluster on FAT32, otherwise 0
+    pub root_cluster: u32,
+}
+
+impl Layout {
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster as u64 - 2) * self.cluster_size
+    }
+
+    /// Is this a cluster number that can hold data
+    pub fn is_data_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.total_clusters + 2
+    }
+}
+
+/// Decoded copy of the first FAT
+pub struct Fat {
+    entries: Vec<u32>,
+    fat_type: FatType,
+}
+
+impl Fat {
+    pub fn read<R: Read + Seek>(r: &mut R, layout: &Layout) -> Result<Self> {
+        let mut raw = vec![0u8; layout.fat_size as usize];
+        r.seek(SeekFrom::Start(layout.fat_offset))?;
+        r.read_exact(&mut raw)?;
+
+        let count = layout.total_clusters as usize + 2;
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let value = match layout.fat_type {
+                FatType::Fat12 => {
+                    let off = i + i / 2;
+                    if off + 1 >= raw.len() {
+                        bail!("FAT is too small for the volume");
+                    }
+                    let v = le16(&raw, off) as u32;
+                    if i % 2 == 0 {
+                        v & 0xfff
+                    } else {
+                        v >> 4
+                    }
+                },
+                FatType::Fat16 => {
+                    if i * 2 + 1 >= raw.len() {
+                        bail!("FAT is too small for the volume");
+                    }
+                    le16(&raw, i * 2) as u32
+                },
+                FatType::Fat32 => {
+                    if i * 4 + 3 >= raw.len() {
+                        bail!("FAT is too small for the volume");
+                    }
+                    le32(&raw, i * 4) & 0x0fff_ffff
+                },
+            };
+            entries.push(value);
+        }
+        Ok(Self {
+            entries,
+            fat_type: layout.fat_type,
+        })
+    }
+
+    pub fn get(&self, cluster: u32) -> u32 {
+        self.entries.get(cluster as usize).copied().unwrap_or(0)
+    }
+
+    /// Number of unallocated data clusters
+    pub fn free_clusters(&self) -> u32 {
+        self.entries[2..].iter().filter(|&&v| v == 0).count() as u32
+    }
+
+    /// End-of-chain marker
+    pub fn is_eoc(&self, value: u32) -> bool {
+        match self.fat_type {
+            FatType::Fat12 => value >= 0xff8,
+            FatType::Fat16 => value >= 0xfff8,
+            FatType::Fat32 => value >= 0x0fff_fff8,
+        }
+    }
+
+    /// Marker of a cluster that must not be used
+    pub fn is_bad(&self, value: u32) -> bool {
+        match self.fat_type {
+            FatType::Fat12 => value == 0xff7,
+            FatType::Fat16 => value == 0xfff7,
+            FatType::Fat32 => value == 0x0fff_fff7,
+        }
+    }
+
+    /// Follows a cluster chain from `start`, failing on loops and
+    /// references outside of the data area
+    pub fn chain(&self, start: u32) -> Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut cluster = start;
+        loop {
+            if cluster < 2 || cluster as usize >= self.entries.len() {
+                bail!(
+                    "cluster chain from {} refers to invalid cluster {}",
+                    start,
+                    cluster
+                );
+            }
+            if chain.len() >= self.entries.len() {
+                bail!("cluster chain from {} loops", start);
+            }
+            chain.push(cluster);
+            let next = self.get(cluster);
+            if self.is_eoc(next) {
+                return Ok(chain);
+            }
+            cluster = next;
+        }
+    }
+}
+
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_LFN: u8 = 0x0f;
+
+/// Flags in the NT reserved byte: show the 8.3 base name or extension
+/// in lowercase
+pub const NT_LOWER_BASE: u8 = 0x08;
+pub const NT_LOWER_EXT: u8 = 0x10;
+
+/// Short names of the `.` and `..` entries
+pub const DOT_NAME: [u8; 11] = *b".          ";
+pub const DOTDOT_NAME: [u8; 11] = *b"..         ";
+
+/// A raw 32-byte directory entry, along with where it is stored
+#[derive(Clone)]
+pub struct RawDirEntry {
+    pub raw: [u8; 32],
+    /// Byte offset of the entry in the volume
+    pub offset: u64,
+}
+
+impl RawDirEntry {
+    /// No entries follow this one
+    pub fn is_end(&self) -> bool {
+        self.raw[0] == 0x00
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.raw[0] == 0xe5
+    }
+
+    pub fn attr(&self) -> u8 {
+        self.raw[11]
+    }
+
+    pub fn is_lfn(&self) -> bool {
+        self.attr() & ATTR_LFN == ATTR_LFN
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attr() & ATTR_DIRECTORY != 0
+    }
+
+    /// The 8.3 name as stored, padded with spaces
+    pub fn name_bytes(&self) -> [u8; 11] {
+        let mut name = [0u8; 11];
+        name.copy_from_slice(&self.raw[..11]);
+        name
+    }
+
+    /// The 8.3 name in `NAME.EXT` form. Non-ASCII bytes of the OEM code
+    /// page are shown as `?`.
+    pub fn short_name(&self) -> String {
+        let mut name = self.name_bytes();
+        if name[0] == 0x05 {
+            name[0] = 0xe5;
+        }
+        let decode = |b: &[u8]| -> String {
+            let s: String = b
+                .iter()
+                .map(|&c| if c.is_ascii() { c as char } else { '?' })
+                .collect();
+            s.trim_end_matches(' ').to_owned()
+        };
+        let base = decode(&name[..8]);
+        let ext = decode(&name[8..]);
+        if ext.is_empty() {
+            base
+        } else {
+            format!("{}.{}", base, ext)
+        }
+    }
+
+    /// The 8.3 name as Windows shows it, lowercased where the NT reserved
+    /// byte says so
+    pub fn display_name(&self) -> String {
+        let short = self.short_name();
+        let flags = self.raw[12];
+        let case = |part: &str, flag: u8| {
+            if flags & flag != 0 {
+                part.to_ascii_lowercase()
+            } else {
+                part.to_owned()
+            }
+        };
+        match short.split_once('.') {
+            Some((base, ext)) => {
+                format!("{}.{}", case(base, NT_LOWER_BASE), case(ext, NT_LOWER_EXT))
+            },
+            None => case(&short, NT_LOWER_BASE),
+        }
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        (le16(&self.raw, 20) as u32) << 16 | le16(&self.raw, 26) as u32
+    }
+
+    /// Sets the modification time and date. Seconds are stored halved,
+    /// so odd ones are rounded down.
+    pub fn set_modified(&mut self, t: &fatfs::DateTime) {
+        let time = (t.time.hour << 11) | (t.time.min << 5) | (t.time.sec / 2);
+        let date = ((t.date.year - 1980) << 9) | (t.date.month << 5) | t.date.day;
+        set_le16(&mut self.raw, 22, time);
+        set_le16(&mut self.raw, 24, date);
+    }
+
+    /// File size in bytes, 0 for directories
+    pub fn size(&self) -> u32 {
+        le32(&self.raw, 28)
+    }
+
+    pub fn set_size(&mut self, size: u32) {
+        set_le32(&mut self.raw, 28, size);
+    }
+
+    /// A file with contents must start at a data cluster. Some broken
+    /// tools leave 0 or 1 there, which fatfs can't make sense of.
+    pub fn has_invalid_start(&self) -> bool {
+        !self.is_dir() && self.size() > 0 && self.first_cluster() < 2
+    }
+
+    pub fn set_first_cluster(&mut self, cluster: u32) {
+        set_le16(&mut self.raw, 20, (cluster >> 16) as u16);
+        set_le16(&mut self.raw, 26, cluster as u16);
+    }
+
+    pub fn write<W: Write + Seek>(&self, w: &mut W) -> io::Result<()> {
+        w.seek(SeekFrom::Start(self.offset))?;
+        w.write_all(&self.raw)
+    }
+}
+
+/// The entry slots of one directory
+pub struct RawDir {
+    /// Entries up to the end marker, including deleted and LFN entries
+    pub entries: Vec<RawDirEntry>,
+    /// Byte ranges holding the directory
+    regions: Vec<(u64, u64)>,
+}
+
+impl RawDir {
+    pub fn read_root<R: Read + Seek>(r: &mut R, layout: &Layout, fat: &Fat) -> Result<Self> {
+        if layout.fat_type == FatType::Fat32 {
+            Self::read(r, layout, fat, layout.root_cluster)
+        } else {
+            Self::read_regions(r, vec![(layout.root_dir_offset, layout.root_dir_size)])
+        }
+    }
+
+    /// Byte ranges of the volume holding the directory, as offsets and
+    /// lengths
+    pub fn regions(&self) -> &[(u64, u64)] {
+        &self.regions
+    }
+
+    /// Reads the directory at the normalized image path `path`
+    pub fn read_path<R: Read + Seek>(
+        r: &mut R, layout: &Layout, fat: &Fat, path: &str,
+    ) -> Result<Self> {
+        let mut dir = Self::read_root(r, layout, fat)?;
+        let mut walked = String::new();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            walked = format!("{}/{}", walked, component);
+            let start = match dir.lookup(component) {
+                Some(entry) if entry.is_dir() => entry.first_cluster(),
+                Some(_) => return Err(exit::NotADirectory(walked).into()),
+                None => return Err(exit::NotFound(walked).into()),
+            };
+            dir = Self::read(r, layout, fat, start)
+                .with_context(|| format!("failed reading directory /{}", path))?;
+        }
+        Ok(dir)
+    }
+
+    /// The entry at the normalized image path `path`
+    pub fn entry_at<R: Read + Seek>(
+        r: &mut R, layout: &Layout, fat: &Fat, path: &str,
+    ) -> Result<RawDirEntry> {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        match Self::read_path(r, layout, fat, parent)?.lookup(name) {
+            Some(entry) => Ok(entry.clone()),
+            None => Err(exit::NotFound(format!("/{}", path)).into()),
+        }
+    }
+
+    /// Offsets of the entries along the normalized image path `path`, one
+    /// per component, up to the first one that doesn't exist. They tell
+    /// entries apart whether the path names them by long or 8.3 name.
+    pub fn offsets_along<R: Read + Seek>(
+        r: &mut R, layout: &Layout, fat: &Fat, path: &str,
+    ) -> Result<Vec<u64>> {
+        let mut offsets = Vec::new();
+        let mut dir = Self::read_root(r, layout, fat)?;
+        let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+        while let Some(component) = components.next() {
+            let (offset, is_dir, cluster) = match dir.lookup(component) {
+                Some(entry) => (entry.offset, entry.is_dir(), entry.first_cluster()),
+                None => break,
+            };
+            offsets.push(offset);
+            if !is_dir || components.peek().is_none() {
+                break;
+            }
+            dir = Self::read(r, layout, fat, cluster)?;
+        }
+        Ok(offsets)
+    }
+
+    /// Reads the subdirectory starting at `cluster`
+    pub fn read<R: Read + Seek>(
+        r: &mut R, layout: &Layout, fat: &Fat, cluster: u32,
+    ) -> Result<Self> {
+        let regions = fat
+            .chain(cluster)?
+            .into_iter()
+            .map(|c| (layout.cluster_offset(c), layout.cluster_size))
+            .collect();
+        Self::read_regions(r, regions)
+    }
+
+    fn read_regions<R: Read + Seek>(r: &mut R, regions: Vec<(u64, u64)>) -> Result<Self> {
+        let mut entries = Vec::new();
+        let mut buf = Vec::new();
+        'outer: for &(offset, size) in &regions {
+            buf.resize(size as usize, 0);
+            r.seek(SeekFrom::Start(offset))?;
+            r.read_exact(&mut buf)?;
+            for (i, chunk) in buf.chunks_exact(32).enumerate() {
+                let mut raw = [0u8; 32];
+                raw.copy_from_slice(chunk);
+                let entry = RawDirEntry {
+                    raw,
+                    offset: offset + i as u64 * 32,
+                };
+                if entry.is_end() {
+                    break 'outer;
+                }
+                entries.push(entry);
+            }
+        }
+        Ok(Self { entries, regions })
+    }
+
+    /// Live entries with their long names. Deleted entries, LFN entries
+    /// and the volume label are left out.
+    pub fn files(&self) -> Vec<(String, &RawDirEntry)> {
+        self.named()
+            .into_iter()
+            .map(|(name, _, entry)| (name, entry))
+            .collect()
+    }
+
+    /// Like `files`, along with the LFN entries the long name came from
+    fn named(&self) -> Vec<(String, Vec<&RawDirEntry>, &RawDirEntry)> {
+        let mut files = Vec::new();
+        let mut lfn: Vec<&RawDirEntry> = Vec::new();
+        for entry in &self.entries {
+            if entry.is_deleted() {
+                lfn.clear();
+                continue;
+            }
+            if entry.is_lfn() {
+                if entry.raw[0] & 0x40 != 0 {
+                    lfn.clear();
+                }
+                lfn.push(entry);
+                continue;
+            }
+            if entry.attr() & ATTR_VOLUME_ID != 0 {
+                lfn.clear();
+                continue;
+            }
+            match long_name(&lfn, entry) {
+                Some(name) => files.push((name, std::mem::take(&mut lfn), entry)),
+                None => {
+                    files.push((entry.display_name(), Vec::new(), entry));
+                    lfn.clear();
+                },
+            }
+        }
+        files
+    }
+
+    /// The long names with unpaired surrogates, escaped by
+    /// `surrogates::escape`, along with the short names of their entries
+    pub fn escaped_names(&self) -> Vec<(String, String)> {
+        self.named()
+            .into_iter()
+            .filter_map(|(_, lfn, entry)| {
+                let units = long_name_units(&lfn, entry)?;
+                let escaped = surrogates::has_unpaired(&units).then(|| surrogates::escape(&units));
+                Some((entry.short_name(), escaped?))
+            })
+            .collect()
+    }
+
+    /// Stores names that are 8.3 except for case without their long name
+    /// entries, setting the NT case flags instead like Windows does. With
+    /// `only`, just that entry is looked at. Returns how many changed.
+    pub fn fold_case<W: Write + Seek>(&self, w: &mut W, only: Option<&str>) -> io::Result<usize> {
+        let mut folded = 0;
+        for (name, lfn, entry) in self.named() {
+            if lfn.is_empty() || only.is_some_and(|only| only != name) {
+                continue;
+            }
+            let flags = match nt_case_flags(&name, &entry.short_name()) {
+                Some(flags) => flags,
+                None => continue,
+            };
+            for part in lfn {
+                let mut deleted = part.clone();
+                deleted.raw[0] = 0xe5;
+                deleted.write(w)?;
+            }
+            let mut entry = entry.clone();
+            entry.raw[12] = entry.raw[12] & !(NT_LOWER_BASE | NT_LOWER_EXT) | flags;
+            entry.write(w)?;
+            folded += 1;
+        }
+        Ok(folded)
+    }
+
+    /// Marks the entry at `offset` deleted, along with its long name
+    pub fn remove<W: Write + Seek>(&self, w: &mut W, offset: u64) -> io::Result<()> {
+        for (_, lfn, entry) in self.named() {
+            if entry.offset != offset {
+                continue;
+            }
+            for part in lfn.into_iter().chain([entry]) {
+                let mut deleted = part.clone();
+                deleted.raw[0] = 0xe5;
+                deleted.write(w)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Entry called `name`, compared case-insensitively like FAT does.
+    /// Both the long and the 8.3 name match.
+    pub fn lookup(&self, name: &str) -> Option<&RawDirEntry> {
+        let upper = name.to_uppercase();
+        self.files()
+            .into_iter()
+            .find(|(n, e)| n.to_uppercase() == upper || e.short_name() == upper)
+            .map(|(_, e)| e)
+    }
+
+    /// Offset of the `index`th slot, if the directory is large enough
+    pub fn slot_offset(&self, index: usize) -> Option<u64> {
+        let mut index = index as u64;
+        for &(offset, size) in &self.regions {
+            let slots = size / 32;
+            if index < slots {
+                return Some(offset + index * 32);
+            }
+            index -= slots;
+        }
+        None
+    }
+}
+
+/// Overwrites `len` bytes at `offset` with `byte`
+pub fn fill<W: Write + Seek>(w: &mut W, offset: u64, len: u64, byte: u8) -> io::Result<()> {
+    w.seek(SeekFrom::Start(offset))?;
+    w.write_all(&vec![byte; len as usize])
+}
+
+/// Are all `len` bytes at `offset` equal to `byte`
+pub fn is_filled<R: Read + Seek>(r: &mut R, offset: u64, len: u64, byte: u8) -> io::Result<bool> {
+    let mut buf = vec![0u8; len as usize];
+    r.seek(SeekFrom::Start(offset))?;
+    r.read_exact(&mut buf)?;
+    Ok(buf.iter().all(|&b| b == byte))
+}
+
+/// Checksum of a short name, stored in each of its LFN entries
+fn lfn_checksum(name: &[u8; 11]) -> u8 {
+    name.iter().fold(0u8, |sum, &b| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b)
+    })
+}
+
+/// NT case flags that make the 8.3 name `short` show as `name`, if that
+/// works without a long name. The base name and the extension must each
+/// be all lowercase or all uppercase.
+pub fn nt_case_flags(name: &str, short: &str) -> Option<u8> {
+    if !name.is_ascii() || name.to_ascii_uppercase() != short {
+        return None;
+    }
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    let flag = |part: &str, flag: u8| {
+        let lower = part.bytes().any(|c| c.is_ascii_lowercase());
+        let upper = part.bytes().any(|c| c.is_ascii_uppercase());
+        match (lower, upper) {
+            (true, true) => None,
+            (true, false) => Some(flag),
+            _ => Some(0),
+        }
+    };
+    Some(flag(base, NT_LOWER_BASE)? | flag(ext, NT_LOWER_EXT)?)
+}
+
+/// Assembles the long name from the LFN entries preceding `entry`,
+/// which are stored last part first. Unpaired surrogates are replaced
+/// with U+FFFD, like fatfs does.
+fn long_name(lfn: &[&RawDirEntry], entry: &RawDirEntry) -> Option<String> {
+    long_name_units(lfn, entry).map(|units| String::from_utf16_lossy(&units))
+}
+
+/// The UTF-16 units of the long name, see `long_name`
+fn long_name_units(lfn: &[&RawDirEntry], entry: &RawDirEntry) -> Option<Vec<u16>> {
+    if lfn.is_empty() {
+        return None;
+    }
+    let checksum = lfn_checksum(&entry.name_bytes());
+    let mut units = Vec::with_capacity(lfn.len() * 13);
+    for (i, part) in lfn.iter().rev().enumerate() {
+        if part.raw[13] != checksum || (part.raw[0] & 0x1f) as usize != i + 1 {
+            return None;
+        }
+        for off in (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2))
+        {
+            units.push(le16(&part.raw, off));
+        }
+    }
+    if let Some(end) = units.iter().position(|&u| u == 0) {
+        units.truncate(end);
+    }
+    Some(units)
+}
+
+/// Converts a volume label to its padded on-disk form.
+/// Lowercase letters are uppercased like DOS and Windows do.
+pub fn parse_volume_label(label: &str) -> Result<[u8; 11]> {
+    if label.len() > 11 {
+        bail!(
+            "Volume label {:?} is {} bytes long, at most 11 are allowed",
+            label,
+            label.len()
+        );
+    }
+    let mut raw = [b' '; 11];
+    for (i, c) in label.chars().enumerate() {
+        if !c.is_ascii() || c.is_ascii_control() || "\"*+,./:;<=>?[\\]|".contains(c) {
+            bail!(
+                "Volume label {:?} contains invalid character {:?}",
+                label,
+                c
+            );
+        }
+        raw[i] = c.to_ascii_uppercase() as u8;
+    }
+    Ok(raw)
+}
+
+/// Updates the volume label entry in the root directory, creating it in
+/// a free slot if there is none. Both the boot sector and this entry hold
+/// the label, and different tools read different ones.
+pub fn write_root_volume_label<F: Read + Write + Seek>(f: &mut F, label: [u8; 11]) -> Result<()> {
+    let bs = BootSector::read(f)?;
+    let layout = bs.layout()?;
+    let fat = Fat::read(f, &layout)?;
+    let dir = RawDir::read_root(f, &layout, &fat)?;
+
+    let existing = dir
+        .entries
+        .iter()
+        .find(|e| !e.is_deleted() && !e.is_lfn() && e.attr() & ATTR_VOLUME_ID != 0);
+    let mut entry = match existing {
+        Some(e) => e.clone(),
+        None => {
+            let free = dir
+                .entries
+                .iter()
+                .find(|e| e.is_deleted())
+                .map(|e| e.offset);
+            let offset = match free.or_else(|| dir.slot_offset(dir.entries.len())) {
+                Some(offset) => offset,
+                None => bail!("No free entry in the root directory for the volume label"),
+            };
+            let mut raw = [0u8; 32];
+            raw[11] = ATTR_VOLUME_ID;
+            RawDirEntry { raw, offset }
+        },
+    };
+    entry.raw[..11].copy_from_slice(&label);
+    entry.write(f)?;
+    Ok(())
+}
+
+/// Problems with the FAT32 reserved area layout that some firmware rejects
+pub fn fat32_layout_problems(bs: &BootSector) -> Vec<String> {
+    let mut problems = Vec::new();
+    if !bs.is_fat32() {
+        return problems;
+    }
+
+    let reserved = bs.reserved_sectors();
+    if reserved < 7 {
+        problems.push(format!(
+            "only {} reserved sectors, FAT32 needs at least 7 (32 recommended)",
+            reserved
+        ));
+    } else if reserved < 32 {
+        problems.push(format!(
+            "{} reserved sectors, 32 is recommended for FAT32",
+            reserved
+        ));
+    }
+
+    let fs_info = bs.fs_info_sector();
+    if fs_info == 0 || fs_info >= reserved {
+        problems.push(format!(
+            "FSInfo sector {} is not inside the reserved area",
+            fs_info
+        ));
+    }
+
+    let backup = bs.backup_boot_sector();
+    if backup == 0 || backup >= reserved {
+        problems.push(format!(
+            "backup boot sector {} is not inside the reserved area",
+            backup
+        ));
+    }
+    problems
+}
+
+/// Both total sector fields are set, to different values. fatfs trusts
+/// the 16-bit one, which may not be the one matching the image.
+pub fn total_sectors_conflict(bs: &BootSector, image_len: u64) -> Option<String> {
+    let (t16, t32) = (bs.total_sectors_16() as u32, bs.total_sectors_32());
+    if t16 == 0 || t32 == 0 || t16 == t32 {
+        return None;
+    }
+    Some(format!(
+        "total sectors fields disagree: 16-bit {}, 32-bit {}, the image size fits {}",
+        t16,
+        t32,
+        bs.total_sectors_for(image_len)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// A 512-byte sector holding `fields` at their offsets. Fixtures spell
+    /// out the on-disk bytes, so that they don't depend on the helpers
+    /// under test or on the byte order of the host.
+    fn sector(fields: &[(usize, &[u8])]) -> [u8; 512] {
+        let mut raw = [0u8; 512];
+        for &(offset, bytes) in fields {
+            raw[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+        raw
+    }
+
+    /// FAT16 with 2 KiB clusters and 5101 of them
+    fn fat16_boot_sector() -> [u8; 512] {
+        sector(&[
+            (0, &[0xeb, 0x3c, 0x90]),
+            (11, &[0x00, 0x02]),
+            (13, &[0x04]),
+            (14, &[0x04, 0x00]),
+            (16, &[0x02]),
+            (17, &[0x00, 0x02]),
+            (19, &[0x00, 0x50]),
+            (22, &[0x14, 0x00]),
+            (28, &[0x78, 0x56, 0x34, 0x12]),
+            (43, b"BOOT       "),
+            (510, &[0x55, 0xaa]),
+        ])
+    }
+
+    /// FAT32 with 512-byte clusters and 68528 of them
+    fn fat32_boot_sector() -> [u8; 512] {
+        sector(&[
+            (0, &[0xe9, 0x00, 0x01]),
+            (11, &[0x00, 0x02]),
+            (13, &[0x01]),
+            (14, &[0x20, 0x00]),
+            (16, &[0x02]),
+            (32, &[0x00, 0x10, 0x01, 0x00]),
+            (36, &[0x18, 0x02, 0x00, 0x00]),
+            (44, &[0x02, 0x00, 0x00, 0x00]),
+            (48, &[0x01, 0x00]),
+            (50, &[0x06, 0x00]),
+            (71, b"EFI        "),
+            (510, &[0x55, 0xaa]),
+        ])
+    }
+
+    fn layout(fat_type: FatType, total_clusters: u32, fat: &[u8]) -> Layout {
+        Layout {
+            fat_type,
+            cluster_size: 512,
+            fat_offset: 0,
+            fat_size: fat.len() as u64,
+            root_dir_offset: 0,
+            root_dir_size: 0,
+            data_offset: 0,
+            total_clusters,
+            root_cluster: 0,
+        }
+    }
+
+    fn read_fat(fat_type: FatType, total_clusters: u32, raw: &[u8]) -> Fat {
+        let layout = layout(fat_type, total_clusters, raw);
+        Fat::read(&mut Cursor::new(raw.to_vec()), &layout).unwrap()
+    }
+
+    fn dir_entry(fields: &[(usize, &[u8])]) -> RawDirEntry {
+        let mut raw = [0u8; 32];
+        for &(offset, bytes) in fields {
+            raw[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+        RawDirEntry { raw, offset: 0 }
+    }
+
+    /// `README.TXT` with contents at cluster 0x11234
+    fn readme() -> RawDirEntry {
+        dir_entry(&[
+            (0, b"README  TXT"),
+            (11, &[0x20]),
+            (20, &[0x01, 0x00]),
+            (26, &[0x34, 0x12]),
+            (28, &[0x00, 0x10, 0x00, 0x00]),
+        ])
+    }
+
+    /// The only LFN entry of `readme.txt` for `readme()`
+    fn readme_lfn(checksum: u8) -> RawDirEntry {
+        dir_entry(&[
+            (0, &[0x41]),
+            (1, &[b'r', 0, b'e', 0, b'a', 0, b'd', 0, b'm', 0]),
+            (11, &[ATTR_LFN]),
+            (13, &[checksum]),
+            (14, &[b'e', 0, b'.', 0, b't', 0, b'x', 0, b't', 0, 0, 0]),
+            (28, &[0xff, 0xff, 0xff, 0xff]),
+        ])
+    }
+
+    fn dir(entries: &[RawDirEntry]) -> RawDir {
+        let raw: Vec<u8> = entries.iter().flat_map(|e| e.raw).chain([0; 32]).collect();
+        let len = raw.len() as u64;
+        RawDir::read_regions(&mut Cursor::new(raw), vec![(0, len)]).unwrap()
+    }
+
+    #[test]
+    fn boot_sector_fields_are_little_endian() {
+        let bs = BootSector::read(&mut Cursor::new(fat16_boot_sector())).unwrap();
+        assert_eq!(bs.bytes_per_sector(), 512);
+        assert_eq!(bs.sectors_per_cluster(), 4);
+        assert_eq!(bs.reserved_sectors(), 4);
+        assert_eq!(bs.fats(), 2);
+        assert_eq!(bs.root_entries(), 512);
+        assert_eq!(bs.total_sectors(), 0x5000);
+        assert_eq!(bs.sectors_per_fat(), 20);
+        assert_eq!(bs.hidden_sectors(), 0x1234_5678);
+        assert_eq!(bs.volume_label(), "BOOT");
+        assert_eq!(bs.jump_target(), Some(0x3e));
+        assert_eq!(bs.boot_code_offset(), 62);
+        assert!(bs.has_signature() && !bs.is_fat32());
+        assert_eq!(bs.backup_offset(), None);
+    }
+
+    #[test]
+    fn fat16_layout() {
+        let layout = BootSector {
+            raw: fat16_boot_sector(),
+        }
+        .layout()
+        .unwrap();
+        assert_eq!(layout.fat_type, FatType::Fat16);
+        assert_eq!(layout.total_clusters, 5101);
+        assert_eq!(layout.cluster_size, 2048);
+        assert_eq!(layout.fat_offset, 2048);
+        assert_eq!(layout.fat_size, 20 * 512);
+        assert_eq!(layout.root_dir_offset, 22528);
+        assert_eq!(layout.root_dir_size, 16384);
+        assert_eq!(layout.data_offset, 38912);
+        assert_eq!(layout.cluster_offset(3), 38912 + 2048);
+    }
+
+    #[test]
+    fn fat32_boot_sector_fields() {
+        let bs = BootSector {
+            raw: fat32_boot_sector(),
+        };
+        assert!(bs.is_fat32());
+        assert_eq!(bs.total_sectors(), 0x0001_1000);
+        assert_eq!(bs.sectors_per_fat(), 0x218);
+        assert_eq!(bs.root_cluster(), 2);
+        assert_eq!(bs.fs_info_sector(), 1);
+        assert_eq!(bs.backup_offset(), Some(6 * 512));
+        assert_eq!(bs.volume_label(), "EFI");
+        assert_eq!(bs.jump_target(), Some(259));
+        assert!(fat32_layout_problems(&bs).is_empty());
+
+        let layout = bs.layout().unwrap();
+        assert_eq!(layout.fat_type, FatType::Fat32);
+        assert_eq!(layout.total_clusters, 68528);
+        assert_eq!(layout.root_cluster, 2);
+        assert_eq!(layout.data_offset, (32 + 2 * 0x218) * 512);
+    }
+
+    #[test]
+    fn boot_sector_setters_write_little_endian() {
+        let mut bs = BootSector {
+            raw: fat16_boot_sector(),
+        };
+        bs.set_hidden_sectors(0x0a0b_0c0d);
+        bs.set_volume_id(0x1234_abcd);
+        bs.set_total_sectors(0x1_0000);
+        assert_eq!(bs.raw[28..32], [0x0d, 0x0c, 0x0b, 0x0a]);
+        assert_eq!(bs.raw[39..43], [0xcd, 0xab, 0x34, 0x12]);
+        assert_eq!(bs.raw[19..21], [0, 0]);
+        assert_eq!(bs.raw[32..36], [0x00, 0x00, 0x01, 0x00]);
+        bs.set_total_sectors(0x5001);
+        assert_eq!(bs.raw[19..21], [0x01, 0x50]);
+        assert_eq!(bs.raw[32..36], [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn invalid_bpbs_are_rejected() {
+        let mut raw = fat16_boot_sector();
+        raw[11..13].copy_from_slice(&[0x00, 0x03]);
+        assert!(BootSector::read(&mut Cursor::new(raw)).is_err());
+        let mut raw = fat16_boot_sector();
+        raw[13] = 3;
+        assert!(BootSector::read(&mut Cursor::new(raw)).is_err());
+    }
+
+    #[test]
+    fn total_sectors_fields_that_disagree() {
+        let mut raw = fat16_boot_sector();
+        raw[32..36].copy_from_slice(&[0x00, 0x60, 0x00, 0x00]);
+        let bs = BootSector { raw };
+        assert_eq!(bs.total_sectors_for(0x6000 * 512), 0x6000);
+        assert_eq!(bs.total_sectors_for(0x5000 * 512), 0x5000);
+        assert!(total_sectors_conflict(&bs, 0x5000 * 512).is_some());
+    }
+
+    #[test]
+    fn fsinfo_fields() {
+        let info = sector(&[
+            (0, b"RRaA"),
+            (484, b"rrAa"),
+            (488, &[0x34, 0x12, 0x00, 0x00]),
+            (508, &[0x00, 0x00, 0x55, 0xaa]),
+        ]);
+        let mut image = fat32_boot_sector().to_vec();
+        image.extend_from_slice(&info);
+        let bs = BootSector {
+            raw: fat32_boot_sector(),
+        };
+        let info = FsInfo::read(&mut Cursor::new(image), &bs).unwrap();
+        assert!(info.is_signed());
+        assert_eq!(info.free_count(), 0x1234);
+
+        let unsigned = FsInfo {
+            raw: sector(&[(0, b"AaRR")]),
+        };
+        assert!(!unsigned.is_signed());
+    }
+
+    #[test]
+    fn fat12_entries() {
+        let fat = read_fat(
+            FatType::Fat12,
+            4,
+            &[0xf0, 0xff, 0xff, 0x03, 0xf0, 0xff, 0x00, 0xc0, 0xab],
+        );
+        let entries: Vec<_> = (0..6).map(|c| fat.get(c)).collect();
+        assert_eq!(entries, [0xff0, 0xfff, 0x003, 0xfff, 0x000, 0xabc]);
+        assert_eq!(fat.chain(2).unwrap(), [2, 3]);
+        assert_eq!(fat.free_clusters(), 1);
+        assert!(fat.is_eoc(0xff8) && !fat.is_eoc(0xff7) && fat.is_bad(0xff7));
+    }
+
+    #[test]
+    fn fat16_entries() {
+        let fat = read_fat(
+            FatType::Fat16,
+            4,
+            &[
+                0xf8, 0xff, 0xff, 0xff, 0x03, 0x00, 0xff, 0xff, 0x00, 0x00, 0xf7, 0xff,
+            ],
+        );
+        let entries: Vec<_> = (0..6).map(|c| fat.get(c)).collect();
+        assert_eq!(entries, [0xfff8, 0xffff, 0x0003, 0xffff, 0x0000, 0xfff7]);
+        assert_eq!(fat.chain(2).unwrap(), [2, 3]);
+        assert!(fat.is_bad(fat.get(5)) && !fat.is_eoc(0xfff7));
+    }
+
+    #[test]
+    fn fat32_entries_ignore_the_top_bits() {
+        #[rustfmt::skip]
+        let raw = [
+            0xf8, 0xff, 0xff, 0x0f,
+            0xff, 0xff, 0xff, 0x0f,
+            0x03, 0x00, 0x00, 0x10,
+            0xf8, 0xff, 0xff, 0xff,
+            0x78, 0x56, 0x34, 0x12,
+        ];
+        let fat = read_fat(FatType::Fat32, 3, &raw);
+        assert_eq!(fat.get(2), 3);
+        assert_eq!(fat.get(3), 0x0fff_fff8);
+        assert_eq!(fat.get(4), 0x0234_5678);
+        assert_eq!(fat.chain(2).unwrap(), [2, 3]);
+        assert!(fat.chain(4).is_err());
+    }
+
+    #[test]
+    fn fats_too_small_for_the_volume_are_rejected() {
+        let raw = [0u8; 8];
+        for fat_type in [FatType::Fat12, FatType::Fat16, FatType::Fat32] {
+            let layout = layout(fat_type, 10, &raw);
+            assert!(Fat::read(&mut Cursor::new(raw.to_vec()), &layout).is_err());
+        }
+    }
+
+    #[test]
+    fn dir_entry_fields() {
+        let entry = readme();
+        assert_eq!(entry.short_name(), "README.TXT");
+        assert_eq!(entry.first_cluster(), 0x0001_1234);
+        assert_eq!(entry.size(), 4096);
+        assert!(!entry.is_dir() && !entry.is_lfn() && !entry.has_invalid_start());
+
+        let mut entry = entry;
+        entry.raw[12] = NT_LOWER_BASE | NT_LOWER_EXT;
+        assert_eq!(entry.display_name(), "readme.txt");
+        entry.raw[0] = 0x05;
+        assert_eq!(entry.short_name(), "?EADME.TXT");
+    }
+
+    #[test]
+    fn dir_entry_setters_write_little_endian() {
+        let mut entry = readme();
+        entry.set_first_cluster(0x0a0b_0c0d);
+        entry.set_size(0x0102_0304);
+        entry.set_modified(&fatfs::DateTime::new(
+            fatfs::Date::new(2024, 5, 1),
+            fatfs::Time::new(12, 34, 57, 0),
+        ));
+        assert_eq!(entry.raw[20..22], [0x0b, 0x0a]);
+        assert_eq!(entry.raw[26..28], [0x0d, 0x0c]);
+        assert_eq!(entry.raw[28..32], [0x04, 0x03, 0x02, 0x01]);
+        // 12:34:56, seconds are halved
+        assert_eq!(entry.raw[22..24], [0x5c, 0x64]);
+        assert_eq!(entry.raw[24..26], [0xa1, 0x58]);
+    }
+
+    #[test]
+    fn lfn_checksum_of_a_known_name() {
+        assert_eq!(lfn_checksum(b"README  TXT"), 0x73);
+    }
+
+    #[test]
+    fn long_names_come_from_lfn_entries() {
+        let dir = dir(&[readme_lfn(0x73), readme()]);
+        let files = dir.files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].0, "readme.txt");
+        assert!(dir.lookup("README.TXT").is_some());
+        assert!(dir.lookup("Readme.txt").is_some());
+    }
+
+    #[test]
+    fn lfn_entries_with_the_wrong_checksum_are_ignored() {
+        let dir = dir(&[readme_lfn(0x74), readme()]);
+        assert_eq!(dir.files()[0].0, "README.TXT");
+    }
+
+    #[test]
+    fn deleted_entries_and_labels_are_not_files() {
+        let mut deleted = readme();
+        deleted.raw[0] = 0xe5;
+        let label = dir_entry(&[(0, b"BOOT       "), (11, &[ATTR_VOLUME_ID])]);
+        let dir = dir(&[label, deleted, readme()]);
+        assert_eq!(dir.entries.len(), 3);
+        assert_eq!(dir.files().len(), 1);
+        assert_eq!(dir.slot_offset(2), Some(64));
+        assert_eq!(dir.slot_offset(4), None);
+    }
+}
@@ -0,0 +1,151 @@
+//! The `--output` format of commands printing more than plain lists.
+//!
+//! `classic` is the text these commands have always printed, kept as it
+//! is for scripts that parse it. `v2` is easier to read but may still
+//! change, and `json` is for machines. Commands printing labelled values,
+//! like `info`, `estimate` and `verify-boot`, lay them out with `Fields`,
+//! and `ls -l` has its own columns. The rest print the same in every
+//! mode, apart from `--output json` standing in for their `--json` flag
+//! where they have one.
+
+use std::fmt::Display;
+
+use crate::json;
+
+/// Output format of the commands that have more than one
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Output {
+    /// The text format of earlier releases, byte for byte
+    Classic,
+    /// Aligned columns, trimmed labels and decimal percentages
+    V2,
+    /// One JSON document, or one JSON object per line for `ls`
+    Json,
+}
+
+impl Output {
+    /// The format for `--output`, telling on stderr that the default is
+    /// going to change when it wasn't given
+    pub fn resolve(requested: Option<Self>) -> Self {
+        requested.unwrap_or_else(|| {
+            eprintln!(
+                "Note: the default --output will change from classic to v2 in the next \
+                 release, give --output classic to keep this format"
+            );
+            Self::Classic
+        })
+    }
+
+    pub fn is_json(requested: Option<Self>) -> bool {
+        requested == Some(Self::Json)
+    }
+}
+
+/// Labelled values like `fs type: FAT16`, one per line. `classic` pads
+/// labels to the width each command always used, `v2` to the longest
+/// label. As JSON they're an object of strings, for commands without a
+/// document of their own.
+pub struct Fields {
+    /// Width of the label and its colon in `classic`
+    classic_width: usize,
+    lines: Vec<(&'static str, String)>,
+}
+
+impl Fields {
+    pub fn new(classic_width: usize) -> Self {
+        Self {
+            classic_width,
+            lines: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, label: &'static str, value: impl Display) {
+        self.lines.push((label, value.to_string()));
+    }
+
+    pub fn render(&self, output: Output) -> String {
+        match output {
+            Output::Classic => self.lines(self.classic_width, " "),
+            Output::V2 => {
+                let width = self.lines.iter().map(|(label, _)| label.len()).max();
+                self.lines(width.unwrap_or(0) + 1, "  ")
+            },
+            Output::Json => {
+                let members = self
+                    .lines
+                    .iter()
+                    .map(|(label, value)| (json_key(label), value.as_str().into()))
+                    .collect();
+                format!("{}\n", json::versioned(json::Value::Object(members)))
+            },
+        }
+    }
+
+    pub fn print(&self, output: Output) {
+        print!("{}", self.render(output));
+    }
+
+    fn lines(&self, width: usize, gap: &str) -> String {
+        self.lines
+            .iter()
+            .map(|(label, value)| {
+                format!(
+                    "{:width$}{}{}\n",
+                    format!("{}:", label),
+                    gap,
+                    value,
+                    width = width
+                )
+            })
+            .collect()
+    }
+}
+
+/// `recommended --size` as `recommended_size`
+fn json_key(label: &str) -> String {
+    label
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Fields {
+        let mut fields = Fields::new(10);
+        fields.push("files", "3 in 1 directories");
+        fields.push("recommended --size", 4096);
+        fields
+    }
+
+    #[test]
+    fn classic_pads_to_its_width() {
+        assert_eq!(
+            fields().render(Output::Classic),
+            "files:     3 in 1 directories\nrecommended --size: 4096\n"
+        );
+    }
+
+    #[test]
+    fn v2_aligns_the_values() {
+        assert_eq!(
+            fields().render(Output::V2),
+            "files:               3 in 1 directories\nrecommended --size:  4096\n"
+        );
+    }
+
+    #[test]
+    fn json_keys_are_the_labels() {
+        assert!(
+            fields()
+                .render(Output::Json)
+                .ends_with(",\"files\":\"3 in 1 directories\",\"recommended_size\":\"4096\"}\n"),
+            "{}",
+            fields().render(Output::Json)
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! What commands may overwrite, shared so that the flags mean the same
+//! everywhere.
+//!
+//! `--force` allows replacing existing destination data. The global
+//! `--no-clobber` refuses replacing anything, even what a command replaces
+//! by default, and wins over `--force`.
+
+use std::fmt::Display;
+
+use anyhow::{bail, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverwritePolicy {
+    /// Whatever the command does by default
+    #[default]
+    Default,
+    /// `--force`
+    Force,
+    /// `--no-clobber`
+    NoClobber,
+}
+
+impl OverwritePolicy {
+    pub fn new(force: bool, no_clobber: bool) -> Self {
+        match (force, no_clobber) {
+            (_, true) => Self::NoClobber,
+            (true, false) => Self::Force,
+            (false, false) => Self::Default,
+        }
+    }
+
+    /// Whether to replace something that a command replaces by default
+    /// if `by_default` is set
+    pub fn allows(self, by_default: bool) -> bool {
+        match self {
+            Self::Default => by_default,
+            Self::Force => true,
+            Self::NoClobber => false,
+        }
+    }
+
+    /// Fails unless the existing `what` may be replaced
+    pub fn check(self, by_default: bool, what: impl Display) -> Result<()> {
+        if self.allows(by_default) {
+            return Ok(());
+        }
+        if self == Self::NoClobber {
+            bail!(
+                "{} already exists, not replacing it with --no-clobber",
+                what
+            );
+        }
+        bail!("{} already exists, use --force to overwrite", what);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_clobber_wins() {
+        assert_eq!(OverwritePolicy::new(false, false), OverwritePolicy::Default);
+        assert_eq!(OverwritePolicy::new(true, false), OverwritePolicy::Force);
+        assert_eq!(
+            OverwritePolicy::new(false, true),
+            OverwritePolicy::NoClobber
+        );
+        assert_eq!(OverwritePolicy::new(true, true), OverwritePolicy::NoClobber);
+    }
+
+    #[test]
+    fn matrix() {
+        for (force, no_clobber, by_default, allowed) in [
+            (false, false, false, false),
+            (false, false, true, true),
+            (true, false, false, true),
+            (true, false, true, true),
+            (false, true, false, false),
+            (false, true, true, false),
+            (true, true, false, false),
+            (true, true, true, false),
+        ] {
+            let policy = OverwritePolicy::new(force, no_clobber);
+            assert_eq!(
+                policy.allows(by_default),
+                allowed,
+                "{:?} {}",
+                policy,
+                by_default
+            );
+            assert_eq!(policy.check(by_default, "/A").is_ok(), allowed);
+        }
+    }
+
+    #[test]
+    fn messages_name_the_flag() {
+        let err = OverwritePolicy::Default
+            .check(false, "out.bin")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "out.bin already exists, use --force to overwrite"
+        );
+        let err = OverwritePolicy::NoClobber.check(true, "/A").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "/A already exists, not replacing it with --no-clobber"
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! Parsing paths inside the image given on the command line.
+//!
+//! Paths are absolute and `/`-separated. Repeated slashes, `.` and trailing
+//! slashes are ignored, `..` goes up one level and stops at the root. The
+//! normalized form has no leading slash, so the root is the empty string.
+
+use anyhow::{bail, Result};
+
+/// Normalizes a path that may refer to the root directory
+pub fn normalize(p: &str) -> Result<String> {
+    let rest = match p.strip_prefix('/') {
+        Some(rest) => rest,
+        None => bail!("Image paths must start with `/`, got {:?}", p),
+    };
+
+    let mut result: Vec<&str> = Vec::new();
+    for component in rest.split('/') {
+        match component {
+            "" | "." => {},
+            ".." => {
+                result.pop();
+            },
+            c => result.push(c),
+        }
+    }
+    Ok(result.join("/"))
+}
+
+/// `path` relative to the directory `dir`, both absolute like
+/// `walk::Walk` gives them, without a leading slash. `dir` itself is
+/// `.`, and paths outside of it are returned as they are.
+pub fn relative<'a>(dir: &str, path: &'a str) -> &'a str {
+    match path.strip_prefix(dir.trim_end_matches('/')) {
+        Some("") | Some("/") => ".",
+        Some(rest) if rest.starts_with('/') => &rest[1..],
+        _ => path,
+    }
+}
+
+/// Normalizes a path that must refer to an entry, not the root directory
+pub fn normalize_entry(p: &str) -> Result<String> {
+    let normalized = normalize(p)?;
+    if normalized.is_empty() {
+        bail!(
+            "{:?} is the root directory, a path to an entry is required",
+            p
+        );
+    }
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalized_forms() {
+        for (given, normalized) in [
+            ("/", ""),
+            ("//", ""),
+            ("/.", ""),
+            ("/./", ""),
+            ("/..", ""),
+            ("/../..", ""),
+            ("/EFI", "EFI"),
+            ("/EFI/", "EFI"),
+            ("/EFI/.", "EFI"),
+            ("//EFI//BOOT//", "EFI/BOOT"),
+            ("/EFI/./BOOT/.", "EFI/BOOT"),
+            ("/EFI/BOOT/..", "EFI"),
+            ("/EFI/../BOOT", "BOOT"),
+            ("/../EFI", "EFI"),
+            ("/a b/.hidden/...", "a b/.hidden/..."),
+        ] {
+            assert_eq!(normalize(given).unwrap(), normalized, "{}", given);
+        }
+    }
+
+    #[test]
+    fn relative_paths_are_refused() {
+        for given in ["", ".", "EFI", "./EFI", "../EFI"] {
+            let err = normalize(given).unwrap_err().to_string();
+            assert!(err.contains("must start with `/`"), "{}: {}", given, err);
+        }
+    }
+
+    #[test]
+    fn entries_are_not_the_root() {
+        assert_eq!(normalize_entry("/EFI/BOOT/").unwrap(), "EFI/BOOT");
+        for given in ["/", "/.", "//", "/EFI/..", "/.."] {
+            let err = normalize_entry(given).unwrap_err().to_string();
+            assert!(err.contains("is the root directory"), "{}: {}", given, err);
+        }
+        assert!(normalize_entry("EFI").is_err());
+    }
+
+    #[test]
+    fn relative_to_a_directory() {
+        for (dir, path, expected) in [
+            ("/EFI", "/EFI/BOOT/X.EFI", "BOOT/X.EFI"),
+            ("/EFI/", "/EFI/BOOT", "BOOT"),
+            ("/EFI", "/EFI", "."),
+            ("/EFI", "/EFI/", "."),
+            ("/", "/EFI", "EFI"),
+            ("", "/EFI", "EFI"),
+            ("/EFI", "/EFIX/A", "/EFIX/A"),
+            ("/EFI", "/OTHER", "/OTHER"),
+        ] {
+            assert_eq!(relative(dir, path), expected, "{} {}", dir, path);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+//! Rule sets an image has to follow to boot on some target.
+//!
+//! Each profile is a list of rules, and each rule explains why it passed
+//! or failed. New targets are added to `PROFILES`.
+
+use anyhow::Result;
+use fatfs::FatType;
+
+use crate::glob;
+use crate::ondisk::{BootSector, Fat, Layout, RawDir, RawDirEntry};
+use crate::region::ImgSlice;
+
+/// The parsed volume the rules look at
+pub struct Volume<'a> {
+    pub img: &'a mut ImgSlice,
+    pub bs: &'a BootSector,
+    pub layout: &'a Layout,
+    pub fat: &'a Fat,
+}
+
+/// Explanation of a pass, or of a failure
+pub type Outcome<T = String> = std::result::Result<T, String>;
+
+#[derive(Debug)]
+pub struct Rule {
+    pub name: &'static str,
+    /// Errors are for unreadable images, rule failures are outcomes
+    pub check: fn(&mut Volume) -> Result<Outcome>,
+}
+
+#[derive(Debug)]
+pub struct Profile {
+    pub name: &'static str,
+    pub rules: &'static [Rule],
+}
+
+pub const PROFILES: &[Profile] = &[
+    Profile {
+        name: "uefi-esp",
+        rules: UEFI_ESP,
+    },
+    BIOS,
+];
+
+/// Volume booted by a BIOS through its boot sector
+pub const BIOS: Profile = Profile {
+    name: "bios",
+    rules: &[
+        Rule {
+            name: "signature",
+            check: signature,
+        },
+        Rule {
+            name: "jump",
+            check: bios_jump,
+        },
+        Rule {
+            name: "backup-boot-sector",
+            check: backup_boot_sector,
+        },
+    ],
+};
+
+/// Boot code that does more than the placeholder formatters put there.
+/// Not part of `BIOS`, the boot code is often installed later.
+pub const BOOT_CODE: Rule = Rule {
+    name: "boot-code",
+    check: bios_boot_code,
+};
+
+/// Parses a `--profile` name
+pub fn by_name(name: &str) -> std::result::Result<&'static Profile, String> {
+    PROFILES.iter().find(|p| p.name == name).ok_or_else(|| {
+        let names: Vec<&str> = PROFILES.iter().map(|p| p.name).collect();
+        format!(
+            "Unknown profile {:?}, available: {}",
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+/// EFI system partition as read by UEFI firmware such as OVMF
+const UEFI_ESP: &[Rule] = &[
+    Rule {
+        name: "fat-type",
+        check: esp_fat_type,
+    },
+    Rule {
+        name: "signature",
+        check: signature,
+    },
+    Rule {
+        name: "cluster-size",
+        check: esp_cluster_size,
+    },
+    Rule {
+        name: "backup-boot-sector",
+        check: backup_boot_sector,
+    },
+    Rule {
+        name: "boot-file",
+        check: esp_boot_file,
+    },
+    Rule {
+        name: "boot-path-names",
+        check: esp_boot_path_names,
+    },
+];
+
+/// Smaller FAT16 volumes are often rejected as an ESP
+const ESP_MIN_FAT16_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Firmware drivers commonly only support clusters up to this size
+const ESP_MAX_CLUSTER_SIZE: u64 = 32 * 1024;
+
+/// Path of the removable media boot loader is `/EFI/BOOT/BOOT<arch>.EFI`
+const ESP_BOOT_DIRS: [&str; 2] = ["EFI", "BOOT"];
+const ESP_BOOT_FILE: &str = "BOOT*.EFI";
+
+fn esp_fat_type(v: &mut Volume) -> Result<Outcome> {
+    let size = v.bs.total_sectors() as u64 * v.bs.bytes_per_sector() as u64;
+    Ok(match v.layout.fat_type {
+        FatType::Fat32 => Ok("FAT32".to_owned()),
+        FatType::Fat16 if size >= ESP_MIN_FAT16_SIZE => Ok(format!("FAT16 with {} bytes", size)),
+        FatType::Fat16 => Err(format!(
+            "FAT16 with only {} bytes, at least {} are needed, or FAT32",
+            size, ESP_MIN_FAT16_SIZE
+        )),
+        FatType::Fat12 => {
+            Err("FAT12, which firmware may not accept for an ESP, use FAT32".to_owned())
+        },
+    })
+}
+
+fn signature(v: &mut Volume) -> Result<Outcome> {
+    Ok(if v.bs.has_signature() {
+        Ok("boot sector ends with 0x55AA".to_owned())
+    } else {
+        Err("boot sector is missing the 0x55AA signature".to_owned())
+    })
+}
+
+fn esp_cluster_size(v: &mut Volume) -> Result<Outcome> {
+    let size = v.layout.cluster_size;
+    Ok(if size <= ESP_MAX_CLUSTER_SIZE {
+        Ok(format!("{} bytes", size))
+    } else {
+        Err(format!(
+            "{} bytes, firmware may only support up to {}",
+            size, ESP_MAX_CLUSTER_SIZE
+        ))
+    })
+}
+
+fn backup_boot_sector(v: &mut Volume) -> Result<Outcome> {
+    if !v.bs.is_fat32() {
+        return Ok(Ok("not used by FAT12/16".to_owned()));
+    }
+    let offset = match v.bs.backup_offset() {
+        Some(offset) => offset,
+        None => return Ok(Err("not present".to_owned())),
+    };
+    let backup = BootSector::read_at(v.img, offset);
+    Ok(match backup {
+        Ok(backup) if backup.raw == v.bs.raw => Ok(format!(
+            "sector {} matches the primary",
+            v.bs.backup_boot_sector()
+        )),
+        _ => Err(format!(
+            "sector {} differs from the primary, run check --fix",
+            v.bs.backup_boot_sector()
+        )),
+    })
+}
+
+/// The BIOS jumps to the start of the boot sector, which has to skip
+/// over the BPB into the boot code
+fn bios_jump(v: &mut Volume) -> Result<Outcome> {
+    let raw = &v.bs.raw;
+    let target = match v.bs.jump_target() {
+        Some(target) => target,
+        None => {
+            return Ok(Err(format!(
+                "starts with {:02x} {:02x} {:02x}, not a jump",
+                raw[0], raw[1], raw[2]
+            )))
+        },
+    };
+    let code = v.bs.boot_code_offset() as isize;
+    Ok(if (code..510).contains(&target) {
+        Ok(format!("jumps to offset {}", target))
+    } else {
+        Err(format!(
+            "jumps to offset {}, outside of the boot code at {}..510",
+            target, code
+        ))
+    })
+}
+
+/// What mkfs.fat and fatfs put in place of boot code prints this
+const PLACEHOLDER_MESSAGE: &[u8] = b"This is not a bootable disk";
+
+fn bios_boot_code(v: &mut Volume) -> Result<Outcome> {
+    let code = v.bs.boot_code();
+    Ok(if code.iter().all(|&b| b == 0) {
+        Err("boot code is all zeros".to_owned())
+    } else if code
+        .windows(PLACEHOLDER_MESSAGE.len())
+        .any(|w| w == PLACEHOLDER_MESSAGE)
+    {
+        Err("boot code is the placeholder of the formatter".to_owned())
+    } else {
+        Ok(format!("{} bytes of boot code", code.len()))
+    })
+}
+
+/// Entries on the path to the boot loaders, the boot loaders last.
+/// Fails if a part of the path is missing.
+fn boot_path(v: &mut Volume) -> Result<Outcome<Vec<(String, RawDirEntry)>>> {
+    let mut found = Vec::new();
+    let mut dir = RawDir::read_root(v.img, v.layout, v.fat)?;
+    let mut path = String::new();
+    for name in ESP_BOOT_DIRS {
+        path = format!("{}/{}", path, name);
+        let entry = match dir
+            .files()
+            .into_iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            Some((n, e)) if e.is_dir() => (n, e.clone()),
+            _ => return Ok(Err(format!("directory {} is missing", path))),
+        };
+        dir = RawDir::read(v.img, v.layout, v.fat, entry.1.first_cluster())?;
+        found.push(entry);
+    }
+
+    let loaders: Vec<_> = dir
+        .files()
+        .into_iter()
+        .filter(|(n, e)| !e.is_dir() && glob::matches(ESP_BOOT_FILE, &n.to_uppercase()))
+        .map(|(n, e)| (n, e.clone()))
+        .collect();
+    if loaders.is_empty() {
+        return Ok(Err(format!("no {}/{} file", path, ESP_BOOT_FILE)));
+    }
+    found.extend(loaders);
+    Ok(Ok(found))
+}
+
+fn esp_boot_file(v: &mut Volume) -> Result<Outcome> {
+    Ok(boot_path(v)?.map(|found| {
+        let names: Vec<&str> = found[ESP_BOOT_DIRS.len()..]
+            .iter()
+            .map(|(n, _)| n.as_str())
+            .collect();
+        format!("found /EFI/BOOT/{}", names.join(", "))
+    }))
+}
+
+/// Some firmware only looks at 8.3 names, so the long names on the boot
+/// path must not differ from them other than in case
+fn esp_boot_path_names(v: &mut Volume) -> Result<Outcome> {
+    let found = match boot_path(v)? {
+        Ok(found) => found,
+        Err(_) => return Ok(Err("no boot path, see boot-file".to_owned())),
+    };
+    let hidden: Vec<String> = found
+        .iter()
+        .filter(|(n, e)| e.short_name() != n.to_uppercase())
+        .map(|(n, e)| format!("{} is {} as 8.3", n, e.short_name()))
+        .collect();
+    Ok(if hidden.is_empty() {
+        Ok("all visible as 8.3 names".to_owned())
+    } else {
+        Err(hidden.join(", "))
+    })
+}
@@ -0,0 +1,71 @@
+//! Paths that `rm`, `prune`, `clean-empty` and `write-tree --clean` never
+//! delete.
+//!
+//! Globs come from `--protect` and from the lines of `.fatimgprotect` in
+//! the root directory of the image, if there is one. Empty lines and
+//! lines starting with `#` are skipped. Globs are matched like other
+//! globs of this tool, against full image paths without the leading `/`.
+//! The protection file itself is always protected.
+
+use std::io::Read;
+
+use anyhow::{Context, Result};
+
+use crate::{glob, ImgDir};
+
+/// Name of the protection file in the root directory
+pub const PROTECT_FILE: &str = ".fatimgprotect";
+
+/// `--protect` of the deleting commands
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ProtectArgs {
+    /// Never delete paths matching this, e.g. `EFI/**` or `*.cfg`. Can be
+    /// repeated. Globs listed in `/.fatimgprotect` are added.
+    #[clap(long)]
+    pub protect: Vec<String>,
+}
+
+pub struct Protection {
+    globs: Vec<String>,
+    /// Entries protected so far
+    hits: usize,
+}
+
+impl Protection {
+    /// Combines `args` with the protection file of the image `root`
+    pub fn load(root: &ImgDir<'_>, args: &ProtectArgs) -> Result<Self> {
+        let mut globs = args.protect.clone();
+        globs.push(PROTECT_FILE.to_owned());
+        if let Ok(mut file) = root.open_file(PROTECT_FILE) {
+            let mut content = String::new();
+            file.read_to_string(&mut content)
+                .with_context(|| format!("failed reading /{}", PROTECT_FILE))?;
+            globs.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(str::to_owned),
+            );
+        }
+        Ok(Self { globs, hits: 0 })
+    }
+
+    /// Is the entry at the image path `path` protected. Protected entries
+    /// are counted for `finish`.
+    pub fn protects(&mut self, path: &str) -> bool {
+        let rel = path.trim_start_matches('/');
+        let protected = self.globs.iter().any(|g| glob::matches(g, rel));
+        if protected {
+            self.hits += 1;
+        }
+        protected
+    }
+
+    /// Notes how many entries were protected, if any
+    pub fn finish(&self) {
+        if self.hits > 0 {
+            eprintln!("{} protected entries kept", self.hits);
+        }
+    }
+}
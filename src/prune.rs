@@ -0,0 +1,118 @@
+//! Deleting the oldest files of a directory by count, total size or age
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::protect::{ProtectArgs, Protection};
+use crate::size::SizeFormat;
+use crate::walk::Walk;
+use crate::{clock, delta, inner_join, open_fs_rw, ImgDir};
+
+/// Options of the `prune` command
+pub struct PruneOptions {
+    /// Keep this many of the newest files
+    pub keep_newest: Option<usize>,
+    /// Delete the oldest files until the rest fit in this many bytes
+    pub max_total_size: Option<u64>,
+    /// Delete files modified before this, in `when::TimeSpec::local_secs`
+    pub older_than: Option<i64>,
+    /// Include files in subdirectories, which are otherwise skipped
+    pub recursive: bool,
+    /// Only list what would be deleted
+    pub dry_run: bool,
+    /// Protected files are left out of the set as if they were elsewhere
+    pub protect: ProtectArgs,
+}
+
+struct Candidate {
+    /// See `clock::local_secs`
+    modified: i64,
+    /// Path relative to the pruned directory
+    rel_path: String,
+    len: u64,
+}
+
+fn collect(dir: &ImgDir<'_>, recursive: bool, out: &mut Vec<Candidate>) -> Result<()> {
+    for item in Walk::new(dir, "", recursive) {
+        let item = item?;
+        if item.entry.is_dir() {
+            continue;
+        }
+        out.push(Candidate {
+            modified: clock::local_secs(&item.entry.modified()),
+            rel_path: item.path.trim_start_matches('/').to_owned(),
+            len: item.entry.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Number of files, oldest first, that have to go to satisfy `opts`
+fn victim_count(files: &[Candidate], opts: &PruneOptions) -> usize {
+    let mut count = match opts.keep_newest {
+        Some(keep) => files.len().saturating_sub(keep),
+        None => 0,
+    };
+    if let Some(cutoff) = opts.older_than {
+        let old = files.iter().take_while(|f| f.modified < cutoff).count();
+        count = count.max(old);
+    }
+    if let Some(cap) = opts.max_total_size {
+        let mut total: u64 = files[count..].iter().map(|f| f.len).sum();
+        while total > cap {
+            total -= files[count].len;
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Prunes the image directory `inner_path`
+pub fn run(
+    img_file: &Path, inner_path: &str, opts: &PruneOptions, sizes: &SizeFormat,
+) -> Result<()> {
+    let fs = open_fs_rw(img_file)?;
+    let mut dir = fs.root_dir();
+    if !inner_path.is_empty() {
+        dir = dir
+            .open_dir(inner_path)
+            .with_context(|| format!("failed opening directory /{}", inner_path))?;
+    }
+
+    let mut protection = Protection::load(&fs.root_dir(), &opts.protect)?;
+    let mut files = Vec::new();
+    collect(&dir, opts.recursive, &mut files)?;
+    files.retain(|f| !protection.protects(&inner_join(&format!("/{}", inner_path), &f.rel_path)));
+    files.sort_by(|a, b| (a.modified, &a.rel_path).cmp(&(b.modified, &b.rel_path)));
+
+    let victims = &files[..victim_count(&files, opts)];
+    for file in victims {
+        let path = inner_join(&format!("/{}", inner_path), &file.rel_path);
+        if opts.dry_run {
+            eprintln!("would delete {}", path);
+        } else {
+            dir.remove(&file.rel_path)
+                .with_context(|| format!("failed deleting {}", path))?;
+            delta::removed(false);
+            eprintln!("deleted {}", path);
+        }
+    }
+    drop(dir);
+    fs.unmount().context("failed flushing the filesystem")?;
+
+    let freed: u64 = victims.iter().map(|f| f.len).sum();
+    let verb = if opts.dry_run {
+        "would delete"
+    } else {
+        "deleted"
+    };
+    eprintln!(
+        "{} {} file(s), {} freed",
+        verb,
+        victims.len(),
+        sizes.logical(freed)
+    );
+    protection.finish();
+    Ok(())
+}
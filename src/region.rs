@@ -0,0 +1,475 @@
+//! The part of the image file that holds the FAT volume.
+//!
+//! That's the whole file by default. `--offset` and `--partition` select a
+//! volume inside a disk image instead. Every open of the image goes through
+//! `ImgSlice`, which addresses the volume from its first byte. Reads stop at
+//! the end of a partition and writes past it fail, so a runaway write
+//! can't reach the next partition.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::{bail, Context, Result};
+use fatfs::FatType;
+
+use crate::timeout::ImgFile;
+use crate::{ondisk, snapshot};
+
+/// An MBR partition table entry
+#[derive(Debug, Clone, Copy)]
+pub struct Partition {
+    /// 1 to 4
+    pub number: u8,
+    pub kind: u8,
+    pub start_lba: u32,
+    pub sectors: u32,
+}
+
+/// MBR sectors are 512 bytes regardless of the volume
+const MBR_SECTOR: u64 = 512;
+
+/// Partition type of the protective MBR entry on GPT disks
+const GPT_PROTECTIVE: u8 = 0xee;
+
+/// Where `create --mbr` starts the partition, at 1 MiB like partitioning
+/// tools do
+pub const DEFAULT_PARTITION_START: u32 = 2048;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Region {
+    start: u64,
+    /// Up to the end of the file if `None`
+    len: Option<u64>,
+    partition: Option<Partition>,
+}
+
+static REGION: OnceLock<Region> = OnceLock::new();
+
+/// Reads entry `number` of the MBR partition table of `img_file`
+pub fn read_partition(img_file: &Path, number: u8) -> Result<Partition> {
+    if !(1..=4).contains(&number) {
+        bail!(
+            "Invalid partition {}, expected 1 to 4 of the MBR partition table",
+            number
+        );
+    }
+    let mut mbr = [0u8; 512];
+    let mut file =
+        File::open(img_file).with_context(|| format!("failed opening {}", img_file.display()))?;
+    file.read_exact(&mut mbr)
+        .with_context(|| format!("{}: no MBR to read", img_file.display()))?;
+    if mbr[510] != 0x55 || mbr[511] != 0xaa {
+        bail!("{}: no MBR partition table", img_file.display());
+    }
+
+    let raw = &mbr[446 + (number as usize - 1) * 16..][..16];
+    let partition = Partition {
+        number,
+        kind: raw[4],
+        start_lba: ondisk::le32(raw, 8),
+        sectors: ondisk::le32(raw, 12),
+    };
+    if partition.kind == GPT_PROTECTIVE {
+        bail!(
+            "{} has a GPT partition table, only MBR partitions are supported, use --offset",
+            img_file.display()
+        );
+    }
+    if partition.kind == 0 || partition.sectors == 0 {
+        bail!("{}: partition {} is empty", img_file.display(), number);
+    }
+    Ok(partition)
+}
+
+/// Sectors of the partition `create --mbr` makes from `start_lba` to the
+/// end of an image of `image_size` bytes
+pub fn partition_sectors(image_size: u64, start_lba: u32) -> Result<u32> {
+    if start_lba == 0 {
+        bail!("Invalid partition start 0, the MBR is in sector 0");
+    }
+    let end = image_size / MBR_SECTOR;
+    if start_lba as u64 >= end {
+        bail!(
+            "Partition start sector {} is past the end of a {} byte image",
+            start_lba,
+            image_size
+        );
+    }
+    let sectors = end - start_lba as u64;
+    if sectors > u32::MAX as u64 {
+        bail!(
+            "A {} byte image is too large for an MBR partition, which ends within 2 TiB",
+            image_size
+        );
+    }
+    Ok(sectors as u32)
+}
+
+/// MBR partition type of a FAT volume of `sectors` sectors, the LBA
+/// variant for FAT32 and FAT16 past 32 MiB
+pub fn partition_type(fat_type: FatType, sectors: u32) -> u8 {
+    match fat_type {
+        FatType::Fat12 => 0x01,
+        FatType::Fat16 if sectors < 65536 => 0x04,
+        FatType::Fat16 => 0x06,
+        FatType::Fat32 => 0x0c,
+    }
+}
+
+/// Head, sector and cylinder bytes of `lba` in the usual translation of
+/// 255 heads and 63 sectors per track, or the largest value for sectors it
+/// can't address
+fn chs(lba: u32) -> [u8; 3] {
+    const HEADS: u32 = 255;
+    const SECTORS: u32 = 63;
+    let cylinder = lba / (HEADS * SECTORS);
+    if cylinder > 1023 {
+        return [0xfe, 0xff, 0xff];
+    }
+    let head = lba / SECTORS % HEADS;
+    let sector = lba % SECTORS + 1;
+    [
+        head as u8,
+        sector as u8 | ((cylinder >> 2) & 0xc0) as u8,
+        cylinder as u8,
+    ]
+}
+
+/// Writes an MBR without boot code and with `partition` as its only,
+/// active entry
+pub fn write_mbr(file: &mut File, partition: &Partition) -> io::Result<()> {
+    let mut mbr = [0u8; 512];
+    let raw = &mut mbr[446 + (partition.number as usize - 1) * 16..][..16];
+    let last = partition.start_lba + partition.sectors - 1;
+    raw[0] = 0x80;
+    raw[1..4].copy_from_slice(&chs(partition.start_lba));
+    raw[4] = partition.kind;
+    raw[5..8].copy_from_slice(&chs(last));
+    ondisk::set_le32(raw, 8, partition.start_lba);
+    ondisk::set_le32(raw, 12, partition.sectors);
+    mbr[510] = 0x55;
+    mbr[511] = 0xaa;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&mbr)
+}
+
+/// Fails with a message naming `img_file` if it can't be an image to
+/// read, or to write with `write`. These are I/O errors for the exit code.
+pub fn check_image(img_file: &Path, write: bool) -> Result<()> {
+    let fail = |kind: ErrorKind, msg: String| Err(io::Error::new(kind, msg).into());
+    let path = img_file.display();
+    let meta = match fs::metadata(img_file) {
+        Ok(meta) => meta,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            let hint = if write {
+                ", use create to make one"
+            } else {
+                ""
+            };
+            return fail(err.kind(), format!("{}: no such image file{}", path, hint));
+        },
+        Err(err) => return Err(err).with_context(|| format!("failed reading {}", path)),
+    };
+    if meta.is_dir() {
+        return fail(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} is a directory, give the image file in it, like {}",
+                path,
+                img_file.join("disk.img").display()
+            ),
+        );
+    }
+    if let Err(err) = OpenOptions::new().read(true).write(write).open(img_file) {
+        if err.kind() == ErrorKind::PermissionDenied {
+            let access = if write { "read and write" } else { "read" };
+            return fail(
+                err.kind(),
+                format!(
+                    "{}: permission denied, the command needs {} access",
+                    path, access
+                ),
+            );
+        }
+        return Err(err).with_context(|| format!("failed opening {}", path));
+    }
+    // Devices have no length here
+    if meta.is_file() && meta.len() < MBR_SECTOR {
+        return fail(
+            ErrorKind::InvalidData,
+            format!(
+                "{}: {} bytes is too small for a FAT volume, which takes at least 512",
+                path,
+                meta.len()
+            ),
+        );
+    }
+    Ok(())
+}
+
+/// Sets up the region of this invocation from `--offset` or `--partition`
+pub fn init(img_file: &Path, offset: Option<u64>, partition: Option<u8>) -> Result<()> {
+    let region = match (offset, partition) {
+        (_, Some(number)) => {
+            let partition = read_partition(img_file, number)?;
+            Region {
+                start: partition.start_lba as u64 * MBR_SECTOR,
+                len: Some(partition.sectors as u64 * MBR_SECTOR),
+                partition: Some(partition),
+            }
+        },
+        (Some(start), None) => Region {
+            start,
+            ..Region::default()
+        },
+        (None, None) => Region::default(),
+    };
+    REGION.set(region).expect("region already set");
+    Ok(())
+}
+
+fn get() -> Region {
+    REGION.get().copied().unwrap_or_default()
+}
+
+/// Byte offset of the volume in the image file
+pub fn start() -> u64 {
+    get().start
+}
+
+/// Whether the volume is the whole image file
+pub fn is_whole_file() -> bool {
+    let region = get();
+    region.start == 0 && region.len.is_none()
+}
+
+/// The partition given with `--partition`
+pub fn partition() -> Option<Partition> {
+    get().partition
+}
+
+/// The volume part of an open image file, with I/O guarded by the
+/// `--io-timeout`
+pub struct ImgSlice {
+    file: ImgFile,
+    start: u64,
+    len: Option<u64>,
+    /// Relative to `start`
+    pos: u64,
+    /// Whether this is a snapshot to `snapshot::fill` before reading
+    lazy: bool,
+}
+
+impl ImgSlice {
+    /// Opens the volume in the image file `path`, for writing if `write`
+    pub fn open(path: &Path, write: bool) -> io::Result<Self> {
+        let lazy = snapshot::is_lazy(path);
+        let region = get();
+        Ok(Self {
+            lazy,
+            ..Self::open_at(path, write || lazy, region.start, region.len)?
+        })
+    }
+
+    /// Opens the `len` bytes from `start` of the image file `path`, or the
+    /// rest of it, whatever `--offset` and `--partition` say
+    pub fn open_at(path: &Path, write: bool, start: u64, len: Option<u64>) -> io::Result<Self> {
+        Ok(Self {
+            file: ImgFile::open(path, write)?,
+            start,
+            len,
+            pos: 0,
+            lazy: false,
+        })
+    }
+
+    /// Size of the volume part, up to the end of the file without a
+    /// partition
+    pub fn len(&mut self) -> io::Result<u64> {
+        match self.len {
+            Some(len) => Ok(len),
+            None => Ok(self.file.len()?.saturating_sub(self.start)),
+        }
+    }
+
+    pub fn sync_all(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            file: self.file.try_clone()?,
+            ..*self
+        })
+    }
+
+    /// Bytes from the position to the end of the partition
+    fn remaining(&self) -> Option<u64> {
+        self.len.map(|len| len.saturating_sub(self.pos))
+    }
+}
+
+impl Read for ImgSlice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = match self.remaining() {
+            Some(remaining) => buf.len().min(remaining as usize),
+            None => buf.len(),
+        };
+        if self.lazy {
+            snapshot::fill(&mut self.file, self.start + self.pos, len as u64)?;
+        }
+        self.file.seek(SeekFrom::Start(self.start + self.pos))?;
+        let n = self.file.read(&mut buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for ImgSlice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.remaining().is_some_and(|r| (buf.len() as u64) > r) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "refusing to write past the end of the partition at byte {}",
+                    self.len.unwrap_or(0)
+                ),
+            ));
+        }
+        self.file.seek(SeekFrom::Start(self.start + self.pos))?;
+        let n = self.file.write(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for ImgSlice {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+            SeekFrom::End(d) => self.len()?.checked_add_signed(d),
+        };
+        match target {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            },
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the volume",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file of its own for the test `name`
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("fatimg-region-{}-{}.img", std::process::id(), name))
+    }
+
+    /// Writes an MBR with the 16-byte table entry `entry` in slot 2
+    fn write_table(path: &Path, entry: [u8; 16]) {
+        let mut mbr = [0u8; 512];
+        mbr[462..478].copy_from_slice(&entry);
+        mbr[510..].copy_from_slice(&[0x55, 0xaa]);
+        fs::write(path, mbr).unwrap();
+    }
+
+    #[test]
+    fn chs_addresses() {
+        assert_eq!(chs(0), [0x00, 0x01, 0x00]);
+        assert_eq!(chs(62), [0x00, 0x3f, 0x00]);
+        assert_eq!(chs(63), [0x01, 0x01, 0x00]);
+        assert_eq!(chs(2048), [0x20, 0x21, 0x00]);
+        assert_eq!(chs(255 * 63), [0x00, 0x01, 0x01]);
+        // Cylinder 256 keeps its high bits in the sector byte
+        assert_eq!(chs(256 * 255 * 63), [0x00, 0x41, 0x00]);
+        assert_eq!(chs(1024 * 255 * 63 - 1), [0xfe, 0xff, 0xff]);
+        assert_eq!(chs(1024 * 255 * 63), [0xfe, 0xff, 0xff]);
+        assert_eq!(chs(u32::MAX), [0xfe, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn mbr_entry_bytes() {
+        let path = temp_path("write");
+        let partition = Partition {
+            number: 1,
+            kind: 0x0c,
+            start_lba: 2048,
+            sectors: 0x0001_f800,
+        };
+        write_mbr(&mut File::create(&path).unwrap(), &partition).unwrap();
+        let mbr = fs::read(&path).unwrap();
+        let read = read_partition(&path, 1);
+        fs::remove_file(&path).unwrap();
+
+        #[rustfmt::skip]
+        let entry = [
+            0x80, 0x20, 0x21, 0x00,
+            0x0c, 0x28, 0x20, 0x08,
+            0x00, 0x08, 0x00, 0x00,
+            0x00, 0xf8, 0x01, 0x00,
+        ];
+        assert_eq!(mbr.len(), 512);
+        assert_eq!(mbr[446..462], entry);
+        assert!(mbr[462..510].iter().all(|&b| b == 0));
+        assert_eq!(mbr[510..], [0x55, 0xaa]);
+
+        let read = read.unwrap();
+        assert_eq!(
+            (read.number, read.kind, read.start_lba, read.sectors),
+            (1, 0x0c, 2048, 0x0001_f800)
+        );
+    }
+
+    #[test]
+    fn mbr_entries_are_read_little_endian() {
+        let path = temp_path("read");
+        #[rustfmt::skip]
+        write_table(&path, [
+            0x00, 0xfe, 0xff, 0xff,
+            0x06, 0xfe, 0xff, 0xff,
+            0x78, 0x56, 0x34, 0x12,
+            0x04, 0x03, 0x02, 0x01,
+        ]);
+        let second = read_partition(&path, 2);
+        let first = read_partition(&path, 1);
+        let fifth = read_partition(&path, 5);
+        fs::remove_file(&path).unwrap();
+
+        let second = second.unwrap();
+        assert_eq!(
+            (second.kind, second.start_lba, second.sectors),
+            (0x06, 0x1234_5678, 0x0102_0304)
+        );
+        assert!(first
+            .unwrap_err()
+            .to_string()
+            .contains("partition 1 is empty"));
+        assert!(fifth.is_err());
+    }
+
+    #[test]
+    fn gpt_disks_are_rejected() {
+        let path = temp_path("gpt");
+        let mut entry = [0u8; 16];
+        entry[4] = GPT_PROTECTIVE;
+        entry[8..12].copy_from_slice(&[0x01, 0x00, 0x00, 0x00]);
+        entry[12..].copy_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+        write_table(&path, entry);
+        let read = read_partition(&path, 2);
+        fs::remove_file(&path).unwrap();
+        assert!(read.unwrap_err().to_string().contains("GPT"));
+    }
+}
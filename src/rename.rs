@@ -0,0 +1,304 @@
+//! Renaming many entries at once by pattern substitution
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+
+use crate::{glob, inner_join, ntcase, open_fs_rw, ImgDir};
+
+/// Options of the `rename` command
+pub struct RenameOptions {
+    /// Rename entries in subdirectories too
+    pub recursive: bool,
+    /// Only print what would be renamed
+    pub dry_run: bool,
+}
+
+/// What names are matched with
+pub enum Matcher {
+    /// Captures are the parts matched by `*` and `?`
+    Glob(String),
+    /// Anchored to the whole name, captures are the groups
+    Regex(Regex),
+}
+
+impl Matcher {
+    pub fn new(pattern: &str, regex: bool) -> Result<Self> {
+        if !regex {
+            return Ok(Self::Glob(pattern.to_owned()));
+        }
+        let re = Regex::new(&format!("^(?:{})$", pattern))
+            .with_context(|| format!("invalid regex {:?}", pattern))?;
+        Ok(Self::Regex(re))
+    }
+
+    /// Number of captures, not counting the whole name
+    fn groups(&self) -> usize {
+        match self {
+            Self::Glob(pattern) => pattern.chars().filter(|&c| c == '*' || c == '?').count(),
+            Self::Regex(re) => re.captures_len() - 1,
+        }
+    }
+
+    /// The whole name followed by the captures, if `name` matches
+    fn captures(&self, name: &str) -> Option<Vec<String>> {
+        let mut caps = vec![name.to_owned()];
+        match self {
+            Self::Glob(pattern) => caps.extend(glob::captures(pattern, name)?),
+            Self::Regex(re) => {
+                let found = re.captures(name)?;
+                caps.extend((1..found.len()).map(|i| {
+                    found
+                        .get(i)
+                        .map_or(String::new(), |m| m.as_str().to_owned())
+                }));
+            },
+        }
+        Some(caps)
+    }
+}
+
+/// Parts of a replacement, where `{N}` stands for capture N
+enum Part<'a> {
+    Text(&'a str),
+    Capture(usize),
+}
+
+fn parse_replacement(replacement: &str) -> Vec<Part<'_>> {
+    let mut parts = Vec::new();
+    let mut rest = replacement;
+    while let Some(open) = rest.find('{') {
+        let capture = rest[open + 1..].find('}').and_then(|close| {
+            let digits = &rest[open + 1..open + 1 + close];
+            let index = digits
+                .parse()
+                .ok()
+                .filter(|_| digits.bytes().all(|c| c.is_ascii_digit()));
+            index.map(|i| (i, open + close + 2))
+        });
+        match capture {
+            Some((index, end)) => {
+                parts.push(Part::Text(&rest[..open]));
+                parts.push(Part::Capture(index));
+                rest = &rest[end..];
+            },
+            None => {
+                parts.push(Part::Text(&rest[..=open]));
+                rest = &rest[open + 1..];
+            },
+        }
+    }
+    parts.push(Part::Text(rest));
+    parts
+}
+
+fn substitute(parts: &[Part], caps: &[String]) -> String {
+    parts
+        .iter()
+        .map(|part| match part {
+            Part::Text(text) => *text,
+            Part::Capture(i) => &caps[*i],
+        })
+        .collect()
+}
+
+/// Renames planned in one directory
+struct DirPlan {
+    /// Normalized image path of the directory
+    path: String,
+    renames: Vec<(String, String)>,
+    /// Uppercased names of all entries, for finding collisions
+    existing: HashSet<String>,
+}
+
+fn plan(
+    dir: &ImgDir<'_>, path: &str, matcher: &Matcher, parts: &[Part], recursive: bool,
+    plans: &mut Vec<DirPlan>,
+) -> Result<()> {
+    let display = |name: &str| inner_join(&format!("/{}", path), name);
+    let mut renames = Vec::new();
+    let mut existing = HashSet::new();
+    let mut subdirs = Vec::new();
+    for entry in dir.iter() {
+        let entry = entry.with_context(|| format!("failed reading directory /{}", path))?;
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        existing.insert(name.to_uppercase());
+        if entry.is_dir() && recursive {
+            subdirs.push(name.clone());
+        }
+        if let Some(caps) = matcher.captures(&name) {
+            let target = substitute(parts, &caps);
+            if target.is_empty() || target == "." || target == ".." || target.contains('/') {
+                bail!(
+                    "{} would be renamed to invalid name {:?}",
+                    display(&name),
+                    target
+                );
+            }
+            if target != name {
+                renames.push((name, target));
+            }
+        }
+    }
+
+    // Every name must be unique when compared like FAT does. Entries that
+    // are renamed themselves free up their names.
+    let sources: HashSet<String> = renames
+        .iter()
+        .map(|(from, _)| from.to_uppercase())
+        .collect();
+    let mut targets = HashSet::new();
+    for (from, to) in &renames {
+        let upper = to.to_uppercase();
+        if !targets.insert(upper.clone()) {
+            bail!(
+                "more than one entry would be renamed to {}, nothing was renamed",
+                display(to)
+            );
+        }
+        if existing.contains(&upper) && !sources.contains(&upper) {
+            bail!(
+                "renaming {} would overwrite {}, nothing was renamed",
+                display(from),
+                display(to)
+            );
+        }
+    }
+
+    for name in subdirs {
+        let sub = dir
+            .open_dir(&name)
+            .with_context(|| format!("failed opening directory {}", display(&name)))?;
+        let sub_path = inner_join(path, &name);
+        plan(
+            &sub,
+            sub_path.trim_start_matches('/'),
+            matcher,
+            parts,
+            recursive,
+            plans,
+        )?;
+    }
+    plans.push(DirPlan {
+        path: path.to_owned(),
+        renames,
+        existing,
+    });
+    Ok(())
+}
+
+/// Performs the renames of one directory. Names that another entry still
+/// has, including case-only renames, go through a temporary name first.
+fn execute(dir: &ImgDir<'_>, plan: &DirPlan) -> Result<()> {
+    let display = |name: &str| inner_join(&format!("/{}", plan.path), name);
+    let sources: HashSet<String> = plan
+        .renames
+        .iter()
+        .map(|(from, _)| from.to_uppercase())
+        .collect();
+    let targets: HashSet<String> = plan
+        .renames
+        .iter()
+        .map(|(_, to)| to.to_uppercase())
+        .collect();
+    let mut counter = 0;
+    let mut temp_name = || loop {
+        counter += 1;
+        let name = format!("~rename{}.tmp", counter);
+        let upper = name.to_uppercase();
+        if !plan.existing.contains(&upper) && !targets.contains(&upper) {
+            return name;
+        }
+    };
+
+    let mut second = Vec::new();
+    for (from, to) in &plan.renames {
+        let via = if sources.contains(&to.to_uppercase()) {
+            let temp = temp_name();
+            second.push((temp.clone(), to));
+            temp
+        } else {
+            to.clone()
+        };
+        dir.rename(from, dir, &via)
+            .with_context(|| format!("failed renaming {} to {}", display(from), display(&via)))?;
+        println!("renamed {} -> {}", display(from), display(to));
+    }
+    for (temp, to) in second {
+        dir.rename(&temp, dir, to)
+            .with_context(|| format!("failed renaming {} to {}", display(&temp), display(to)))?;
+    }
+    Ok(())
+}
+
+/// Renames entries of the image directory `inner_path` matching `matcher`
+/// to `replacement`. All collisions are found before anything is renamed.
+pub fn run(
+    img_file: &Path, inner_path: &str, matcher: &Matcher, replacement: &str, opts: &RenameOptions,
+) -> Result<()> {
+    let parts = parse_replacement(replacement);
+    for part in &parts {
+        if let Part::Capture(i) = part {
+            if *i > matcher.groups() {
+                bail!(
+                    "replacement refers to {{{}}}, but the pattern has {} capture(s)",
+                    i,
+                    matcher.groups()
+                );
+            }
+        }
+    }
+
+    let fs = open_fs_rw(img_file)?;
+    let root = fs.root_dir();
+    let open = |path: &str| -> Result<ImgDir<'_>> {
+        if path.is_empty() {
+            return Ok(fs.root_dir());
+        }
+        root.open_dir(path)
+            .with_context(|| format!("failed opening directory /{}", path))
+    };
+
+    // Subdirectories come before their parents, so paths stay valid
+    let mut plans = Vec::new();
+    plan(
+        &open(inner_path)?,
+        inner_path,
+        matcher,
+        &parts,
+        opts.recursive,
+        &mut plans,
+    )?;
+    let count: usize = plans.iter().map(|p| p.renames.len()).sum();
+
+    if opts.dry_run {
+        for plan in &plans {
+            for (from, to) in &plan.renames {
+                let dir = format!("/{}", plan.path);
+                println!(
+                    "would rename {} -> {}",
+                    inner_join(&dir, from),
+                    inner_join(&dir, to)
+                );
+            }
+        }
+        println!("would rename {} entries", count);
+        return Ok(());
+    }
+
+    for plan in &plans {
+        if !plan.renames.is_empty() {
+            execute(&open(&plan.path)?, plan)?;
+        }
+    }
+    drop(root);
+    fs.unmount().context("failed flushing the filesystem")?;
+    ntcase::fold_tree(img_file, inner_path)?;
+    println!("renamed {} entries", count);
+    Ok(())
+}
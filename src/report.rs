@@ -0,0 +1,311 @@
+//! The `--report` file, recording what one invocation did.
+//!
+//! Changes are found by comparing the image tree before and after the
+//! command, so commands need no bookkeeping of their own. Warnings and
+//! reads are noted as they happen.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+
+use crate::clock;
+use crate::delta::Changes;
+use crate::json::{self, Value};
+use crate::ondisk::{BootSector, Fat, RawDir};
+use crate::region::ImgSlice;
+use crate::warnings::Category;
+
+static WARNINGS: Mutex<Vec<(Category, String)>> = Mutex::new(Vec::new());
+static READS: Mutex<Vec<(String, u64)>> = Mutex::new(Vec::new());
+static RENAMES: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// Notes a warning for the report, see `warnings::warn`
+pub fn warning(category: Category, msg: String) {
+    WARNINGS.lock().unwrap().push((category, msg));
+}
+
+/// Categories of the warnings noted so far
+pub fn warning_categories() -> BTreeSet<Category> {
+    WARNINGS.lock().unwrap().iter().map(|(c, _)| *c).collect()
+}
+
+/// Notes that `bytes` were read from the image path `path`
+pub fn read(path: String, bytes: u64) {
+    READS.lock().unwrap().push((path, bytes));
+}
+
+/// Notes that the image path `path` was extracted as `host` instead of
+/// under its own name
+pub fn renamed(path: String, host: String) {
+    RENAMES.lock().unwrap().push((path, host));
+}
+
+/// What an entry looked like, enough to tell whether it changed
+#[derive(PartialEq, Eq)]
+struct Stamp {
+    is_dir: bool,
+    size: u32,
+    first_cluster: u32,
+    /// Raw modification time and date
+    modified: [u8; 4],
+}
+
+struct Snapshot {
+    entries: BTreeMap<String, Stamp>,
+    free_bytes: u64,
+}
+
+impl Snapshot {
+    /// Fails on images that aren't a readable filesystem, like one that
+    /// is yet to be created
+    fn take(img_file: &Path) -> Result<Self> {
+        let mut img = ImgSlice::open(img_file, false)?;
+        let layout = BootSector::read(&mut img)?.layout()?;
+        let fat = Fat::read(&mut img, &layout)?;
+        let free = (2..layout.total_clusters + 2)
+            .filter(|&c| fat.get(c) == 0)
+            .count() as u64;
+
+        let mut entries = BTreeMap::new();
+        let mut visited = HashSet::new();
+        let mut stack = vec![(String::new(), RawDir::read_root(&mut img, &layout, &fat)?)];
+        while let Some((path, dir)) = stack.pop() {
+            for (name, entry) in dir.files() {
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let sub_path = format!("{}/{}", path, name);
+                let mut modified = [0u8; 4];
+                modified.copy_from_slice(&entry.raw[22..26]);
+                let stamp = Stamp {
+                    is_dir: entry.is_dir(),
+                    size: entry.size(),
+                    first_cluster: entry.first_cluster(),
+                    modified,
+                };
+                if stamp.is_dir && visited.insert(stamp.first_cluster) {
+                    stack.push((
+                        sub_path.clone(),
+                        RawDir::read(&mut img, &layout, &fat, stamp.first_cluster)?,
+                    ));
+                }
+                entries.insert(sub_path, stamp);
+            }
+        }
+        Ok(Self {
+            entries,
+            free_bytes: free * layout.cluster_size,
+        })
+    }
+}
+
+fn entry_json(path: &str, stamp: &Stamp) -> Value {
+    json::object([
+        ("path", path.into()),
+        ("type", if stamp.is_dir { "dir" } else { "file" }.into()),
+        ("size", (!stamp.is_dir).then_some(stamp.size as u64).into()),
+    ])
+}
+
+/// Whether the report `path` would be written over the image `img_file`
+fn is_image(path: &Path, img_file: &Path) -> bool {
+    match (fs::canonicalize(path), fs::canonicalize(img_file)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => path == img_file,
+    }
+}
+
+/// A report being collected while a command runs
+pub struct Report {
+    path: PathBuf,
+    /// Unknown until `--image-by-label` has been resolved
+    img_file: Option<PathBuf>,
+    command: Option<String>,
+    mutating: bool,
+    args: Vec<String>,
+    start: SystemTime,
+    /// Taken for commands that change the image
+    before: Option<Option<Snapshot>>,
+}
+
+impl Report {
+    /// Starts the report for a command, which changes the image if
+    /// `mutating`. Started right after parsing the arguments, so that
+    /// failures while opening the image are reported too.
+    pub fn start(path: &Path, command: Option<String>, mutating: bool) -> Self {
+        Self {
+            path: path.to_owned(),
+            img_file: None,
+            command,
+            mutating,
+            args: std::env::args_os()
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect(),
+            start: SystemTime::now(),
+            before: None,
+        }
+    }
+
+    /// Records the image the command runs on, which the report must not
+    /// be written over
+    pub fn image(&mut self, img_file: &Path) -> Result<()> {
+        self.img_file = Some(img_file.to_owned());
+        if is_image(&self.path, img_file) {
+            bail!(
+                "The report can't be written over the image {}",
+                img_file.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Notes the state of the image before a mutating command runs. The
+    /// image region must be initialized first.
+    pub fn take_before(&mut self) {
+        if let (true, Some(img_file)) = (self.mutating, &self.img_file) {
+            self.before = Some(Snapshot::take(img_file).ok());
+        }
+    }
+
+    /// Writes the report, recording `result` if the command failed and
+    /// the summary `delta` of a mutating one. Nothing is written if the
+    /// report path is the image, `image` failed for that already.
+    pub fn finish(self, result: &Result<()>, delta: Option<&Changes>) -> Result<()> {
+        if let Some(img_file) = &self.img_file {
+            if is_image(&self.path, img_file) {
+                return Ok(());
+            }
+        }
+        let path = self.path.clone();
+        let report = self.into_json(result, delta);
+        fs::write(&path, format!("{}\n", report))
+            .with_context(|| format!("failed writing the report {}", path.display()))
+    }
+
+    /// The report document, see `finish`
+    fn into_json(self, result: &Result<()>, delta: Option<&Changes>) -> Value {
+        let mut changes = Vec::new();
+        if let (Some(before), Some(img_file)) = (self.before, &self.img_file) {
+            let after = Snapshot::take(img_file).ok();
+            let empty = BTreeMap::new();
+            let old = before.as_ref().map_or(&empty, |s| &s.entries);
+            let new = after.as_ref().map_or(&empty, |s| &s.entries);
+
+            let mut created = Vec::new();
+            let mut modified = Vec::new();
+            let mut bytes_written = 0;
+            for (path, stamp) in new {
+                match old.get(path) {
+                    Some(previous) if previous == stamp => continue,
+                    Some(_) => modified.push(entry_json(path, stamp)),
+                    None => created.push(entry_json(path, stamp)),
+                }
+                if !stamp.is_dir {
+                    bytes_written += stamp.size as u64;
+                }
+            }
+            let deleted = old
+                .iter()
+                .filter(|(path, _)| !new.contains_key(*path))
+                .map(|(path, stamp)| entry_json(path, stamp));
+
+            let free = |s: &Option<Snapshot>| s.as_ref().map(|s| s.free_bytes);
+            changes = vec![
+                ("created", Value::Array(created)),
+                ("modified", Value::Array(modified)),
+                ("deleted", deleted.collect()),
+                ("bytes_written", bytes_written.into()),
+                ("free_before", free(&before).into()),
+                ("free_after", free(&after).into()),
+                ("delta", delta.map(Changes::to_json).into()),
+            ];
+        }
+
+        let reads = READS.lock().unwrap();
+        let read = reads.iter().map(|(path, bytes)| {
+            json::object([("path", path.as_str().into()), ("bytes", (*bytes).into())])
+        });
+        let renames = RENAMES.lock().unwrap();
+        let renamed = renames.iter().map(|(path, host)| {
+            json::object([
+                ("path", path.as_str().into()),
+                ("host", host.as_str().into()),
+            ])
+        });
+        let warnings = WARNINGS.lock().unwrap();
+        let warnings = warnings.iter().map(|(category, msg)| {
+            json::object([
+                ("code", category.code().into()),
+                ("message", msg.as_str().into()),
+            ])
+        });
+        let error = result.as_ref().err().map(|e| format!("{:#}", e));
+
+        let mut members = vec![
+            ("command", self.command.as_deref().into()),
+            ("arguments", self.args.iter().map(String::as_str).collect()),
+            (
+                "image",
+                self.img_file
+                    .as_ref()
+                    .map(|p| p.to_string_lossy().into_owned())
+                    .into(),
+            ),
+            ("start", clock::format_utc(self.start).into()),
+            ("end", clock::format_utc(SystemTime::now()).into()),
+        ];
+        members.extend(changes);
+        members.extend([
+            ("read", read.collect()),
+            ("renamed", renamed.collect()),
+            ("warnings", warnings.collect()),
+            ("error", error.into()),
+        ]);
+        json::versioned(Value::Object(
+            members
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v))
+                .collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Document;
+
+    #[test]
+    fn json_matches_the_schema() {
+        let report = Report::start(Path::new("report.json"), Some("ls".to_owned()), false);
+        let doc = report.into_json(&Ok(()), None);
+        assert_eq!(Document::Report.validate(&doc), Ok(()));
+
+        let report = Report::start(Path::new("report.json"), None, false);
+        let doc = report.into_json(&Err(anyhow::Error::msg("No such file")), None);
+        assert_eq!(Document::Report.validate(&doc), Ok(()));
+        assert_eq!(doc.get("error"), Some(&"No such file".into()));
+    }
+
+    #[test]
+    fn mutating_json_matches_the_schema() {
+        let img_file =
+            std::env::temp_dir().join(format!("fatimg-report-{}.img", std::process::id()));
+        let mut report = Report::start(Path::new("report.json"), Some("mkdir".to_owned()), true);
+        report.image(&img_file).unwrap();
+        report.take_before();
+        let doc = report.into_json(&Ok(()), None);
+        assert_eq!(Document::Report.validate(&doc), Ok(()));
+        assert!(doc.get("created").is_some());
+    }
+
+    #[test]
+    fn report_is_not_written_over_the_image() {
+        let mut report = Report::start(Path::new("disk.img"), None, false);
+        assert!(report.image(Path::new("disk.img")).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+//! Removing entries, optionally asking about each one like `rm -i`
+
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::glob::{self, GlobArgs};
+use crate::protect::{ProtectArgs, Protection};
+use crate::{delta, exit};
+use crate::{inner_join, open_fs_rw, parent_of, refuse_file_components, ImgDir, ImgFs};
+
+/// Options of the `rm` command
+pub struct RmOptions {
+    /// Remove directories along with their contents
+    pub recursive: bool,
+    /// A missing entry is not an error, and with `recursive` the root
+    /// directory can be emptied
+    pub force: bool,
+    /// Ask before removing each entry
+    pub interactive: bool,
+    /// Only list what would be removed
+    pub dry_run: bool,
+    pub protect: ProtectArgs,
+}
+
+struct Removal<'a> {
+    opts: &'a RmOptions,
+    protection: Protection,
+    /// `a` was answered, the rest is removed without asking
+    all: bool,
+    removed: usize,
+}
+
+impl Removal<'_> {
+    /// Asks on stderr whether to remove `path`
+    fn confirm(&mut self, path: &str, is_dir: bool) -> Result<bool> {
+        if !self.opts.interactive || self.all {
+            return Ok(true);
+        }
+        let kind = if is_dir { "directory" } else { "file" };
+        loop {
+            eprint!("remove {} {}? [y]es, [n]o, [a]ll, [q]uit: ", kind, path);
+            io::stderr().flush()?;
+            let mut answer = String::new();
+            if io::stdin().lock().read_line(&mut answer)? == 0 {
+                bail!("Aborted, no answer");
+            }
+            match answer.trim() {
+                "y" | "Y" => return Ok(true),
+                "n" | "N" | "" => return Ok(false),
+                "a" | "A" => {
+                    self.all = true;
+                    return Ok(true);
+                },
+                "q" | "Q" => bail!("Aborted, {} entries removed so far", self.removed),
+                _ => {},
+            }
+        }
+    }
+
+    /// Removes the entry `name` of `parent`, which is at `path`.
+    /// A directory goes after its contents, and only if all of them do.
+    /// Returns whether the entry is gone.
+    fn entry(&mut self, parent: &ImgDir<'_>, name: &str, path: &str, is_dir: bool) -> Result<bool> {
+        if self.protection.protects(path) {
+            eprintln!("protected {}", path);
+            return Ok(false);
+        }
+        if is_dir {
+            let dir = parent
+                .open_dir(name)
+                .with_context(|| format!("failed opening directory {}", path))?;
+            let mut children = Vec::new();
+            for entry in dir.iter() {
+                let entry = entry.with_context(|| format!("failed reading directory {}", path))?;
+                let child = entry.file_name();
+                if child != "." && child != ".." {
+                    children.push((child, entry.is_dir()));
+                }
+            }
+            let mut kept = false;
+            for (child, child_is_dir) in children {
+                let child_path = inner_join(path, &child);
+                kept |= !self.entry(&dir, &child, &child_path, child_is_dir)?;
+            }
+            if kept {
+                return Ok(false);
+            }
+        }
+        if !self.confirm(path, is_dir)? {
+            return Ok(false);
+        }
+
+        if self.opts.dry_run {
+            eprintln!("would remove {}", path);
+        } else {
+            parent
+                .remove(name)
+                .with_context(|| format!("failed removing {}", path))?;
+            delta::removed(is_dir);
+            eprintln!("removed {}", path);
+        }
+        self.removed += 1;
+        Ok(true)
+    }
+}
+
+fn is_empty(dir: &ImgDir<'_>, path: &str) -> Result<bool> {
+    for entry in dir.iter() {
+        let entry = entry.with_context(|| format!("failed reading directory {}", path))?;
+        let name = entry.file_name();
+        if name != "." && name != ".." {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Removes the entry at the normalized image path `inner_path`. Without
+/// `recursive` only files and empty directories can be removed.
+pub fn run(img_file: &Path, inner_path: &str, opts: &RmOptions) -> Result<()> {
+    if opts.interactive && !(io::stdin().is_terminal() && io::stderr().is_terminal()) {
+        bail!("--interactive needs a terminal to ask on");
+    }
+
+    if inner_path.is_empty() && !(opts.recursive && opts.force) {
+        bail!("Refusing to remove /, use -r --force to remove everything in it");
+    }
+
+    refuse_file_components(img_file, parent_of(inner_path))?;
+    let fs = open_fs_rw(img_file)?;
+    let root = fs.root_dir();
+    let mut removal = Removal {
+        opts,
+        protection: Protection::load(&root, &opts.protect)?,
+        all: false,
+        removed: 0,
+    };
+    if inner_path.is_empty() {
+        let mut children = Vec::new();
+        for entry in root.iter() {
+            let entry = entry.context("failed reading directory /")?;
+            children.push((entry.file_name(), entry.is_dir()));
+        }
+        for (name, is_dir) in children {
+            removal.entry(&root, &name, &inner_join("", &name), is_dir)?;
+        }
+        drop(root);
+        return finish(fs, removal);
+    }
+
+    let path = format!("/{}", inner_path);
+    let is_dir = match root.open_dir(inner_path) {
+        Ok(dir) => {
+            if !opts.recursive && !is_empty(&dir, &path)? {
+                bail!("{}: directory not empty, use -r to remove it", path);
+            }
+            true
+        },
+        Err(_) if root.open_file(inner_path).is_ok() => false,
+        Err(_) if opts.force => return Ok(()),
+        Err(_) => return Err(exit::NotFound(path).into()),
+    };
+
+    let (parent_path, name) = inner_path.rsplit_once('/').unwrap_or(("", inner_path));
+    let parent = if parent_path.is_empty() {
+        fs.root_dir()
+    } else {
+        root.open_dir(parent_path)
+            .with_context(|| format!("failed opening directory /{}", parent_path))?
+    };
+    removal.entry(&parent, name, &path, is_dir)?;
+    drop((parent, root));
+    finish(fs, removal)
+}
+
+/// Removes the entries matching the normalized image path `pattern`, see
+/// `glob::expand`. Without `recursive` nothing is removed if a matched
+/// directory isn't empty.
+pub fn run_matches(
+    img_file: &Path, pattern: &str, opts: &RmOptions, glob_args: &GlobArgs,
+) -> Result<()> {
+    if opts.interactive && !(io::stdin().is_terminal() && io::stderr().is_terminal()) {
+        bail!("--interactive needs a terminal to ask on");
+    }
+
+    refuse_file_components(img_file, glob::literal_dir(pattern))?;
+    let fs = open_fs_rw(img_file)?;
+    let root = fs.root_dir();
+    let matches = glob::expand(&root, pattern, glob_args)?;
+    if !opts.recursive {
+        for (path, is_dir) in &matches.entries {
+            if *is_dir && !is_empty(&root.open_dir(&path[1..])?, path)? {
+                bail!("{}: directory not empty, use -r to remove it", path);
+            }
+        }
+    }
+    let mut removal = Removal {
+        opts,
+        protection: Protection::load(&root, &opts.protect)?,
+        all: false,
+        removed: 0,
+    };
+    for (path, is_dir) in &matches.entries {
+        // Went with the matched directory it is in
+        if matches.is_below(path) {
+            continue;
+        }
+        let (parent_path, name) = path.rsplit_once('/').expect("matches are absolute");
+        let parent = if parent_path.is_empty() {
+            root.clone()
+        } else {
+            root.open_dir(&parent_path[1..])
+                .with_context(|| format!("failed opening directory {}", parent_path))?
+        };
+        removal.entry(&parent, name, path, *is_dir)?;
+    }
+    drop(root);
+    finish(fs, removal)
+}
+
+/// Removes the entries at the normalized image paths `paths`, given along
+/// with whether they are directories, for `write-tree`. Protected entries
+/// are kept like `rm -r` keeps them, and with `dry_run` only listed.
+pub fn remove_all(
+    fs: &ImgFs, paths: &[(String, bool)], protect: &ProtectArgs, dry_run: bool,
+) -> Result<()> {
+    let root = fs.root_dir();
+    let opts = RmOptions {
+        recursive: true,
+        force: true,
+        interactive: false,
+        dry_run,
+        protect: protect.clone(),
+    };
+    let mut removal = Removal {
+        opts: &opts,
+        protection: Protection::load(&root, protect)?,
+        all: false,
+        removed: 0,
+    };
+    for (inner_path, is_dir) in paths {
+        let (parent_path, name) = inner_path.rsplit_once('/').unwrap_or(("", inner_path));
+        let parent = if parent_path.is_empty() {
+            root.clone()
+        } else {
+            root.open_dir(parent_path)
+                .with_context(|| format!("failed opening directory /{}", parent_path))?
+        };
+        removal.entry(&parent, name, &format!("/{}", inner_path), *is_dir)?;
+    }
+    removal.protection.finish();
+    Ok(())
+}
+
+/// The entries of the normalized image directory `inner_path`, as
+/// `remove_all` takes them
+pub fn children(fs: &ImgFs, inner_path: &str) -> Result<Vec<(String, bool)>> {
+    let path = format!("/{}", inner_path);
+    let mut dir = fs.root_dir();
+    if !inner_path.is_empty() {
+        dir = dir
+            .open_dir(inner_path)
+            .with_context(|| format!("failed opening directory {}", path))?;
+    }
+    let mut children = Vec::new();
+    for entry in dir.iter() {
+        let entry = entry.with_context(|| format!("failed reading directory {}", path))?;
+        let name = entry.file_name();
+        if name != "." && name != ".." {
+            children.push((inner_join(&path, &name)[1..].to_owned(), entry.is_dir()));
+        }
+    }
+    Ok(children)
+}
+
+fn finish(fs: ImgFs, removal: Removal<'_>) -> Result<()> {
+    fs.unmount().context("failed flushing the filesystem")?;
+    let verb = if removal.opts.dry_run {
+        "would remove"
+    } else {
+        "removed"
+    };
+    eprintln!("{} {} entries", verb, removal.removed);
+    removal.protection.finish();
+    Ok(())
+}
@@ -0,0 +1,641 @@
+//! JSON Schemas of the documents the commands print, maintained next to
+//! the code writing them.
+//!
+//! `json-schema <document>` prints one as a JSON Schema, and the tests of
+//! each command check its real output against them with `validate`. Any
+//! change to a schema bumps `json::SCHEMA_VERSION`, which the tests here
+//! hold to by a fingerprint of every schema per version.
+
+use crate::json::{self, Value};
+
+/// What a member or an item of a document holds
+pub enum Schema {
+    Bool,
+    Number,
+    String,
+    /// `json::SCHEMA_VERSION`, the first member of every document
+    Version,
+    Array(Box<Schema>),
+    /// Members in output order, all required unless `Optional`. Other
+    /// members aren't allowed.
+    Object(Vec<(&'static str, Schema)>),
+    /// `null` or the inner schema
+    Nullable(Box<Schema>),
+    /// A member that is only there sometimes
+    Optional(Box<Schema>),
+    /// A definition of the document, for trees
+    Ref(&'static str),
+}
+
+fn array(item: Schema) -> Schema {
+    Schema::Array(Box::new(item))
+}
+
+fn nullable(inner: Schema) -> Schema {
+    Schema::Nullable(Box::new(inner))
+}
+
+fn optional(inner: Schema) -> Schema {
+    Schema::Optional(Box::new(inner))
+}
+
+fn object<const N: usize>(members: [(&'static str, Schema); N]) -> Schema {
+    Schema::Object(members.into())
+}
+
+/// A top-level document, starting with `schema_version`
+fn document<const N: usize>(members: [(&'static str, Schema); N]) -> Schema {
+    let mut all = vec![("schema_version", Schema::Version)];
+    all.extend(members);
+    Schema::Object(all)
+}
+
+/// Members of an entry in `ls --jsonl`, `find --json` and `serve ?json`
+fn ls_entry() -> Vec<(&'static str, Schema)> {
+    vec![
+        ("path", Schema::String),
+        ("name", Schema::String),
+        ("type", Schema::String),
+        ("size", nullable(Schema::Number)),
+        ("attribute_byte", Schema::Number),
+        ("attributes", array(Schema::String)),
+        ("created", Schema::String),
+        ("modified", Schema::String),
+        ("accessed", Schema::String),
+    ]
+}
+
+/// `{"schema_version": .., "entries": [..]}` of `ls --jsonl` entries
+fn listing() -> Schema {
+    document([("entries", array(Schema::Object(ls_entry())))])
+}
+
+fn path_bytes() -> Schema {
+    object([("path", Schema::String), ("bytes", Schema::Number)])
+}
+
+fn report_entry() -> Schema {
+    object([
+        ("path", Schema::String),
+        ("type", Schema::String),
+        ("size", nullable(Schema::Number)),
+    ])
+}
+
+fn counts() -> Schema {
+    object([("created", Schema::Number), ("removed", Schema::Number)])
+}
+
+/// The documents with a schema
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Document {
+    /// `--version-json`
+    Version,
+    /// Each line of `ls --jsonl`
+    LsJsonl,
+    /// `ls --json`
+    Ls,
+    /// `find --json`
+    Find,
+    /// `serve` listings with `?json`
+    Serve,
+    /// `info --json`
+    Info,
+    /// `attrib --json`
+    Attrib,
+    /// `extents --json`
+    Extents,
+    /// `health`
+    Health,
+    /// `verify-boot --json`
+    VerifyBoot,
+    /// The `--report` file
+    Report,
+}
+
+impl Document {
+    pub const ALL: [Self; 11] = [
+        Self::Version,
+        Self::LsJsonl,
+        Self::Ls,
+        Self::Find,
+        Self::Serve,
+        Self::Info,
+        Self::Attrib,
+        Self::Extents,
+        Self::Health,
+        Self::VerifyBoot,
+        Self::Report,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Version => "version",
+            Self::LsJsonl => "ls-jsonl",
+            Self::Ls => "ls",
+            Self::Find => "find",
+            Self::Serve => "serve",
+            Self::Info => "info",
+            Self::Attrib => "attrib",
+            Self::Extents => "extents",
+            Self::Health => "health",
+            Self::VerifyBoot => "verify-boot",
+            Self::Report => "report",
+        }
+    }
+
+    pub fn schema(self) -> Schema {
+        match self {
+            Self::Version => document([
+                ("version", Schema::String),
+                ("commit", nullable(Schema::String)),
+                ("features", array(Schema::String)),
+                ("changes", array(Schema::String)),
+                ("subcommands", array(Schema::String)),
+            ]),
+            Self::LsJsonl => {
+                let mut members = vec![("schema_version", Schema::Version)];
+                members.extend(ls_entry());
+                Schema::Object(members)
+            },
+            Self::Ls => document([("entries", array(Schema::Ref("entry")))]),
+            Self::Find | Self::Serve => listing(),
+            Self::Info => document([
+                ("fat_type", Schema::String),
+                ("volume_id", Schema::String),
+                ("volume_label", Schema::String),
+                ("cluster_size", Schema::Number),
+                ("sector_size", Schema::Number),
+                ("reserved_sectors", Schema::Number),
+                ("fats", Schema::Number),
+                ("root_entries", Schema::Number),
+                ("total_clusters", Schema::Number),
+                ("free_clusters", Schema::Number),
+                ("total_bytes", Schema::Number),
+                ("free_bytes", Schema::Number),
+                ("used_bytes", Schema::Number),
+                (
+                    "partition",
+                    nullable(object([
+                        ("number", Schema::Number),
+                        ("type", Schema::Number),
+                        ("start_lba", Schema::Number),
+                        ("sectors", Schema::Number),
+                    ])),
+                ),
+                ("hidden_sectors", Schema::Number),
+            ]),
+            Self::Attrib => document([
+                ("path", Schema::String),
+                ("attribute_byte", Schema::Number),
+                ("attributes", array(Schema::String)),
+            ]),
+            Self::Extents => document([
+                ("path", Schema::String),
+                ("size", Schema::Number),
+                ("cluster_size", Schema::Number),
+                (
+                    "extents",
+                    array(object([
+                        ("cluster", Schema::Number),
+                        ("count", Schema::Number),
+                        ("offset", Schema::Number),
+                        ("length", Schema::Number),
+                    ])),
+                ),
+            ]),
+            Self::Health => document([
+                ("healthy", Schema::Bool),
+                ("mountable", Schema::Bool),
+                ("fat_type", nullable(Schema::String)),
+                ("fat_copies_consistent", nullable(Schema::Bool)),
+                ("fsinfo_consistent", nullable(Schema::Bool)),
+                ("dirty", nullable(Schema::Bool)),
+                ("total_bytes", nullable(Schema::Number)),
+                ("free_bytes", nullable(Schema::Number)),
+                ("files", Schema::Number),
+                ("directories", Schema::Number),
+                ("deepest_path", nullable(Schema::String)),
+                ("depth", Schema::Number),
+                ("largest_file", nullable(path_bytes())),
+                ("walk_complete", Schema::Bool),
+                ("problems", array(Schema::String)),
+                ("elapsed_ms", Schema::Number),
+            ]),
+            Self::VerifyBoot => document([
+                ("passed", Schema::Bool),
+                (
+                    "checks",
+                    array(object([
+                        ("name", Schema::String),
+                        ("passed", Schema::Bool),
+                        ("detail", Schema::String),
+                    ])),
+                ),
+            ]),
+            Self::Report => document([
+                ("command", nullable(Schema::String)),
+                ("arguments", array(Schema::String)),
+                ("image", nullable(Schema::String)),
+                ("start", Schema::String),
+                ("end", Schema::String),
+                // Only for commands that change the image
+                ("created", optional(array(report_entry()))),
+                ("modified", optional(array(report_entry()))),
+                ("deleted", optional(array(report_entry()))),
+                ("bytes_written", optional(Schema::Number)),
+                ("free_before", optional(nullable(Schema::Number))),
+                ("free_after", optional(nullable(Schema::Number))),
+                (
+                    "delta",
+                    optional(nullable(object([
+                        ("free_before", Schema::Number),
+                        ("free_after", Schema::Number),
+                        ("files", counts()),
+                        ("dirs", counts()),
+                    ]))),
+                ),
+                ("read", array(path_bytes())),
+                (
+                    "renamed",
+                    array(object([("path", Schema::String), ("host", Schema::String)])),
+                ),
+                (
+                    "warnings",
+                    array(object([
+                        ("code", Schema::String),
+                        ("message", Schema::String),
+                    ])),
+                ),
+                ("error", nullable(Schema::String)),
+            ]),
+        }
+    }
+
+    /// Definitions `Schema::Ref` refers to
+    fn defs(self) -> Vec<(&'static str, Schema)> {
+        match self {
+            Self::Ls => vec![(
+                "entry",
+                object([
+                    ("name", Schema::String),
+                    ("path", Schema::String),
+                    ("is_dir", Schema::Bool),
+                    ("size", nullable(Schema::Number)),
+                    ("attribute_byte", Schema::Number),
+                    ("attributes", array(Schema::String)),
+                    ("created", Schema::String),
+                    ("modified", Schema::String),
+                    ("accessed", Schema::String),
+                    ("children", optional(array(Schema::Ref("entry")))),
+                ]),
+            )],
+            _ => Vec::new(),
+        }
+    }
+
+    /// The schema as a JSON Schema document
+    pub fn to_json(self) -> Value {
+        let mut members = vec![
+            (
+                "$schema".to_owned(),
+                "https://json-schema.org/draft/2020-12/schema".into(),
+            ),
+            (
+                "title".to_owned(),
+                format!("fatimg {} output", self.name()).into(),
+            ),
+        ];
+        if let Value::Object(schema) = self.schema().to_json() {
+            members.extend(schema);
+        }
+        let defs = self.defs();
+        if !defs.is_empty() {
+            let defs = defs
+                .iter()
+                .map(|(name, schema)| (name.to_string(), schema.to_json()))
+                .collect();
+            members.push(("$defs".to_owned(), Value::Object(defs)));
+        }
+        Value::Object(members)
+    }
+
+    /// Checks that `doc` follows the schema, naming the first member that
+    /// doesn't
+    #[cfg(test)]
+    pub fn validate(self, doc: &Value) -> Result<(), String> {
+        self.schema().validate(doc, &self.defs(), "")
+    }
+}
+
+/// The schemas of all documents, by name
+pub fn all() -> Value {
+    Value::Object(
+        Document::ALL
+            .iter()
+            .map(|d| (d.name().to_owned(), d.to_json()))
+            .collect(),
+    )
+}
+
+impl Schema {
+    fn to_json(&self) -> Value {
+        let kind = |name: &str| json::object([("type", name.into())]);
+        match self {
+            Self::Bool => kind("boolean"),
+            Self::Number => json::object([("type", "integer".into()), ("minimum", 0u64.into())]),
+            Self::String => kind("string"),
+            Self::Version => json::object([("const", json::SCHEMA_VERSION.into())]),
+            Self::Array(item) => {
+                json::object([("type", "array".into()), ("items", item.to_json())])
+            },
+            Self::Object(members) => {
+                let properties = members
+                    .iter()
+                    .map(|(name, schema)| (name.to_string(), schema.to_json()))
+                    .collect();
+                let required = members
+                    .iter()
+                    .filter(|(_, schema)| !matches!(schema, Self::Optional(_)))
+                    .map(|(name, _)| *name);
+                json::object([
+                    ("type", "object".into()),
+                    ("properties", Value::Object(properties)),
+                    ("required", required.collect()),
+                    ("additionalProperties", false.into()),
+                ])
+            },
+            Self::Nullable(inner) => {
+                json::object([("anyOf", Value::Array(vec![kind("null"), inner.to_json()]))])
+            },
+            Self::Optional(inner) => inner.to_json(),
+            Self::Ref(name) => json::object([("$ref", format!("#/$defs/{}", name).into())]),
+        }
+    }
+
+    /// Checks `value`, found at `path` in the document
+    #[cfg(test)]
+    fn validate(
+        &self, value: &Value, defs: &[(&'static str, Schema)], path: &str,
+    ) -> Result<(), String> {
+        let fail = |expected: &str| Err(format!("{}: expected {}", display(path), expected));
+        match (self, value) {
+            (Self::Bool, Value::Bool(_))
+            | (Self::Number, Value::Number(_))
+            | (Self::String, Value::String(_)) => Ok(()),
+            (Self::Version, Value::Number(n)) if *n == json::SCHEMA_VERSION => Ok(()),
+            (Self::Version, _) => fail(&format!("schema version {}", json::SCHEMA_VERSION)),
+            (Self::Array(item), Value::Array(items)) => {
+                for (i, v) in items.iter().enumerate() {
+                    item.validate(v, defs, &format!("{}[{}]", path, i))?;
+                }
+                Ok(())
+            },
+            (Self::Object(members), Value::Object(values)) => {
+                for (name, schema) in members {
+                    let sub_path = format!("{}.{}", path, name);
+                    match values.iter().find(|(k, _)| k == name) {
+                        Some((_, v)) => schema.validate(v, defs, &sub_path)?,
+                        None if matches!(schema, Self::Optional(_)) => {},
+                        None => return Err(format!("{}: missing", display(&sub_path))),
+                    }
+                }
+                match values
+                    .iter()
+                    .find(|(k, _)| !members.iter().any(|(name, _)| name == k))
+                {
+                    Some((k, _)) => Err(format!("{}.{}: not in the schema", display(path), k)),
+                    None => Ok(()),
+                }
+            },
+            (Self::Nullable(_), Value::Null) => Ok(()),
+            (Self::Nullable(inner) | Self::Optional(inner), value) => {
+                inner.validate(value, defs, path)
+            },
+            (Self::Ref(name), value) => match defs.iter().find(|(n, _)| n == name) {
+                Some((_, schema)) => schema.validate(value, defs, path),
+                None => Err(format!("{}: no definition {:?}", display(path), name)),
+            },
+            (Self::Bool, _) => fail("a boolean"),
+            (Self::Number, _) => fail("a number"),
+            (Self::String, _) => fail("a string"),
+            (Self::Array(_), _) => fail("an array"),
+            (Self::Object(_), _) => fail("an object"),
+        }
+    }
+}
+
+/// `path` in messages, `$` for the document itself
+#[cfg(test)]
+fn display(path: &str) -> String {
+    format!("${}", path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fingerprints of the schemas of each version. If the test fails,
+    /// a schema changed: bump `json::SCHEMA_VERSION` and add a line for it.
+    /// Lines of released versions are never edited.
+    const FINGERPRINTS: &[(u64, u64)] = &[(1, 0x6529_a342_e7f8_b1a9)];
+
+    /// FNV-1a of all schemas as printed, which unlike `DefaultHasher`
+    /// stays the same across Rust releases
+    fn fingerprint() -> u64 {
+        all()
+            .to_string()
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+                (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+            })
+    }
+
+    fn entry(name: &str, children: Option<Value>) -> Value {
+        let mut members = vec![
+            ("name".to_owned(), name.into()),
+            ("path".to_owned(), format!("/{}", name).into()),
+            ("is_dir".to_owned(), children.is_some().into()),
+            ("size".to_owned(), children.is_none().then_some(3u64).into()),
+            ("attribute_byte".to_owned(), 0x20u64.into()),
+            ("attributes".to_owned(), ["archive"].into_iter().collect()),
+            ("created".to_owned(), "2024-05-01T12:00:00".into()),
+            ("modified".to_owned(), "2024-05-01T12:00:00".into()),
+            ("accessed".to_owned(), "2024-05-01".into()),
+        ];
+        if let Some(children) = children {
+            members.push(("children".to_owned(), children));
+        }
+        Value::Object(members)
+    }
+
+    fn attrib(members: Vec<(&str, Value)>) -> Value {
+        Value::Object(
+            members
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn changed_schemas_bump_the_version() {
+        let current = fingerprint();
+        let recorded = FINGERPRINTS
+            .iter()
+            .find(|&&(version, _)| version == json::SCHEMA_VERSION)
+            .map(|&(_, f)| f);
+        assert_eq!(
+            recorded,
+            Some(current),
+            "schemas changed, bump json::SCHEMA_VERSION and record {:#x} for it",
+            current
+        );
+        for (i, &(version, f)) in FINGERPRINTS.iter().enumerate() {
+            assert!(FINGERPRINTS[..i]
+                .iter()
+                .all(|&(v, g)| v < version && g != f));
+        }
+    }
+
+    #[test]
+    fn documents_start_with_the_version() {
+        for document in Document::ALL {
+            match document.schema() {
+                Schema::Object(members) => assert_eq!(members[0].0, "schema_version"),
+                _ => panic!("{} is not an object", document.name()),
+            }
+        }
+    }
+
+    #[test]
+    fn names_are_unique() {
+        for (i, document) in Document::ALL.iter().enumerate() {
+            assert!(Document::ALL[..i]
+                .iter()
+                .all(|d| d.name() != document.name()));
+        }
+    }
+
+    #[test]
+    fn schemas_are_json_schema_documents() {
+        for document in Document::ALL {
+            let printed = json::parse(&document.to_json().to_string()).unwrap();
+            assert!(matches!(printed.get("$schema"), Some(Value::String(_))));
+            assert!(matches!(printed.get("type"), Some(Value::String(t)) if t == "object"));
+            let required = printed.get("required").and_then(Value::as_array).unwrap();
+            assert!(matches!(&required[0], Value::String(s) if s == "schema_version"));
+        }
+        let ls = Document::Ls.to_json();
+        assert!(ls.get("$defs").and_then(|d| d.get("entry")).is_some());
+        assert!(Document::Attrib.to_json().get("$defs").is_none());
+    }
+
+    #[test]
+    fn optional_members_are_not_required() {
+        let report = Document::Report.to_json();
+        let required = report.get("required").and_then(Value::as_array).unwrap();
+        let required: Vec<_> = required
+            .iter()
+            .filter_map(|v| match v {
+                Value::String(s) => Some(s.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert!(required.contains(&"error"));
+        assert!(!required.contains(&"created"));
+    }
+
+    #[test]
+    fn validate_accepts_matching_documents() {
+        let doc = json::versioned(attrib(vec![
+            ("path", "/BOOT".into()),
+            ("attribute_byte", 0x21u64.into()),
+            ("attributes", ["read_only", "archive"].into_iter().collect()),
+        ]));
+        assert_eq!(Document::Attrib.validate(&doc), Ok(()));
+    }
+
+    #[test]
+    fn validate_names_the_first_mismatch() {
+        let check = |members: Vec<(&str, Value)>| Document::Attrib.validate(&attrib(members));
+        let version = || ("schema_version", json::SCHEMA_VERSION.into());
+        let byte = || ("attribute_byte", 0x20u64.into());
+        let list = || ("attributes", Value::Array(Vec::new()));
+        assert_eq!(
+            check(vec![version(), byte(), list()]),
+            Err("$.path: missing".to_owned())
+        );
+        assert_eq!(
+            check(vec![version(), ("path", 1u64.into()), byte(), list()]),
+            Err("$.path: expected a string".to_owned())
+        );
+        assert_eq!(
+            check(vec![
+                ("schema_version", 0u64.into()),
+                ("path", "/A".into()),
+                byte(),
+                list()
+            ]),
+            Err(format!(
+                "$.schema_version: expected schema version {}",
+                json::SCHEMA_VERSION
+            ))
+        );
+        assert_eq!(
+            check(vec![
+                version(),
+                ("path", "/A".into()),
+                byte(),
+                list(),
+                ("mode", 1u64.into())
+            ]),
+            Err("$.mode: not in the schema".to_owned())
+        );
+        assert_eq!(
+            Document::Attrib.validate(&Value::Array(Vec::new())),
+            Err("$: expected an object".to_owned())
+        );
+    }
+
+    #[test]
+    fn validate_follows_definitions_into_trees() {
+        let file = entry("A.TXT", None);
+        let dir = entry("DIR", Some(Value::Array(vec![file])));
+        let doc = json::versioned(json::object([("entries", Value::Array(vec![dir]))]));
+        assert_eq!(Document::Ls.validate(&doc), Ok(()));
+
+        let broken = entry("DIR", Some(Value::Array(vec![Value::Null])));
+        let doc = json::versioned(json::object([("entries", Value::Array(vec![broken]))]));
+        assert_eq!(
+            Document::Ls.validate(&doc),
+            Err("$.entries[0].children[0]: expected an object".to_owned())
+        );
+    }
+
+    #[test]
+    fn nullable_members_take_null() {
+        let members = |size: Value| {
+            json::versioned(json::object([(
+                "entries",
+                Value::Array(vec![json::object([
+                    ("path", "/A".into()),
+                    ("name", "A".into()),
+                    ("type", "dir".into()),
+                    ("size", size),
+                    ("attribute_byte", 0x10u64.into()),
+                    ("attributes", ["directory"].into_iter().collect()),
+                    ("created", "2024-05-01T12:00:00.000".into()),
+                    ("modified", "2024-05-01T12:00:00.000".into()),
+                    ("accessed", "2024-05-01".into()),
+                ])]),
+            )]))
+        };
+        assert_eq!(Document::Find.validate(&members(Value::Null)), Ok(()));
+        assert_eq!(Document::Find.validate(&members(5u64.into())), Ok(()));
+        assert_eq!(
+            Document::Find.validate(&members(true.into())),
+            Err("$.entries[0].size: expected a number".to_owned())
+        );
+    }
+}
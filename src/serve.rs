@@ -0,0 +1,295 @@
+//! Read-only HTTP access to the image tree, for browsing it with a web
+//! browser or a file manager instead of mounting it. Directories are
+//! listed as HTML, or as JSON with `?json`, and files are downloaded as
+//! they are. Nothing can be written.
+//!
+//! Requests are handled one at a time, which is plenty for casual
+//! browsing and keeps the filesystem single-threaded. Ctrl-C stops the
+//! server between requests.
+
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use fatfs::{FileSystem, FsOptions};
+use fscommon::BufStream;
+
+use crate::region::ImgSlice;
+use crate::{clock, inner_join, json, ls_json, paths, surrogates, ImgDir};
+
+/// A client that sends its request slowly, or stops reading the
+/// response, can hold up the others for this long
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the listener checks for Ctrl-C while no one connects
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Serves the image directory `inner_path` on `listen`, until interrupted
+pub fn run(img_file: &Path, inner_path: &str, listen: &str) -> Result<()> {
+    let file = ImgSlice::open(img_file, false)?;
+    let buf_file = BufStream::new(file);
+    let fs = FileSystem::new(buf_file, FsOptions::new().time_provider(clock::get()))?;
+    let mut root = fs.root_dir();
+    if !inner_path.is_empty() {
+        root = root
+            .open_dir(inner_path)
+            .with_context(|| format!("failed opening directory /{}", inner_path))?;
+    }
+
+    let listener =
+        TcpListener::bind(listen).with_context(|| format!("failed listening on {}", listen))?;
+    // Accepting without blocking lets the loop notice Ctrl-C
+    listener.set_nonblocking(true)?;
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&interrupted);
+    ctrlc::set_handler(move || flag.store(true, Ordering::SeqCst))
+        .context("failed installing the Ctrl-C handler")?;
+    println!(
+        "serving /{} read-only on http://{}/",
+        inner_path,
+        listener.local_addr()?
+    );
+    while !interrupted.load(Ordering::SeqCst) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            },
+            Err(err) => {
+                eprintln!("request failed: failed accepting a connection: {}", err);
+                continue;
+            },
+        };
+        if let Err(err) = handle(stream, &root) {
+            eprintln!("request failed: {:#}", err);
+        }
+    }
+    eprintln!("interrupted, shutting down");
+    Ok(())
+}
+
+fn handle(stream: TcpStream, root: &ImgDir<'_>) -> Result<()> {
+    // Accepted sockets may inherit non-blocking mode from the listener
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    let mut reader = BufReader::new(Deadline {
+        stream: stream.try_clone()?,
+        deadline,
+    });
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Headers don't matter for anything served here
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+
+    let mut out = stream;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let head = match method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return error(&mut out, "405 Method Not Allowed", "only GET and HEAD"),
+    };
+    let (raw_path, query) = target.split_once('?').unwrap_or((target, ""));
+    let as_json = query.split('&').any(|q| q == "json");
+    // `..` stops at the root, so nothing outside of it can be reached
+    let path = match percent_decode(raw_path).map(|p| paths::normalize(&p)) {
+        Some(Ok(path)) => path,
+        _ => return error(&mut out, "400 Bad Request", "invalid path"),
+    };
+
+    if path.is_empty() {
+        return list(&mut out, root, "", as_json, head);
+    }
+    if let Ok(dir) = root.open_dir(&path) {
+        if !raw_path.ends_with('/') && !as_json {
+            let location = format!("{}/", raw_path);
+            let headers = format!("Location: {}\r\n", location);
+            return respond(
+                &mut out,
+                "301 Moved Permanently",
+                "text/plain",
+                &headers,
+                b"",
+                head,
+            );
+        }
+        return list(&mut out, &dir, &path, as_json, head);
+    }
+    let mut file = match root.open_file(&path) {
+        Ok(file) => file,
+        Err(_) => return error(&mut out, "404 Not Found", "no such file or directory"),
+    };
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    write!(
+        out,
+        "HTTP/1.0 200 OK\r\nContent-Type: application/octet-stream\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        len
+    )?;
+    if !head {
+        io::copy(&mut file, &mut out).with_context(|| format!("failed sending /{}", path))?;
+    }
+    Ok(())
+}
+
+/// Reads from a client, failing once the whole request has taken longer
+/// than its deadline. A timeout per read alone would let a client that
+/// trickles a byte at a time keep the connection open indefinitely.
+struct Deadline {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "request not received in time",
+            ));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Lists `dir`, the directory at `path` relative to the served root
+fn list(
+    out: &mut TcpStream, dir: &ImgDir<'_>, path: &str, as_json: bool, head: bool,
+) -> Result<()> {
+    let url_path = format!("/{}", path);
+    let mut entries = Vec::new();
+    for entry in dir.iter() {
+        let entry = entry.with_context(|| format!("failed reading directory {}", url_path))?;
+        let name = entry.file_name();
+        if name != "." && name != ".." {
+            entries.push(entry);
+        }
+    }
+
+    if as_json {
+        let listing: json::Value = entries
+            .iter()
+            .map(|e| {
+                let path = inner_join(&url_path, &e.file_name());
+                let name = surrogates::display_name(e, &path)?;
+                Ok(ls_json(e, path, name))
+            })
+            .collect::<Result<_>>()?;
+        let body = format!(
+            "{}\n",
+            json::versioned(json::object([("entries", listing)]))
+        );
+        return respond(out, "200 OK", "application/json", "", body.as_bytes(), head);
+    }
+
+    let title = html_escape(&url_path);
+    let mut body = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title></head>\n\
+         <body><h1>{}</h1><ul>\n",
+        title, title
+    );
+    if !path.is_empty() {
+        body.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for entry in &entries {
+        let name = entry.file_name();
+        let suffix = if entry.is_dir() { "/" } else { "" };
+        let size = if entry.is_file() {
+            format!(" ({} bytes)", entry.len())
+        } else {
+            String::new()
+        };
+        body.push_str(&format!(
+            "<li><a href=\"{}{}\">{}{}</a>{}</li>\n",
+            percent_encode(&name),
+            suffix,
+            html_escape(&name),
+            suffix,
+            size
+        ));
+    }
+    body.push_str("</ul></body></html>\n");
+    respond(
+        out,
+        "200 OK",
+        "text/html; charset=utf-8",
+        "",
+        body.as_bytes(),
+        head,
+    )
+}
+
+fn error(out: &mut TcpStream, status: &str, msg: &str) -> Result<()> {
+    let body = format!("{}\n", msg);
+    respond(out, status, "text/plain", "", body.as_bytes(), false)
+}
+
+/// Writes a whole response. `headers` are extra header lines, each
+/// ending with CRLF.
+fn respond(
+    out: &mut TcpStream, status: &str, content_type: &str, headers: &str, body: &[u8], head: bool,
+) -> Result<()> {
+    write!(
+        out,
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len(),
+        headers
+    )?;
+    if !head {
+        out.write_all(body)?;
+    }
+    Ok(())
+}
+
+/// Decodes `%XX` escapes of a URL path, if the result is UTF-8
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail
+                .get(..2)
+                .filter(|h| h.iter().all(u8::is_ascii_hexdigit))?;
+            bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Escapes a name for use as a relative URL
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            },
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
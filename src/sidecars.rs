@@ -0,0 +1,19 @@
+//! Metadata files other tools leave next to the files they manage, which
+//! mean nothing to the device an image is for. Tree imports leave them
+//! out unless `--include-sidecars` is given.
+
+use crate::glob;
+
+/// Name globs of known sidecar files, with what leaves them behind
+pub const SIDECARS: &[(&str, &str)] = &[
+    (".rsync-meta", "rsync ownership metadata"),
+    (".nfs*", "NFS files deleted while open"),
+];
+
+/// What leaves behind the host file `name`, if it's a known sidecar
+pub fn recognize(name: &str) -> Option<&'static str> {
+    SIDECARS
+        .iter()
+        .find(|(pattern, _)| glob::matches(pattern, name))
+        .map(|(_, what)| *what)
+}
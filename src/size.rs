@@ -0,0 +1,187 @@
+//! Formatting sizes for output, shared by all commands that report them
+
+/// How sizes are printed, set by the global `--block-size` and `-H`
+#[derive(Debug, Clone, Copy)]
+pub struct SizeFormat {
+    /// Unit sizes are scaled to, 1 for plain bytes
+    pub block_size: u64,
+    /// Print with a binary suffix (`1.5K`, `12M`) instead
+    pub human: bool,
+}
+
+/// Parses a `--block-size` value like `512`, `1K`, `4KiB`, `1MB` or `M`.
+/// Single letters and `*iB` suffixes are powers of 1024, `*B` powers of 1000.
+pub fn parse_block_size(s: &str) -> Result<u64, String> {
+    match parse_size(s) {
+        Ok(0) => Err(format!("Invalid block size {:?}", s)),
+        result => result,
+    }
+}
+
+/// Parses a size with the same suffixes as `--block-size`, in any case.
+/// A fractional part like `1.5G` is allowed if the result is whole bytes.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, suffix) = s.split_at(split);
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    // A bare unit like `M` is one of it, for `--block-size`
+    let bare_unit = number.is_empty() && !suffix.is_empty();
+    let malformed = number == "." || fraction.contains('.') || fraction.len() > 18;
+    if !bare_unit && (number.is_empty() || malformed) {
+        return Err(format!("Invalid size {:?}", s));
+    }
+
+    let mut chars = suffix.chars();
+    let unit: u128 = match chars.next() {
+        None => 1,
+        Some(letter) => {
+            let exp = match "KMGTPE".find(letter.to_ascii_uppercase()) {
+                Some(i) => i as u32 + 1,
+                None => return Err(format!("Unknown suffix {:?} in size {:?}", suffix, s)),
+            };
+            let base: u128 = match chars.as_str().to_ascii_lowercase().as_str() {
+                "" | "ib" => 1024,
+                "b" => 1000,
+                _ => return Err(format!("Unknown suffix {:?} in size {:?}", suffix, s)),
+            };
+            base.pow(exp)
+        },
+    };
+
+    let too_large = || format!("Size {:?} overflows 64 bits", s);
+    let digits = format!("{}{}", whole, fraction);
+    let scaled: u128 = if digits.is_empty() {
+        unit
+    } else {
+        let n: u128 = digits.parse().map_err(|_| too_large())?;
+        n.checked_mul(unit).ok_or_else(too_large)?
+    };
+    let divisor = 10u128.pow(fraction.len() as u32);
+    if !scaled.is_multiple_of(divisor) {
+        return Err(format!("Size {:?} is not a whole number of bytes", s));
+    }
+    u64::try_from(scaled / divisor).map_err(|_| too_large())
+}
+
+impl SizeFormat {
+    /// Size of data, e.g. a file length. Rounded to the nearest unit.
+    pub fn logical(&self, bytes: u64) -> String {
+        self.format(bytes, false)
+    }
+
+    /// Space taken up, e.g. clusters. Rounded up, so that a nonzero
+    /// amount never shows as zero.
+    pub fn allocated(&self, bytes: u64) -> String {
+        self.format(bytes, true)
+    }
+
+    fn format(&self, bytes: u64, round_up: bool) -> String {
+        if self.human {
+            return human(bytes, round_up);
+        }
+        divide(bytes as u128, self.block_size as u128, round_up).to_string()
+    }
+}
+
+fn divide(n: u128, d: u128, round_up: bool) -> u128 {
+    let (q, r) = (n / d, n % d);
+    let carry = if round_up { r > 0 } else { r >= d - r };
+    q + carry as u128
+}
+
+/// Like `ls -h`: one decimal below 10, whole numbers above
+fn human(bytes: u64, round_up: bool) -> String {
+    if bytes < 1024 {
+        return bytes.to_string();
+    }
+    let bytes = bytes as u128;
+    let mut unit = 1024u128;
+    for suffix in ['K', 'M', 'G', 'T', 'P'] {
+        let tenths = divide(bytes * 10, unit, round_up);
+        if tenths < 100 {
+            return format!("{}.{}{}", tenths / 10, tenths % 10, suffix);
+        }
+        let whole = divide(bytes, unit, round_up);
+        if whole < 1024 {
+            return format!("{}{}", whole, suffix);
+        }
+        unit *= 1024;
+    }
+    format!("{}E", divide(bytes, unit, round_up))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(block_size: u64, human: bool) -> SizeFormat {
+        SizeFormat { block_size, human }
+    }
+
+    #[test]
+    fn suffixes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("1K"), Ok(1024));
+        assert_eq!(parse_size("1k"), Ok(1024));
+        assert_eq!(parse_size("4KiB"), Ok(4096));
+        assert_eq!(parse_size("1MB"), Ok(1_000_000));
+        assert_eq!(parse_size("1.5K"), Ok(1536));
+        assert_eq!(parse_size("1.5GiB"), Ok(3 << 29));
+        assert_eq!(parse_size("M"), Ok(1 << 20));
+        assert_eq!(parse_size("15E"), Ok(15 << 60));
+        for invalid in [
+            "",
+            ".",
+            "1.2.3",
+            "1X",
+            "1KB5",
+            "0.5",
+            "16E",
+            "-1",
+            "1.0000000000000000001",
+        ] {
+            assert!(parse_size(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(parse_block_size("512"), Ok(512));
+        assert!(parse_block_size("0").is_err());
+        assert!(parse_block_size("0K").is_err());
+    }
+
+    #[test]
+    fn lengths_round_to_nearest_and_usage_up() {
+        let k = format(1024, false);
+        assert_eq!(k.logical(0), "0");
+        assert_eq!(k.allocated(0), "0");
+        assert_eq!(k.logical(1), "0");
+        assert_eq!(k.allocated(1), "1");
+        assert_eq!(k.logical(511), "0");
+        assert_eq!(k.logical(512), "1");
+        assert_eq!(k.logical(1535), "1");
+        assert_eq!(k.logical(1536), "2");
+        assert_eq!(k.allocated(1025), "2");
+        assert_eq!(k.allocated(2048), "2");
+        assert_eq!(format(1, false).logical(u64::MAX), u64::MAX.to_string());
+        assert_eq!(format(1000, false).allocated(u64::MAX), "18446744073709552");
+    }
+
+    #[test]
+    fn human_readable() {
+        let h = format(1, true);
+        assert_eq!(h.logical(1023), "1023");
+        assert_eq!(h.logical(1024), "1.0K");
+        assert_eq!(h.logical(1025), "1.0K");
+        assert_eq!(h.allocated(1025), "1.1K");
+        assert_eq!(h.logical(1536), "1.5K");
+        assert_eq!(h.logical(9217), "9.0K");
+        assert_eq!(h.allocated(9217), "9.1K");
+        assert_eq!(h.logical(10239), "10K");
+        assert_eq!(h.logical((1 << 20) - 1), "1.0M");
+        assert_eq!(h.logical(1 << 20), "1.0M");
+        assert_eq!(h.allocated((1 << 30) + 1), "1.1G");
+        assert_eq!(h.logical(u64::MAX), "16E");
+        // `-H` wins over the block size
+        assert_eq!(format(512, true).logical(1536), "1.5K");
+    }
+}
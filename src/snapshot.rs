@@ -0,0 +1,134 @@
+//! `--snapshot`: reading a copy of the image instead of the image, for
+//! looking into one another command is still writing.
+//!
+//! `full` copies the whole image file up front. The default `metadata`
+//! copies the boot sector, the FATs and the fixed root directory up front
+//! and everything else in blocks the first time it's read, so that large
+//! images don't have to be copied for an `ls`. Either way the copy is a
+//! temporary file removed when the command is done, and the writer may
+//! have been in the middle of an update when it was taken.
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+
+use crate::ondisk::BootSector;
+use crate::region::{self, ImgSlice};
+
+/// How much of the image `--snapshot` copies up front
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// The filesystem structures, the rest as it is read
+    Metadata,
+    /// The whole image file
+    Full,
+}
+
+/// Granularity of copying on read
+const BLOCK: u64 = 64 * 1024;
+
+/// The image `metadata` snapshots copy from on read
+struct Lazy {
+    snapshot: PathBuf,
+    original: File,
+    /// Indices of the blocks copied so far
+    copied: HashSet<u64>,
+}
+
+static LAZY: Mutex<Option<Lazy>> = Mutex::new(None);
+
+/// The temporary copy of the image, removed when dropped
+pub struct Snapshot {
+    path: PathBuf,
+}
+
+impl Snapshot {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        *LAZY.lock().unwrap() = None;
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Takes a snapshot of `img_file` for the rest of this invocation
+pub fn take(img_file: &Path, mode: Mode) -> Result<Snapshot> {
+    let path = std::env::temp_dir().join(format!("fatimg-snapshot-{}.img", std::process::id()));
+    let context = || format!("failed taking a snapshot of {}", img_file.display());
+    let mut original = File::open(img_file).with_context(context)?;
+    let mut copy = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .with_context(|| format!("failed creating {}", path.display()))?;
+    let snapshot = Snapshot { path };
+    match mode {
+        Mode::Full => {
+            io::copy(&mut original, &mut copy).with_context(context)?;
+        },
+        Mode::Metadata => {
+            let len = original.metadata().with_context(context)?.len();
+            copy.set_len(len).with_context(context)?;
+            *LAZY.lock().unwrap() = Some(Lazy {
+                snapshot: snapshot.path.clone(),
+                original,
+                copied: HashSet::new(),
+            });
+            // Without a readable boot sector everything is copied as read
+            let mut img = ImgSlice::open(img_file, false).with_context(context)?;
+            if let Ok(layout) = BootSector::read(&mut img).and_then(|bs| bs.layout()) {
+                fill(&mut copy, 0, region::start() + layout.data_offset).with_context(context)?;
+            }
+        },
+    }
+    copy.sync_all().with_context(context)?;
+    eprintln!(
+        "Note: reading a snapshot of {}, which may show it in the middle of an update",
+        img_file.display()
+    );
+    Ok(snapshot)
+}
+
+/// Whether `path` is a `metadata` snapshot, which `fill` has to be called
+/// for before reading it
+pub fn is_lazy(path: &Path) -> bool {
+    LAZY.lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|lazy| lazy.snapshot == path)
+}
+
+/// Copies the blocks of the `len` bytes at `offset` of the snapshot file
+/// `file` that haven't been read before from the image
+pub fn fill<W: Write + Seek>(file: &mut W, offset: u64, len: u64) -> io::Result<()> {
+    let mut guard = LAZY.lock().unwrap();
+    let lazy = match guard.as_mut() {
+        Some(lazy) => lazy,
+        None => return Ok(()),
+    };
+    let mut buf = vec![0u8; BLOCK as usize];
+    for block in offset / BLOCK..(offset + len).div_ceil(BLOCK) {
+        if !lazy.copied.insert(block) {
+            continue;
+        }
+        lazy.original.seek(SeekFrom::Start(block * BLOCK))?;
+        let mut n = 0;
+        while n < buf.len() {
+            match lazy.original.read(&mut buf[n..])? {
+                0 => break,
+                read => n += read,
+            }
+        }
+        file.seek(SeekFrom::Start(block * BLOCK))?;
+        file.write_all(&buf[..n])?;
+    }
+    Ok(())
+}
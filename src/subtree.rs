@@ -0,0 +1,33 @@
+//! `--subtree`, the part of the image tree the analysis commands look at.
+//!
+//! `du` and `find` also take it as their path argument, and `health`
+//! only as `--subtree`. Either way it is resolved here, so that it is
+//! normalized and a path through a file fails the same way everywhere.
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::{parent_of, paths, refuse_file_components};
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct Subtree {
+    /// Only look at this path of the image and what is below it, the
+    /// whole image by default
+    #[clap(long, value_name = "PATH")]
+    subtree: Option<String>,
+}
+
+impl Subtree {
+    /// The normalized image path to start at in `img_file`, from
+    /// `--subtree` or the path argument `given`
+    pub fn resolve(&self, img_file: &Path, given: Option<&str>) -> Result<String> {
+        let path = match (self.subtree.as_deref(), given) {
+            (Some(_), Some(_)) => bail!("Give the path either as an argument or as --subtree"),
+            (Some(path), None) | (None, Some(path)) => paths::normalize(path)?,
+            (None, None) => String::new(),
+        };
+        refuse_file_components(img_file, parent_of(&path))?;
+        Ok(path)
+    }
+}
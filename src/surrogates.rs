@@ -0,0 +1,100 @@
+//! Long names with unpaired UTF-16 surrogates.
+//!
+//! FAT stores long names as UCS-2, where a lone surrogate is just another
+//! unit, but Rust strings can't hold one. fatfs replaces them with U+FFFD,
+//! so different names can come out the same. Where names are shown they
+//! are looked up in the raw directory entries instead, and the surrogates
+//! escaped like `\u{dc80}`. `read-tree --surrogate-policy` decides what
+//! extracted files are called.
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{bail, Result};
+
+use crate::ondisk::{BootSector, Fat, RawDir};
+use crate::region::ImgSlice;
+
+/// What `read-tree` names files whose long names have unpaired surrogates
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Escape them like `\u{dc80}`, which keeps names apart
+    Escape,
+    /// Replace them with U+FFFD, as earlier releases did
+    Replace,
+    /// Fail, naming the entry
+    Error,
+}
+
+static IMAGE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Sets the image to look up raw names in for this invocation
+pub fn init(img_file: &Path) {
+    IMAGE.set(img_file.to_owned()).expect("image already set");
+}
+
+pub fn has_unpaired(units: &[u16]) -> bool {
+    char::decode_utf16(units.iter().copied()).any(|c| c.is_err())
+}
+
+/// `units` as text, with unpaired surrogates escaped
+pub fn escape(units: &[u16]) -> String {
+    char::decode_utf16(units.iter().copied())
+        .map(|c| match c {
+            Ok(c) => c.to_string(),
+            Err(err) => format!("\\u{{{:x}}}", err.unpaired_surrogate()),
+        })
+        .collect()
+}
+
+/// The escaped long name of the entry at the image path `path` with the
+/// short name `short`, if it has unpaired surrogates
+fn escaped(path: &str, short: &str) -> Result<Option<String>> {
+    let img_file = match IMAGE.get() {
+        Some(img_file) => img_file,
+        None => return Ok(None),
+    };
+    let mut img = ImgSlice::open(img_file, false)?;
+    let layout = BootSector::read(&mut img)?.layout()?;
+    let fat = Fat::read(&mut img, &layout)?;
+    let path = path.trim_start_matches('/');
+    let parent = path.rsplit_once('/').map_or("", |(parent, _)| parent);
+    let dir = RawDir::read_path(&mut img, &layout, &fat, parent)?;
+    let escaped = dir
+        .escaped_names()
+        .into_iter()
+        .find(|(s, _)| s.eq_ignore_ascii_case(short))
+        .map(|(_, name)| name);
+    Ok(escaped)
+}
+
+/// The name of `entry`, the image entry `path`, to show
+pub fn display_name<IO: fatfs::ReadWriteSeek, TP, OCC: fatfs::OemCpConverter>(
+    entry: &fatfs::DirEntry<IO, TP, OCC>, path: &str,
+) -> Result<String> {
+    let name = entry.file_name();
+    if !name.contains(char::REPLACEMENT_CHARACTER) {
+        return Ok(name);
+    }
+    Ok(escaped(path, &entry.short_file_name())?.unwrap_or(name))
+}
+
+/// The host name to extract `entry`, the image entry `path`, as
+pub fn host_name<IO: fatfs::ReadWriteSeek, TP, OCC: fatfs::OemCpConverter>(
+    entry: &fatfs::DirEntry<IO, TP, OCC>, path: &str, policy: Policy,
+) -> Result<String> {
+    let name = entry.file_name();
+    if policy == Policy::Replace || !name.contains(char::REPLACEMENT_CHARACTER) {
+        return Ok(name);
+    }
+    match escaped(path, &entry.short_file_name())? {
+        None => Ok(name),
+        Some(escaped) if policy == Policy::Escape => Ok(escaped),
+        Some(escaped) => bail!(
+            "{}: the long name {} has unpaired surrogates, use --surrogate-policy escape or \
+             replace to extract it",
+            path,
+            escaped
+        ),
+    }
+}
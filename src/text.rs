@@ -0,0 +1,187 @@
+//! Newline conversion for copying text files in and out of the image
+
+use std::io::{self, Read, Write};
+
+/// Newline conversion applied while copying file contents
+#[derive(clap::ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TextMode {
+    /// Copy bytes as-is
+    #[default]
+    None,
+    /// Convert LF to CRLF, leaving existing CRLF pairs alone
+    LfToCrlf,
+    /// Convert CRLF to LF, leaving lone CR bytes alone. A pass converts
+    /// the pairs it's given, so it isn't idempotent: a stray CR before a
+    /// pair, as in `\r\r\n`, is left as `\r\n`, which another pass
+    /// converts again.
+    CrlfToLf,
+}
+
+/// Copies `source` to `target`, converting newlines according to `mode`.
+/// With `TextMode::None` the data is passed through untouched.
+pub fn copy<R: Read, W: Write>(mode: TextMode, source: &mut R, target: &mut W) -> io::Result<u64> {
+    match mode {
+        TextMode::None => io::copy(source, target),
+        _ => io::copy(&mut NewlineReader::new(source, mode), target),
+    }
+}
+
+/// Streaming newline converter. A CR at the end of one read is remembered
+/// so that CRLF pairs split across buffer boundaries are still recognized.
+pub struct NewlineReader<R> {
+    inner: R,
+    mode: TextMode,
+    /// Last input byte was CR (`LfToCrlf`)
+    prev_cr: bool,
+    /// CR held back until the next byte is known (`CrlfToLf`)
+    pending_cr: bool,
+    converted: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+impl<R: Read> NewlineReader<R> {
+    pub fn new(inner: R, mode: TextMode) -> Self {
+        Self {
+            inner,
+            mode,
+            prev_cr: false,
+            pending_cr: false,
+            converted: Vec::new(),
+            pos: 0,
+            eof: false,
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        let mut raw = [0u8; 8192];
+        let n = self.inner.read(&mut raw)?;
+        self.converted.clear();
+        self.pos = 0;
+
+        if n == 0 {
+            self.eof = true;
+            if self.pending_cr {
+                self.converted.push(b'\r');
+                self.pending_cr = false;
+            }
+            return Ok(());
+        }
+
+        for &b in &raw[..n] {
+            match self.mode {
+                TextMode::None => self.converted.push(b),
+                TextMode::LfToCrlf => {
+                    if b == b'\n' && !self.prev_cr {
+                        self.converted.push(b'\r');
+                    }
+                    self.converted.push(b);
+                    self.prev_cr = b == b'\r';
+                },
+                TextMode::CrlfToLf => {
+                    if self.pending_cr {
+                        self.pending_cr = false;
+                        if b != b'\n' {
+                            self.converted.push(b'\r');
+                        }
+                    }
+                    if b == b'\r' {
+                        self.pending_cr = true;
+                    } else {
+                        self.converted.push(b);
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for NewlineReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        while self.pos == self.converted.len() {
+            if self.eof {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let n = (self.converted.len() - self.pos).min(out.len());
+        out[..n].copy_from_slice(&self.converted[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads `input` through the converter, in reads of at most `chunk`
+    /// bytes from the source
+    fn convert(mode: TextMode, input: &[u8], chunk: usize) -> Vec<u8> {
+        struct Chunked<'a>(&'a [u8], usize);
+        impl Read for Chunked<'_> {
+            fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+                let n = self.0.len().min(self.1).min(out.len());
+                out[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+        let mut out = Vec::new();
+        copy(mode, &mut Chunked(input, chunk), &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn newlines_are_converted() {
+        let input = b"a\nb\r\nc\rd\n\n";
+        for chunk in [1, 2, 3, 8192] {
+            assert_eq!(convert(TextMode::None, input, chunk), input);
+            assert_eq!(
+                convert(TextMode::LfToCrlf, input, chunk),
+                b"a\r\nb\r\nc\rd\r\n\r\n",
+                "{}",
+                chunk
+            );
+            assert_eq!(
+                convert(TextMode::CrlfToLf, input, chunk),
+                b"a\nb\nc\rd\n\n",
+                "{}",
+                chunk
+            );
+        }
+    }
+
+    #[test]
+    fn a_trailing_cr_is_kept() {
+        assert_eq!(convert(TextMode::CrlfToLf, b"a\r", 1), b"a\r");
+        assert_eq!(convert(TextMode::LfToCrlf, b"\r", 1), b"\r");
+    }
+
+    #[test]
+    fn a_stray_cr_before_a_pair_is_kept() {
+        for chunk in [1, 2, 8192] {
+            let once = convert(TextMode::CrlfToLf, b"a\r\r\nb\r\r\r\n", chunk);
+            assert_eq!(once, b"a\r\nb\r\r\n", "{}", chunk);
+            // Only the pairs of the input are converted, each pass anew
+            let twice = convert(TextMode::CrlfToLf, &once, chunk);
+            assert_eq!(twice, b"a\nb\r\n", "{}", chunk);
+        }
+    }
+
+    #[test]
+    fn pairs_split_across_buffers() {
+        // The pair straddles the end of the first 8192 byte buffer
+        let mut input = vec![b'x'; 8191];
+        input.extend_from_slice(b"\r\n");
+        let mut lf = vec![b'x'; 8191];
+        lf.push(b'\n');
+        assert_eq!(convert(TextMode::CrlfToLf, &input, usize::MAX), lf);
+        assert_eq!(convert(TextMode::LfToCrlf, &input, usize::MAX), input);
+        assert_eq!(convert(TextMode::LfToCrlf, &lf, usize::MAX), input);
+    }
+}
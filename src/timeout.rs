@@ -0,0 +1,398 @@
+//! Image I/O that gives up on media that stop responding.
+//!
+//! A dying card can block a single read for minutes. With `--io-timeout`
+//! each read, write, flush and sync of the image runs on a worker thread
+//! and fails with `TimedOut` if it doesn't finish in time. The stuck
+//! thread can't be stopped, so it is abandoned along with its file
+//! descriptor, which may be left in any state. The next operation
+//! reopens the image on a new thread.
+//!
+//! `ImgSlice` does all its I/O through this, so the timeout covers the
+//! filesystem and the commands that read the on-disk structures alike.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+static TIMEOUT: OnceLock<Option<Duration>> = OnceLock::new();
+
+/// Sets the `--io-timeout` for this invocation
+pub fn init(timeout: Option<Duration>) {
+    TIMEOUT.set(timeout).expect("timeout already set");
+}
+
+/// Parses `--io-timeout`, in seconds
+pub fn parse_timeout(s: &str) -> Result<Duration, String> {
+    match s.parse::<f64>() {
+        Ok(secs) if secs > 0.0 && secs.is_finite() => Ok(Duration::from_secs_f64(secs)),
+        _ => Err(format!("Invalid timeout {:?}, expected seconds", s)),
+    }
+}
+
+/// What a worker does its I/O on, the image file outside of tests
+trait Stream: Read + Write + Seek + Send + 'static {
+    fn sync_all(&mut self) -> io::Result<()>;
+    fn len(&mut self) -> io::Result<u64>;
+}
+
+impl Stream for File {
+    fn sync_all(&mut self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+
+    fn len(&mut self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+/// An operation at an absolute offset, so it means the same on a
+/// reopened file
+enum Op {
+    Read { pos: u64, len: usize },
+    Write { pos: u64, data: Vec<u8> },
+    Flush,
+    Sync,
+    Len,
+}
+
+enum Reply {
+    Read(Vec<u8>),
+    Written(usize),
+    Flushed,
+    Synced,
+    Len(u64),
+}
+
+impl Op {
+    fn run(self, file: &mut impl Stream) -> io::Result<Reply> {
+        match self {
+            Self::Read { pos, len } => {
+                file.seek(SeekFrom::Start(pos))?;
+                let mut buf = vec![0u8; len];
+                let n = file.read(&mut buf)?;
+                buf.truncate(n);
+                Ok(Reply::Read(buf))
+            },
+            Self::Write { pos, data } => {
+                file.seek(SeekFrom::Start(pos))?;
+                Ok(Reply::Written(file.write(&data)?))
+            },
+            Self::Flush => file.flush().map(|()| Reply::Flushed),
+            Self::Sync => file.sync_all().map(|()| Reply::Synced),
+            Self::Len => file.len().map(Reply::Len),
+        }
+    }
+}
+
+struct Worker {
+    ops: Sender<Op>,
+    replies: Receiver<io::Result<Reply>>,
+}
+
+impl Worker {
+    fn spawn(mut file: impl Stream) -> Self {
+        let (ops, op_rx) = mpsc::channel::<Op>();
+        let (reply_tx, replies) = mpsc::channel();
+        // Ends once the image is dropped, or when an abandoned operation
+        // finally returns
+        thread::spawn(move || {
+            for op in op_rx {
+                if reply_tx.send(op.run(&mut file)).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { ops, replies }
+    }
+}
+
+fn open_file(path: &Path, write: bool) -> io::Result<File> {
+    OpenOptions::new().read(true).write(write).open(path)
+}
+
+/// Image file guarded by the `--io-timeout`, if one was given
+pub struct ImgFile {
+    inner: Inner,
+}
+
+enum Inner {
+    Direct(File),
+    Guarded {
+        path: PathBuf,
+        write: bool,
+        timeout: Duration,
+        pos: u64,
+        /// `None` after a timeout, until the image is reopened
+        worker: Option<Worker>,
+    },
+}
+
+impl ImgFile {
+    /// Opens the image file `path`, for writing if `write`
+    pub fn open(path: &Path, write: bool) -> io::Result<Self> {
+        let file = open_file(path, write)?;
+        let inner = match TIMEOUT.get().copied().flatten() {
+            Some(timeout) => Inner::Guarded {
+                path: path.to_owned(),
+                write,
+                timeout,
+                pos: 0,
+                worker: Some(Worker::spawn(file)),
+            },
+            None => Inner::Direct(file),
+        };
+        Ok(Self { inner })
+    }
+
+    /// Size of the whole image file
+    pub fn len(&mut self) -> io::Result<u64> {
+        if let Inner::Direct(file) = &self.inner {
+            return Ok(file.metadata()?.len());
+        }
+        match self.call(Op::Len)? {
+            Reply::Len(len) => Ok(len),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn sync_all(&mut self) -> io::Result<()> {
+        if let Inner::Direct(file) = &self.inner {
+            return file.sync_all();
+        }
+        match self.call(Op::Sync)? {
+            Reply::Synced => Ok(()),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Opens the image again, at the same position. A guarded clone gets
+    /// its own worker, so a timeout in one leaves the other usable.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        let inner = match &self.inner {
+            Inner::Direct(file) => Inner::Direct(file.try_clone()?),
+            Inner::Guarded {
+                path,
+                write,
+                timeout,
+                pos,
+                ..
+            } => Inner::Guarded {
+                path: path.clone(),
+                write: *write,
+                timeout: *timeout,
+                pos: *pos,
+                worker: Some(Worker::spawn(open_file(path, *write)?)),
+            },
+        };
+        Ok(Self { inner })
+    }
+
+    fn call(&mut self, op: Op) -> io::Result<Reply> {
+        let (path, write, timeout, worker) = match &mut self.inner {
+            Inner::Guarded {
+                path,
+                write,
+                timeout,
+                worker,
+                ..
+            } => (path, *write, *timeout, worker),
+            Inner::Direct(_) => unreachable!("direct I/O has no worker"),
+        };
+        if worker.is_none() {
+            eprintln!("reopening {} after a timeout", path.display());
+            *worker = Some(Worker::spawn(open_file(path, write)?));
+        }
+        let w = worker.as_ref().unwrap();
+        let stopped = || io::Error::new(io::ErrorKind::BrokenPipe, "image I/O thread stopped");
+        w.ops.send(op).map_err(|_| stopped())?;
+        match w.replies.recv_timeout(timeout) {
+            Ok(reply) => reply,
+            Err(RecvTimeoutError::Timeout) => {
+                eprintln!(
+                    "image I/O timed out after {:?}, abandoning the operation",
+                    timeout
+                );
+                *worker = None;
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("image I/O timed out after {:?}", timeout),
+                ))
+            },
+            Err(RecvTimeoutError::Disconnected) => Err(stopped()),
+        }
+    }
+
+    fn pos(&mut self) -> &mut u64 {
+        match &mut self.inner {
+            Inner::Guarded { pos, .. } => pos,
+            Inner::Direct(_) => unreachable!("direct I/O keeps no position"),
+        }
+    }
+}
+
+impl Read for ImgFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Inner::Direct(file) = &mut self.inner {
+            return file.read(buf);
+        }
+        let pos = *self.pos();
+        match self.call(Op::Read {
+            pos,
+            len: buf.len(),
+        })? {
+            Reply::Read(data) => {
+                buf[..data.len()].copy_from_slice(&data);
+                *self.pos() += data.len() as u64;
+                Ok(data.len())
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Write for ImgFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Inner::Direct(file) = &mut self.inner {
+            #[cfg(test)]
+            return crate::fault::write(file, buf);
+            #[cfg(not(test))]
+            return file.write(buf);
+        }
+        let pos = *self.pos();
+        match self.call(Op::Write {
+            pos,
+            data: buf.to_vec(),
+        })? {
+            Reply::Written(n) => {
+                *self.pos() += n as u64;
+                Ok(n)
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Inner::Direct(file) = &mut self.inner {
+            return file.flush();
+        }
+        match self.call(Op::Flush)? {
+            Reply::Flushed => Ok(()),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Seek for ImgFile {
+    fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+        if let Inner::Direct(file) = &mut self.inner {
+            return file.seek(from);
+        }
+        let (base, delta) = match from {
+            SeekFrom::Start(n) => {
+                *self.pos() = n;
+                return Ok(n);
+            },
+            SeekFrom::Current(d) => (*self.pos(), d),
+            SeekFrom::End(d) => (self.len()?, d),
+        };
+        let target = base.checked_add_signed(delta).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the image",
+            )
+        })?;
+        *self.pos() = target;
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::time::Instant;
+
+    use anyhow::Context;
+
+    use super::*;
+    use crate::exit;
+
+    /// An image whose reads take `delay`
+    struct Slow {
+        image: Cursor<Vec<u8>>,
+        delay: Duration,
+    }
+
+    impl Read for Slow {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(self.delay);
+            self.image.read(buf)
+        }
+    }
+
+    impl Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.image.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for Slow {
+        fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+            self.image.seek(from)
+        }
+    }
+
+    impl Stream for Slow {
+        fn sync_all(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn len(&mut self) -> io::Result<u64> {
+            Ok(self.image.get_ref().len() as u64)
+        }
+    }
+
+    #[test]
+    fn slow_reads_time_out_and_the_image_is_reopened() {
+        let path = std::env::temp_dir().join(format!("fatimg-timeout-{}.img", std::process::id()));
+        std::fs::write(&path, b"fast").unwrap();
+        let timeout = Duration::from_millis(50);
+        let slow = Slow {
+            image: Cursor::new(b"slow".to_vec()),
+            delay: Duration::from_secs(5),
+        };
+        let mut img = ImgFile {
+            inner: Inner::Guarded {
+                path: path.clone(),
+                write: false,
+                timeout,
+                pos: 0,
+                worker: Some(Worker::spawn(slow)),
+            },
+        };
+
+        let started = Instant::now();
+        let mut buf = [0u8; 4];
+        let err = img.read(&mut buf).unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "image I/O timed out after 50ms");
+        let err = Err::<(), _>(err)
+            .context("failed reading /F.TXT")
+            .unwrap_err();
+        assert_eq!(exit::code(&err), exit::IO);
+
+        // The next read goes to the image file opened again
+        img.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(img.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"fast");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+//! Creating empty files and setting timestamps of existing ones
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use fatfs::TimeProvider;
+
+use crate::clock::{self, GivenTime, OutOfRange};
+use crate::warnings::{Category, Warnings};
+use crate::{delta, open_fs_rw, parent_of, refuse_file_components};
+
+/// Times to set, all of them `None` meaning the modification time now
+pub struct TouchOptions {
+    pub modified: Option<GivenTime>,
+    pub created: Option<GivenTime>,
+    pub accessed: Option<GivenTime>,
+}
+
+/// The timestamp to store for `t`, clamped to what FAT can store
+fn stored(t: &GivenTime, path: &str, warnings: &mut Warnings) -> fatfs::DateTime {
+    let (local, out_of_range) = clock::clamp_local(t.local_secs());
+    match out_of_range {
+        Some(OutOfRange::Before1980) => warnings.warn(Category::TimeBefore1980, path),
+        Some(OutOfRange::After2107) => warnings.warn(Category::TimeAfter2107, path),
+        None => {},
+    }
+    clock::date_time(local, 0)
+}
+
+/// Creates the file at the normalized image path `inner_path` if it is
+/// missing, and sets its timestamps. Contents of an existing file are
+/// kept.
+pub fn run(
+    img_file: &Path, inner_path: &str, opts: &TouchOptions, warnings: &mut Warnings,
+) -> Result<()> {
+    let path = format!("/{}", inner_path);
+    refuse_file_components(img_file, parent_of(inner_path))?;
+    let fs = open_fs_rw(img_file)?;
+    let root = fs.root_dir();
+    if root.open_dir(inner_path).is_ok() {
+        bail!("{}: is a directory, only file times can be set", path);
+    }
+    let mut file = match root.open_file(inner_path) {
+        Ok(file) => file,
+        Err(_) => {
+            let file = root
+                .create_file(inner_path)
+                .with_context(|| format!("failed creating {}", path))?;
+            delta::created(false);
+            eprintln!("created {}", path);
+            file
+        },
+    };
+
+    let now = [&opts.modified, &opts.created, &opts.accessed]
+        .iter()
+        .all(|t| t.is_none());
+    if now {
+        file.set_modified(clock::get().get_current_date_time());
+    }
+    if let Some(t) = &opts.modified {
+        file.set_modified(stored(t, &path, warnings));
+    }
+    if let Some(t) = &opts.created {
+        file.set_created(stored(t, &path, warnings));
+    }
+    if let Some(t) = &opts.accessed {
+        file.set_accessed(stored(t, &path, warnings).date);
+    }
+    fatfs::Write::flush(&mut file).with_context(|| format!("failed writing {}", path))?;
+    drop((file, root));
+    fs.unmount().context("failed flushing the filesystem")?;
+    Ok(())
+}
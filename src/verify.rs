@@ -0,0 +1,85 @@
+//! The `verify-boot` command, running the `bios` profile on its own
+
+use anyhow::{bail, Result};
+
+use crate::json;
+use crate::ondisk::{BootSector, Fat};
+use crate::output::{Fields, Output};
+use crate::profile::{self, Outcome, Volume};
+use crate::region::ImgSlice;
+
+/// The `verify-boot --json` document of the outcomes of the rules
+fn to_json(results: &[(&str, Outcome)]) -> json::Value {
+    let checks = results.iter().map(|(name, outcome)| {
+        let (passed, why) = match outcome {
+            Ok(why) => (true, why),
+            Err(why) => (false, why),
+        };
+        json::object([
+            ("name", (*name).into()),
+            ("passed", passed.into()),
+            ("detail", why.as_str().into()),
+        ])
+    });
+    json::versioned(json::object([
+        ("passed", results.iter().all(|(_, o)| o.is_ok()).into()),
+        ("checks", checks.collect()),
+    ]))
+}
+
+/// Prints a line per rule in the `output` format, and fails if any rule
+/// does. `expect_bootcode` adds `profile::BOOT_CODE`.
+pub fn run(img: &mut ImgSlice, expect_bootcode: bool, output: Output) -> Result<()> {
+    let bs = BootSector::read(img)?;
+    let layout = bs.layout()?;
+    let fat = Fat::read(img, &layout)?;
+    let mut volume = Volume {
+        img,
+        bs: &bs,
+        layout: &layout,
+        fat: &fat,
+    };
+
+    let extra = expect_bootcode.then_some(&profile::BOOT_CODE);
+    let mut results = Vec::new();
+    for rule in profile::BIOS.rules.iter().chain(extra) {
+        results.push((rule.name, (rule.check)(&mut volume)?));
+    }
+    let failed = results.iter().filter(|(_, o)| o.is_err()).count();
+
+    if output == Output::Json {
+        println!("{}", to_json(&results));
+    } else {
+        let mut fields = Fields::new(0);
+        for (name, outcome) in &results {
+            match outcome {
+                Ok(why) => fields.push(name, format!("pass, {}", why)),
+                Err(why) => fields.push(name, format!("FAIL, {}", why)),
+            }
+        }
+        fields.print(output);
+    }
+
+    if failed > 0 {
+        bail!("{} of {} check(s) failed", failed, results.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Document;
+
+    #[test]
+    fn json_matches_the_schema() {
+        let results = [
+            ("signature", Ok("0x55aa at 510".to_owned())),
+            ("bootcode", Err("all zeroes".to_owned())),
+        ];
+        let doc = to_json(&results);
+        assert_eq!(Document::VerifyBoot.validate(&doc), Ok(()));
+        assert_eq!(doc.get("passed"), Some(&false.into()));
+        assert_eq!(Document::VerifyBoot.validate(&to_json(&[])), Ok(()));
+    }
+}
@@ -0,0 +1,81 @@
+//! Walking the image tree through fatfs without recursion.
+//!
+//! The walk keeps one directory iterator per level below the start, each
+//! holding a cluster position, so memory grows with the depth of the tree
+//! and not with the number of entries. Entries are yielded in directory
+//! order, each directory before its contents, like `ls -R` prints them.
+//! Deep trees can't overflow the stack.
+
+use anyhow::{Context, Result};
+use fatfs::{Dir, DirEntry, DirIter, OemCpConverter, ReadWriteSeek, TimeProvider};
+
+use crate::inner_join;
+
+/// An entry found by `Walk`
+pub struct Entry<'a, IO: ReadWriteSeek, TP, OCC> {
+    pub entry: DirEntry<'a, IO, TP, OCC>,
+    /// Image path of the entry
+    pub path: String,
+    /// 0 for entries of the start directory
+    pub depth: usize,
+}
+
+pub struct Walk<'a, IO: ReadWriteSeek, TP, OCC> {
+    /// Iterators of the directories being listed, with their paths
+    stack: Vec<(String, DirIter<'a, IO, TP, OCC>)>,
+    recursive: bool,
+}
+
+impl<'a, IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter> Walk<'a, IO, TP, OCC> {
+    /// Walks `dir`, the image directory `path`, and with `recursive`
+    /// everything below it
+    pub fn new(dir: &Dir<'a, IO, TP, OCC>, path: &str, recursive: bool) -> Self {
+        Self {
+            stack: vec![(path.to_owned(), dir.iter())],
+            recursive,
+        }
+    }
+
+    /// Leaves out the contents of the directory the walk just returned.
+    /// Only valid right after a directory entry of a recursive walk.
+    pub fn skip_dir(&mut self) {
+        self.stack.pop();
+    }
+}
+
+impl<'a, IO: ReadWriteSeek, TP: TimeProvider, OCC: OemCpConverter> Iterator
+    for Walk<'a, IO, TP, OCC>
+where
+    fatfs::Error<IO::Error>: std::error::Error + Send + Sync + 'static,
+{
+    type Item = Result<Entry<'a, IO, TP, OCC>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (dir_path, iter) = self.stack.last_mut()?;
+            let entry = match iter.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => {
+                    let msg = format!("failed reading directory {}", dir_path);
+                    // A broken directory would keep failing, leave it
+                    self.stack.pop();
+                    return Some(Err(err).context(msg));
+                },
+                None => {
+                    self.stack.pop();
+                    continue;
+                },
+            };
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = inner_join(dir_path, &name);
+            let depth = self.stack.len() - 1;
+            if self.recursive && entry.is_dir() {
+                self.stack.push((path.clone(), entry.to_dir().iter()));
+            }
+            return Some(Ok(Entry { entry, path, depth }));
+        }
+    }
+}
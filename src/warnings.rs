@@ -0,0 +1,261 @@
+//! Warnings and their codes, and grouping the warnings of tree
+//! operations, which can be very repetitive.
+//!
+//! Every warning has a category with a stable code like `W001`, printed
+//! with it and recorded in `--report`, so that scripts and
+//! `--fail-on-warning` don't have to match the text. Codes are never
+//! reused for another category.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use anyhow::{bail, Result};
+
+use crate::report;
+
+/// How many warnings of each category are printed before only counting
+const SHOWN_PER_CATEGORY: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+    SymlinkSkipped,
+    NonUtf8Name,
+    TimeBefore1980,
+    TimeAfter2107,
+    OtherFilesystem,
+    TotalSectorsConflict,
+    Fat32Layout,
+    NameMapUnmatched,
+    Latin1Name,
+    SidecarSkipped,
+    KindChanged,
+    ModeIgnored,
+}
+
+impl Category {
+    pub const ALL: [Self; 12] = [
+        Self::SymlinkSkipped,
+        Self::NonUtf8Name,
+        Self::TimeBefore1980,
+        Self::TimeAfter2107,
+        Self::OtherFilesystem,
+        Self::TotalSectorsConflict,
+        Self::Fat32Layout,
+        Self::NameMapUnmatched,
+        Self::Latin1Name,
+        Self::SidecarSkipped,
+        Self::KindChanged,
+        Self::ModeIgnored,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::SymlinkSkipped => "W001",
+            Self::NonUtf8Name => "W002",
+            Self::TimeBefore1980 => "W003",
+            Self::TimeAfter2107 => "W004",
+            Self::OtherFilesystem => "W005",
+            Self::TotalSectorsConflict => "W006",
+            Self::Fat32Layout => "W007",
+            Self::NameMapUnmatched => "W008",
+            Self::Latin1Name => "W009",
+            Self::SidecarSkipped => "W010",
+            Self::KindChanged => "W011",
+            Self::ModeIgnored => "W012",
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Self::SymlinkSkipped => "symlinks skipped",
+            Self::NonUtf8Name => "non-UTF-8 names skipped",
+            Self::TimeBefore1980 => "times before 1980 stored as 1980-01-01",
+            Self::TimeAfter2107 => "times after 2107 stored as 2107-12-31",
+            Self::OtherFilesystem => "directories on other filesystems skipped",
+            Self::TotalSectorsConflict => "boot sector total sector fields disagreeing",
+            Self::Fat32Layout => "FAT32 reserved area layouts some firmware rejects",
+            Self::NameMapUnmatched => "name map entries matching nothing",
+            Self::Latin1Name => "non-UTF-8 names stored as Latin-1",
+            Self::SidecarSkipped => "metadata sidecar files skipped",
+            Self::KindChanged => "entries that changed between file and directory skipped",
+            Self::ModeIgnored => "permission modes not set on a non-Unix host",
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            Self::SymlinkSkipped => "Not copying a symlink",
+            Self::NonUtf8Name => "Not copying a non-UTF-8 name",
+            Self::TimeBefore1980 => "Time before 1980 stored as 1980-01-01",
+            Self::TimeAfter2107 => "Time after 2107 stored as 2107-12-31",
+            Self::OtherFilesystem => "Not crossing into another filesystem",
+            Self::TotalSectorsConflict => "Total sector fields disagree",
+            Self::Fat32Layout => "FAT32 reserved area layout",
+            Self::NameMapUnmatched => "Name map entry matches no image entry",
+            Self::Latin1Name => "Non-UTF-8 name stored as Latin-1",
+            Self::SidecarSkipped => "Not copying a metadata sidecar",
+            Self::KindChanged => "Not replacing a file with a directory or back without --delete",
+            Self::ModeIgnored => "Permission modes are only set on Unix hosts",
+        }
+    }
+}
+
+/// Parses a warning code like `W001`, for `--fail-on-warning`
+pub fn parse_code(s: &str) -> Result<Category, String> {
+    Category::ALL
+        .into_iter()
+        .find(|c| c.code().eq_ignore_ascii_case(s))
+        .ok_or_else(|| {
+            let codes: Vec<&str> = Category::ALL.iter().map(|c| c.code()).collect();
+            format!(
+                "Unknown warning code {:?}, expected one of {}",
+                s,
+                codes.join(", ")
+            )
+        })
+}
+
+/// Prints a warning that isn't one of many like it, see `Warnings` for
+/// those
+pub fn warn(category: Category, detail: impl Display) {
+    eprintln!("Warning [{}]: {}", category.code(), detail);
+    report::warning(category, detail.to_string());
+}
+
+/// `--fail-on-warning`: fails if a warning of `categories`, or of any
+/// category if it's empty, was given during this invocation
+pub fn fail_on(categories: &[Category]) -> Result<()> {
+    let given: Vec<&str> = report::warning_categories()
+        .into_iter()
+        .filter(|c| categories.is_empty() || categories.contains(c))
+        .map(Category::code)
+        .collect();
+    if !given.is_empty() {
+        bail!(
+            "Failing for --fail-on-warning, warnings given: {}",
+            given.join(", ")
+        );
+    }
+    Ok(())
+}
+
+struct Group {
+    count: usize,
+    first: String,
+}
+
+/// Counts warnings per category. The first few of each category are
+/// printed as they happen, the rest only show up in the summary.
+pub struct Warnings {
+    verbose: bool,
+    groups: BTreeMap<Category, Group>,
+}
+
+impl Warnings {
+    /// With `verbose` every single warning is printed
+    pub fn new(verbose: bool) -> Self {
+        Self {
+            verbose,
+            groups: BTreeMap::new(),
+        }
+    }
+
+    pub fn warn(&mut self, category: Category, path: impl Display) {
+        let group = self.groups.entry(category).or_insert_with(|| Group {
+            count: 0,
+            first: path.to_string(),
+        });
+        group.count += 1;
+        report::warning(category, format!("{}: {}", category.message(), path));
+        if self.verbose || group.count <= SHOWN_PER_CATEGORY {
+            eprintln!(
+                "Warning [{}]: {}: {}",
+                category.code(),
+                category.message(),
+                path
+            );
+        } else if group.count == SHOWN_PER_CATEGORY + 1 {
+            eprintln!(
+                "Warning [{}]: more {} follow, see the summary at the end",
+                category.code(),
+                category.describe()
+            );
+        }
+    }
+
+    /// How many warnings of `category` were given
+    pub fn count(&self, category: Category) -> usize {
+        self.groups.get(&category).map_or(0, |g| g.count)
+    }
+
+    /// Prints the summary of warnings that weren't all shown
+    pub fn finish(self) {
+        if !self.verbose {
+            for (category, group) in &self.groups {
+                if group.count > SHOWN_PER_CATEGORY {
+                    eprintln!(
+                        "{} {}: {} (first: {})",
+                        category.code(),
+                        category.describe(),
+                        group.count,
+                        group.first
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    #[test]
+    fn codes_are_unique() {
+        // Declaration order, so every category is listed once
+        assert!(Category::ALL.windows(2).all(|w| w[0] < w[1]));
+        let codes: BTreeSet<&str> = Category::ALL.iter().map(|c| c.code()).collect();
+        assert_eq!(codes.len(), Category::ALL.len());
+        for code in codes {
+            assert!(
+                code.len() == 4
+                    && code.starts_with('W')
+                    && code[1..].bytes().all(|b| b.is_ascii_digit()),
+                "{}",
+                code
+            );
+        }
+        let described: BTreeSet<&str> = Category::ALL.iter().map(|c| c.describe()).collect();
+        assert_eq!(described.len(), Category::ALL.len());
+    }
+
+    #[test]
+    fn codes_parse_back() {
+        for category in Category::ALL {
+            assert_eq!(parse_code(category.code()), Ok(category));
+            assert_eq!(parse_code(&category.code().to_lowercase()), Ok(category));
+        }
+        let err = parse_code("W999").unwrap_err();
+        assert!(
+            err.starts_with("Unknown warning code \"W999\", expected one of W001, W002"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn every_warning_is_reported_with_its_category() {
+        let mut warnings = Warnings::new(false);
+        for i in 0..5 {
+            warnings.warn(Category::KindChanged, format!("/F{}", i));
+        }
+        assert_eq!(warnings.count(Category::KindChanged), 5);
+        assert_eq!(warnings.count(Category::SymlinkSkipped), 0);
+        assert!(report::warning_categories().contains(&Category::KindChanged));
+        let err = fail_on(&[Category::KindChanged]).unwrap_err();
+        assert!(err.to_string().contains("W011"), "{}", err);
+        warnings.finish();
+    }
+}
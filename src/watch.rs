@@ -0,0 +1,216 @@
+//! Mirroring a host directory into the image as it changes
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+
+use crate::overwrite::OverwritePolicy;
+use crate::warnings::{self, Category};
+use crate::{delta, ntcase, open_fs_rw};
+
+/// What a host entry looked like when the tree was last scanned
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stamp {
+    is_dir: bool,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Host entries by path relative to the watched directory, parents
+/// sorted before their children
+type Snapshot = BTreeMap<String, Stamp>;
+
+/// Scans the host tree. Entries that vanish while scanning are left out,
+/// the next scan will see whatever replaced them.
+fn snapshot(host_path: &Path) -> Result<Snapshot> {
+    let mut result = BTreeMap::new();
+    let mut stack = vec![(host_path.to_owned(), String::new())];
+    while let Some((dir, rel_dir)) = stack.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Err(err) if err.kind() == ErrorKind::NotFound && !rel_dir.is_empty() => continue,
+            r => r.with_context(|| format!("failed reading host directory {}", dir.display()))?,
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(name) => {
+                    warnings::warn(Category::NonUtf8Name, format!("{:?}", name));
+                    continue;
+                },
+            };
+            let meta = match fs::symlink_metadata(entry.path()) {
+                Ok(meta) if !meta.file_type().is_symlink() => meta,
+                _ => continue,
+            };
+            let rel = if rel_dir.is_empty() {
+                name
+            } else {
+                format!("{}/{}", rel_dir, name)
+            };
+            if meta.is_dir() {
+                stack.push((entry.path(), rel.clone()));
+            }
+            let stamp = Stamp {
+                is_dir: meta.is_dir(),
+                len: meta.len(),
+                modified: meta.modified().ok(),
+            };
+            result.insert(rel, stamp);
+        }
+    }
+    Ok(result)
+}
+
+/// Options of the `watch` command
+pub struct WatchOptions {
+    /// How often the host tree is scanned
+    pub interval: Duration,
+    /// Remove image entries whose host counterpart was removed
+    pub delete: bool,
+    /// Image files that were there before watching are replaced by
+    /// default. Ones written by the watch itself are always updated.
+    pub overwrite: OverwritePolicy,
+}
+
+/// Copies the host tree into the image directory `inner_path`, then keeps
+/// applying changes until the process is stopped. Each batch of changes is
+/// flushed before waiting for the next one, so stopping the process while
+/// it waits leaves a consistent image.
+pub fn run(img_file: &Path, inner_path: &str, host_path: &Path, opts: &WatchOptions) -> Result<()> {
+    {
+        let fs = open_fs_rw(img_file)?;
+        if !inner_path.is_empty() {
+            fs.root_dir()
+                .open_dir(inner_path)
+                .with_context(|| format!("failed opening directory /{}", inner_path))?;
+        }
+    }
+
+    let mut synced = Snapshot::new();
+    loop {
+        let mut current = snapshot(host_path)?;
+        if current != synced {
+            // Wait for the tree to settle, so that a burst of changes is
+            // applied as one batch
+            loop {
+                thread::sleep(opts.interval);
+                let next = snapshot(host_path)?;
+                if next == current {
+                    break;
+                }
+                current = next;
+            }
+            apply(img_file, inner_path, host_path, &synced, &current, opts)?;
+            synced = current;
+        }
+        thread::sleep(opts.interval);
+    }
+}
+
+/// Applies the difference between two snapshots to the image
+fn apply(
+    img_file: &Path, inner_path: &str, host_path: &Path, old: &Snapshot, new: &Snapshot,
+    opts: &WatchOptions,
+) -> Result<()> {
+    let inner = |rel: &str| {
+        if inner_path.is_empty() {
+            rel.to_owned()
+        } else {
+            format!("{}/{}", inner_path, rel)
+        }
+    };
+
+    let fs = open_fs_rw(img_file)?;
+    let root = fs.root_dir();
+
+    if opts.delete {
+        // Children sort after their parents, so removing in reverse order
+        // empties directories before they are removed
+        for (rel, stamp) in old.iter().rev() {
+            if new.get(rel).is_some_and(|s| s.is_dir == stamp.is_dir) {
+                continue;
+            }
+            let path = inner(rel);
+            root.remove(&path)
+                .with_context(|| format!("failed removing /{}", path))?;
+            println!("- /{}", path);
+        }
+    }
+
+    // Entries the image keeps as the other kind, which nothing can be
+    // written below
+    let mut skipped: Vec<&str> = Vec::new();
+    for (rel, stamp) in new {
+        let previous = old.get(rel);
+        match previous {
+            Some(p) if p == stamp => continue,
+            Some(p) if p.is_dir && stamp.is_dir => continue,
+            _ => {},
+        }
+        if skipped
+            .iter()
+            .any(|s| rel.strip_prefix(s).is_some_and(|r| r.starts_with('/')))
+        {
+            continue;
+        }
+        let path = inner(rel);
+        let other_kind = if stamp.is_dir {
+            root.open_file(&path).is_ok()
+        } else {
+            root.open_dir(&path).is_ok()
+        };
+        if other_kind {
+            // With --delete the old entry was removed above
+            let msg = format!(
+                "/{} changed between file and directory, give --delete to replace it",
+                path
+            );
+            warnings::warn(Category::KindChanged, msg);
+            skipped.push(rel);
+            continue;
+        }
+        if stamp.is_dir {
+            if root.open_dir(&path).is_err() {
+                delta::created(true);
+            }
+            root.create_dir(&path)
+                .with_context(|| format!("failed creating directory /{}", path))?;
+        } else {
+            let host = host_path.join(rel);
+            let mut source = match File::open(&host) {
+                // Removed since the scan, the next scan will notice
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                r => r.with_context(|| format!("failed reading {}", host.display()))?,
+            };
+            let exists = root.open_file(&path).is_ok();
+            if previous.is_none() && exists {
+                opts.overwrite.check(true, format_args!("/{}", path))?;
+            }
+            if !exists {
+                delta::created(false);
+            }
+            let context = || format!("failed writing /{} from {}", path, host.display());
+            let mut target = root.create_file(&path).with_context(context)?;
+            target.truncate().with_context(context)?;
+            // See `write_file` for why the empty size is committed first
+            target.flush().with_context(context)?;
+            io::copy(&mut source, &mut target).with_context(context)?;
+            target.flush().with_context(context)?;
+        }
+        let kind = match previous {
+            Some(_) => "~",
+            None => "+",
+        };
+        println!("{} /{}", kind, path);
+    }
+
+    drop(root);
+    fs.unmount().context("failed flushing the filesystem")?;
+    ntcase::fold_tree(img_file, inner_path)
+}
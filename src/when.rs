@@ -0,0 +1,271 @@
+//! Points in time given on the command line, for filtering entries by
+//! their modification time.
+//!
+//! A time is either a duration before now like `30d` or `1d12h`, an
+//! RFC 3339 time, a date like `2024-03-01` for its local midnight, or
+//! Unix seconds like `@1714564800`. Entry times are
+//! local time without a zone, so absolute times are converted to the
+//! local time of the clock, i.e. `--tz` or the host's zone. FAT stores
+//! modification times in 2 second steps, so cutoffs are rounded down to
+//! an even second before comparing.
+
+use fatfs::DateTime;
+
+use crate::clock;
+
+/// A `--newer-than` or `--older-than` value
+#[derive(Debug, Clone, Copy)]
+pub enum TimeSpec {
+    /// Seconds before now
+    Ago(i64),
+    /// Seconds since the Unix epoch
+    At(i64),
+    /// Local seconds, for a date without a time
+    Local(i64),
+}
+
+const UNITS: &[(char, i64)] = &[
+    ('s', 1),
+    ('m', 60),
+    ('h', 3600),
+    ('d', 86400),
+    ('w', 7 * 86400),
+];
+
+const EXAMPLES: &str = "e.g. 30d, 1d12h, 2024-05-01, 2024-05-01T12:00:00Z or @1714564800";
+
+/// Parses a duration like `90m`, `1d12h` or `2w`. Each unit is given once,
+/// largest first.
+fn parse_duration(s: &str) -> Result<i64, String> {
+    let mut total: i64 = 0;
+    let mut smallest = i64::MAX;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let unit = rest[digits..].chars().next();
+        let months = rest[digits..].starts_with("mo");
+        let factor = match unit.and_then(|u| UNITS.iter().find(|&&(c, _)| c == u)) {
+            Some(&(_, factor)) if !months => factor,
+            None if digits == rest.len() => {
+                return Err(format!(
+                    "Ambiguous time {:?}, give a unit like {}d or a Unix time as @{}",
+                    s, rest, rest
+                ))
+            },
+            _ => {
+                return Err(format!(
+                    "Unknown unit in {:?}, use s, m, h, d or w. Months and years vary \
+                     in length, give days instead, e.g. 30d or 365d",
+                    s
+                ))
+            },
+        };
+        let value: i64 = match rest[..digits].parse() {
+            Ok(value) => value,
+            Err(_) => return Err(format!("Invalid duration {:?}, {}", s, EXAMPLES)),
+        };
+        if factor >= smallest {
+            return Err(format!(
+                "Invalid duration {:?}, give each unit once and largest first, e.g. 1d12h",
+                s
+            ));
+        }
+        smallest = factor;
+        total = value
+            .checked_mul(factor)
+            .and_then(|v| total.checked_add(v))
+            .ok_or_else(|| format!("Duration {:?} is too long", s))?;
+        rest = &rest[digits + 1..];
+    }
+    Ok(total)
+}
+
+/// Parses a `--newer-than`, `--older-than`, `--since` or `--until` value
+pub fn parse_time(s: &str) -> Result<TimeSpec, String> {
+    if let Some(epoch) = s.strip_prefix('@') {
+        return match epoch.parse() {
+            Ok(secs) => Ok(TimeSpec::At(secs)),
+            Err(_) => Err(format!("Invalid Unix time {:?}, {}", s, EXAMPLES)),
+        };
+    }
+    if s.len() == 10 && s.as_bytes()[4] == b'-' {
+        return clock::parse_rfc3339(&format!("{}T00:00:00Z", s))
+            .map(|t| TimeSpec::Local(t.unix_secs()))
+            .map_err(|_| format!("Invalid date {:?}, {}", s, EXAMPLES));
+    }
+    if s.len() > 10 && s.as_bytes()[4] == b'-' {
+        return clock::parse_rfc3339(s)
+            .map(|t| TimeSpec::At(t.unix_secs()))
+            .map_err(|e| format!("{}, {}", e, EXAMPLES));
+    }
+    if s.is_empty() {
+        return Err(format!("Empty time, {}", EXAMPLES));
+    }
+    parse_duration(s).map(TimeSpec::Ago)
+}
+
+impl TimeSpec {
+    /// Local seconds comparable to `clock::local_secs` of entry times,
+    /// rounded down to FAT's 2 second steps
+    pub fn local_secs(&self) -> i64 {
+        let clock = clock::get();
+        let local = match *self {
+            Self::Ago(secs) => clock.now_local() - secs,
+            Self::At(secs) => secs + clock.utc_offset() as i64,
+            Self::Local(secs) => secs,
+        };
+        local - local.rem_euclid(2)
+    }
+}
+
+/// Modification time limits, both exclusive
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimeFilter {
+    pub newer_than: Option<i64>,
+    pub older_than: Option<i64>,
+}
+
+impl TimeFilter {
+    pub fn new(newer_than: Option<TimeSpec>, older_than: Option<TimeSpec>) -> Self {
+        Self {
+            newer_than: newer_than.map(|t| t.local_secs()),
+            older_than: older_than.map(|t| t.local_secs()),
+        }
+    }
+
+    /// The window of `--since` and `--until`: at or after `since` and
+    /// before `until`. Entry times are in FAT's 2 second steps, so being
+    /// at or after `since` is being newer than the step before it.
+    pub fn window(since: Option<TimeSpec>, until: Option<TimeSpec>) -> Self {
+        Self {
+            newer_than: since.map(|t| t.local_secs() - 2),
+            older_than: until.map(|t| t.local_secs()),
+        }
+    }
+
+    /// Does a modification time `modified` pass the limits
+    pub fn matches(&self, modified: &DateTime) -> bool {
+        let t = clock::local_secs(modified);
+        self.newer_than.is_none_or(|n| t > n) && self.older_than.is_none_or(|o| t < o)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-05-01T12:00:00 in local seconds
+    const NOON: i64 = 1_714_564_800;
+
+    fn at(local: i64) -> DateTime {
+        clock::date_time(local, 0)
+    }
+
+    #[test]
+    fn durations() {
+        for (given, secs) in [
+            ("0s", 0),
+            ("90s", 90),
+            ("90m", 5400),
+            ("1d12h", 129_600),
+            ("2w", 1_209_600),
+            ("1w1d1h1m1s", 694_861),
+            ("1d0h", 86400),
+        ] {
+            assert_eq!(parse_duration(given), Ok(secs), "{}", given);
+            assert!(matches!(parse_time(given), Ok(TimeSpec::Ago(s)) if s == secs));
+        }
+    }
+
+    #[test]
+    fn invalid_durations_give_examples() {
+        for (given, error) in [
+            (
+                "30",
+                "Ambiguous time \"30\", give a unit like 30d or a Unix time as @30",
+            ),
+            ("1d12", "give a unit like 12d"),
+            ("1mo", "Months and years vary in length"),
+            ("1y", "Unknown unit in \"1y\""),
+            ("1h1d", "largest first"),
+            ("1h1h", "give each unit once"),
+            ("d", "e.g. 30d, 1d12h"),
+            ("106751991167301d", "is too long"),
+            ("99999999999999999999s", "Invalid duration"),
+        ] {
+            let err = parse_time(given).unwrap_err();
+            assert!(err.contains(error), "{}: {}", given, err);
+        }
+    }
+
+    #[test]
+    fn dates_and_unix_times() {
+        assert!(matches!(
+            parse_time("2024-05-01"),
+            Ok(TimeSpec::Local(t)) if t == NOON - 12 * 3600
+        ));
+        assert!(matches!(parse_time("@1714564800"), Ok(TimeSpec::At(NOON))));
+        assert!(matches!(
+            parse_time("2024-05-01T14:00:00+02:00"),
+            Ok(TimeSpec::At(NOON))
+        ));
+        assert!(parse_time("2024-13-01").is_err());
+        assert!(parse_time("@noon").is_err());
+        assert!(parse_time("2024-05-01T12:00").is_err());
+        assert!(parse_time("").is_err());
+    }
+
+    #[test]
+    fn cutoffs_round_down_to_even_seconds() {
+        assert_eq!(TimeSpec::Local(NOON).local_secs(), NOON);
+        assert_eq!(TimeSpec::Local(NOON + 1).local_secs(), NOON);
+        assert_eq!(TimeSpec::Local(NOON - 1).local_secs(), NOON - 2);
+    }
+
+    #[test]
+    fn since_is_inclusive_and_until_exclusive() {
+        let window =
+            TimeFilter::window(Some(TimeSpec::Local(NOON)), Some(TimeSpec::Local(NOON + 4)));
+        assert!(!window.matches(&at(NOON - 2)));
+        assert!(window.matches(&at(NOON)));
+        assert!(window.matches(&at(NOON + 2)));
+        assert!(!window.matches(&at(NOON + 4)));
+    }
+
+    #[test]
+    fn odd_seconds_select_the_step_they_fall_in() {
+        // An entry stored at noon was modified in the 2 seconds from noon
+        let since = TimeFilter::window(Some(TimeSpec::Local(NOON + 1)), None);
+        assert!(since.matches(&at(NOON)));
+        assert!(!since.matches(&at(NOON - 2)));
+        let until = TimeFilter::window(None, Some(TimeSpec::Local(NOON + 1)));
+        assert!(until.matches(&at(NOON - 2)));
+        assert!(!until.matches(&at(NOON)));
+    }
+
+    #[test]
+    fn a_window_of_one_step() {
+        let window =
+            TimeFilter::window(Some(TimeSpec::Local(NOON)), Some(TimeSpec::Local(NOON + 2)));
+        let matching: Vec<i64> = (-4..6)
+            .map(|d| NOON + d * 2)
+            .filter(|&t| window.matches(&at(t)))
+            .collect();
+        assert_eq!(matching, [NOON]);
+        let empty = TimeFilter::window(Some(TimeSpec::Local(NOON)), Some(TimeSpec::Local(NOON)));
+        assert!(!empty.matches(&at(NOON)));
+    }
+
+    #[test]
+    fn whole_days() {
+        let midnight = NOON - 12 * 3600;
+        let may_first = TimeFilter::window(
+            Some(TimeSpec::Local(midnight)),
+            Some(TimeSpec::Local(midnight + 86400)),
+        );
+        assert!(may_first.matches(&at(midnight)));
+        assert!(may_first.matches(&at(midnight + 86398)));
+        assert!(!may_first.matches(&at(midnight + 86400)));
+        assert!(!may_first.matches(&at(midnight - 2)));
+    }
+}
@@ -0,0 +1,66 @@
+//! `mkdir --attributes` and `write --attributes`, as `attrib` shows them
+//! right after the entry is made
+
+mod common;
+
+use common::Image;
+
+#[test]
+fn shown_right_after_creation() {
+    let image = Image::new("shown", "4M");
+    for (dir, given, shown) in [
+        ("/NAMES", "hidden,system", "-hs-d-"),
+        ("/LETTERS", "-h----", "-h--d-"),
+        ("/HEX", "0x01", "r---d-"),
+    ] {
+        // `=` for the values beginning with a dash
+        image.ok(&["mkdir", dir, &format!("--attributes={}", given)]);
+        assert_eq!(
+            image.ok(&["attrib", dir]),
+            format!("{} {}\n", shown, dir),
+            "{}",
+            given
+        );
+    }
+    for (file, given, shown) in [
+        ("/NAMES.TXT", "read_only,archive", "r--a--"),
+        ("/LETTERS.TXT", "-hs---", "-hs---"),
+        ("/HEX.TXT", "0x06", "-hs---"),
+    ] {
+        let out = image.run_with_input(&["write", file, &format!("--attributes={}", given)], b"x");
+        assert!(out.status.success(), "{}", given);
+        assert_eq!(
+            image.ok(&["attrib", file]),
+            format!("{} {}\n", shown, file),
+            "{}",
+            given
+        );
+    }
+    assert_eq!(
+        image.ok(&["attrib", "--numeric-attrs", "/NAMES"]),
+        "0x16 /NAMES\n"
+    );
+    assert_eq!(
+        image.ok(&["attrib", "--numeric-attrs", "/HEX.TXT"]),
+        "0x06 /HEX.TXT\n"
+    );
+    // Without the flag, as before
+    image.ok(&["mkdir", "/PLAIN"]);
+    image.write("/PLAIN.TXT", b"x");
+    assert_eq!(image.ok(&["attrib", "/PLAIN"]), "----d- /PLAIN\n");
+    assert_eq!(image.ok(&["attrib", "/PLAIN.TXT"]), "---a-- /PLAIN.TXT\n");
+}
+
+#[test]
+fn kinds_are_refused_when_parsing() {
+    let image = Image::new("kinds", "4M");
+    let before = image.hash();
+    for given in ["directory", "volume_id", "0x10", "0x08"] {
+        let (code, stderr) = image.fails(&["mkdir", "/D", "--attributes", given]);
+        assert_eq!(code, 1, "{}", given);
+        assert!(stderr.contains("can't be chosen"), "{}: {}", given, stderr);
+        let out = image.run_with_input(&["write", "/F.TXT", "--attributes", given], b"x");
+        assert_eq!(out.status.code(), Some(1), "{}", given);
+    }
+    assert_eq!(image.hash(), before);
+}
@@ -0,0 +1,41 @@
+//! `--block-size` and `-H` rounding in the reports of a real image
+
+mod common;
+
+use common::Image;
+
+fn du(image: &Image, args: &[&str]) -> String {
+    let out = image.ok(&[args, &["du", "--summarize", "/D"]].concat());
+    out.split('\t').next().unwrap().to_owned()
+}
+
+#[test]
+fn usage_rounds_up_and_lengths_to_nearest() {
+    let image = Image::new("rounding", "4M");
+    image.ok(&["mkdir", "/D"]);
+    image.write("/D/ONE.BIN", b"x");
+    let cluster = image.boot().cluster_size();
+
+    // The directory and the file take a cluster each
+    assert_eq!(du(&image, &[]), (2 * cluster).to_string());
+    assert_eq!(du(&image, &["--block-size", "1M"]), "1");
+    assert_eq!(du(&image, &["--block-size", "1M", "--apparent-size"]), "0");
+    assert_eq!(
+        du(&image, &["--block-size", "1"]),
+        du(&image, &["--block-size", "1B"])
+    );
+    assert_eq!(du(&image, &["-H", "--apparent-size"]), "1");
+
+    image.write("/D/ONE.BIN", &vec![b'x'; 1536]);
+    assert_eq!(du(&image, &["--block-size", "1K", "--apparent-size"]), "2");
+    assert_eq!(du(&image, &["-H", "--apparent-size"]), "1.5K");
+}
+
+#[test]
+fn zero_and_unknown_block_sizes_are_refused() {
+    let image = Image::new("refused", "4M");
+    for size in ["0", "1X", "1.5"] {
+        let (code, _) = image.fails(&["--block-size", size, "du"]);
+        assert_eq!(code, 1, "{}", size);
+    }
+}
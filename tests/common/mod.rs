@@ -0,0 +1,434 @@
+//! Images for the integration tests, made, changed and inspected by
+//! running the `fatimg` binary like a script would. Where a test needs
+//! damage the commands won't cause, the image bytes are patched directly,
+//! at offsets taken from the boot sector.
+
+// Each test file uses its own part of this
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// A temporary directory with an image in it, removed when dropped
+pub struct Image {
+    dir: PathBuf,
+    pub path: PathBuf,
+}
+
+impl Image {
+    /// A new image of `size`, like `4M`, for the test `name`
+    pub fn new(name: &str, size: &str) -> Self {
+        Self::create(name, &["--size", size])
+    }
+
+    /// A new 4M image for the test `name` holding `entries`, made in order
+    /// from `(path, contents)`. Paths ending in `/` are directories.
+    pub fn with(name: &str, entries: &[(&str, &[u8])]) -> Self {
+        let image = Self::new(name, "4M");
+        for &(path, contents) in entries {
+            if path.ends_with('/') {
+                image.ok(&["mkdir", path]);
+            } else {
+                image.write(path, contents);
+            }
+        }
+        image
+    }
+
+    /// A new image made with the `create` arguments `args`
+    pub fn create(name: &str, args: &[&str]) -> Self {
+        let dir = std::env::temp_dir().join(format!("fatimg-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let image = Self {
+            path: dir.join("test.img"),
+            dir,
+        };
+        image.ok(&[&["create"], args].concat());
+        image
+    }
+
+    /// Runs `fatimg <image> args...`
+    pub fn run(&self, args: &[&str]) -> Output {
+        self.run_with_input(args, &[])
+    }
+
+    /// Runs `fatimg <image> args...` with `input` on stdin
+    pub fn run_with_input(&self, args: &[&str], input: &[u8]) -> Output {
+        self.spawn(args, input, &[], None)
+    }
+
+    /// Runs `fatimg <image> args...` with `input` on stdin in the
+    /// directory of the image, where relative host paths are the ones of
+    /// `host_path`
+    pub fn run_beside(&self, args: &[&str], input: &[u8]) -> Output {
+        self.spawn(args, input, &[], Some(&self.dir))
+    }
+
+    /// Runs `fatimg <image> args...` with the environment variables `env`
+    pub fn run_with_env(&self, args: &[&str], env: &[(&str, &str)]) -> Output {
+        self.spawn(args, &[], env, None)
+    }
+
+    fn spawn(
+        &self, args: &[&str], input: &[u8], env: &[(&str, &str)], dir: Option<&Path>,
+    ) -> Output {
+        use std::io::Write;
+
+        let mut command = Command::new(env!("CARGO_BIN_EXE_fatimg"));
+        if let Some(dir) = dir {
+            command.current_dir(dir);
+        }
+        let mut child = command
+            .arg(&self.path)
+            .args(args)
+            .env_remove("SOURCE_DATE_EPOCH")
+            .envs(env.iter().copied())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    /// Runs the command, which has to succeed, and returns its stdout
+    pub fn ok(&self, args: &[&str]) -> String {
+        let out = self.run(args);
+        assert!(
+            out.status.success(),
+            "fatimg {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&out.stderr)
+        );
+        String::from_utf8(out.stdout).unwrap()
+    }
+
+    /// Runs the command, which has to fail, and returns its exit code
+    /// and stderr
+    pub fn fails(&self, args: &[&str]) -> (i32, String) {
+        let out = self.run(args);
+        assert!(!out.status.success(), "fatimg {} succeeded", args.join(" "));
+        (
+            out.status.code().unwrap(),
+            String::from_utf8(out.stderr).unwrap(),
+        )
+    }
+
+    /// Writes `contents` to the file `inner_path` of the image
+    pub fn write(&self, inner_path: &str, contents: &[u8]) {
+        let out = self.run_with_input(&["write", inner_path, "--allow-empty"], contents);
+        assert!(
+            out.status.success(),
+            "writing {} failed: {}",
+            inner_path,
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+
+    /// Sets the modification time of each `(path, time)`, with the time
+    /// as `touch --mtime` takes it, like `Image::with(..).mtimes(..)`
+    pub fn mtimes(self, mtimes: &[(&str, &str)]) -> Self {
+        for &(path, mtime) in mtimes {
+            self.ok(&["touch", path, "--mtime", mtime]);
+        }
+        self
+    }
+
+    /// Reads the file `inner_path` of the image
+    pub fn read(&self, inner_path: &str) -> Vec<u8> {
+        let out = self.run(&["read", inner_path]);
+        assert!(out.status.success(), "reading {} failed", inner_path);
+        out.stdout
+    }
+
+    /// A path next to the image for host files of the test, which is
+    /// removed with it
+    pub fn host_path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Makes the host directory `name` next to the image with `files`, see
+    /// `host_tree`, and returns its path as an argument
+    pub fn host_tree(&self, name: &str, files: &[(&str, &[u8])]) -> String {
+        let dir = self.host_path(name);
+        fs::create_dir_all(&dir).unwrap();
+        host_tree(&dir, files);
+        dir.to_str().unwrap().to_owned()
+    }
+
+    /// The image file contents
+    pub fn bytes(&self) -> Vec<u8> {
+        fs::read(&self.path).unwrap()
+    }
+
+    /// FNV-1a hash of the image file, for telling whether it changed
+    pub fn hash(&self) -> u64 {
+        hash(&self.bytes())
+    }
+
+    /// Overwrites the image at `offset` with `bytes`
+    pub fn patch(&self, offset: u64, bytes: &[u8]) {
+        let mut image = self.bytes();
+        let offset = offset as usize;
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+        fs::write(&self.path, image).unwrap();
+    }
+
+    /// The layout of the volume, from its boot sector
+    pub fn boot(&self) -> Boot {
+        Boot::parse(&self.bytes()[..512])
+    }
+
+    /// Free space counted from the first FAT
+    pub fn free_bytes(&self) -> u64 {
+        let boot = self.boot();
+        let image = self.bytes();
+        let fat = &image[boot.fat_offset(0) as usize..];
+        let free = (2..boot.clusters() + 2)
+            .filter(|&cluster| boot.fat_entry(fat, cluster) == 0)
+            .count() as u64;
+        free * boot.cluster_size()
+    }
+
+    /// The image offset of the 32-byte directory entry with the short
+    /// name `name`, like `b"README  TXT"`, in the root directory of a
+    /// FAT12/16 volume
+    pub fn root_entry(&self, name: &[u8; 11]) -> u64 {
+        let boot = self.boot();
+        let image = self.bytes();
+        let start = boot.root_dir_offset();
+        (start..start + boot.root_entries * 32)
+            .step_by(32)
+            .find(|&offset| image[offset as usize..][..11] == name[..])
+            .unwrap_or_else(|| panic!("no root entry {}", String::from_utf8_lossy(name)))
+    }
+
+    /// The start cluster of the directory entry at image offset `entry`
+    pub fn first_cluster(&self, entry: u64) -> u32 {
+        let bytes = self.bytes();
+        let raw = &bytes[entry as usize..][..32];
+        let high = u16::from_le_bytes([raw[20], raw[21]]) as u32;
+        (high << 16) | u16::from_le_bytes([raw[26], raw[27]]) as u32
+    }
+
+    /// The clusters of the chain starting at `first`, from the first FAT
+    pub fn chain(&self, first: u32) -> Vec<u32> {
+        let boot = self.boot();
+        let image = self.bytes();
+        let fat = &image[boot.fat_offset(0) as usize..];
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while (2..boot.clusters() + 2).contains(&cluster) {
+            chain.push(cluster);
+            cluster = boot.fat_entry(fat, cluster);
+        }
+        chain
+    }
+
+    /// Image offsets of every 32-byte slot of the directory `dir`, like
+    /// `/` or `/EFI/BOOT`, whose components have short names only
+    pub fn slots(&self, dir: &str) -> Vec<u64> {
+        let boot = self.boot();
+        if dir == "/" && boot.root_cluster == 0 {
+            let start = boot.root_dir_offset();
+            return (start..start + boot.root_entries * 32)
+                .step_by(32)
+                .collect();
+        }
+        let first = match dir {
+            "/" => boot.root_cluster,
+            _ => self.first_cluster(self.entry(dir)),
+        };
+        self.chain(first)
+            .into_iter()
+            .flat_map(|cluster| {
+                let start = boot.cluster_offset(cluster);
+                (start..start + boot.cluster_size()).step_by(32)
+            })
+            .collect()
+    }
+
+    /// The image offset of the directory entry of `path`, like
+    /// `/EFI/BOOT.CFG`, whose components have short names only
+    pub fn entry(&self, path: &str) -> u64 {
+        let (dir, name) = path.rsplit_once('/').unwrap();
+        let dir = if dir.is_empty() { "/" } else { dir };
+        let name = short_name(name);
+        let image = self.bytes();
+        self.slots(dir)
+            .into_iter()
+            .find(|&offset| image[offset as usize..][..11] == name[..])
+            .unwrap_or_else(|| panic!("no entry {}", path))
+    }
+
+    /// Writes `entry` to the first free slot of `dir`, the FAT12/16 root
+    /// directory `/` or a directory in it like `/EFI`, looking only in its
+    /// first cluster. Returns the image offset of the slot.
+    pub fn add_entry(&self, dir: &str, entry: [u8; 32]) -> u64 {
+        let boot = self.boot();
+        let (start, slots) = match dir.strip_prefix('/').filter(|name| !name.is_empty()) {
+            None => (boot.root_dir_offset(), boot.root_entries),
+            Some(name) => {
+                let cluster = self.first_cluster(self.root_entry(&short_name(name)));
+                (boot.cluster_offset(cluster), boot.cluster_size() / 32)
+            },
+        };
+        let bytes = self.bytes();
+        let free = (start..start + slots * 32)
+            .step_by(32)
+            .find(|&offset| bytes[offset as usize] == 0)
+            .expect("no free directory slot");
+        self.patch(free, &entry);
+        free
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// The BPB fields the tests need
+#[derive(Debug, Clone, Copy)]
+pub struct Boot {
+    pub bytes_per_sector: u64,
+    pub sectors_per_cluster: u64,
+    pub reserved_sectors: u64,
+    pub fats: u64,
+    pub root_entries: u64,
+    pub sectors_per_fat: u64,
+    pub root_cluster: u32,
+    pub total_sectors: u64,
+}
+
+impl Boot {
+    pub fn parse(sector: &[u8]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([sector[i], sector[i + 1]]) as u64;
+        let u32_at = |i: usize| u32::from_le_bytes(sector[i..i + 4].try_into().unwrap());
+        let fat16_size = u16_at(22);
+        Self {
+            bytes_per_sector: u16_at(11),
+            sectors_per_cluster: sector[13] as u64,
+            reserved_sectors: u16_at(14),
+            fats: sector[16] as u64,
+            root_entries: u16_at(17),
+            sectors_per_fat: if fat16_size != 0 {
+                fat16_size
+            } else {
+                u32_at(36) as u64
+            },
+            root_cluster: if fat16_size != 0 { 0 } else { u32_at(44) },
+            total_sectors: if u16_at(19) != 0 {
+                u16_at(19)
+            } else {
+                u32_at(32) as u64
+            },
+        }
+    }
+
+    /// Image offset of FAT copy `copy`, numbered from 0
+    pub fn fat_offset(&self, copy: u64) -> u64 {
+        (self.reserved_sectors + copy * self.sectors_per_fat) * self.bytes_per_sector
+    }
+
+    /// Image offset of the FAT12/16 root directory area
+    pub fn root_dir_offset(&self) -> u64 {
+        self.fat_offset(self.fats)
+    }
+
+    /// Number of data clusters, which also tells the FAT type
+    pub fn clusters(&self) -> u32 {
+        let data = self.cluster_offset(2) / self.bytes_per_sector;
+        ((self.total_sectors - data) / self.sectors_per_cluster) as u32
+    }
+
+    /// FAT entry `cluster` of the FAT copy at the start of `fat`
+    pub fn fat_entry(&self, fat: &[u8], cluster: u32) -> u32 {
+        let n = cluster as usize;
+        if self.clusters() < 4085 {
+            let pair = u16::from_le_bytes([fat[n * 3 / 2], fat[n * 3 / 2 + 1]]);
+            (if n.is_multiple_of(2) {
+                pair & 0xfff
+            } else {
+                pair >> 4
+            }) as u32
+        } else if self.clusters() < 65525 {
+            u16::from_le_bytes([fat[n * 2], fat[n * 2 + 1]]) as u32
+        } else {
+            u32::from_le_bytes(fat[n * 4..n * 4 + 4].try_into().unwrap()) & 0x0fff_ffff
+        }
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.sectors_per_cluster * self.bytes_per_sector
+    }
+
+    /// Image offset of data cluster `cluster`, numbered from 2
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        let root_dir =
+            (self.root_entries * 32).div_ceil(self.bytes_per_sector) * self.bytes_per_sector;
+        self.root_dir_offset() + root_dir + (cluster as u64 - 2) * self.cluster_size()
+    }
+}
+
+/// The 8.3 directory entry form of `name`, like `b"README  TXT"` for
+/// `README.TXT`
+pub fn short_name(name: &str) -> [u8; 11] {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    short
+}
+
+/// 2024-05-01 12:00:00 as a FAT date and time
+pub const DATE: u16 = (44 << 9) | (5 << 5) | 1;
+pub const TIME: u16 = 12 << 11;
+
+/// A raw directory entry with the short name `name`, like `b"README  TXT"`,
+/// starting at `cluster` with `size` bytes, created, modified and
+/// accessed at `DATE` and `TIME`
+pub fn dir_entry(name: &[u8; 11], attributes: u8, cluster: u32, size: u32) -> [u8; 32] {
+    let mut entry = [0u8; 32];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attributes;
+    for at in [14, 22] {
+        entry[at..at + 2].copy_from_slice(&TIME.to_le_bytes());
+    }
+    for at in [16, 18, 24] {
+        entry[at..at + 2].copy_from_slice(&DATE.to_le_bytes());
+    }
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+/// The string member `key` of the one-line JSON object `line`, like the
+/// `name` of an `ls --jsonl` line. Enough for the strings fatimg writes
+/// without escapes.
+pub fn json_str<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let start = line.find(&format!("\"{}\":\"", key))? + key.len() + 4;
+    let len = line[start..].find('"')?;
+    Some(&line[start..start + len])
+}
+
+/// FNV-1a, enough to tell whether two images differ
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Makes the host files `files`, as `(relative path, contents)`, below `dir`
+pub fn host_tree(dir: &Path, files: &[(&str, &[u8])]) {
+    for (path, contents) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+//! `cp` inside the image: what `--preserve` keeps of the source entries,
+//! and copies of a directory into itself
+
+mod common;
+
+use common::Image;
+
+/// Bytes of a raw entry `--preserve` copies: attributes and timestamps
+const META: [std::ops::Range<usize>; 3] = [11..12, 13..20, 22..26];
+
+/// A tree whose file times are in 2024, so a copy made now can't have
+/// them by chance
+fn tree(name: &str) -> Image {
+    let image = Image::new(name, "4M");
+    image.ok(&["mkdir", "/A", "--attributes", "hidden"]);
+    image.ok(&["mkdir", "/A/B", "--attributes", "system"]);
+    let out = image.run_with_input(
+        &["write", "/A/B/F.TXT", "--attributes", "read_only,archive"],
+        b"f",
+    );
+    assert!(out.status.success());
+    image.write("/A/G.TXT", b"g");
+    for path in ["/A/B/F.TXT", "/A/G.TXT"] {
+        let time = "2024-05-01T12:00:00";
+        image.ok(&[
+            "touch", path, "--mtime", time, "--ctime", time, "--atime", time,
+        ]);
+    }
+    image
+}
+
+/// The `META` bytes of the entry of `path`
+fn meta(image: &Image, path: &str) -> Vec<u8> {
+    let bytes = image.bytes();
+    let entry = &bytes[image.entry(path) as usize..][..32];
+    META.iter()
+        .flat_map(|r| entry[r.clone()].to_vec())
+        .collect()
+}
+
+/// Names `ls --jsonl` shows in the root directory
+fn names(image: &Image) -> Vec<String> {
+    image
+        .ok(&["ls", "--jsonl", "/"])
+        .lines()
+        .filter_map(|line| common::json_str(line, "name"))
+        .map(str::to_owned)
+        .collect()
+}
+
+#[test]
+fn preserve_keeps_the_copys_own_name_case() {
+    let image = Image::new("case", "4M");
+    let out = image.run_with_input(
+        &["write", "/readme.txt", "--attributes", "hidden"],
+        b"readme",
+    );
+    assert!(out.status.success());
+    image.ok(&["cp", "--preserve", "/readme.txt", "/COPY.TXT"]);
+
+    let bytes = image.bytes();
+    let src = &bytes[image.root_entry(b"README  TXT") as usize..][..32];
+    let copy = &bytes[image.root_entry(b"COPY    TXT") as usize..][..32];
+    // The lowercase flags of the NT byte stay with the source
+    assert_ne!(src[12], 0);
+    assert_eq!(copy[12], 0);
+    assert_eq!(copy[11], src[11]);
+    assert_eq!(copy[13..20], src[13..20]);
+    assert_eq!(copy[22..26], src[22..26]);
+
+    let names = names(&image);
+    assert!(names.iter().any(|n| n == "readme.txt"), "{:?}", names);
+    assert!(names.iter().any(|n| n == "COPY.TXT"), "{:?}", names);
+    assert_eq!(image.read("/COPY.TXT"), b"readme");
+}
+
+#[test]
+fn recursive_preserve_keeps_the_tree_metadata() {
+    let image = tree("tree");
+    image.ok(&["cp", "-r", "--preserve", "/A", "/C"]);
+    image.ok(&["cp", "-r", "/A", "/D"]);
+    for path in ["/B", "/B/F.TXT", "/G.TXT"] {
+        let src = meta(&image, &format!("/A{}", path));
+        assert_eq!(meta(&image, &format!("/C{}", path)), src, "{}", path);
+    }
+    assert_eq!(meta(&image, "/C"), meta(&image, "/A"));
+    // Without it the copies are made now, with archive only
+    for path in ["/B/F.TXT", "/G.TXT"] {
+        let src = meta(&image, &format!("/A{}", path));
+        assert_ne!(meta(&image, &format!("/D{}", path)), src, "{}", path);
+    }
+    let bytes = image.bytes();
+    assert_eq!(bytes[image.entry("/D/B/F.TXT") as usize + 11], 0x20);
+    assert_eq!(image.read("/C/B/F.TXT"), b"f");
+    assert_eq!(image.read("/D/G.TXT"), b"g");
+    assert_eq!(image.ok(&["check"]), "no problems found\n");
+}
+
+#[test]
+fn into_itself_is_refused() {
+    let image = tree("itself");
+    let before = image.hash();
+    for (src, dst) in [("/A", "/A/B"), ("/A", "/A/NEW"), ("/A", "/A/B/C/D")] {
+        let (code, stderr) = image.fails(&["cp", "-r", src, dst]);
+        assert_eq!(code, 1, "{}", dst);
+        assert!(
+            stderr.contains(&format!(
+                "Can't copy {} into itself, {} is inside it",
+                src, dst
+            )),
+            "{}",
+            stderr
+        );
+    }
+    let (_, stderr) = image.fails(&["cp", "-r", "/A", "/A"]);
+    assert!(
+        stderr.contains("/A and /A are the same entry"),
+        "{}",
+        stderr
+    );
+    assert_eq!(image.hash(), before);
+
+    // Next to it is fine
+    image.ok(&["cp", "-r", "/A/B", "/A/B2"]);
+    assert_eq!(image.read("/A/B2/F.TXT"), b"f");
+}
@@ -0,0 +1,104 @@
+//! Image and host paths beginning with a dash for `read`, `write`, `rm`
+//! and `attrib`, with `--` and with `=` for option values
+
+mod common;
+
+use std::fs;
+
+use common::Image;
+
+#[test]
+fn image_paths() {
+    let image = Image::new("image", "4M");
+    // Beginning with `/`, so neither needs `--`
+    let out = image.run_with_input(&["write", "--", "/-F.TXT"], b"f");
+    assert!(out.status.success());
+    let out = image.run_with_input(&["write", "/-G.TXT"], b"g");
+    assert!(out.status.success());
+    assert_eq!(image.ok(&["read", "--", "/-F.TXT"]), "f");
+    assert_eq!(image.ok(&["read", "/-G.TXT"]), "g");
+
+    assert_eq!(
+        image.ok(&["attrib", "--", "/-F.TXT", "+h"]),
+        "-h-a-- /-F.TXT\n"
+    );
+    // A change beginning with `-` after `--`
+    assert_eq!(
+        image.ok(&["attrib", "/-F.TXT", "--", "-h"]),
+        "---a-- /-F.TXT\n"
+    );
+
+    image.ok(&["rm", "--", "/-F.TXT"]);
+    image.ok(&["rm", "/-G.TXT"]);
+    for path in ["/-F.TXT", "/-G.TXT"] {
+        let (code, _) = image.fails(&["read", path]);
+        assert_eq!(code, 2, "{}", path);
+    }
+}
+
+#[test]
+fn relative_image_paths() {
+    let image = Image::with("relative", &[("/-F.TXT", b"f")]);
+    let before = image.hash();
+    // After `--` the path gets as far as the path check
+    for args in [
+        &["read", "--", "-F.TXT"][..],
+        &["write", "--", "-F.TXT"],
+        &["rm", "--", "-F.TXT"],
+        &["attrib", "--", "-F.TXT"],
+    ] {
+        let (code, stderr) = image.fails(args);
+        assert_eq!(code, 1, "{:?}", args);
+        assert!(
+            stderr.contains("Image paths must start with `/`, got \"-F.TXT\""),
+            "{:?}: {}",
+            args,
+            stderr
+        );
+    }
+    // Without it the path is taken for flags
+    for args in [&["read", "-F.TXT"][..], &["rm", "-F.TXT"]] {
+        let (code, stderr) = image.fails(args);
+        assert_eq!(code, 1, "{:?}", args);
+        assert!(!stderr.contains("Image paths"), "{:?}: {}", args, stderr);
+    }
+    assert_eq!(image.hash(), before);
+}
+
+#[test]
+fn host_paths() {
+    let image = Image::new("host", "4M");
+    fs::write(image.host_path("-in.txt"), b"in").unwrap();
+    fs::write(image.host_path("-"), b"dash").unwrap();
+
+    let out = image.run_beside(&["write", "/IN.TXT", "--input=-in.txt"], &[]);
+    assert!(out.status.success());
+    assert_eq!(image.read("/IN.TXT"), b"in");
+    // `-` alone is stdin, a file called `-` is `./-`
+    let out = image.run_beside(&["write", "/STDIN.TXT", "-i", "-"], b"stdin");
+    assert!(out.status.success());
+    assert_eq!(image.read("/STDIN.TXT"), b"stdin");
+    let out = image.run_beside(&["write", "/DASH.TXT", "-i", "./-"], b"stdin");
+    assert!(out.status.success());
+    assert_eq!(image.read("/DASH.TXT"), b"dash");
+
+    let out = image.run_beside(&["read", "/IN.TXT", "--output-file=-out.txt"], &[]);
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+    assert_eq!(fs::read(image.host_path("-out.txt")).unwrap(), b"in");
+    let out = image.run_beside(&["read", "/IN.TXT", "-o", "-"], &[]);
+    assert_eq!(out.stdout, b"in");
+    let out = image.run_beside(&["read", "/IN.TXT", "-o", "./-", "--force"], &[]);
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+    assert_eq!(fs::read(image.host_path("-")).unwrap(), b"in");
+
+    // Without `=` the value is taken for a flag and nothing is written
+    let before = image.hash();
+    let out = image.run_beside(&["write", "/NEW.TXT", "--input", "-in.txt"], &[]);
+    assert_eq!(out.status.code(), Some(1));
+    let out = image.run_beside(&["read", "/IN.TXT", "-o", "-new.txt"], &[]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(!image.host_path("-new.txt").exists());
+    assert_eq!(image.hash(), before);
+}
@@ -0,0 +1,140 @@
+//! The summary mutating commands print on stderr and record in
+//! `--report`, against free space counted from the FAT and entry counts
+//! known from the fixture
+
+mod common;
+
+use std::fs;
+
+use common::Image;
+
+/// The `free: ...` line of a command's stderr
+fn summary(stderr: &[u8]) -> Option<String> {
+    String::from_utf8_lossy(stderr)
+        .lines()
+        .find(|l| l.starts_with("free: "))
+        .map(str::to_owned)
+}
+
+/// Runs a mutating command and returns its summary line
+fn changed(image: &Image, args: &[&str]) -> String {
+    let out = image.run(args);
+    assert!(
+        out.status.success(),
+        "fatimg {} failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&out.stderr)
+    );
+    summary(&out.stderr).unwrap_or_else(|| panic!("no summary from {}", args.join(" ")))
+}
+
+/// `/A/ONE.TXT`, `/A/EMPTY.TXT`, `/A/B/TWO.BIN` and `/TOP.TXT`; 4 files in
+/// 2 directories
+const FILES: &[(&str, usize)] = &[
+    ("A/ONE.TXT", 100),
+    ("A/EMPTY.TXT", 0),
+    ("A/B/TWO.BIN", 5000),
+    ("TOP.TXT", 1),
+];
+
+#[test]
+fn write_tree_and_rm() {
+    let image = Image::new("tree", "4M");
+    let cluster = image.boot().cluster_size();
+    let contents: Vec<(&str, Vec<u8>)> =
+        FILES.iter().map(|&(p, len)| (p, vec![b'x'; len])).collect();
+    let files: Vec<(&str, &[u8])> = contents.iter().map(|(p, c)| (*p, &c[..])).collect();
+    let tree = image.host_tree("tree", &files);
+    // A cluster per directory and as many as each file needs
+    let clusters = 2 + FILES
+        .iter()
+        .map(|&(_, len)| (len as u64).div_ceil(cluster))
+        .sum::<u64>();
+
+    let before = image.free_bytes();
+    let report = image.host_path("report.json");
+    let line = changed(
+        &image,
+        &["--report", report.to_str().unwrap(), "write-tree", &tree],
+    );
+    let after = image.free_bytes();
+    assert_eq!(after, before - clusters * cluster);
+    assert_eq!(
+        line,
+        format!(
+            "free: {} -> {} (-{}), files: +4, dirs: +2",
+            before,
+            after,
+            clusters * cluster
+        )
+    );
+    let report = fs::read_to_string(report).unwrap();
+    let delta = format!(
+        "\"delta\":{{\"free_before\":{},\"free_after\":{},\"files\":{{\"created\":4,\"removed\":0}},\
+         \"dirs\":{{\"created\":2,\"removed\":0}}}}",
+        before, after
+    );
+    assert!(report.contains(&delta), "{}", report);
+
+    // /A holds 3 of the files and both directories
+    let line = changed(&image, &["rm", "-r", "/A"]);
+    let top = image.free_bytes();
+    assert_eq!(top, before - cluster);
+    assert_eq!(
+        line,
+        format!(
+            "free: {} -> {} (+{}), files: -3, dirs: -2",
+            after,
+            top,
+            top - after
+        )
+    );
+}
+
+#[test]
+fn replacing_and_block_sizes() {
+    let image = Image::new("replace", "4M");
+    let cluster = image.boot().cluster_size();
+    let before = image.free_bytes();
+    let line = changed(&image, &["mkdir", "/D"]);
+    assert_eq!(
+        line,
+        format!(
+            "free: {} -> {} (-{}), files: +0, dirs: +1",
+            before,
+            before - cluster,
+            cluster
+        )
+    );
+
+    image.write("/D/F.TXT", b"first");
+    let free = image.free_bytes();
+    // Replacing a file of the same size changes nothing
+    let out = image.run_with_input(&["write", "/D/F.TXT"], b"again");
+    assert_eq!(
+        summary(&out.stderr).unwrap(),
+        format!("free: {} -> {} (+0), files: +0, dirs: +0", free, free)
+    );
+
+    let out = image.run_with_input(&["--block-size", "512", "write", "/G.TXT"], b"g");
+    assert_eq!(
+        summary(&out.stderr).unwrap(),
+        format!(
+            "free: {} -> {} (-{}), files: +1, dirs: +0",
+            free / 512,
+            (free - cluster) / 512,
+            cluster / 512
+        )
+    );
+}
+
+#[test]
+fn quiet_and_read_only_commands_print_none() {
+    let image = Image::new("quiet", "4M");
+    let out = image.run_with_input(&["--quiet", "write", "/F.TXT"], b"f");
+    assert!(out.status.success());
+    assert_eq!(summary(&out.stderr), None);
+    for args in [&["ls"][..], &["read", "/F.TXT"], &["check"]] {
+        assert_eq!(summary(&image.run(args).stderr), None, "{:?}", args);
+    }
+}
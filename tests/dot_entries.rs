@@ -0,0 +1,70 @@
+//! The `.` and `..` entries of directories `mkdir` and `write-tree` make,
+//! read from the raw directory slots
+
+mod common;
+
+use std::fs;
+
+use common::Image;
+
+/// Name, attributes and start cluster of the first two slots of `dir`
+fn dots(image: &Image, dir: &str) -> Vec<(String, u8, u32)> {
+    let bytes = image.bytes();
+    image.slots(dir)[..2]
+        .iter()
+        .map(|&offset| {
+            let raw = &bytes[offset as usize..][..32];
+            (
+                String::from_utf8_lossy(&raw[..11]).into_owned(),
+                raw[11],
+                image.first_cluster(offset),
+            )
+        })
+        .collect()
+}
+
+/// The start cluster of the directory `dir`, 0 for `/` as `..` stores it
+fn cluster(image: &Image, dir: &str) -> u32 {
+    match dir {
+        "/" => 0,
+        _ => image.first_cluster(image.entry(dir)),
+    }
+}
+
+#[test]
+fn mkdir_and_write_tree() {
+    for (name, args) in [
+        ("fat16", &["--size", "16M"][..]),
+        ("fat32", &["--size", "64M", "--fat-type", "32"]),
+    ] {
+        let image = Image::create(name, args);
+        image.ok(&["mkdir", "/A"]);
+        image.ok(&["mkdir", "/A/B", "--attributes", "hidden"]);
+        let tree = image.host_path("tree");
+        fs::create_dir_all(tree.join("T/U")).unwrap();
+        fs::write(tree.join("T/U/F.TXT"), b"f").unwrap();
+        image.ok(&["write-tree", tree.to_str().unwrap()]);
+        image.ok(&["write-tree", "-s", "/A/B", tree.to_str().unwrap()]);
+
+        for (dir, parent) in [
+            ("/A", "/"),
+            ("/A/B", "/A"),
+            ("/T", "/"),
+            ("/T/U", "/T"),
+            ("/A/B/T", "/A/B"),
+            ("/A/B/T/U", "/A/B/T"),
+        ] {
+            assert_eq!(
+                dots(&image, dir),
+                [
+                    (".          ".to_owned(), 0x10, cluster(&image, dir)),
+                    ("..         ".to_owned(), 0x10, cluster(&image, parent)),
+                ],
+                "{}: {}",
+                name,
+                dir
+            );
+        }
+        assert_eq!(image.ok(&["check"]), "no problems found\n", "{}", name);
+    }
+}
@@ -0,0 +1,88 @@
+//! `du` totals against the clusters `info` counts as used, and apparent
+//! sizes against allocated ones
+
+mod common;
+
+use common::{json_str, Image};
+
+const CLUSTER: u64 = 512;
+
+/// `/MANY` with enough small files for its entries to take several
+/// clusters, and nested directories with a few files each
+fn files() -> Vec<(String, Vec<u8>)> {
+    let mut files = Vec::new();
+    for i in 0..100 {
+        files.push((format!("MANY/F{:03}.TXT", i), vec![b'm'; i * 13]));
+    }
+    for depth in 1..6 {
+        let dir = vec!["DEEP"; depth].join("/");
+        files.push((format!("{}/FILE.BIN", dir), vec![b'd'; depth * 1000]));
+        files.push((format!("{}/a long name.txt", dir), vec![b'l'; 1]));
+    }
+    files
+}
+
+/// The first number `du -s` prints for `path`
+fn du(image: &Image, args: &[&str], path: &str) -> u64 {
+    let out = image.ok(&[&["du", "-s"], args, &[path]].concat());
+    out.split('\t').next().unwrap().parse().unwrap()
+}
+
+#[test]
+fn allocated_total_is_the_used_clusters() {
+    for (name, fat_type, size) in [("fat16", "16", "4M"), ("fat32", "32", "40M")] {
+        let image = Image::create(
+            name,
+            &[
+                "--size",
+                size,
+                "--fat-type",
+                fat_type,
+                "--cluster-size",
+                "512",
+            ],
+        );
+        let files = files();
+        let host: Vec<(&str, &[u8])> = files.iter().map(|(p, c)| (&p[..], &c[..])).collect();
+        let tree = image.host_tree("tree", &host);
+        image.ok(&["write-tree", &tree]);
+
+        let info = image.ok(&["--output", "json", "info"]);
+        let count = |key| -> u64 { json_str(&info, key).unwrap().parse().unwrap() };
+        let used = count("cluster_count") - count("clusters_free");
+        let allocated = du(&image, &[], "/");
+        assert_eq!(allocated % CLUSTER, 0, "{}", name);
+        assert!(
+            (allocated / CLUSTER).abs_diff(used) <= 1,
+            "{}: du counts {} clusters, info {}",
+            name,
+            allocated / CLUSTER,
+            used
+        );
+
+        let apparent = du(&image, &["--apparent-size"], "/");
+        let sizes: u64 = files.iter().map(|(_, c)| c.len() as u64).sum();
+        assert_eq!(apparent, sizes, "{}", name);
+    }
+}
+
+#[test]
+fn apparent_and_allocated_within_a_cluster() {
+    let image = Image::create(
+        "files",
+        &["--size", "4M", "--fat-type", "16", "--cluster-size", "512"],
+    );
+    for len in [1, 511, 512, 513, 5000] {
+        let path = format!("/F{}.BIN", len);
+        image.write(&path, &vec![b'x'; len]);
+        let apparent = du(&image, &["--apparent-size"], &path);
+        let allocated = du(&image, &[], &path);
+        assert_eq!(apparent, len as u64);
+        assert!(allocated >= apparent, "{}", path);
+        assert!(allocated - apparent < CLUSTER, "{}", path);
+    }
+    // An empty file has no clusters
+    image.write("/EMPTY.BIN", b"");
+    assert_eq!(du(&image, &[], "/EMPTY.BIN"), 0);
+    assert_eq!(du(&image, &["--apparent-size"], "/EMPTY.BIN"), 0);
+}
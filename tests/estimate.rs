@@ -0,0 +1,99 @@
+//! `estimate` checked against images built with the geometry it was
+//! given and the `--size` it recommends
+
+mod common;
+
+use std::fs;
+
+use common::{json_str, Image};
+
+/// Clusters the tree may take more or less than estimated
+const TOLERANCE: u64 = 1;
+
+/// The leading number of the `estimate --output json` member `key`, like
+/// `4096` of `"4096 (with 10% margin)"`
+fn number(estimate: &str, key: &str) -> u64 {
+    let value = json_str(estimate, key).unwrap_or_else(|| panic!("no {}: {}", key, estimate));
+    value.split(' ').next().unwrap().parse().unwrap()
+}
+
+/// A tree with long names, nested directories and a sparse `BIG.BIN` of
+/// `big` bytes, so the data outgrows the smallest volume of each type
+fn tree(image: &Image, big: u64) -> String {
+    let mut files: Vec<(String, Vec<u8>)> = vec![
+        ("A/ONE.TXT".to_owned(), vec![b'1'; 100]),
+        ("A/a long file name.txt".to_owned(), vec![b'l'; 5000]),
+        ("A/EMPTY.TXT".to_owned(), Vec::new()),
+    ];
+    for i in 0..40 {
+        files.push((format!("B/C/file number {}.dat", i), vec![b'n'; i * 700]));
+    }
+    let files: Vec<(&str, &[u8])> = files.iter().map(|(p, c)| (&p[..], &c[..])).collect();
+    let tree = image.host_tree("tree", &files);
+    fs::File::create(image.host_path("tree/BIG.BIN"))
+        .unwrap()
+        .set_len(big)
+        .unwrap();
+    tree
+}
+
+#[test]
+fn built_images_fit() {
+    for (fat_type, cluster_size, big) in [
+        ("12", 4096, 1 << 20),
+        ("16", 512, 3 << 20),
+        ("32", 512, 36 << 20),
+    ] {
+        let host = Image::new(&format!("host{}", fat_type), "4M");
+        let tree = tree(&host, big);
+        let cs = cluster_size.to_string();
+        let estimate = host.ok(&[
+            "--output",
+            "json",
+            "estimate",
+            &tree,
+            "--fat-type",
+            fat_type,
+            "--cluster-size",
+            &cs,
+        ]);
+        let recommended = json_str(&estimate, "recommended_size").unwrap();
+        assert!(recommended.ends_with("(with 10% margin)"), "{}", estimate);
+        let needed = number(&estimate, "data_clusters") + number(&estimate, "directory_clusters");
+
+        let image = Image::create(
+            &format!("fat{}", fat_type),
+            &[
+                "--size",
+                &number(&estimate, "recommended_size").to_string(),
+                "--fat-type",
+                fat_type,
+                "--cluster-size",
+                &cs,
+            ],
+        );
+        let before = image.free_bytes();
+        image.ok(&["write-tree", &tree]);
+        let after = image.free_bytes();
+        // The FAT32 root directory cluster was there before
+        let root = if fat_type == "32" { 1 } else { 0 };
+        let used = (before - after) / cluster_size + root;
+        assert!(
+            used.abs_diff(needed) <= TOLERANCE,
+            "FAT{}: {} clusters used, {} estimated",
+            fat_type,
+            used,
+            needed
+        );
+        // And what is left is about the margin
+        let margin = needed.div_ceil(10);
+        assert!(
+            after / cluster_size <= margin + TOLERANCE,
+            "FAT{}: {} clusters left, {} as margin",
+            fat_type,
+            after / cluster_size,
+            margin
+        );
+        assert_eq!(image.ok(&["check"]), "no problems found\n");
+    }
+}
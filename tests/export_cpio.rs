@@ -0,0 +1,193 @@
+//! `export-cpio` archives taken apart record by record as newc, and
+//! listed with the system `cpio -it` where there is one
+
+mod common;
+
+use std::collections::HashSet;
+use std::fs;
+use std::process::{Command, Stdio};
+
+use common::Image;
+
+const FILES: [(&str, &[u8]); 6] = [
+    ("/D/", b""),
+    ("/D/E/", b""),
+    ("/D/E/F.TXT", b"four bytes"),
+    ("/D/ODD.BIN", &[7; 1001]),
+    ("/EMPTY.TXT", b""),
+    ("/TOP.TXT", b"top"),
+];
+
+/// 2024-05-01T12:00:00Z
+const MTIME: u32 = 1714564800;
+
+/// A newc record, with the fields FAT has nothing for
+#[derive(Debug)]
+struct Record {
+    name: String,
+    ino: u32,
+    mode: u32,
+    nlink: u32,
+    mtime: u32,
+    data: Vec<u8>,
+    /// uid, gid, the device numbers and the checksum
+    zeros: [u32; 7],
+}
+
+/// The records of `archive` up to and without the trailer, checking the
+/// padding and that nothing follows it
+fn parse(archive: &[u8]) -> Vec<Record> {
+    let mut records = Vec::new();
+    let mut at = 0;
+    loop {
+        let header = &archive[at..at + 110];
+        assert_eq!(&header[..6], b"070701", "magic at {}", at);
+        let field = |i: usize| {
+            let hex = std::str::from_utf8(&header[6 + i * 8..][..8]).unwrap();
+            u32::from_str_radix(hex, 16).unwrap()
+        };
+        let (size, namesize) = (field(6) as usize, field(11) as usize);
+        let name = &archive[at + 110..at + 110 + namesize];
+        assert_eq!(name.last(), Some(&0), "name at {}", at);
+        let name = String::from_utf8(name[..namesize - 1].to_vec()).unwrap();
+        let data_at = (at + 110 + namesize).next_multiple_of(4);
+        assert!(archive[at + 110 + namesize..data_at]
+            .iter()
+            .all(|&b| b == 0));
+        let end = (data_at + size).next_multiple_of(4);
+        assert!(archive[data_at + size..end].iter().all(|&b| b == 0));
+        let record = Record {
+            name,
+            ino: field(0),
+            mode: field(1),
+            nlink: field(4),
+            mtime: field(5),
+            data: archive[data_at..data_at + size].to_vec(),
+            zeros: [2, 3, 7, 8, 9, 10, 12].map(field),
+        };
+        at = end;
+        if record.name == "TRAILER!!!" {
+            assert_eq!(at, archive.len());
+            assert!(record.data.is_empty());
+            return records;
+        }
+        records.push(record);
+    }
+}
+
+/// Runs `export-cpio` with `args`, returning the archive it prints
+fn export(image: &Image, args: &[&str]) -> Vec<u8> {
+    let out = image.run(&[&["--tz", "Z", "export-cpio"], args].concat());
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    out.stdout
+}
+
+#[test]
+fn records() {
+    let image = Image::with("records", &FILES);
+    for (path, _) in FILES.iter().filter(|(p, _)| !p.ends_with('/')) {
+        image.ok(&[
+            "--tz",
+            "Z",
+            "touch",
+            path,
+            "--mtime",
+            "2024-05-01T12:00:00Z",
+        ]);
+    }
+    image.ok(&["attrib", "/TOP.TXT", "+r"]);
+    let archive = export(&image, &[]);
+    assert_eq!(archive.len() % 4, 0);
+    let records = parse(&archive);
+
+    let names: Vec<&str> = records.iter().map(|r| &r.name[..]).collect();
+    let mut sorted = names.clone();
+    sorted.sort_unstable();
+    assert_eq!(
+        sorted,
+        ["D", "D/E", "D/E/F.TXT", "D/ODD.BIN", "EMPTY.TXT", "TOP.TXT"]
+    );
+    // Directories come before what is in them
+    for (i, name) in names.iter().enumerate() {
+        if let Some((parent, _)) = name.rsplit_once('/') {
+            assert!(names[..i].contains(&parent), "{:?}", names);
+        }
+    }
+    let inodes: HashSet<u32> = records.iter().map(|r| r.ino).collect();
+    assert_eq!(inodes.len(), records.len());
+    assert!(!inodes.contains(&0));
+
+    for record in &records {
+        assert_eq!(record.zeros, [0; 7], "{}", record.name);
+        let path = format!("/{}", record.name);
+        match FILES.iter().find(|(p, _)| p.trim_end_matches('/') == path) {
+            Some((p, _)) if p.ends_with('/') => {
+                assert_eq!(record.mode, 0o040755, "{}", path);
+                assert_eq!(record.nlink, 2, "{}", path);
+                assert!(record.data.is_empty());
+            },
+            Some((_, contents)) => {
+                let mode = if path == "/TOP.TXT" {
+                    0o100444
+                } else {
+                    0o100644
+                };
+                assert_eq!(record.mode, mode, "{}", path);
+                assert_eq!(record.nlink, 1, "{}", path);
+                assert_eq!(record.mtime, MTIME, "{}", path);
+                assert_eq!(&record.data[..], *contents, "{}", path);
+            },
+            None => panic!("unexpected {}", path),
+        }
+    }
+
+    if let Some(listed) = system_cpio(&image, &archive) {
+        let mut listed: Vec<&str> = listed.lines().collect();
+        listed.sort_unstable();
+        assert_eq!(listed, sorted);
+    }
+}
+
+#[test]
+fn subtree_and_excludes() {
+    let image = Image::with("subtree", &FILES);
+    let names = |args: &[&str]| -> Vec<String> {
+        let mut names: Vec<String> = parse(&export(&image, args))
+            .into_iter()
+            .map(|r| r.name)
+            .collect();
+        names.sort_unstable();
+        names
+    };
+    assert_eq!(names(&["-s", "/D"]), ["E", "E/F.TXT", "ODD.BIN"]);
+    assert_eq!(
+        names(&["--exclude", "D/E", "--exclude", "*.TXT"]),
+        ["D", "D/ODD.BIN"]
+    );
+    // An empty subtree is just the trailer
+    image.ok(&["mkdir", "/NONE"]);
+    assert_eq!(export(&image, &["-s", "/NONE"]).len(), 124);
+}
+
+/// The names `cpio -it` lists, if there is a system `cpio`
+fn system_cpio(image: &Image, archive: &[u8]) -> Option<String> {
+    let path = image.host_path("archive.cpio");
+    fs::write(&path, archive).unwrap();
+    let out = Command::new("cpio")
+        .args(["-it", "--quiet"])
+        .stdin(fs::File::open(&path).unwrap())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .ok()?;
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    Some(String::from_utf8(out.stdout).unwrap())
+}
@@ -0,0 +1,109 @@
+//! `find --since` and `--until` at the 2 second steps FAT stores
+//! modification times in
+
+mod common;
+
+use common::Image;
+
+const FILES: [(&str, &[u8]); 4] = [
+    ("/C.TXT", b"x"),
+    ("/A.TXT", b"x"),
+    ("/B.TXT", b"x"),
+    ("/D.TXT", b"x"),
+];
+
+/// The odd times are stored as the even second before
+const MTIMES: [(&str, &str); 4] = [
+    ("/C.TXT", "2024-05-01T11:59:58"),
+    ("/A.TXT", "2024-05-01T12:00:00"),
+    ("/B.TXT", "2024-05-01T12:00:01"),
+    ("/D.TXT", "2024-05-01T12:00:03"),
+];
+
+fn found(image: &Image, args: &[&str]) -> Vec<String> {
+    let out = image.ok(&[&["--tz", "Z", "find", "--type", "f"], args].concat());
+    let mut found: Vec<String> = out
+        .lines()
+        .map(|l| l.trim_start_matches('/').to_owned())
+        .collect();
+    found.sort_unstable();
+    found
+}
+
+#[test]
+fn boundaries() {
+    let image = Image::with("boundaries", &FILES).mtimes(&MTIMES);
+    assert_eq!(
+        found(&image, &["--since", "2024-05-01T12:00:00Z"]),
+        ["A.TXT", "B.TXT", "D.TXT"]
+    );
+    assert_eq!(
+        found(&image, &["--until", "2024-05-01T12:00:00Z"]),
+        ["C.TXT"]
+    );
+    assert_eq!(
+        found(
+            &image,
+            &[
+                "--since",
+                "2024-05-01T12:00:00Z",
+                "--until",
+                "2024-05-01T12:00:02Z"
+            ]
+        ),
+        ["A.TXT", "B.TXT"]
+    );
+    assert_eq!(
+        found(&image, &["--since", "2024-05-01T12:00:02Z"]),
+        ["D.TXT"]
+    );
+}
+
+#[test]
+fn odd_seconds_select_the_step_they_fall_in() {
+    let image = Image::with("odd", &FILES).mtimes(&MTIMES);
+    assert_eq!(
+        found(&image, &["--since", "2024-05-01T12:00:01Z"]),
+        ["A.TXT", "B.TXT", "D.TXT"]
+    );
+    assert_eq!(
+        found(
+            &image,
+            &[
+                "--since",
+                "2024-05-01T11:59:59Z",
+                "--until",
+                "2024-05-01T12:00:01Z"
+            ]
+        ),
+        ["C.TXT"]
+    );
+    assert!(found(&image, &["--since", "2024-05-01T12:00:04Z"]).is_empty());
+}
+
+#[test]
+fn offsets_and_dates() {
+    let image = Image::with("dates", &FILES).mtimes(&MTIMES);
+    assert_eq!(
+        found(
+            &image,
+            &[
+                "--since",
+                "2024-05-01T14:00:00+02:00",
+                "--until",
+                "@1714564802"
+            ]
+        ),
+        ["A.TXT", "B.TXT"]
+    );
+    assert_eq!(found(&image, &["--since", "2024-05-01"]).len(), 4);
+    assert!(found(&image, &["--until", "2024-05-01"]).is_empty());
+    assert!(found(&image, &["--since", "2024-05-02"]).is_empty());
+}
+
+#[test]
+fn windows_conflict_with_newer_and_older() {
+    let image = Image::with("conflict", &FILES).mtimes(&MTIMES);
+    let (code, _) = image.fails(&["find", "--since", "1d", "--newer", "2d"]);
+    assert_eq!(code, 1);
+}
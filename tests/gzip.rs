@@ -0,0 +1,71 @@
+//! `write --gzip`, `write-tree --gzip-glob` and `read --gunzip`
+
+mod common;
+
+use std::fs;
+
+use common::Image;
+
+/// Compressible contents
+fn text() -> Vec<u8> {
+    (0..5000u32)
+        .flat_map(|i| format!("msgid \"{}\"\n", i % 50).into_bytes())
+        .collect()
+}
+
+#[test]
+fn write_and_read_back() {
+    let image = Image::new("write", "4M");
+    let input = image.host_path("messages.mo");
+    fs::write(&input, text()).unwrap();
+    let input = input.to_str().unwrap();
+
+    image.ok(&["write", "/MSG.MO", "-i", input, "--gzip"]);
+    let ls = image.ok(&["ls", "/"]);
+    assert!(ls.contains("MSG.MO.gz"), "{}", ls);
+    let stored = image.read("/MSG.MO.gz");
+    assert_eq!(stored[..2], [0x1f, 0x8b]);
+    assert!(stored.len() < text().len() / 10);
+    assert_eq!(
+        image.ok(&["read", "/MSG.MO.gz", "--gunzip"]).into_bytes(),
+        text()
+    );
+
+    image.ok(&["write", "/KEPT.MO", "-i", input, "--gzip", "--keep-name"]);
+    assert_eq!(image.read("/KEPT.MO"), stored);
+    assert_eq!(
+        image.ok(&["read", "/KEPT.MO", "--gunzip"]).into_bytes(),
+        text()
+    );
+}
+
+#[test]
+fn only_matching_files_of_a_tree() {
+    let image = Image::new("tree", "4M");
+    let tree = image.host_tree(
+        "tree",
+        &[("LOCALE/DE.MO", &text()), ("LOCALE/README", b"plain")],
+    );
+
+    let out = image.run(&["write-tree", &tree, "--gzip-glob", "*.MO"]);
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("gzip: 1 file(s), "), "{}", stderr);
+    assert_eq!(image.read("/LOCALE/README"), b"plain");
+    assert_eq!(
+        image
+            .ok(&["read", "/LOCALE/DE.MO.gz", "--gunzip"])
+            .into_bytes(),
+        text()
+    );
+    let (code, _) = image.fails(&["read", "/LOCALE/DE.MO"]);
+    assert_eq!(code, 2);
+}
+
+#[test]
+fn gunzip_of_a_plain_file_fails() {
+    let image = Image::new("plain", "4M");
+    image.write("/PLAIN.TXT", b"not compressed");
+    let (code, _) = image.fails(&["read", "/PLAIN.TXT", "--gunzip"]);
+    assert_ne!(code, 0);
+}
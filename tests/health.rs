@@ -0,0 +1,169 @@
+//! The `health` document of healthy images and of images damaged in each
+//! of the ways it looks for, and its exit code
+
+mod common;
+
+use common::{dir_entry, Image};
+
+const FILES: [(&str, &[u8]); 5] = [
+    ("/A/", b""),
+    ("/A/B/", b""),
+    ("/A/B/DEEP.TXT", b"deep"),
+    ("/A/ONE.TXT", b"one"),
+    ("/BIG.BIN", &[b'b'; 9000]),
+];
+
+/// The member `key` of the one-line document `doc` as written, up to the
+/// next `,`. Enough for the numbers, booleans and strings without commas
+/// these tests look at.
+fn member<'a>(doc: &'a str, key: &str) -> &'a str {
+    let start = doc
+        .find(&format!("\"{}\":", key))
+        .unwrap_or_else(|| panic!("no {}: {}", key, doc))
+        + key.len()
+        + 3;
+    let len = doc[start..].find([',', '}']).unwrap();
+    &doc[start..start + len]
+}
+
+/// Runs `health` on `image`, returning its document and whether it
+/// succeeded
+fn health(image: &Image, args: &[&str]) -> (String, bool) {
+    let out = image.run(&[&["health"], args].concat());
+    let doc = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(doc.lines().count(), 1, "{}", doc);
+    if !out.status.success() {
+        assert_eq!(out.status.code(), Some(1));
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(stderr.contains("image is not healthy"), "{}", stderr);
+    }
+    (doc, out.status.success())
+}
+
+/// A FAT16 or FAT32 image of `FILES`
+fn fixture(name: &str, fat_type: &str) -> Image {
+    let (size, cluster) = if fat_type == "32" {
+        ("40M", "512")
+    } else {
+        ("16M", "2048")
+    };
+    let image = Image::create(
+        name,
+        &[
+            "--size",
+            size,
+            "--fat-type",
+            fat_type,
+            "--cluster-size",
+            cluster,
+        ],
+    );
+    for &(path, contents) in &FILES {
+        if path.ends_with('/') {
+            image.ok(&["mkdir", path]);
+        } else {
+            image.write(path, contents);
+        }
+    }
+    image
+}
+
+#[test]
+fn healthy() {
+    for fat_type in ["16", "32"] {
+        let image = fixture(&format!("healthy{}", fat_type), fat_type);
+        let (doc, ok) = health(&image, &[]);
+        assert!(ok, "{}", doc);
+        assert!(doc.starts_with("{\"schema_version\":1,"), "{}", doc);
+        for (key, value) in [
+            ("healthy", "true"),
+            ("mountable", "true"),
+            ("fat_type", &*format!("\"FAT{}\"", fat_type)),
+            ("fat_copies_consistent", "true"),
+            ("dirty", "false"),
+            ("files", "3"),
+            ("directories", "2"),
+            ("deepest_path", "\"/A/B/DEEP.TXT\""),
+            ("depth", "3"),
+            ("walk_complete", "true"),
+            ("problems", "[]"),
+        ] {
+            assert_eq!(member(&doc, key), value, "FAT{}: {}", fat_type, key);
+        }
+        let fsinfo = if fat_type == "32" { "true" } else { "null" };
+        assert_eq!(member(&doc, "fsinfo_consistent"), fsinfo);
+        assert!(
+            doc.contains("\"largest_file\":{\"path\":\"/BIG.BIN\",\"bytes\":9000}"),
+            "{}",
+            doc
+        );
+        assert_eq!(
+            member(&doc, "free_bytes").parse::<u64>().unwrap(),
+            image.free_bytes()
+        );
+
+        // Stopping early is not a problem
+        let (doc, ok) = health(&image, &["--max-entries", "2"]);
+        assert!(ok, "{}", doc);
+        assert_eq!(member(&doc, "walk_complete"), "false");
+        assert_eq!(member(&doc, "files"), "1");
+    }
+}
+
+#[test]
+fn corrupted() {
+    // FAT copies that differ in an entry of a free cluster
+    let image = fixture("copies", "16");
+    let boot = image.boot();
+    image.patch(boot.fat_offset(1) + 2 * 1000, &0xffffu16.to_le_bytes());
+    let (doc, ok) = health(&image, &[]);
+    assert!(!ok);
+    assert_eq!(member(&doc, "healthy"), "false");
+    assert_eq!(member(&doc, "fat_copies_consistent"), "false");
+    assert_eq!(member(&doc, "mountable"), "true");
+
+    // The clean shutdown bit cleared in both copies
+    let image = fixture("dirty", "16");
+    let boot = image.boot();
+    for copy in 0..boot.fats {
+        image.patch(boot.fat_offset(copy) + 2, &0x7fffu16.to_le_bytes());
+    }
+    let (doc, ok) = health(&image, &[]);
+    assert!(!ok);
+    assert_eq!(member(&doc, "dirty"), "true");
+    assert_eq!(member(&doc, "fat_copies_consistent"), "true");
+
+    // An FSInfo free count the FAT doesn't agree with
+    let image = fixture("fsinfo", "32");
+    let bytes = image.bytes();
+    let sector = u16::from_le_bytes([bytes[48], bytes[49]]) as u64;
+    let at = sector * image.boot().bytes_per_sector + 488;
+    image.patch(at, &12345u32.to_le_bytes());
+    let (doc, ok) = health(&image, &[]);
+    assert!(!ok);
+    assert_eq!(member(&doc, "fsinfo_consistent"), "false");
+
+    // A directory starting at cluster 1
+    let image = fixture("start", "16");
+    image.add_entry("/A", dir_entry(b"BROKEN     ", 0x10, 1, 0));
+    let (doc, ok) = health(&image, &[]);
+    assert!(!ok);
+    assert!(
+        doc.contains("\"problems\":[\"/A/BROKEN: invalid directory start cluster\"]"),
+        "{}",
+        doc
+    );
+
+    // A boot sector with no sector size, which nothing can be read with
+    let image = fixture("boot", "16");
+    image.patch(11, &[0, 0]);
+    let (doc, ok) = health(&image, &[]);
+    assert!(!ok);
+    assert_eq!(member(&doc, "mountable"), "false");
+    assert_eq!(member(&doc, "fat_type"), "null");
+    assert!(
+        doc.contains("\"boot sector: Invalid BPB: bytes per sector 0\""),
+        "{}",
+        doc
+    );
+}
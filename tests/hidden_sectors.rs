@@ -0,0 +1,98 @@
+//! Images whose boot sector keeps the hidden sectors value of the
+//! partition they were cut out of
+
+mod common;
+
+use common::Image;
+
+/// Offset of the hidden sectors field in the boot sector
+const HIDDEN_SECTORS: u64 = 28;
+
+fn hidden_sectors(image: &Image, sector: u64) -> u32 {
+    let offset = (sector * 512 + HIDDEN_SECTORS) as usize;
+    u32::from_le_bytes(image.bytes()[offset..offset + 4].try_into().unwrap())
+}
+
+fn check(image: &Image, args: &[&str]) -> (bool, String) {
+    let out = image.run(&[args, &["check"]].concat());
+    (out.status.success(), String::from_utf8(out.stdout).unwrap())
+}
+
+#[test]
+fn a_stale_value_is_reported_and_fixed() {
+    let image = Image::new("stale", "4M");
+    image.ok(&["mkdir", "/DIR"]);
+    image.write("/DIR/FILE.TXT", b"contents");
+    image.patch(HIDDEN_SECTORS, &63u32.to_le_bytes());
+
+    // Nothing else reads the field
+    assert!(image.ok(&["ls", "/DIR"]).contains("FILE.TXT"));
+    assert_eq!(image.read("/DIR/FILE.TXT"), b"contents");
+    assert!(image
+        .ok(&["--verbose", "info"])
+        .contains("hidden sectors: 63"));
+
+    let (ok, out) = check(&image, &[]);
+    assert!(!ok);
+    assert!(
+        out.contains("boot sector: hidden sectors is 63, expected 0 for where the volume starts"),
+        "{}",
+        out
+    );
+
+    let out = image.ok(&["check", "--fix", "--no-backup"]);
+    assert!(out.contains("hidden sectors is 63, expected 0"), "{}", out);
+    assert!(out.contains("1 problem(s) fixed"), "{}", out);
+    assert_eq!(hidden_sectors(&image, 0), 0);
+    assert_eq!(check(&image, &[]), (true, "no problems found\n".to_owned()));
+    assert_eq!(image.read("/DIR/FILE.TXT"), b"contents");
+}
+
+#[test]
+fn the_fat32_backup_boot_sector_is_fixed_too() {
+    let image = Image::create("fat32", &["--size", "64M", "--fat-type", "32"]);
+    let backup = {
+        let bytes = image.bytes();
+        u16::from_le_bytes([bytes[50], bytes[51]]) as u64
+    };
+    assert_ne!(backup, 0);
+    image.patch(HIDDEN_SECTORS, &2048u32.to_le_bytes());
+    image.patch(backup * 512 + HIDDEN_SECTORS, &2048u32.to_le_bytes());
+
+    let (ok, out) = check(&image, &[]);
+    assert!(!ok);
+    assert!(
+        out.contains("hidden sectors is 2048, expected 0"),
+        "{}",
+        out
+    );
+
+    image.ok(&["check", "--fix", "--no-backup"]);
+    assert_eq!(hidden_sectors(&image, 0), 0);
+    assert_eq!(hidden_sectors(&image, backup), 0);
+    let bytes = image.bytes();
+    let backup = backup as usize * 512;
+    assert_eq!(bytes[..512], bytes[backup..backup + 512]);
+}
+
+#[test]
+fn partitions_expect_their_start() {
+    let image = Image::create("partition", &["--size", "8M", "--mbr"]);
+    let start = 2048;
+    assert_eq!(hidden_sectors(&image, start), 2048);
+    assert_eq!(
+        check(&image, &["--partition", "1"]),
+        (true, "no problems found\n".to_owned())
+    );
+
+    image.patch(start * 512 + HIDDEN_SECTORS, &0u32.to_le_bytes());
+    let (ok, out) = check(&image, &["--partition", "1"]);
+    assert!(!ok);
+    assert!(
+        out.contains("hidden sectors is 0, expected 2048"),
+        "{}",
+        out
+    );
+    image.ok(&["--partition", "1", "check", "--fix", "--no-backup"]);
+    assert_eq!(hidden_sectors(&image, start), 2048);
+}
@@ -0,0 +1,97 @@
+//! Image paths that aren't an image file: missing, a directory, unreadable
+//! or too small. Each is named in the error and exits with the I/O code.
+
+mod common;
+
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+use common::Image;
+
+/// The exit code of I/O errors
+const IO: i32 = 3;
+
+#[test]
+fn missing() {
+    let mut image = Image::new("missing", "4M");
+    image.path = image.host_path("typo.img");
+    let path = image.path.display().to_string();
+
+    let (code, stderr) = image.fails(&["ls", "/"]);
+    assert_eq!(code, IO);
+    assert!(
+        stderr.contains(&format!("{}: no such image file", path)),
+        "{}",
+        stderr
+    );
+    assert!(!stderr.contains("use create"), "{}", stderr);
+
+    let (code, stderr) = image.fails(&["mkdir", "/D"]);
+    assert_eq!(code, IO);
+    assert!(
+        stderr.contains(&format!(
+            "{}: no such image file, use create to make one",
+            path
+        )),
+        "{}",
+        stderr
+    );
+    assert!(!image.path.exists());
+}
+
+#[test]
+fn a_directory() {
+    let mut image = Image::new("directory", "4M");
+    image.path = image.host_path("");
+    let (code, stderr) = image.fails(&["ls", "/"]);
+    assert_eq!(code, IO);
+    assert!(
+        stderr.contains("is a directory, give the image file in it, like "),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn unreadable() {
+    let image = Image::new("unreadable", "4M");
+    fs::set_permissions(&image.path, fs::Permissions::from_mode(0o000)).unwrap();
+    // Root reads it anyway
+    if fs::File::open(&image.path).is_ok() {
+        return;
+    }
+    let (code, stderr) = image.fails(&["ls", "/"]);
+    assert_eq!(code, IO);
+    assert!(
+        stderr.contains(&format!(
+            "{}: permission denied, the command needs read access",
+            image.path.display()
+        )),
+        "{}",
+        stderr
+    );
+
+    fs::set_permissions(&image.path, fs::Permissions::from_mode(0o444)).unwrap();
+    let (code, stderr) = image.fails(&["mkdir", "/D"]);
+    assert_eq!(code, IO);
+    assert!(
+        stderr.contains("permission denied, the command needs read and write access"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn too_small() {
+    let image = Image::new("small", "4M");
+    fs::write(&image.path, [0u8; 100]).unwrap();
+    let (code, stderr) = image.fails(&["ls", "/"]);
+    assert_eq!(code, IO);
+    assert!(
+        stderr.contains("100 bytes is too small for a FAT volume"),
+        "{}",
+        stderr
+    );
+}
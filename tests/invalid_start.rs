@@ -0,0 +1,96 @@
+//! File entries with a size but start cluster 0 or 1, as some tools
+//! write them, made by hand in the directory slots
+
+mod common;
+
+use common::{dir_entry, Image};
+
+const FILES: [(&str, &[u8]); 2] = [("/D/", b""), ("/D/GOOD.TXT", b"good")];
+
+/// Puts a 1000 byte `BROKEN.BIN` starting at `cluster` next to `GOOD.TXT`
+fn add_broken(image: &Image, cluster: u32) {
+    image.add_entry("/D", dir_entry(b"BROKEN  BIN", 0x20, cluster, 1000));
+}
+
+#[test]
+fn reported_with_path_size_and_cluster() {
+    for cluster in [0, 1] {
+        let image = Image::with(&format!("report-{}", cluster), &FILES);
+        add_broken(&image, cluster);
+        let out = image.run(&["check"]);
+        assert!(!out.status.success());
+        let stdout = String::from_utf8(out.stdout).unwrap();
+        let expected = format!(
+            "/D/BROKEN.BIN: 1000 byte file has invalid start cluster {}",
+            cluster
+        );
+        assert!(stdout.contains(&expected), "{}", stdout);
+    }
+}
+
+#[test]
+fn the_directory_is_left_alone() {
+    let image = Image::with("refuse", &FILES);
+    add_broken(&image, 1);
+    let before = image.hash();
+    let refusal =
+        "/D/BROKEN.BIN: entry has invalid start cluster 1 for its 1000 bytes, run check --fix";
+
+    let (_, stderr) = image.fails(&["read", "/D/BROKEN.BIN"]);
+    assert!(stderr.contains(refusal), "{}", stderr);
+    let out = image.run_with_input(&["write", "/D/NEW.TXT"], b"new");
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains(refusal));
+    let (_, stderr) = image.fails(&["mkdir", "/D/SUB"]);
+    assert!(stderr.contains(refusal), "{}", stderr);
+    let tree = image.host_tree("tree", &[("NEW.TXT", b"new")]);
+    let (_, stderr) = image.fails(&["write-tree", "--subtree", "/D", &tree]);
+    assert!(stderr.contains(refusal), "{}", stderr);
+    assert_eq!(image.hash(), before);
+
+    // Other files and directories are fine
+    assert_eq!(image.read("/D/GOOD.TXT"), b"good");
+    image.write("/ELSEWHERE.TXT", b"fine");
+}
+
+#[test]
+fn truncated_by_default() {
+    let image = Image::with("truncate", &FILES);
+    add_broken(&image, 0);
+    let out = image.ok(&["check", "--fix", "--no-backup"]);
+    assert!(
+        out.contains(
+            "/D/BROKEN.BIN: 1000 byte file has invalid start cluster 0, truncated (fixed)"
+        ),
+        "{}",
+        out
+    );
+    assert_eq!(image.ok(&["check"]), "no problems found\n");
+    assert_eq!(image.read("/D/BROKEN.BIN"), b"");
+    assert_eq!(image.read("/D/GOOD.TXT"), b"good");
+    image.write("/D/BROKEN.BIN", b"rewritten");
+    assert_eq!(image.read("/D/BROKEN.BIN"), b"rewritten");
+}
+
+#[test]
+fn or_deleted() {
+    let image = Image::with("delete", &FILES);
+    add_broken(&image, 1);
+    let out = image.ok(&["check", "--fix", "--fix-policy", "delete", "--no-backup"]);
+    assert!(
+        out.contains("invalid start cluster 1, deleted (fixed)"),
+        "{}",
+        out
+    );
+    assert_eq!(image.ok(&["check"]), "no problems found\n");
+    assert_eq!(image.fails(&["read", "/D/BROKEN.BIN"]).0, 2);
+    assert_eq!(image.read("/D/GOOD.TXT"), b"good");
+}
+
+#[test]
+fn empty_files_may_start_at_0() {
+    let image = Image::new("empty", "4M");
+    image.add_entry("/", dir_entry(b"EMPTY   TXT", 0x20, 0, 0));
+    assert_eq!(image.ok(&["check"]), "no problems found\n");
+    assert_eq!(image.read("/EMPTY.TXT"), b"");
+}
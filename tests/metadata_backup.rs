@@ -0,0 +1,159 @@
+//! `check --fix` metadata backups put back with `restore-metadata`: the
+//! image hash from before the fix has to return
+
+mod common;
+
+use std::fs;
+
+use common::{dir_entry, Image};
+
+/// How a test damages an image of `FILES`, for `check --fix` to repair
+#[derive(Debug, Clone, Copy)]
+enum Damage {
+    /// A hidden sectors count in the boot sector, as in an image cut out
+    /// of a disk
+    HiddenSectors,
+    /// A 1000 byte `/D/BROKEN.BIN` starting at cluster 1, which a
+    /// directory cluster holds
+    InvalidStart,
+}
+
+const FILES: [(&str, &[u8]); 3] = [
+    ("/D/", b""),
+    ("/D/GOOD.TXT", b"good"),
+    ("/TOP.TXT", &[b't'; 3000]),
+];
+
+impl Damage {
+    fn apply(self, image: &Image) {
+        match self {
+            Self::HiddenSectors => image.patch(28, &2048u32.to_le_bytes()),
+            Self::InvalidStart => {
+                image.add_entry("/D", dir_entry(b"BROKEN  BIN", 0x20, 1, 1000));
+            },
+        }
+    }
+}
+
+fn backup_path(image: &Image, suffix: &str) -> String {
+    format!("{}{}", image.path.display(), suffix)
+}
+
+#[test]
+fn restoring_undoes_the_fix() {
+    for (damage, args) in [
+        (Damage::HiddenSectors, &["check", "--fix"][..]),
+        (Damage::InvalidStart, &["check", "--fix"]),
+        (
+            Damage::InvalidStart,
+            &["check", "--fix", "--fix-policy", "delete"],
+        ),
+    ] {
+        let image = Image::with(&format!("undo-{:?}-{}", damage, args.len()), &FILES);
+        damage.apply(&image);
+        let damaged = image.hash();
+        let problems = image.run(&["check"]);
+        assert!(!problems.status.success(), "{:?}", damage);
+
+        let out = image.run(args);
+        assert!(out.status.success(), "{:?}", damage);
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        let backup = backup_path(&image, ".metadata");
+        assert!(
+            stderr.contains(&format!("saved a metadata backup to {}", backup)),
+            "{}",
+            stderr
+        );
+        assert_ne!(image.hash(), damaged, "{:?}", damage);
+        assert_eq!(image.ok(&["check"]), "no problems found\n");
+
+        let restored = image.ok(&["restore-metadata", &backup]);
+        assert!(restored.starts_with("restored "), "{}", restored);
+        assert!(
+            restored.ends_with(&format!(" from {}\n", backup)),
+            "{}",
+            restored
+        );
+        assert_eq!(image.hash(), damaged, "{:?}", damage);
+        let again = image.run(&["check"]);
+        assert_eq!(again.stdout, problems.stdout, "{:?}", damage);
+
+        // File contents are never part of the backup, and never touched
+        assert_eq!(image.read("/D/GOOD.TXT"), b"good");
+        assert_eq!(image.read("/TOP.TXT"), [b't'; 3000]);
+    }
+}
+
+#[test]
+fn backup_names() {
+    let image = Image::with("names", &FILES);
+    Damage::HiddenSectors.apply(&image);
+    let damaged = image.hash();
+    image.ok(&["check", "--fix"]);
+    image.patch(28, &2048u32.to_le_bytes());
+    // The first backup is kept, the next one gets a number
+    let out = image.run(&["check", "--fix"]);
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    let numbered = backup_path(&image, ".metadata.1");
+    assert!(stderr.contains(&numbered), "{}", stderr);
+    image.ok(&["restore-metadata", &numbered]);
+    assert_eq!(image.hash(), damaged);
+
+    let given = image.host_path("given.bin");
+    let given = given.to_str().unwrap();
+    image.ok(&["check", "--fix", "--backup-metadata", given]);
+    assert!(!fs::exists(backup_path(&image, ".metadata.2")).unwrap());
+    image.ok(&["restore-metadata", given]);
+    assert_eq!(image.hash(), damaged);
+
+    image.ok(&["check", "--fix", "--no-backup"]);
+    assert!(!fs::exists(backup_path(&image, ".metadata.2")).unwrap());
+    assert_ne!(image.hash(), damaged);
+}
+
+#[test]
+fn backup_flags_need_fix() {
+    let image = Image::with("flags", &FILES);
+    Damage::HiddenSectors.apply(&image);
+    let given = image.host_path("given.bin");
+    let given = given.to_str().unwrap();
+    for args in [
+        &["check", "--backup-metadata", given][..],
+        &["check", "--no-backup"],
+        &["check", "--fix", "--no-backup", "--backup-metadata", given],
+    ] {
+        let (code, _) = image.fails(args);
+        assert_eq!(code, 1, "{:?}", args);
+    }
+    assert!(!fs::exists(given).unwrap());
+}
+
+#[test]
+fn broken_backups_are_refused_before_writing() {
+    let image = Image::with("broken", &FILES);
+    Damage::HiddenSectors.apply(&image);
+    image.ok(&["check", "--fix"]);
+    let fixed = image.hash();
+    let backup = backup_path(&image, ".metadata");
+    let full = fs::read(&backup).unwrap();
+
+    let cut = image.host_path("cut.bin");
+    fs::write(&cut, &full[..full.len() - 1]).unwrap();
+    let cut = cut.to_str().unwrap();
+    let (_, stderr) = image.fails(&["restore-metadata", cut]);
+    assert!(
+        stderr.contains(&format!("{} is not a complete metadata backup", cut)),
+        "{}",
+        stderr
+    );
+    assert_eq!(image.hash(), fixed);
+
+    // A backup of another volume
+    let other = Image::new("broken-other", "8M");
+    let (_, stderr) = other.fails(&["restore-metadata", &backup]);
+    assert!(
+        stderr.contains("was saved from a volume of 4194304 bytes, this one has 8388608"),
+        "{}",
+        stderr
+    );
+}
@@ -0,0 +1,163 @@
+//! `--now`, `--tz` and `SOURCE_DATE_EPOCH` in the timestamps of written
+//! entries
+
+mod common;
+
+use std::fs::File;
+use std::time::{Duration, UNIX_EPOCH};
+
+use common::Image;
+
+/// 2024-05-01T12:00:00Z
+const NOON: &str = "1714564800";
+
+/// The `ls --jsonl` line of the root directory entry `name`
+fn entry(image: &Image, name: &str) -> String {
+    let listing = image.ok(&["ls", "--jsonl"]);
+    let name = format!("\"name\":\"{}\"", name);
+    listing
+        .lines()
+        .find(|line| line.contains(&name))
+        .unwrap_or_else(|| panic!("no {} in {}", name, listing))
+        .to_owned()
+}
+
+fn modified(image: &Image, name: &str) -> String {
+    let line = entry(image, name);
+    common::json_str(&line, "modified").unwrap().to_owned()
+}
+
+#[test]
+fn every_timestamp_of_a_new_entry_is_now() {
+    let image = Image::new("stamps", "4M");
+    image.ok(&[
+        "--now",
+        "2024-05-01T12:00:00Z",
+        "--tz",
+        "Z",
+        "mkdir",
+        "/DIR",
+    ]);
+    let out = image.run_with_input(&["--now", NOON, "write", "/F.TXT"], b"x");
+    assert!(out.status.success());
+
+    let line = entry(&image, "F.TXT");
+    assert!(
+        line.contains("\"created\":\"2024-05-01T12:00:00.000\""),
+        "{}",
+        line
+    );
+    assert!(
+        line.contains("\"modified\":\"2024-05-01T12:00:00.000\""),
+        "{}",
+        line
+    );
+    assert!(line.contains("\"accessed\":\"2024-05-01\""), "{}", line);
+    assert_eq!(modified(&image, "DIR"), "2024-05-01T12:00:00.000");
+}
+
+#[test]
+fn offsets() {
+    let image = Image::new("offsets", "4M");
+    image.ok(&["--now", "2024-05-01T12:00:00+02:00", "touch", "/OWN.TXT"]);
+    image.ok(&[
+        "--now",
+        "2024-05-01T12:00:00+02:00",
+        "--tz",
+        "Z",
+        "touch",
+        "/UTC.TXT",
+    ]);
+    image.ok(&["--now", NOON, "--tz", "-05:30", "touch", "/WEST.TXT"]);
+    assert_eq!(modified(&image, "OWN.TXT"), "2024-05-01T12:00:00.000");
+    assert_eq!(modified(&image, "UTC.TXT"), "2024-05-01T10:00:00.000");
+    assert_eq!(modified(&image, "WEST.TXT"), "2024-05-01T06:30:00.000");
+}
+
+#[test]
+fn source_date_epoch() {
+    let image = Image::new("epoch", "4M");
+    let epoch = [("SOURCE_DATE_EPOCH", NOON)];
+    assert!(image
+        .run_with_env(&["touch", "/ENV.TXT"], &epoch)
+        .status
+        .success());
+    assert!(image
+        .run_with_env(
+            &["--now", "2030-01-01T00:00:00Z", "touch", "/NOW.TXT"],
+            &epoch
+        )
+        .status
+        .success());
+    assert_eq!(modified(&image, "ENV.TXT"), "2024-05-01T12:00:00.000");
+    assert_eq!(modified(&image, "NOW.TXT"), "2030-01-01T00:00:00.000");
+
+    let out = image.run_with_env(&["touch", "/BAD.TXT"], &[("SOURCE_DATE_EPOCH", "soon")]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Invalid SOURCE_DATE_EPOCH"));
+}
+
+#[test]
+fn the_same_inputs_make_the_same_image() {
+    let images = ["same-1", "same-2"].map(|name| {
+        let image = Image::create(
+            name,
+            &["--size", "4M", "--volume-id", "1234abcd", "--now", NOON],
+        );
+        image.ok(&["--now", NOON, "mkdir", "/EFI"]);
+        let out = image.run_with_input(&["--now", NOON, "write", "/EFI/BOOT.CFG"], b"timeout=3\n");
+        assert!(out.status.success());
+        image.ok(&["--now", NOON, "cp", "/EFI/BOOT.CFG", "/EFI/BOOT.BAK"]);
+        image
+    });
+    assert_eq!(images[0].hash(), images[1].hash());
+
+    let other = Image::create(
+        "same-3",
+        &["--size", "4M", "--volume-id", "1234abcd", "--now", NOON],
+    );
+    other.ok(&["--now", "1714564802", "mkdir", "/EFI"]);
+    assert_ne!(other.hash(), images[0].hash());
+}
+
+#[test]
+fn preserved_host_times_win() {
+    let image = Image::new("preserve", "4M");
+    let host = image.host_path("old.txt");
+    std::fs::write(&host, b"old").unwrap();
+    // 2020-01-01T00:00:00Z
+    File::options()
+        .write(true)
+        .open(&host)
+        .unwrap()
+        .set_modified(UNIX_EPOCH + Duration::from_secs(1_577_836_800))
+        .unwrap();
+
+    let host = host.to_str().unwrap();
+    let now = ["--now", NOON, "--tz", "Z"];
+    image.ok(&[
+        &now[..],
+        &["write", "/OLD.TXT", "-i", host, "--preserve-times"],
+    ]
+    .concat());
+    image.ok(&[&now[..], &["write", "/NEW.TXT", "-i", host]].concat());
+    assert_eq!(modified(&image, "OLD.TXT"), "2020-01-01T00:00:00.000");
+    assert_eq!(modified(&image, "NEW.TXT"), "2024-05-01T12:00:00.000");
+}
+
+#[test]
+fn times_fat_cant_store_are_refused() {
+    let image = Image::new("range", "4M");
+    let before = image.hash();
+    for now in ["1979-12-31T23:59:59Z", "2108-01-01T00:00:00Z", "0"] {
+        let (code, stderr) = image.fails(&["--now", now, "touch", "/F.TXT"]);
+        assert_eq!(code, 1);
+        assert!(
+            stderr.contains("FAT can only store 1980 to 2107"),
+            "{}: {}",
+            now,
+            stderr
+        );
+    }
+    assert_eq!(image.hash(), before);
+}
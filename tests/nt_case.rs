@@ -0,0 +1,144 @@
+//! Names that are 8.3 except for case, stored with the NT case flags
+//! instead of long names: written by fatimg, or found in an entry as
+//! Windows leaves it, and carried through `ls`, `find`, `read-tree` and
+//! `write-tree` unchanged
+
+mod common;
+
+use std::fs;
+
+use common::{dir_entry, short_name, Image};
+
+/// Lowercase base and extension flags of the NT byte
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXT: u8 = 0x10;
+
+/// Files of the round trip, by name with the short name and the NT case
+/// flags they are stored with. `None` keeps a long name.
+const FILES: [(&str, &str, Option<u8>); 5] = [
+    ("readme.txt", "README.TXT", Some(LOWER_BASE | LOWER_EXT)),
+    ("UPPER.txt", "UPPER.TXT", Some(LOWER_EXT)),
+    ("notes.TXT", "NOTES.TXT", Some(LOWER_BASE)),
+    ("PLAIN.TXT", "PLAIN.TXT", Some(0)),
+    ("Mixed.txt", "MIXED.TXT", None),
+];
+
+/// Sorted names `ls --jsonl` shows in the root directory
+fn names(image: &Image) -> Vec<String> {
+    let mut names: Vec<String> = image
+        .ok(&["ls", "--jsonl", "/"])
+        .lines()
+        .filter_map(|line| common::json_str(line, "name"))
+        .map(str::to_owned)
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+/// Sorted names of the host directory `dir`
+fn host_names(dir: &std::path::Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+/// The NT byte of the root entry `short`, and whether a long name entry
+/// comes right before it
+fn stored(image: &Image, short: &str) -> (u8, bool) {
+    let bytes = image.bytes();
+    let at = image.entry(&format!("/{}", short));
+    let long = image.slots("/").contains(&(at - 32)) && bytes[at as usize - 32 + 11] == 0x0f;
+    (bytes[at as usize + 12], long)
+}
+
+/// Sorted names of `FILES`, with or without the ones keeping long names
+fn expected(long: bool) -> Vec<String> {
+    let mut names: Vec<String> = FILES
+        .iter()
+        .filter(|f| long || f.2.is_some())
+        .map(|f| f.0.to_owned())
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+/// Checks the entries of `FILES` are stored as listed and shown by name
+fn assert_stored(image: &Image) {
+    for (name, short, flags) in FILES {
+        let (nt, long) = stored(image, short);
+        match flags {
+            Some(flags) => {
+                assert_eq!(nt & (LOWER_BASE | LOWER_EXT), flags, "{}", name);
+                assert!(!long, "{}", name);
+            },
+            None => assert!(long, "{}", name),
+        }
+    }
+    assert_eq!(names(image), expected(true));
+    let mut found: Vec<String> = image
+        .ok(&["find", "/"])
+        .lines()
+        .map(|l| l[1..].to_owned())
+        .collect();
+    found.sort_unstable();
+    assert_eq!(found, expected(true));
+    assert_eq!(image.ok(&["check"]), "no problems found\n");
+}
+
+#[test]
+fn from_fatimg() {
+    let image = Image::new("fatimg", "4M");
+    for (name, _, _) in FILES {
+        image.write(&format!("/{}", name), name.as_bytes());
+    }
+    assert_stored(&image);
+    for (name, _, _) in FILES {
+        assert_eq!(image.read(&format!("/{}", name)), name.as_bytes());
+    }
+
+    let host = image.host_path("out");
+    image.ok(&["read-tree", host.to_str().unwrap()]);
+    assert_eq!(host_names(&host), expected(true));
+
+    let copy = Image::new("fatimg-copy", "4M");
+    copy.ok(&["write-tree", host.to_str().unwrap()]);
+    assert_stored(&copy);
+    for (name, _, _) in FILES {
+        assert_eq!(copy.read(&format!("/{}", name)), name.as_bytes());
+    }
+}
+
+#[test]
+fn from_a_folded_entry() {
+    let image = Image::new("folded", "4M");
+    // As Windows stores them, with no long names
+    for (name, short, flags) in FILES {
+        let flags = match flags {
+            Some(flags) => flags,
+            None => continue,
+        };
+        let mut entry = dir_entry(&short_name(short), 0x20, 0, 0);
+        entry[12] = flags;
+        image.add_entry("/", entry);
+        assert_eq!(image.read(&format!("/{}", name)), b"", "{}", name);
+        // Looked up without regard to case
+        assert_eq!(image.read(&format!("/{}", short)), b"", "{}", name);
+    }
+    assert_eq!(names(&image), expected(false));
+
+    let host = image.host_path("out");
+    image.ok(&["read-tree", host.to_str().unwrap()]);
+    assert_eq!(host_names(&host), expected(false));
+    let copy = Image::new("folded-copy", "4M");
+    copy.ok(&["write-tree", host.to_str().unwrap()]);
+    for (name, short, flags) in FILES {
+        if let Some(flags) = flags {
+            assert_eq!(stored(&copy, short), stored(&image, short), "{}", name);
+            assert_eq!(stored(&copy, short), (flags, false), "{}", name);
+        }
+    }
+    assert_eq!(names(&copy), names(&image));
+}
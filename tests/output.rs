@@ -0,0 +1,46 @@
+//! The global `--output` format, given before or after the command
+
+mod common;
+
+use std::fs;
+
+use common::Image;
+
+const NOTE: &str = "Note: the default --output will change from classic to v2";
+
+#[test]
+fn given_anywhere() {
+    let image = Image::with("anywhere", &[("/F.TXT", b"f")]);
+    for format in ["classic", "v2", "json"] {
+        let before = image.run(&["--output", format, "info"]);
+        let after = image.run(&["info", "--output", format]);
+        assert!(
+            before.status.success() && after.status.success(),
+            "{}",
+            format
+        );
+        assert_eq!(before.stdout, after.stdout, "{}", format);
+        assert!(!String::from_utf8_lossy(&after.stderr).contains(NOTE));
+    }
+
+    let out = image.run(&["info"]);
+    assert_eq!(
+        out.stdout,
+        image.run(&["info", "--output", "classic"]).stdout
+    );
+    assert!(String::from_utf8_lossy(&out.stderr).contains(NOTE));
+    let out = image.run(&["verify-boot"]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains(NOTE));
+}
+
+#[test]
+fn host_files_are_output_file() {
+    let image = Image::with("file", &[("/F.TXT", b"f")]);
+    let out = image.host_path("out.bin");
+    let out = out.to_str().unwrap();
+    image.ok(&["read", "/F.TXT", "--output-file", out]);
+    assert_eq!(fs::read(out).unwrap(), b"f");
+    fs::remove_file(out).unwrap();
+    image.ok(&["read", "/F.TXT", "-o", out, "--output", "v2"]);
+    assert_eq!(fs::read(out).unwrap(), b"f");
+}
@@ -0,0 +1,158 @@
+//! What each command replaces with no flag, with `--force`, with
+//! `--no-clobber` and with both
+
+mod common;
+
+use std::fs;
+
+use common::Image;
+
+/// How a command left an existing target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Replaced,
+    /// Refused, with `--force` suggested
+    NeedsForce,
+    /// Refused because of `--no-clobber`
+    NoClobber,
+}
+
+use Outcome::*;
+
+/// The flag combinations, in the order of the expected outcomes
+const FLAGS: [&[&str]; 4] = [
+    &[],
+    &["--force"],
+    &["--no-clobber"],
+    &["--force", "--no-clobber"],
+];
+
+fn outcome(image: &Image, args: &[&str]) -> Outcome {
+    let out = image.run(args);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    if out.status.success() {
+        Replaced
+    } else if stderr.contains("not replacing it with --no-clobber") {
+        NoClobber
+    } else if stderr.contains("already exists, use --force to overwrite") {
+        NeedsForce
+    } else {
+        panic!("fatimg {} failed: {}", args.join(" "), stderr)
+    }
+}
+
+/// Host outputs are never replaced by default
+#[test]
+fn host_outputs() {
+    let image = Image::new("host", "4M");
+    image.write("/F.TXT", b"contents");
+    let existing = image.host_path("out");
+    let out = existing.to_str().unwrap();
+    let clone = image.host_path("clone.img");
+    let commands: [&[&str]; 4] = [
+        &["read", "/F.TXT", "-o", out],
+        &["cluster-read", "2", "-o", out],
+        &["export-cpio", "-o", out],
+        &["clone", clone.to_str().unwrap()],
+    ];
+    for command in commands {
+        let mut outcomes = Vec::new();
+        for flags in FLAGS {
+            fs::write(&existing, b"keep").unwrap();
+            fs::write(&clone, b"keep").unwrap();
+            let outcome = outcome(&image, &[command, flags].concat());
+            if outcome != Replaced {
+                assert_eq!(fs::read(&existing).unwrap(), b"keep");
+                assert_eq!(fs::read(&clone).unwrap(), b"keep");
+            }
+            outcomes.push(outcome);
+        }
+        assert_eq!(
+            outcomes,
+            [NeedsForce, Replaced, NoClobber, NoClobber],
+            "{:?}",
+            command
+        );
+    }
+}
+
+#[test]
+fn create_over_an_image() {
+    let image = Image::new("create", "4M");
+    image.write("/F.TXT", b"contents");
+    let before = image.hash();
+    assert_eq!(outcome(&image, &["create", "--size", "4M"]), NeedsForce);
+    assert_eq!(
+        outcome(&image, &["create", "--size", "4M", "--no-clobber"]),
+        NoClobber
+    );
+    let both = ["create", "--size", "4M", "--force", "--no-clobber"];
+    assert_eq!(outcome(&image, &both), NoClobber);
+    assert_eq!(image.hash(), before);
+    assert_eq!(
+        outcome(&image, &["create", "--size", "4M", "--force"]),
+        Replaced
+    );
+    assert_eq!(image.fails(&["read", "/F.TXT"]).0, 2);
+}
+
+/// Image files are replaced by default, except by `mv`
+#[test]
+fn image_entries() {
+    let image = Image::new("entries", "4M");
+    let host = image.host_path("in");
+    fs::write(&host, b"new").unwrap();
+    let host = host.to_str().unwrap();
+
+    for (command, default, flags) in [
+        (&["write", "/DST", "-i", host][..], Replaced, &[][..]),
+        (&["cp", "/SRC", "/DST"], Replaced, &[][..]),
+        (&["mv", "/SRC", "/DST"], NeedsForce, &["--force"][..]),
+    ] {
+        let mut outcomes = Vec::new();
+        for extra in [
+            &[][..],
+            flags,
+            &["--no-clobber"],
+            &[flags, &["--no-clobber"]].concat(),
+        ] {
+            image.write("/SRC", b"new");
+            image.write("/DST", b"old");
+            let outcome = outcome(&image, &[command, extra].concat());
+            let expected: &[u8] = if outcome == Replaced { b"new" } else { b"old" };
+            assert_eq!(image.read("/DST"), expected, "{:?} {:?}", command, extra);
+            outcomes.push(outcome);
+        }
+        let forced = if flags.is_empty() { default } else { Replaced };
+        assert_eq!(
+            outcomes,
+            [default, forced, NoClobber, NoClobber],
+            "{:?}",
+            command
+        );
+    }
+}
+
+#[test]
+fn commands_own_refusals() {
+    let image = Image::new("own", "4M");
+    image.write("/SRC", b"new");
+    image.write("/DST", b"old");
+    let (_, stderr) = image.fails(&["cp", "-n", "/SRC", "/DST"]);
+    assert!(stderr.contains("/DST"), "{}", stderr);
+    assert_eq!(image.read("/DST"), b"old");
+
+    let tree = image.host_tree("tree", &[("DST", b"new")]);
+    let tree = tree.as_str();
+    let (_, stderr) = image.fails(&["write-tree", tree, "--no-overwrite"]);
+    assert!(
+        stderr.contains("/DST exists and --no-overwrite is given"),
+        "{}",
+        stderr
+    );
+    let (_, no_clobber) = image.fails(&["write-tree", tree, "--no-clobber"]);
+    assert_eq!(no_clobber, stderr);
+    assert_eq!(image.read("/DST"), b"old");
+    image.ok(&["write-tree", tree]);
+    assert_eq!(image.read("/DST"), b"new");
+}
@@ -0,0 +1,37 @@
+//! Every command given a path through a file names that file and exits
+//! with the not found code
+
+mod common;
+
+use common::Image;
+
+const MESSAGE: &str = "path component /F.TXT is a file, not a directory";
+
+#[test]
+fn every_command_names_the_file() {
+    let image = Image::with("parent", &[("/F.TXT", b"f"), ("/A.TXT", b"a")]);
+    let tree = image.host_tree("tree", &[("T.TXT", b"t")]);
+    let input = image.host_path("input.txt");
+    std::fs::write(&input, b"input").unwrap();
+    let input = input.to_str().unwrap();
+    let before = image.hash();
+
+    let commands: &[&[&str]] = &[
+        &["write", "/F.TXT/X.TXT", "-i", input],
+        &["mkdir", "/F.TXT/D"],
+        &["write-tree", "-s", "/F.TXT/SUB", &tree],
+        &["cp", "/A.TXT", "/F.TXT/B.TXT"],
+        &["mv", "/A.TXT", "/F.TXT/B.TXT"],
+        &["touch", "/F.TXT/X.TXT"],
+        &["rm", "/F.TXT/X.TXT"],
+        &["read", "/F.TXT/X.TXT"],
+        &["ls", "/F.TXT/D"],
+        &["find", "/F.TXT/D"],
+    ];
+    for args in commands {
+        let (code, stderr) = image.fails(args);
+        assert_eq!(code, 2, "{:?}: {}", args, stderr);
+        assert!(stderr.contains(MESSAGE), "{:?}: {}", args, stderr);
+        assert_eq!(image.hash(), before, "{:?}", args);
+    }
+}
@@ -0,0 +1,65 @@
+//! `read-tree --file-mode` and `--dir-mode` on Unix hosts
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use common::Image;
+
+const FILES: [(&str, &[u8]); 4] = [
+    ("/D/", b""),
+    ("/D/E/", b""),
+    ("/D/E/F.TXT", b"f"),
+    ("/TOP.TXT", b"top"),
+];
+
+fn mode(path: &Path) -> u32 {
+    fs::metadata(path).unwrap().permissions().mode() & 0o7777
+}
+
+#[test]
+fn modes_are_set_after_extracting() {
+    let image = Image::with("modes", &FILES);
+    let out = image.host_path("out");
+    image.ok(&[
+        "read-tree",
+        out.to_str().unwrap(),
+        "--dir-mode",
+        "0555",
+        "--file-mode",
+        "0640",
+    ]);
+    assert_eq!(fs::read(out.join("D/E/F.TXT")).unwrap(), b"f");
+    assert_eq!(mode(&out.join("D")), 0o555);
+    assert_eq!(mode(&out.join("D/E")), 0o555);
+    assert_eq!(mode(&out.join("D/E/F.TXT")), 0o640);
+    assert_eq!(mode(&out.join("TOP.TXT")), 0o640);
+    // The host directory given isn't one of the extracted ones
+    assert_ne!(mode(&out), 0o555);
+
+    // Writable again, so that the test directory can be removed
+    for dir in ["D", "D/E"] {
+        fs::set_permissions(out.join(dir), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
+
+#[test]
+fn file_mode_wins_over_preserve_attrs() {
+    let image = Image::with("attrs", &FILES);
+    image.ok(&["attrib", "/TOP.TXT", "+r"]);
+    let out = image.host_path("out");
+    let out = out.to_str().unwrap();
+    image.ok(&["read-tree", out, "--preserve-attrs", "--file-mode", "0600"]);
+    assert_eq!(mode(&Path::new(out).join("TOP.TXT")), 0o600);
+
+    let (code, stderr) = image.fails(&["read-tree", out, "--force", "--file-mode", "0800"]);
+    assert_eq!(code, 1);
+    assert!(
+        stderr.contains("\"0800\" is not an octal mode from 0 to 7777"),
+        "{}",
+        stderr
+    );
+}
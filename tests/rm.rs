@@ -0,0 +1,69 @@
+//! `rm` flags: `--missing-ok` for a missing entry, the global `--quiet`
+//! and removing from several images with `--also`
+
+mod common;
+
+use common::Image;
+
+#[test]
+fn quiet_is_the_global_flag() {
+    let image = Image::new("quiet", "4M");
+    image.write("/F.TXT", b"f");
+
+    // Not `--force`: a missing entry is still an error
+    let (code, stderr) = image.fails(&["rm", "--quiet", "/MISSING.TXT"]);
+    assert_eq!(code, 2);
+    assert!(stderr.contains("/MISSING.TXT"), "{}", stderr);
+    image.ok(&["rm", "--missing-ok", "/MISSING.TXT"]);
+    image.ok(&["rm", "-f", "/MISSING.TXT"]);
+
+    // Removing with `--quiet` leaves out the summary of what changed
+    let out = image.run(&["rm", "--quiet", "/F.TXT"]);
+    assert!(out.status.success());
+    assert!(
+        out.stderr.is_empty(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let (code, _) = image.fails(&["read", "/F.TXT"]);
+    assert_eq!(code, 2);
+}
+
+#[test]
+fn removing_from_several_images() {
+    let images: Vec<Image> = ["also-a", "also-b", "also-c"]
+        .iter()
+        .map(|name| Image::new(name, "4M"))
+        .collect();
+    for image in [&images[0], &images[2]] {
+        image.write("/F.TXT", b"f");
+    }
+    let paths: Vec<&str> = images.iter().map(|i| i.path.to_str().unwrap()).collect();
+
+    let (code, stderr) = images[0].fails(&["rm", "/F.TXT", "--also", paths[1], "--also", paths[2]]);
+    assert_eq!(code, 1);
+    for line in [
+        format!("{}: ok", paths[0]),
+        format!("{}: failed: ", paths[1]),
+        format!("{}: ok", paths[2]),
+        "1 of 3 images failed".to_owned(),
+    ] {
+        assert!(stderr.contains(&line), "{}", stderr);
+    }
+    for image in &images {
+        assert_eq!(image.fails(&["read", "/F.TXT"]).0, 2);
+    }
+
+    // The image lacking the file first, so the others are never reached
+    images[2].write("/F.TXT", b"f");
+    let (code, stderr) = images[1].fails(&["rm", "/F.TXT", "--also", paths[2], "--fail-fast"]);
+    assert_eq!(code, 1);
+    assert!(
+        stderr.contains("Skipping 1 remaining image(s)"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("1 of 2 images failed"), "{}", stderr);
+    assert!(!stderr.contains(&format!("{}: ok", paths[2])), "{}", stderr);
+    assert_eq!(images[2].read("/F.TXT"), b"f");
+}
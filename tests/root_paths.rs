@@ -0,0 +1,82 @@
+//! Spellings of the same image path, and of the root directory, across
+//! commands
+
+mod common;
+
+use common::Image;
+
+const FILES: [(&str, &[u8]); 3] = [("/A/", b""), ("/A/./F.TXT", b"file"), ("/TOP.TXT", b"top")];
+
+#[test]
+fn spellings_of_the_root() {
+    let image = Image::with("root", &FILES);
+    let root = image.ok(&["ls", "/"]);
+    assert!(root.contains("TOP.TXT"), "{}", root);
+    for spelling in ["//", "/.", "/./", "/..", "/A/..", "/A/../."] {
+        assert_eq!(image.ok(&["ls", spelling]), root, "{}", spelling);
+    }
+    assert_eq!(image.ok(&["ls"]), root);
+}
+
+#[test]
+fn spellings_of_an_entry() {
+    let image = Image::with("entry", &FILES);
+    for spelling in [
+        "/A/F.TXT",
+        "//A//F.TXT",
+        "/A/./F.TXT",
+        "/../A/F.TXT",
+        "/TOP.TXT/../A/F.TXT",
+    ] {
+        assert_eq!(image.read(spelling), b"file", "{}", spelling);
+    }
+    let listing = image.ok(&["ls", "/A"]);
+    assert_eq!(image.ok(&["ls", "/A/"]), listing);
+    assert_eq!(image.ok(&["ls", "/A/."]), listing);
+}
+
+#[test]
+fn commands_needing_an_entry_refuse_the_root() {
+    let image = Image::with("refuse", &FILES);
+    let before = image.hash();
+    for args in [
+        &["read", "/"][..],
+        &["read", "/."],
+        &["write", "/"],
+        &["mkdir", "/A/.."],
+        &["touch", "//"],
+        &["mv", "/", "/B"],
+    ] {
+        let (code, stderr) = image.fails(args);
+        assert_eq!(code, 1, "{:?}", args);
+        assert!(
+            stderr.contains("is the root directory"),
+            "{:?}: {}",
+            args,
+            stderr
+        );
+    }
+    let (_, stderr) = image.fails(&["rm", "-r", "/."]);
+    assert!(stderr.contains("Refusing to remove /"), "{}", stderr);
+    assert_eq!(image.hash(), before);
+}
+
+#[test]
+fn relative_paths_are_refused() {
+    let image = Image::with("relative", &FILES);
+    for args in [
+        &["ls", "A"][..],
+        &["read", "A/F.TXT"],
+        &["mkdir", "B"],
+        &["rm", "./TOP.TXT"],
+    ] {
+        let (code, stderr) = image.fails(args);
+        assert_eq!(code, 1, "{:?}", args);
+        assert!(
+            stderr.contains("Image paths must start with `/`"),
+            "{:?}: {}",
+            args,
+            stderr
+        );
+    }
+}
@@ -0,0 +1,91 @@
+//! `write-tree --stable-dir-times`: rebuilding an image in place with one
+//! host file changed changes only that file's clusters and entry
+
+mod common;
+
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+use common::Image;
+
+/// 2024-05-01T12:00:00Z, when the target directory is made
+const MADE: &str = "1714564800";
+/// An hour later, when the tree is written
+const BUILT: &str = "1714568400";
+
+/// Short names only, so that every entry is a single slot
+const TREE: [(&str, &[u8]); 4] = [
+    ("A.TXT", &[b'a'; 3000]),
+    ("SUB/B.BIN", &[b'b'; 5000]),
+    ("SUB/C.TXT", b"c"),
+    ("Z.TXT", b"z"),
+];
+
+fn build(image: &Image, tree: &str) {
+    image.ok(&[
+        "--now",
+        BUILT,
+        "write-tree",
+        "-s",
+        "/OUT",
+        "--stable-dir-times",
+        tree,
+    ]);
+}
+
+/// Ranges of the bytes that differ between `a` and `b`
+fn changed(a: &[u8], b: &[u8]) -> Vec<Range<u64>> {
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    for i in (0..a.len()).filter(|&i| a[i] != b[i]) {
+        let i = i as u64;
+        match ranges.last_mut() {
+            Some(last) if last.end == i => last.end = i + 1,
+            _ => ranges.push(i..i + 1),
+        }
+    }
+    ranges
+}
+
+#[test]
+fn one_changed_file() {
+    let image = Image::create(
+        "rebuild",
+        &["--size", "4M", "--volume-id", "1234abcd", "--now", MADE],
+    );
+    image.ok(&["--now", MADE, "mkdir", "/OUT"]);
+    let out = image.entry("/OUT");
+    let made = image.bytes()[out as usize..][..32].to_vec();
+    let tree = image.host_tree("tree", &TREE);
+    build(&image, &tree);
+    assert_eq!(image.bytes()[out as usize..][..32], made[..]);
+
+    let before = image.bytes();
+    let entry = image.entry("/OUT/SUB/B.BIN");
+    let clusters = image.chain(image.first_cluster(entry));
+    fs::write(Path::new(&tree).join("SUB/B.BIN"), [b'B'; 5000]).unwrap();
+    build(&image, &tree);
+    let after = image.bytes();
+    assert_eq!(image.chain(image.first_cluster(entry)), clusters);
+    assert_eq!(image.read("/OUT/SUB/B.BIN"), [b'B'; 5000]);
+
+    let boot = image.boot();
+    let allowed: Vec<Range<u64>> = clusters
+        .iter()
+        .map(|&cluster| boot.cluster_offset(cluster))
+        .map(|start| start..start + boot.cluster_size())
+        .chain(std::iter::once(entry..entry + 32))
+        .collect();
+    let ranges = changed(&before, &after);
+    assert!(!ranges.is_empty());
+    for range in &ranges {
+        assert!(
+            allowed
+                .iter()
+                .any(|a| a.start <= range.start && range.end <= a.end),
+            "{:?} changed outside of {:?}",
+            range,
+            allowed
+        );
+    }
+}
@@ -0,0 +1,165 @@
+//! The tree walks on a 1000 level deep directory chain and on a directory
+//! with every slot FAT allows in use. A directory holds at most 65536
+//! entries, so that one stands in for the 100k entries flat directory.
+//! Also many read-only commands on one image at once.
+
+mod common;
+
+use std::fs;
+use std::thread;
+
+use common::{dir_entry, Image};
+
+const DEPTH: usize = 1000;
+
+/// Threads and commands each runs for `parallel_readers`
+const READERS: usize = 8;
+const ROUNDS: usize = 10;
+
+/// Slots of the full directory, `.` and `..` included
+const SLOTS: usize = 65536;
+
+/// `/D/D/.../D`, `DEPTH` directories with nothing at the bottom
+fn deep(name: &str) -> Image {
+    let image = Image::new(name, "8M");
+    let tree = image.host_path("tree");
+    fs::create_dir_all(tree.join(["D"; DEPTH].join("/"))).unwrap();
+    image.ok(&["write-tree", tree.to_str().unwrap()]);
+    image
+}
+
+/// `/FLAT` with the empty files `F00000.TXT` and up in all of its slots.
+/// Writing them one by one would take long, so the slots are written as
+/// the contents of a file that is then turned into the directory.
+fn flat(name: &str) -> Image {
+    let image = Image::new(name, "16M");
+    let mut slots = Vec::with_capacity(SLOTS * 32);
+    slots.extend(dir_entry(b".          ", 0x10, 0, 0));
+    slots.extend(dir_entry(b"..         ", 0x10, 0, 0));
+    for i in 0..SLOTS - 2 {
+        let name = format!("F{:05}  TXT", i);
+        slots.extend(dir_entry(name.as_bytes().try_into().unwrap(), 0x20, 0, 0));
+    }
+    image.write("/FLAT.BIN", &slots);
+
+    let entry = image.root_entry(b"FLAT    BIN");
+    let cluster = image.first_cluster(entry);
+    image.patch(entry, &dir_entry(b"FLAT       ", 0x10, cluster, 0));
+    let dot = image.boot().cluster_offset(cluster);
+    image.patch(dot, &dir_entry(b".          ", 0x10, cluster, 0));
+    image
+}
+
+#[test]
+fn deep_chain() {
+    let image = deep("deep");
+    let bottom = format!("/{}", ["D"; DEPTH].join("/"));
+
+    let ls = image.ok(&["ls", "-r", "/"]);
+    assert_eq!(ls.lines().count(), DEPTH);
+    assert_eq!(
+        ls.lines().last(),
+        Some(&*format!("{}D/", "  ".repeat(DEPTH - 1)))
+    );
+    assert_eq!(
+        image.ok(&["ls", "-r", "--count", "/"]).trim(),
+        DEPTH.to_string()
+    );
+    let jsonl = image.ok(&["ls", "-r", "--jsonl", "/"]);
+    assert!(jsonl.lines().last().unwrap().contains(&bottom), "{}", jsonl);
+
+    let find = image.ok(&["find", "/"]);
+    assert_eq!(find.lines().count(), DEPTH);
+    assert!(find.lines().any(|l| l == bottom));
+
+    let du = image.ok(&["du", "/"]);
+    let dirs: Vec<&str> = du
+        .lines()
+        .filter_map(|l| Some(l.split_once('\t')?.1))
+        .collect();
+    assert_eq!(dirs.len(), DEPTH + 1);
+    assert_eq!(dirs[0], bottom);
+    assert_eq!(dirs[DEPTH], "/");
+
+    assert_eq!(image.ok(&["check"]), "no problems found\n");
+
+    image.ok(&["clean-empty", "/"]);
+    assert_eq!(image.ok(&["find", "/"]), "");
+    assert_eq!(image.ok(&["check"]), "no problems found\n");
+}
+
+#[test]
+fn full_directory() {
+    let image = flat("flat");
+    let files = SLOTS - 2;
+
+    assert_eq!(
+        image.ok(&["ls", "--count", "/FLAT"]).trim(),
+        files.to_string()
+    );
+    assert_eq!(
+        image.ok(&["ls", "-r", "--count", "/"]).trim(),
+        (files + 1).to_string()
+    );
+    let limited = image.ok(&["ls", "--jsonl", "--limit", "10", "/FLAT"]);
+    assert_eq!(limited.lines().count(), 10);
+    let jsonl = image.ok(&["ls", "--jsonl", "/FLAT"]);
+    assert_eq!(jsonl.lines().count(), files);
+    assert!(jsonl.lines().last().unwrap().contains("/FLAT/F65533.TXT"));
+
+    let find = image.ok(&["find", "/FLAT", "--type", "f"]);
+    assert_eq!(find.lines().count(), files);
+    assert_eq!(find.lines().next(), Some("/FLAT/F00000.TXT"));
+
+    let du = image.ok(&["du", "/"]);
+    let dirs: Vec<&str> = du
+        .lines()
+        .filter_map(|l| Some(l.split_once('\t')?.1))
+        .collect();
+    assert_eq!(dirs, ["/FLAT", "/"]);
+
+    assert_eq!(image.ok(&["check"]), "no problems found\n");
+    image.ok(&["clean-empty", "/"]);
+    assert_eq!(
+        image.ok(&["ls", "--count", "/FLAT"]).trim(),
+        files.to_string()
+    );
+}
+
+#[test]
+fn parallel_readers() {
+    let files: [(&str, &[u8]); 3] = [
+        ("/D/", b""),
+        ("/D/F.TXT", &[b'f'; 5000]),
+        ("/TOP.TXT", b"top"),
+    ];
+    let image = Image::with("parallel", &files);
+    let before = image.hash();
+    let info = image.ok(&["--output", "classic", "info"]);
+    let ls = image.ok(&["ls", "-r", "/"]);
+
+    thread::scope(|scope| {
+        for reader in 0..READERS {
+            let (image, info, ls) = (&image, &info, &ls);
+            scope.spawn(move || {
+                for round in 0..ROUNDS {
+                    // Every other round on a snapshot, which is named
+                    // after the process taking it
+                    let snapshot: &[&str] = if (reader + round).is_multiple_of(2) {
+                        &[]
+                    } else {
+                        &["--snapshot"]
+                    };
+                    let args = |rest: &[&'static str]| [snapshot, rest].concat();
+                    assert_eq!(&image.ok(&args(&["--output", "classic", "info"])), info);
+                    assert_eq!(&image.ok(&args(&["ls", "-r", "/"])), ls);
+                    let out = image.run(&args(&["read", "/D/F.TXT"]));
+                    assert!(out.status.success(), "{}", reader);
+                    assert_eq!(out.stdout, [b'f'; 5000]);
+                }
+            });
+        }
+    });
+    assert_eq!(image.hash(), before);
+    assert_eq!(image.ok(&["check"]), "no problems found\n");
+}
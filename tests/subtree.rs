@@ -0,0 +1,100 @@
+//! `--subtree` of the analysis commands: each takes it, looks only below
+//! it and fails the same way for paths that aren't directories
+
+mod common;
+
+use common::Image;
+
+const FILES: [(&str, &[u8]); 5] = [
+    ("/A/", b""),
+    ("/A/B/", b""),
+    ("/A/B/F.TXT", b"hello"),
+    ("/A/G.TXT", b"world!"),
+    ("/OTHER.TXT", &[b'x'; 5000]),
+];
+
+#[test]
+fn every_analysis_command_takes_it() {
+    let image = Image::with("takes", &FILES);
+
+    let du = image.ok(&["du", "--subtree", "/A"]);
+    assert_eq!(du, image.ok(&["du", "/A"]));
+    let dirs: Vec<&str> = du
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|l| l.1)
+        .collect();
+    assert_eq!(dirs, ["/A/B", "/A"], "{}", du);
+    let summary = image.ok(&["du", "--subtree", "/A/", "--summarize"]);
+    assert_eq!(summary.lines().count(), 1);
+    assert!(summary.ends_with("\t/A\n"), "{}", summary);
+
+    let find = image.ok(&["find", "--subtree", "/A"]);
+    assert_eq!(find, image.ok(&["find", "/A"]));
+    let mut found: Vec<&str> = find.lines().collect();
+    found.sort_unstable();
+    assert_eq!(found, ["/A/B", "/A/B/F.TXT", "/A/G.TXT"]);
+
+    let health = image.ok(&["health", "--subtree", "/A"]);
+    assert!(
+        health.contains("\"files\":2,\"directories\":1,"),
+        "{}",
+        health
+    );
+    assert!(
+        health.contains("\"deepest_path\":\"/A/B/F.TXT\",\"depth\":2,"),
+        "{}",
+        health
+    );
+    assert!(
+        health.contains("\"largest_file\":{\"path\":\"/A/G.TXT\",\"bytes\":6}"),
+        "{}",
+        health
+    );
+    let whole = image.ok(&["health"]);
+    assert!(
+        whole.contains("\"files\":3,\"directories\":2,"),
+        "{}",
+        whole
+    );
+}
+
+#[test]
+fn both_forms_at_once_are_refused() {
+    let image = Image::with("both", &FILES);
+    for command in ["du", "find"] {
+        let (code, stderr) = image.fails(&[command, "/A", "--subtree", "/A"]);
+        assert_eq!(code, 1);
+        assert!(
+            stderr.contains("either as an argument or as --subtree"),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn missing_paths_exit_with_2() {
+    let image = Image::with("missing", &FILES);
+    for command in ["du", "find", "health"] {
+        let (code, stderr) = image.fails(&[command, "--subtree", "/A/NOPE"]);
+        assert_eq!(code, 2, "{}: {}", command, stderr);
+        let (code, stderr) = image.fails(&[command, "--subtree", "/NOPE/C"]);
+        assert_eq!(code, 2, "{}: {}", command, stderr);
+    }
+}
+
+#[test]
+fn paths_through_files_exit_with_2() {
+    let image = Image::with("file", &FILES);
+    for command in ["du", "find", "health"] {
+        let (code, stderr) = image.fails(&[command, "--subtree", "/A/G.TXT/C"]);
+        assert_eq!(code, 2, "{}: {}", command, stderr);
+        assert!(
+            stderr.contains("path component /A/G.TXT is a file, not a directory"),
+            "{}: {}",
+            command,
+            stderr
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! `ls --newer-than`/`--older-than`, `find --newer`/`--older` and
+//! `prune --older-than` with durations before a fixed `--now`, across
+//! FAT's 2 second steps
+
+mod common;
+
+use common::Image;
+
+const FILES: [(&str, &[u8]); 5] = [
+    ("/LOGS/", b""),
+    ("/LOGS/F0.LOG", b"log"),
+    ("/LOGS/F2.LOG", b"log"),
+    ("/LOGS/F4.LOG", b"log"),
+    ("/LOGS/F60.LOG", b"log"),
+];
+
+/// 0, 2, 4 and 60 seconds before 2024-05-01T12:00:00
+const MTIMES: [(&str, &str); 4] = [
+    ("/LOGS/F0.LOG", "2024-05-01T12:00:00"),
+    ("/LOGS/F2.LOG", "2024-05-01T11:59:58"),
+    ("/LOGS/F4.LOG", "2024-05-01T11:59:56"),
+    ("/LOGS/F60.LOG", "2024-05-01T11:59:00"),
+];
+
+/// Names `ls --jsonl` lists in `/LOGS` at `now` with `filter`
+fn ls(image: &Image, now: &str, filter: &[&str]) -> Vec<String> {
+    let out = image.ok(&[
+        &["--now", now, "--tz", "Z", "ls", "/LOGS", "--jsonl"],
+        filter,
+    ]
+    .concat());
+    let mut names: Vec<String> = out
+        .lines()
+        .filter_map(|line| common::json_str(line, "name"))
+        .map(str::to_owned)
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+/// Names `find` lists in `/LOGS` at `now` with `filter`
+fn find(image: &Image, now: &str, filter: &[&str]) -> Vec<String> {
+    let out = image.ok(&[
+        &["--now", now, "--tz", "Z", "find", "/LOGS", "--type", "f"],
+        filter,
+    ]
+    .concat());
+    let mut names: Vec<String> = out
+        .lines()
+        .map(|line| line.rsplit('/').next().unwrap().to_owned())
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+const NOON: &str = "2024-05-01T12:00:00Z";
+
+#[test]
+fn cutoffs_are_exclusive() {
+    let image = Image::with("exclusive", &FILES).mtimes(&MTIMES);
+    assert_eq!(ls(&image, NOON, &["--newer-than", "2s"]), ["F0.LOG"]);
+    assert_eq!(
+        ls(&image, NOON, &["--older-than", "2s"]),
+        ["F4.LOG", "F60.LOG"]
+    );
+    assert_eq!(
+        ls(&image, NOON, &["--newer-than", "1m"]),
+        ["F0.LOG", "F2.LOG", "F4.LOG"]
+    );
+    assert!(ls(&image, NOON, &["--older-than", "1m"]).is_empty());
+    assert_eq!(
+        ls(&image, NOON, &["--newer-than", "1m", "--older-than", "2s"]),
+        ["F4.LOG"]
+    );
+}
+
+#[test]
+fn odd_seconds_round_down_to_a_step() {
+    let image = Image::with("odd", &FILES).mtimes(&MTIMES);
+    // 3 seconds before noon is in the step starting at 11:59:56
+    assert_eq!(
+        ls(&image, NOON, &["--newer-than", "3s"]),
+        ["F0.LOG", "F2.LOG"]
+    );
+    assert_eq!(ls(&image, NOON, &["--older-than", "3s"]), ["F60.LOG"]);
+
+    let odd_now = "2024-05-01T12:00:01Z";
+    assert_eq!(ls(&image, odd_now, &["--newer-than", "2s"]), ["F0.LOG"]);
+    assert_eq!(
+        ls(&image, odd_now, &["--newer-than", "1s"]),
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        ls(&image, odd_now, &["--older-than", "1s"]),
+        ["F2.LOG", "F4.LOG", "F60.LOG"]
+    );
+    assert_eq!(
+        ls(&image, odd_now, &["--newer-than", "1m1s"]),
+        ["F0.LOG", "F2.LOG", "F4.LOG"]
+    );
+}
+
+#[test]
+fn the_same_in_every_command() {
+    let image = Image::with("same", &FILES).mtimes(&MTIMES);
+    for (ls_flag, find_flag) in [("--newer-than", "--newer"), ("--older-than", "--older")] {
+        for value in [
+            "2s",
+            "3s",
+            "1m",
+            "1d",
+            "@1714564796",
+            "2024-05-01T11:59:57Z",
+            "2024-05-01",
+        ] {
+            assert_eq!(
+                ls(&image, NOON, &[ls_flag, value]),
+                find(&image, NOON, &[find_flag, value]),
+                "{} {}",
+                ls_flag,
+                value
+            );
+        }
+    }
+
+    // Prune lists what it would delete on stderr
+    let out = image.run(&[
+        "--now",
+        NOON,
+        "--tz",
+        "Z",
+        "prune",
+        "/LOGS",
+        "--older-than",
+        "3s",
+        "--dry-run",
+    ]);
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("would delete /LOGS/F60.LOG"), "{}", stderr);
+    for kept in ["F0.LOG", "F2.LOG", "F4.LOG"] {
+        assert!(!stderr.contains(kept), "{}", stderr);
+    }
+}
+
+#[test]
+fn invalid_durations_are_usage_errors() {
+    let image = Image::with("invalid", &FILES).mtimes(&MTIMES);
+    for value in ["30", "1mo", "1h1d"] {
+        let (code, stderr) = image.fails(&["ls", "/LOGS", "--newer-than", value]);
+        assert_eq!(code, 1, "{}", value);
+        assert!(stderr.contains(&format!("{:?}", value)), "{}", stderr);
+    }
+}
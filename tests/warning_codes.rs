@@ -0,0 +1,102 @@
+//! Warning codes on stderr, in the grouped summary and in `--report`, and
+//! `--fail-on-warning` with and without codes
+
+mod common;
+
+use std::fs;
+
+use common::Image;
+
+/// Four `.nfs` sidecars, which `write-tree` skips with W010
+const TREE: [(&str, &[u8]); 5] = [
+    ("KEEP.TXT", b"keep"),
+    (".nfs1", b""),
+    (".nfs2", b""),
+    (".nfs3", b""),
+    (".nfs4", b""),
+];
+
+#[test]
+fn printed_and_reported_with_codes() {
+    let image = Image::new("printed", "4M");
+    let tree = image.host_tree("tree", &TREE);
+    let report = image.host_path("report.json");
+    let out = image.run(&["--report", report.to_str().unwrap(), "write-tree", &tree]);
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    let shown = stderr
+        .lines()
+        .filter(|l| l.starts_with("Warning [W010]: Not copying a metadata sidecar: "))
+        .count();
+    assert_eq!(shown, 3, "{}", stderr);
+    assert!(
+        stderr.contains("Warning [W010]: more metadata sidecar files skipped follow"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("W010 metadata sidecar files skipped: 4 (first: "),
+        "{}",
+        stderr
+    );
+    for line in stderr.lines().filter(|l| l.starts_with("Warning")) {
+        assert!(line.starts_with("Warning [W"), "{}", line);
+    }
+
+    let report = fs::read_to_string(report).unwrap();
+    assert_eq!(
+        report.matches("{\"code\":\"W010\",\"message\":").count(),
+        4,
+        "{}",
+        report
+    );
+    assert_eq!(image.read("/KEEP.TXT"), b"keep");
+}
+
+#[test]
+fn fail_on_warning() {
+    let image = Image::new("fail", "4M");
+    let tree = image.host_tree("tree", &TREE);
+    let (code, stderr) = image.fails(&["--fail-on-warning", "write-tree", &tree]);
+    assert_eq!(code, 1);
+    assert!(
+        stderr.contains("Failing for --fail-on-warning, warnings given: W010"),
+        "{}",
+        stderr
+    );
+    // The command still ran to the end
+    assert_eq!(image.read("/KEEP.TXT"), b"keep");
+
+    let (_, stderr) = image.fails(&["--fail-on-warning=W001,w010", "write-tree", &tree]);
+    assert!(stderr.contains("warnings given: W010"), "{}", stderr);
+    image.ok(&["--fail-on-warning=W001", "write-tree", &tree]);
+    image.ok(&["--fail-on-warning", "ls"]);
+
+    let (code, stderr) = image.fails(&["--fail-on-warning=W999", "ls"]);
+    assert_eq!(code, 1);
+    assert!(
+        stderr.contains("Unknown warning code \"W999\""),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn warnings_as_errors_is_bare_fail_on_warning() {
+    let mut runs = Vec::new();
+    for flag in ["--warnings-as-errors", "--fail-on-warning"] {
+        let image = Image::new(&format!("as-errors{}", runs.len()), "4M");
+        let tree = image.host_tree("tree", &TREE);
+        let (code, stderr) = image.fails(&[flag, "write-tree", &tree]);
+        assert!(
+            stderr.contains("Failing for --fail-on-warning, warnings given: W010"),
+            "{}",
+            stderr
+        );
+        // The command still ran to the end
+        assert_eq!(image.read("/KEEP.TXT"), b"keep");
+        image.ok(&[flag, "ls"]);
+        runs.push(code);
+    }
+    assert_eq!(runs[0], runs[1]);
+}